@@ -0,0 +1,122 @@
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use tc_core::database::{DatabaseHandle, PoolConfig, QueryParam, SqlType};
+use tokio::runtime::Runtime;
+
+// Needs a database to write to, the benchmark is skipped without one
+const DATABASE_URL_VAR: &str = "TC_BENCH_DATABASE_URL";
+const ROWS: usize = 1000;
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS tc_bench_spawns (guid BIGINT NOT NULL, entry INTEGER NOT NULL, x REAL NOT NULL, y REAL NOT NULL, z REAL NOT NULL)";
+const INSERT: &str =
+    "INSERT INTO tc_bench_spawns (guid, entry, x, y, z) VALUES ($1, $2, $3, $4, $5)";
+const COPY_IN: &str = "COPY tc_bench_spawns (guid, entry, x, y, z) FROM STDIN (FORMAT binary)";
+const COPY_OUT: &str = "COPY tc_bench_spawns (guid, entry, x, y, z) TO STDOUT (FORMAT binary)";
+const COPY_TYPES: &[SqlType] = &[
+    SqlType::INT8,
+    SqlType::INT4,
+    SqlType::FLOAT4,
+    SqlType::FLOAT4,
+    SqlType::FLOAT4,
+];
+
+type Spawn = (i64, i32, f32, f32, f32);
+
+fn spawns() -> Vec<Spawn> {
+    (0..ROWS)
+        .map(|i| (i as i64, (i % 50) as i32, i as f32, i as f32 * 0.5, 10.0))
+        .collect()
+}
+
+async fn reset(db: &DatabaseHandle) {
+    db.execute("TRUNCATE tc_bench_spawns", &[]).await.unwrap();
+}
+
+fn bench_database(c: &mut Criterion) {
+    let Ok(url) = std::env::var(DATABASE_URL_VAR) else {
+        eprintln!(
+            "{} is not set, skipping database benchmarks",
+            DATABASE_URL_VAR
+        );
+        return;
+    };
+
+    let runtime = Runtime::new().unwrap();
+    let db = runtime.block_on(async {
+        let db = DatabaseHandle::connect(PoolConfig {
+            connection_string: url,
+            min_connections: 1,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        db.execute(CREATE_TABLE, &[]).await.unwrap();
+        db
+    });
+
+    let rows = spawns();
+    let mut group = c.benchmark_group("database_insert_1000");
+    group.sample_size(10);
+
+    group.bench_function("execute_per_row", |b| {
+        b.iter_batched(
+            || runtime.block_on(reset(&db)),
+            |_| {
+                runtime.block_on(async {
+                    for (guid, entry, x, y, z) in &rows {
+                        db.execute(INSERT, &[guid, entry, x, y, z]).await.unwrap();
+                    }
+                })
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.bench_function("execute_batch", |b| {
+        b.iter_batched(
+            || runtime.block_on(reset(&db)),
+            |_| {
+                runtime.block_on(async {
+                    let params = rows
+                        .iter()
+                        .map(|(guid, entry, x, y, z)| [guid as &QueryParam, entry, x, y, z])
+                        .collect::<Vec<_>>();
+                    let statements = params.iter().map(|p| (INSERT, &p[..])).collect::<Vec<_>>();
+
+                    db.execute_batch(&statements).await.unwrap();
+                })
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.bench_function("copy_in", |b| {
+        b.iter_batched(
+            || runtime.block_on(reset(&db)),
+            |_| {
+                runtime.block_on(async {
+                    db.copy_in(COPY_IN, COPY_TYPES, rows.iter().copied())
+                        .await
+                        .unwrap();
+                })
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.bench_function("copy_out", |b| {
+        b.iter(|| {
+            runtime.block_on(async { db.copy_out::<Spawn>(COPY_OUT, COPY_TYPES).await.unwrap() })
+        })
+    });
+
+    group.finish();
+
+    runtime.block_on(async {
+        db.execute("DROP TABLE tc_bench_spawns", &[]).await.unwrap();
+        db.shutdown().await;
+    });
+}
+
+criterion_group!(benches, bench_database);
+criterion_main!(benches);
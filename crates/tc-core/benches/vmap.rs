@@ -0,0 +1,177 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::{hint::black_box, path::PathBuf};
+use tc_core::{
+    files::vmap::{
+        VmapGroup, VmapModel, VmapModelKind, VmapPlacement, VmapPlacementSet, vmap_map_file_name,
+        vmap_tile_file_name,
+    },
+    math::{Aabb, Mat3, Vec3},
+    vmap::VmapManager,
+};
+
+const MAP_ID: u32 = 1;
+const GRID: usize = 32;
+const CELL: f32 = 1.0;
+
+// Uneven 32x32 floor with a block in the middle, roughly the triangle
+// density of a small building
+fn building() -> VmapModel {
+    let mut vertices = Vec::new();
+    for y in 0..=GRID {
+        for x in 0..=GRID {
+            let height = ((x as f32 * 0.7).sin() + (y as f32 * 0.3).cos()) * 0.5;
+            vertices.push(Vec3::new(x as f32 * CELL, y as f32 * CELL, height));
+        }
+    }
+
+    let mut triangles = Vec::new();
+    for y in 0..GRID as u32 {
+        for x in 0..GRID as u32 {
+            let i = y * (GRID as u32 + 1) + x;
+            let row = GRID as u32 + 1;
+            triangles.push([i, i + 1, i + row + 1]);
+            triangles.push([i, i + row + 1, i + row]);
+        }
+    }
+
+    let base = vertices.len() as u32;
+    let block = Aabb::new(Vec3::new(12.0, 12.0, 0.0), Vec3::new(20.0, 20.0, 8.0));
+    vertices.extend(block.corners());
+    for [a, b, c] in [
+        [0, 1, 3],
+        [0, 3, 2],
+        [4, 5, 7],
+        [4, 7, 6],
+        [0, 1, 5],
+        [0, 5, 4],
+        [2, 3, 7],
+        [2, 7, 6],
+        [0, 2, 6],
+        [0, 6, 4],
+        [1, 3, 7],
+        [1, 7, 5],
+    ] {
+        triangles.push([base + a, base + b, base + c]);
+    }
+
+    let bounds = Aabb::from_points(vertices.iter().copied());
+    VmapModel {
+        kind: VmapModelKind::Wmo,
+        flags: 0,
+        root_id: 1,
+        bounds,
+        groups: vec![VmapGroup {
+            flags: 0x2000,
+            group_id: 1,
+            bounds,
+            vertices,
+            triangles,
+            liquid: None,
+        }],
+    }
+}
+
+// One tile covered by a 12x12 grid of buildings with random yaw
+fn setup() -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("tc-vmap-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+
+    let model = building();
+    std::fs::write(directory.join("building.vmo"), model.write()).unwrap();
+    std::fs::write(
+        directory.join(vmap_map_file_name(MAP_ID)),
+        VmapPlacementSet::default().write(),
+    )
+    .unwrap();
+
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut placements = Vec::new();
+    for y in 0..12 {
+        for x in 0..12 {
+            let position = Vec3::new(20.0 + x as f32 * 42.0, 20.0 + y as f32 * 42.0, 0.0);
+            let rotation = Mat3::rotation_z(rng.random_range(0.0..std::f32::consts::TAU));
+            placements.push(VmapPlacement {
+                id: placements.len() as u64,
+                kind: VmapModelKind::Wmo,
+                flags: 0,
+                name_set: 0,
+                model: "building.vmo".to_string(),
+                position,
+                rotation,
+                scale: 1.0,
+                bounds: model.bounds.transform(&rotation, 1.0, position),
+            });
+        }
+    }
+
+    std::fs::write(
+        directory.join(vmap_tile_file_name(MAP_ID, 31, 31)),
+        VmapPlacementSet { placements }.write(),
+    )
+    .unwrap();
+
+    directory
+}
+
+fn random_point(rng: &mut StdRng) -> Vec3 {
+    Vec3::new(
+        rng.random_range(10.0..520.0),
+        rng.random_range(10.0..520.0),
+        rng.random_range(0.5..10.0),
+    )
+}
+
+fn bench_vmap(c: &mut Criterion) {
+    let directory = setup();
+    let vmaps = VmapManager::new(&directory);
+    vmaps.load_tile(MAP_ID, 31, 31).unwrap();
+
+    let mut rng = StdRng::seed_from_u64(2);
+    let segments = (0..1024)
+        .map(|_| {
+            let a = random_point(&mut rng);
+            let offset = Vec3::new(
+                rng.random_range(-40.0..40.0),
+                rng.random_range(-40.0..40.0),
+                0.0,
+            );
+            (a, a + offset)
+        })
+        .collect::<Vec<_>>();
+    let points = (0..1024)
+        .map(|_| random_point(&mut rng))
+        .collect::<Vec<_>>();
+
+    let mut i = 0;
+    c.bench_function("vmap_line_of_sight", |b| {
+        b.iter(|| {
+            let (a, b) = segments[i % segments.len()];
+            i += 1;
+            black_box(vmaps.is_in_line_of_sight(MAP_ID, a, b))
+        })
+    });
+
+    let mut i = 0;
+    c.bench_function("vmap_get_height", |b| {
+        b.iter(|| {
+            let p = points[i % points.len()];
+            i += 1;
+            black_box(vmaps.get_height(MAP_ID, p.x, p.y, p.z, 50.0))
+        })
+    });
+
+    let mut i = 0;
+    c.bench_function("vmap_get_area_info", |b| {
+        b.iter(|| {
+            let p = points[i % points.len()];
+            i += 1;
+            black_box(vmaps.get_area_info(MAP_ID, p.x, p.y, p.z))
+        })
+    });
+
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+criterion_group!(benches, bench_vmap);
+criterion_main!(benches);
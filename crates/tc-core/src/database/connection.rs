@@ -1,14 +1,16 @@
 use crate::database::{
-    ConnectionPool, Result, SqlError, SqlResultExt,
+    ConnectionPool, Result, SqlError, SqlResultExt, StatementDef,
     cache::{CacheStats, PreparedStatementCache},
+    statements::find_statement,
 };
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 use tokio::time::{Instant, timeout};
 use tokio_postgres::{Client, Statement};
 
 pub struct PooledConnection {
     pub client: Client,
     cache: PreparedStatementCache,
+    pub(crate) statements: HashMap<&'static str, Statement>,
     created_at: Instant,
     last_used: Instant,
 }
@@ -19,6 +21,7 @@ impl PooledConnection {
         Self {
             client,
             cache: PreparedStatementCache::new(cache_capacity),
+            statements: HashMap::new(),
             created_at: now,
             last_used: now,
         }
@@ -51,6 +54,34 @@ impl PooledConnection {
         Ok(stmt)
     }
 
+    pub async fn prepare_statements(
+        &mut self,
+        statements: &[StatementDef],
+        query_timeout: Duration,
+    ) -> Result<()> {
+        for def in statements {
+            let stmt = timeout(
+                query_timeout,
+                self.client.prepare_typed(def.sql, def.params),
+            )
+            .await
+            .map_err(|_| SqlError::new(super::SqlErrorKind::Timeout, "Prepare statement timedout"))?
+            .sql_err(super::SqlErrorKind::Query)
+            .map_err(|e| {
+                e.query(def.sql)
+                    .context(format!("Failed to prepare statement {}", def.name))
+            })?;
+
+            self.statements.insert(def.name, stmt);
+        }
+
+        Ok(())
+    }
+
+    pub fn statement(&self, def: &StatementDef) -> Result<Statement> {
+        find_statement(&self.statements, def)
+    }
+
     pub fn cache_states(&self) -> CacheStats {
         self.cache.stats()
     }
@@ -83,9 +114,19 @@ impl<'a> ConnectionGuard<'a> {
         &mut self.conn.as_mut().unwrap().client
     }
 
+    pub fn conn(&self) -> &PooledConnection {
+        self.conn.as_ref().unwrap()
+    }
+
     pub fn conn_mut(&mut self) -> &mut PooledConnection {
         self.conn.as_mut().unwrap()
     }
+
+    pub(crate) fn discard(mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.discard_connection(conn);
+        }
+    }
 }
 
 impl Drop for ConnectionGuard<'_> {
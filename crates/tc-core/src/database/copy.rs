@@ -0,0 +1,67 @@
+use crate::database::{QueryParam, Result, SqlError, SqlErrorKind};
+use tokio_postgres::{
+    binary_copy::BinaryCopyOutRow,
+    types::{FromSql, ToSql},
+};
+
+// Row written with COPY ... FROM STDIN (FORMAT binary), values in the
+// order of the column types passed to copy_in
+pub trait CopyInRow {
+    fn copy_values(&self) -> Vec<&QueryParam>;
+}
+
+// Row read with COPY ... TO STDOUT (FORMAT binary)
+pub trait FromCopyRow: Sized {
+    fn from_copy_row(row: &BinaryCopyOutRow) -> Result<Self>;
+}
+
+impl CopyInRow for Vec<&QueryParam> {
+    fn copy_values(&self) -> Vec<&QueryParam> {
+        self.clone()
+    }
+}
+
+pub fn copy_column<T>(row: &BinaryCopyOutRow, index: usize) -> Result<T>
+where
+    T: for<'a> FromSql<'a>,
+{
+    row.try_get(index).map_err(|e| {
+        SqlError::with_source(SqlErrorKind::Query, e)
+            .context(format!("Failed to read copied column {}", index))
+    })
+}
+
+macro_rules! impl_copy_row_tuple {
+    ($($name: ident $index: tt),+) => {
+        impl<$($name),+> CopyInRow for ($($name,)+)
+        where
+            $($name: ToSql + Sync + 'static),+
+        {
+            fn copy_values(&self) -> Vec<&QueryParam> {
+                vec![$(&self.$index as &QueryParam),+]
+            }
+        }
+
+        impl<$($name),+> FromCopyRow for ($($name,)+)
+        where
+            $($name: for<'a> FromSql<'a>),+
+        {
+            fn from_copy_row(row: &BinaryCopyOutRow) -> Result<Self> {
+                Ok(($(copy_column::<$name>(row, $index)?,)+))
+            }
+        }
+    };
+}
+
+impl_copy_row_tuple!(A 0);
+impl_copy_row_tuple!(A 0, B 1);
+impl_copy_row_tuple!(A 0, B 1, C 2);
+impl_copy_row_tuple!(A 0, B 1, C 2, D 3);
+impl_copy_row_tuple!(A 0, B 1, C 2, D 3, E 4);
+impl_copy_row_tuple!(A 0, B 1, C 2, D 3, E 4, F 5);
+impl_copy_row_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_copy_row_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+impl_copy_row_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8);
+impl_copy_row_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9);
+impl_copy_row_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10);
+impl_copy_row_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11);
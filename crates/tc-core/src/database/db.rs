@@ -1,32 +1,61 @@
 use crate::database::{
-    ConnectionPool, ConnectionPoolStats, PoolConfig, Result, SqlError, SqlErrorKind, SqlResultExt,
-    TransactionContext, cache::PreparedStatementCache,
+    AdvisoryLock, ConnectionPool, ConnectionPoolStats, CopyInRow, FromCopyRow, FromRow,
+    MetricsSnapshot, NotificationListener, NotificationStream, PoolConfig, QueueStats,
+    QueuedOperation, Result, RetryPolicy, SqlError, SqlErrorKind, SqlResultExt, StatementSet,
+    TransactionContext, TransactionOptions, WriteQueue,
+    cache::PreparedStatementCache,
+    metrics::render_prometheus,
+    row::{rows_as, single_row, single_row_as},
 };
-use futures::FutureExt;
+use futures::{FutureExt, TryStreamExt, future::try_join_all, pin_mut};
 use std::{panic::AssertUnwindSafe, sync::Arc, time::Duration};
-use tokio::{sync::RwLock, time::timeout};
-use tokio_postgres::{Row, types::ToSql};
+use tokio::{
+    sync::RwLock,
+    time::{Instant, timeout},
+};
+use tokio_postgres::{
+    Row,
+    binary_copy::{BinaryCopyInWriter, BinaryCopyOutStream},
+    types::{ToSql, Type},
+};
 
 pub type QueryParam = dyn ToSql + Sync;
 
+pub(crate) const NOTIFY_SQL: &str = "SELECT pg_notify($1, $2)";
+
 pub struct DatabaseHandle {
     pool: Arc<ConnectionPool>,
+    queue: WriteQueue,
+    listener: NotificationListener,
     query_timeout: Duration,
 }
 
 impl DatabaseHandle {
     pub async fn connect(config: PoolConfig) -> Result<Self> {
-        let timeout = config.query_timeout;
         let pool = ConnectionPool::new(config).await?;
+        Ok(Self::with_pool(pool))
+    }
 
-        Ok(Self {
+    // For databases that may be down at startup, like a replica. No
+    // connection is opened until the handle is first used
+    pub fn connect_lazy(config: PoolConfig) -> Result<Self> {
+        let pool = ConnectionPool::new_lazy(config)?;
+        Ok(Self::with_pool(pool))
+    }
+
+    fn with_pool(pool: Arc<ConnectionPool>) -> Self {
+        Self {
+            queue: WriteQueue::new(pool.clone()),
+            listener: NotificationListener::new(pool.clone()),
+            query_timeout: pool.config.query_timeout,
             pool,
-            query_timeout: timeout,
-        })
+        }
     }
 
+    // Retried as a read with PoolConfig::query_retry, use execute or a
+    // transaction for writes that must not run twice
     pub async fn query(&self, sql: &str, params: &[&QueryParam]) -> Result<Vec<Row>> {
-        self.with_panic_recovery(sql, async {
+        self.with_retry(&self.pool.config.query_retry, sql, || async move {
             let mut conn = self.pool.acquire().await?;
             let stmt = conn
                 .conn_mut()
@@ -48,17 +77,7 @@ impl DatabaseHandle {
 
     pub async fn query_single(&self, sql: &str, params: &[&QueryParam]) -> Result<Row> {
         let rows = self.query(sql, params).await?;
-        match rows.len() {
-            0 => Err(
-                SqlError::new(SqlErrorKind::Query, "Expected a single row, got none").query(sql),
-            ),
-            1 => Ok(rows.into_iter().next().unwrap()),
-            n => Err(SqlError::new(
-                SqlErrorKind::Query,
-                format!("Expected a single row, got {}", n),
-            )
-            .query(sql)),
-        }
+        single_row(rows, sql)
     }
 
     pub async fn query_scalar<T>(&self, sql: &str, params: &[&QueryParam]) -> Result<T>
@@ -73,8 +92,27 @@ impl DatabaseHandle {
         })
     }
 
+    pub async fn query_as<T: FromRow>(&self, sql: &str, params: &[&QueryParam]) -> Result<Vec<T>> {
+        let rows = self.query(sql, params).await?;
+        rows_as(&rows, sql)
+    }
+
+    pub async fn query_one_as<T: FromRow>(&self, sql: &str, params: &[&QueryParam]) -> Result<T> {
+        let row = self.query_single(sql, params).await?;
+        T::from_row(&row).map_err(|e| e.query(sql))
+    }
+
+    pub async fn query_opt_as<T: FromRow>(
+        &self,
+        sql: &str,
+        params: &[&QueryParam],
+    ) -> Result<Option<T>> {
+        let rows = self.query(sql, params).await?;
+        single_row_as(rows, sql)
+    }
+
     pub async fn execute(&self, sql: &str, params: &[&QueryParam]) -> Result<u64> {
-        self.with_panic_recovery(sql, async {
+        self.with_retry(&self.pool.config.write_retry, sql, || async move {
             let mut conn = self.pool.acquire().await?;
             let stmt = conn
                 .conn_mut()
@@ -92,8 +130,184 @@ impl DatabaseHandle {
         .await
     }
 
+    // Sends every statement on one connection without waiting for the
+    // previous result, returning the affected row counts in order. The
+    // statements are not atomic, run them in a transaction for that. Never
+    // retried, statements before a failure may already have committed
+    pub async fn execute_batch(&self, statements: &[(&str, &[&QueryParam])]) -> Result<Vec<u64>> {
+        self.instrumented("BATCH", async {
+            let mut conn = self.pool.acquire().await?;
+            let mut prepared = Vec::with_capacity(statements.len());
+            for (sql, _) in statements {
+                prepared.push(
+                    conn.conn_mut()
+                        .prepare_cached(sql, self.query_timeout)
+                        .await?,
+                );
+            }
+
+            let client = conn.client();
+            let pending =
+                statements
+                    .iter()
+                    .zip(&prepared)
+                    .map(|((sql, params), stmt)| async move {
+                        client.execute(stmt, params).await.with_query(sql)
+                    });
+
+            timeout(self.query_timeout, try_join_all(pending))
+                .await
+                .map_err(|_| SqlError::new(SqlErrorKind::Timeout, "Batch execution timed out"))?
+                .map_err(|e| e.context("Batch execution failed"))
+        })
+        .await
+    }
+
+    // Streams rows into COPY ... FROM STDIN (FORMAT binary). Copies are not
+    // bound by the query timeout since they may move a lot of data
+    pub async fn copy_in<R: CopyInRow>(
+        &self,
+        sql: &str,
+        types: &[Type],
+        rows: impl IntoIterator<Item = R>,
+    ) -> Result<u64> {
+        self.instrumented(sql, async {
+            let conn = self.pool.acquire().await?;
+            let sink = conn
+                .client()
+                .copy_in(sql)
+                .await
+                .with_query(sql)
+                .map_err(|e| e.context("Failed to start copy in"))?;
+
+            let writer = BinaryCopyInWriter::new(sink, types);
+            pin_mut!(writer);
+            for row in rows {
+                writer
+                    .as_mut()
+                    .write(&row.copy_values())
+                    .await
+                    .with_query(sql)
+                    .map_err(|e| e.context("Failed to write copied row"))?;
+            }
+
+            writer
+                .finish()
+                .await
+                .with_query(sql)
+                .map_err(|e| e.context("Failed to finish copy in"))
+        })
+        .await
+    }
+
+    // Reads the rows of COPY ... TO STDOUT (FORMAT binary)
+    pub async fn copy_out<T: FromCopyRow>(&self, sql: &str, types: &[Type]) -> Result<Vec<T>> {
+        self.with_retry(&self.pool.config.query_retry, sql, || async move {
+            let conn = self.pool.acquire().await?;
+            let stream = conn
+                .client()
+                .copy_out(sql)
+                .await
+                .with_query(sql)
+                .map_err(|e| e.context("Failed to start copy out"))?;
+
+            let rows = BinaryCopyOutStream::new(stream, types);
+            pin_mut!(rows);
+
+            let mut result = Vec::new();
+            while let Some(row) = rows
+                .try_next()
+                .await
+                .with_query(sql)
+                .map_err(|e| e.context("Failed to read copied row"))?
+            {
+                result.push(T::from_copy_row(&row).map_err(|e| e.query(sql))?);
+            }
+
+            Ok(result)
+        })
+        .await
+    }
+
+    pub async fn query_statement<S: StatementSet>(
+        &self,
+        statement: S,
+        params: &[&QueryParam],
+    ) -> Result<Vec<Row>> {
+        let def = statement.definition();
+        self.with_retry(&self.pool.config.query_retry, def.sql, || async move {
+            let conn = self.pool.acquire().await?;
+            let stmt = conn.conn().statement(def)?;
+
+            timeout(self.query_timeout, conn.client().query(&stmt, params))
+                .await
+                .map_err(|_| {
+                    SqlError::new(SqlErrorKind::Timeout, "Statement query timed out").query(def.sql)
+                })?
+                .sql_err(SqlErrorKind::Query)
+                .map_err(|e| {
+                    e.query(def.sql)
+                        .context(format!("Statement {} failed", def.name))
+                })
+        })
+        .await
+    }
+
+    pub async fn query_statement_as<S: StatementSet, T: FromRow>(
+        &self,
+        statement: S,
+        params: &[&QueryParam],
+    ) -> Result<Vec<T>> {
+        let rows = self.query_statement(statement, params).await?;
+        rows_as(&rows, statement.definition().sql)
+    }
+
+    pub async fn query_statement_scalar<S: StatementSet, T>(
+        &self,
+        statement: S,
+        params: &[&QueryParam],
+    ) -> Result<T>
+    where
+        T: for<'a> tokio_postgres::types::FromSql<'a>,
+    {
+        let sql = statement.definition().sql;
+        let rows = self.query_statement(statement, params).await?;
+        let row = single_row(rows, sql)?;
+
+        row.try_get(0).map_err(|e| {
+            SqlError::with_source(SqlErrorKind::Query, e)
+                .query(sql)
+                .context("Failed to extract scalar value")
+        })
+    }
+
+    pub async fn execute_statement<S: StatementSet>(
+        &self,
+        statement: S,
+        params: &[&QueryParam],
+    ) -> Result<u64> {
+        let def = statement.definition();
+        self.with_retry(&self.pool.config.write_retry, def.sql, || async move {
+            let conn = self.pool.acquire().await?;
+            let stmt = conn.conn().statement(def)?;
+
+            timeout(self.query_timeout, conn.client().execute(&stmt, params))
+                .await
+                .map_err(|_| {
+                    SqlError::new(SqlErrorKind::Timeout, "Statement execution timed out")
+                        .query(def.sql)
+                })?
+                .sql_err(SqlErrorKind::Query)
+                .map_err(|e| {
+                    e.query(def.sql)
+                        .context(format!("Statement {} failed", def.name))
+                })
+        })
+        .await
+    }
+
     pub async fn query_unprepared(&self, sql: &str, params: &[&QueryParam]) -> Result<Vec<Row>> {
-        self.with_panic_recovery(sql, async {
+        self.with_retry(&self.pool.config.query_retry, sql, || async move {
             let conn = self.pool.acquire().await?;
             let stmt = timeout(self.query_timeout, conn.client().prepare(sql))
                 .await
@@ -110,45 +324,110 @@ impl DatabaseHandle {
         .await
     }
 
+    // The closure runs again from the start when the transaction fails with
+    // a transient error, so it must not have effects outside of it
     pub async fn transaction<F, T>(&self, f: F) -> Result<T>
     where
-        F: for<'c> AsyncFnOnce(TransactionContext<'c>) -> Result<T>,
+        F: for<'c> AsyncFnMut(TransactionContext<'c>) -> Result<T>,
     {
-        self.with_panic_recovery("TRANSACTION", async {
-            let mut conn = self.pool.acquire().await?;
-            let tx = conn
-                .client_mut()
-                .transaction()
+        self.transaction_with(TransactionOptions::default(), f)
+            .await
+    }
+
+    pub async fn transaction_with<F, T>(&self, options: TransactionOptions, mut f: F) -> Result<T>
+    where
+        F: for<'c> AsyncFnMut(TransactionContext<'c>) -> Result<T>,
+    {
+        let policy = &self.pool.config.transaction_retry;
+        let mut attempt = 1;
+        loop {
+            match self
+                .instrumented("TRANSACTION", self.run_transaction(&options, &mut f))
                 .await
-                .sql_err(SqlErrorKind::Transaction)
-                .map_err(|e| e.context("Failed to begin transaction"))?;
-
-            let tx_cache = RwLock::new(PreparedStatementCache::new(
-                self.pool.config.statement_cache_capacity,
-            ));
-
-            let ctx = TransactionContext {
-                tx: &tx,
-                cache: &tx_cache,
-                query_timeout: self.query_timeout,
-            };
-
-            match f(ctx).await {
-                Ok(result) => {
-                    tx.commit()
-                        .await
-                        .sql_err(SqlErrorKind::Transaction)
-                        .map_err(|e| e.context("Failed to commit transaction"))?;
-
-                    Ok(result)
+            {
+                Err(e) if policy.should_retry(&e, attempt) => {
+                    policy.wait("TRANSACTION", attempt, &e).await;
+                    attempt += 1;
                 }
-                Err(e) => Err(e.context("Transaction rolled back")),
+                result => return result,
             }
-        })
-        .await
+        }
+    }
+
+    async fn run_transaction<F, T>(&self, options: &TransactionOptions, f: &mut F) -> Result<T>
+    where
+        F: for<'c> AsyncFnMut(TransactionContext<'c>) -> Result<T>,
+    {
+        let mut guard = self.pool.acquire().await?;
+        let conn = guard.conn_mut();
+
+        let mut builder = conn
+            .client
+            .build_transaction()
+            .read_only(options.read_only)
+            .deferrable(options.deferrable);
+        if let Some(isolation) = options.isolation {
+            builder = builder.isolation_level(isolation);
+        }
+
+        let tx = builder
+            .start()
+            .await
+            .sql_err(SqlErrorKind::Transaction)
+            .map_err(|e| e.context("Failed to begin transaction"))?;
+
+        let tx_cache = RwLock::new(PreparedStatementCache::new(
+            self.pool.config.statement_cache_capacity,
+        ));
+
+        let ctx = TransactionContext {
+            tx: &tx,
+            cache: &tx_cache,
+            statements: &conn.statements,
+            query_timeout: self.query_timeout,
+            depth: 0,
+        };
+
+        match f(ctx).await {
+            Ok(result) => {
+                tx.commit()
+                    .await
+                    .sql_err(SqlErrorKind::Transaction)
+                    .map_err(|e| e.context("Failed to commit transaction"))?;
+
+                Ok(result)
+            }
+            Err(e) => Err(e.context("Transaction rolled back")),
+        }
+    }
+
+    // Hands the operation to the write-behind queue without waiting for it
+    pub fn enqueue(&self, operation: QueuedOperation) -> Result<()> {
+        self.queue.enqueue(operation)
+    }
+
+    // Waits up to the given time for the lock, which is held until the
+    // guard is released or dropped
+    pub async fn advisory_lock(&self, key: i64, wait: Duration) -> Result<AdvisoryLock> {
+        AdvisoryLock::acquire(&self.pool, key, wait).await
+    }
+
+    // Notifications on the channel, received on a dedicated connection that
+    // is not taken from the pool
+    pub async fn subscribe(&self, channel: &str) -> Result<NotificationStream> {
+        self.listener.subscribe(channel).await
+    }
+
+    pub async fn notify(&self, channel: &str, payload: &str) -> Result<()> {
+        let (channel, payload) = (channel.to_string(), payload.to_string());
+        self.execute(NOTIFY_SQL, &[&channel, &payload]).await?;
+        Ok(())
     }
 
+    // Queued operations are flushed before the pool closes
     pub async fn shutdown(&self) {
+        self.listener.shutdown();
+        self.queue.shutdown(self.pool.config.acquire_timeout).await;
         self.pool.shutdown().await;
     }
 
@@ -156,6 +435,47 @@ impl DatabaseHandle {
         self.pool.stats()
     }
 
+    pub fn queue_stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.pool.metrics.snapshot()
+    }
+
+    pub fn prometheus_metrics(&self) -> String {
+        render_prometheus(&self.metrics(), &self.stats(), &self.queue_stats())
+    }
+
+    async fn with_retry<F, Fut, T>(
+        &self,
+        policy: &RetryPolicy,
+        context: &str,
+        mut f: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        policy
+            .run(context, || self.instrumented(context, f()))
+            .await
+    }
+
+    // Every attempt is timed and counted on its own
+    async fn instrumented<F, T>(&self, context: &str, f: F) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>>,
+    {
+        let started = Instant::now();
+        let result = self.with_panic_recovery(context, f).await;
+        self.pool
+            .metrics
+            .record_query(context, started.elapsed(), result.as_ref().err());
+
+        result
+    }
+
     async fn with_panic_recovery<F, T>(&self, context: &str, f: F) -> Result<T>
     where
         F: std::future::Future<Output = Result<T>>,
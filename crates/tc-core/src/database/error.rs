@@ -1,3 +1,5 @@
+use tokio_postgres::error::SqlState;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SqlErrorKind {
     Connection,
@@ -47,6 +49,30 @@ impl SqlError {
         self
     }
 
+    // SQLSTATE the server answered with, when the error came from it
+    pub fn sqlstate(&self) -> Option<&SqlState> {
+        self.postgres_error().and_then(|e| e.code())
+    }
+
+    // Failures that can go away on their own: serialization failures,
+    // deadlocks and connections that were dropped or could not be opened
+    pub fn is_transient(&self) -> bool {
+        if let Some(state) = self.sqlstate() {
+            return *state == SqlState::T_R_SERIALIZATION_FAILURE
+                || *state == SqlState::T_R_DEADLOCK_DETECTED
+                || *state == SqlState::ADMIN_SHUTDOWN
+                || state.code().starts_with("08");
+        }
+
+        self.postgres_error().is_some_and(|e| e.is_closed())
+            || self.chain().any(|e| e.is::<std::io::Error>())
+    }
+
+    fn postgres_error(&self) -> Option<&tokio_postgres::Error> {
+        self.chain()
+            .find_map(|e| e.downcast_ref::<tokio_postgres::Error>())
+    }
+
     pub fn chain(&self) -> impl Iterator<Item = &(dyn std::error::Error + 'static)> {
         self.source.chain()
     }
@@ -55,6 +81,10 @@ impl SqlError {
 impl std::fmt::Display for SqlError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "[{:?}]", self.kind)?;
+        if let Some(state) = self.sqlstate() {
+            write!(f, " sqlstate={}", state.code())?;
+        }
+
         if let Some(q) = &self.query {
             let q = if q.len() > 10000 { &q[..10000] } else { q };
             write!(f, " query=\n{}\n", q)?;
@@ -0,0 +1,112 @@
+use crate::database::{Result, SqlError, SqlErrorKind};
+
+// Postgres silently truncates longer identifiers, so two long names could
+// end up naming the same object
+pub const MAX_IDENTIFIER_LEN: usize = 63;
+
+// Checks a name taken from configuration before it is used as an
+// identifier: not empty, no NUL byte and short enough to be kept whole
+pub fn validate_identifier(name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(SqlError::new(SqlErrorKind::Query, "Empty identifier"));
+    }
+
+    if name.contains('\0') {
+        return Err(SqlError::new(
+            SqlErrorKind::Query,
+            format!("Identifier {:?} contains a NUL byte", name),
+        ));
+    }
+
+    if name.len() > MAX_IDENTIFIER_LEN {
+        return Err(SqlError::new(
+            SqlErrorKind::Query,
+            format!(
+                "Identifier {:?} is longer than {} bytes",
+                name, MAX_IDENTIFIER_LEN
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+// Validates and double quotes an identifier for sql that can not take it
+// as a parameter, like table or database names. Quoted identifiers keep
+// their case, "Auth" and auth are different names
+pub fn quote_identifier(name: &str) -> Result<String> {
+    validate_identifier(name)?;
+    Ok(format!("\"{}\"", name.replace('"', "\"\"")))
+}
+
+// Quotes a table name that may be qualified by its schema, like
+// auth._migrations. Each part is quoted on its own, so names given this way
+// can not contain a dot themselves
+pub fn quote_qualified_name(name: &str) -> Result<String> {
+    let parts = name.split('.').collect::<Vec<_>>();
+    if parts.len() > 2 {
+        return Err(SqlError::new(
+            SqlErrorKind::Query,
+            format!("{:?} is not a table or schema.table name", name),
+        ));
+    }
+
+    let quoted = parts
+        .into_iter()
+        .map(quote_identifier)
+        .collect::<Result<Vec<_>>>()?;
+    Ok(quoted.join("."))
+}
+
+// Single quotes a string literal for utility statements that take no
+// parameters, like the options of CREATE DATABASE
+pub fn quote_literal(value: &str) -> Result<String> {
+    if value.contains('\0') {
+        return Err(SqlError::new(
+            SqlErrorKind::Query,
+            format!("Literal {:?} contains a NUL byte", value),
+        ));
+    }
+
+    Ok(format!("'{}'", value.replace('\'', "''")))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::{
+        quote_identifier, quote_literal, quote_qualified_name, validate_identifier,
+    };
+
+    #[test]
+    fn test_quote_identifier() {
+        assert_eq!(quote_identifier("_migrations").unwrap(), "\"_migrations\"");
+        assert_eq!(
+            quote_identifier("Realm Events").unwrap(),
+            "\"Realm Events\""
+        );
+        assert_eq!(
+            quote_identifier("auth\"; DROP TABLE account; --").unwrap(),
+            "\"auth\"\"; DROP TABLE account; --\""
+        );
+
+        assert!(quote_identifier("").is_err());
+        assert!(quote_identifier("a\0b").is_err());
+        assert!(validate_identifier(&"a".repeat(63)).is_ok());
+        assert!(validate_identifier(&"a".repeat(64)).is_err());
+
+        assert_eq!(
+            quote_qualified_name("_migrations").unwrap(),
+            "\"_migrations\""
+        );
+        assert_eq!(
+            quote_qualified_name("auth._migrations").unwrap(),
+            "\"auth\".\"_migrations\""
+        );
+        assert!(quote_qualified_name("a.b.c").is_err());
+        assert!(quote_qualified_name(".table").is_err());
+        assert!(quote_qualified_name("schema.").is_err());
+
+        assert_eq!(quote_literal("UTF8").unwrap(), "'UTF8'");
+        assert_eq!(quote_literal("it's").unwrap(), "'it''s'");
+    }
+}
@@ -0,0 +1,88 @@
+use crate::database::{ConnectionPool, Result, SqlError, SqlErrorKind, SqlResultExt};
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_postgres::Client;
+
+// How often a held lock is tried again while waiting for it
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+const TRY_LOCK_SQL: &str = "SELECT pg_try_advisory_lock($1)";
+const UNLOCK_SQL: &str = "SELECT pg_advisory_unlock($1)";
+const HOLDER_SQL: &str = r#"
+    SELECT l.pid, COALESCE(a.application_name, '')
+    FROM pg_locks l LEFT JOIN pg_stat_activity a ON a.pid = l.pid
+    WHERE l.locktype = 'advisory' AND l.granted
+        AND l.classid = $1 AND l.objid = $2 AND l.objsubid = 1
+        AND l.database = (SELECT oid FROM pg_database WHERE datname = current_database())
+"#;
+
+// Session level advisory lock held on a connection of its own. Postgres
+// drops the lock with that connection, so dropping the guard or a crashed
+// holder never leaves it behind
+pub struct AdvisoryLock {
+    key: i64,
+    client: Client,
+}
+
+impl AdvisoryLock {
+    pub(crate) async fn acquire(pool: &ConnectionPool, key: i64, wait: Duration) -> Result<Self> {
+        let client = pool.connect_client(None).await?;
+        let deadline = Instant::now() + wait;
+
+        loop {
+            let locked: bool = client
+                .query_one(TRY_LOCK_SQL, &[&key])
+                .await
+                .with_query(TRY_LOCK_SQL)?
+                .get(0);
+
+            if locked {
+                return Ok(Self { key, client });
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                let holder = match lock_holder(&client, key).await {
+                    Some((pid, app)) if !app.is_empty() => format!("pid {} ({})", pid, app),
+                    Some((pid, _)) => format!("pid {}", pid),
+                    None => "another session".to_string(),
+                };
+
+                return Err(SqlError::new(
+                    SqlErrorKind::Timeout,
+                    format!(
+                        "Advisory lock {} is held by {}, gave up after {:?}",
+                        key, holder, wait
+                    ),
+                ));
+            }
+
+            tokio::time::sleep(LOCK_POLL_INTERVAL.min(deadline - now)).await;
+        }
+    }
+
+    pub fn key(&self) -> i64 {
+        self.key
+    }
+
+    pub async fn release(self) -> Result<()> {
+        self.client
+            .query_one(UNLOCK_SQL, &[&self.key])
+            .await
+            .with_query(UNLOCK_SQL)?;
+
+        Ok(())
+    }
+}
+
+// Advisory locks on a bigint key show up in pg_locks split into two oids
+async fn lock_holder(client: &Client, key: i64) -> Option<(i32, String)> {
+    let classid = (key >> 32) as u32;
+    let objid = key as u32;
+    let row = client
+        .query_opt(HOLDER_SQL, &[&classid, &objid])
+        .await
+        .ok()??;
+
+    Some((row.get(0), row.get(1)))
+}
@@ -0,0 +1,468 @@
+use crate::database::{ConnectionPoolStats, QueueStats, SqlError, SqlErrorKind};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+// Upper bounds in seconds, the last bucket takes everything above
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// Statements beyond this share one histogram so ad hoc sql can not grow the
+// metrics without bound
+const MAX_TRACKED_STATEMENTS: usize = 256;
+const OTHER_STATEMENTS: &str = "other";
+
+// In the order of error_index
+const ERROR_KINDS: [SqlErrorKind; 8] = [
+    SqlErrorKind::Connection,
+    SqlErrorKind::Timeout,
+    SqlErrorKind::Query,
+    SqlErrorKind::Transaction,
+    SqlErrorKind::Pool,
+    SqlErrorKind::HealthCheck,
+    SqlErrorKind::Panic,
+    SqlErrorKind::Shutdown,
+];
+
+fn error_index(kind: SqlErrorKind) -> usize {
+    match kind {
+        SqlErrorKind::Connection => 0,
+        SqlErrorKind::Timeout => 1,
+        SqlErrorKind::Query => 2,
+        SqlErrorKind::Transaction => 3,
+        SqlErrorKind::Pool => 4,
+        SqlErrorKind::HealthCheck => 5,
+        SqlErrorKind::Panic => 6,
+        SqlErrorKind::Shutdown => 7,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HistogramSnapshot {
+    // Cumulative counts per upper bound in seconds, as prometheus expects
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl HistogramSnapshot {
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            n => self.sum.div_f64(n as f64),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsSnapshot {
+    // Keyed by sql text, or TRANSACTION/BATCH for grouped work
+    pub statements: Vec<(String, HistogramSnapshot)>,
+    pub acquire_wait: HistogramSnapshot,
+    pub errors: Vec<(SqlErrorKind, u64)>,
+    pub slow_queries: u64,
+}
+
+struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: (0..=DURATION_BUCKETS.len())
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut total = 0;
+        let buckets = DURATION_BUCKETS
+            .iter()
+            .zip(&self.buckets)
+            .map(|(&bound, count)| {
+                total += count.load(Ordering::Relaxed);
+                (bound, total)
+            })
+            .collect();
+
+        HistogramSnapshot {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+// Histograms by normalized sql, reached through the sql text as it was run
+// so the same statement only needs a shared lock after its first run
+#[derive(Default)]
+struct StatementHistograms {
+    by_sql: HashMap<String, Arc<Histogram>>,
+    by_label: HashMap<String, Arc<Histogram>>,
+}
+
+// Timings and error counts of everything run through a pool
+pub struct PoolMetrics {
+    statements: RwLock<StatementHistograms>,
+    acquire_wait: Histogram,
+    errors: [AtomicU64; ERROR_KINDS.len()],
+    slow_queries: AtomicU64,
+    slow_query_threshold: Option<Duration>,
+}
+
+impl PoolMetrics {
+    pub fn new(slow_query_threshold: Option<Duration>) -> Self {
+        Self {
+            statements: RwLock::default(),
+            acquire_wait: Histogram::new(),
+            errors: Default::default(),
+            slow_queries: AtomicU64::new(0),
+            slow_query_threshold,
+        }
+    }
+
+    pub fn record_query(&self, sql: &str, elapsed: Duration, error: Option<&SqlError>) {
+        if let Some(threshold) = self.slow_query_threshold
+            && elapsed >= threshold
+        {
+            let label = normalize_sql(sql);
+            self.slow_queries.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(
+                "Slow query {} took {:?}: {}",
+                statement_label(&label),
+                elapsed,
+                label
+            );
+        }
+
+        if let Some(error) = error {
+            self.record_error(error.kind);
+        }
+
+        self.histogram(sql).observe(elapsed);
+    }
+
+    fn histogram(&self, sql: &str) -> Arc<Histogram> {
+        if let Some(histogram) = self.statements.read().unwrap().by_sql.get(sql) {
+            return histogram.clone();
+        }
+
+        let label = normalize_sql(sql);
+        let mut statements = self.statements.write().unwrap();
+        let StatementHistograms { by_sql, by_label } = &mut *statements;
+        let key = if by_label.contains_key(&label) || by_label.len() < MAX_TRACKED_STATEMENTS {
+            label
+        } else {
+            OTHER_STATEMENTS.to_string()
+        };
+
+        let histogram = by_label
+            .entry(key)
+            .or_insert_with(|| Arc::new(Histogram::new()))
+            .clone();
+        if by_sql.len() < MAX_TRACKED_STATEMENTS {
+            by_sql.insert(sql.to_string(), histogram.clone());
+        }
+
+        histogram
+    }
+
+    pub fn record_acquire(&self, elapsed: Duration) {
+        self.acquire_wait.observe(elapsed);
+    }
+
+    pub fn record_error(&self, kind: SqlErrorKind) {
+        self.errors[error_index(kind)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let mut statements = self
+            .statements
+            .read()
+            .unwrap()
+            .by_label
+            .iter()
+            .map(|(sql, histogram)| (sql.clone(), histogram.snapshot()))
+            .collect::<Vec<_>>();
+        statements.sort_by(|a, b| a.0.cmp(&b.0));
+
+        MetricsSnapshot {
+            statements,
+            acquire_wait: self.acquire_wait.snapshot(),
+            errors: ERROR_KINDS
+                .iter()
+                .zip(&self.errors)
+                .map(|(&kind, count)| (kind, count.load(Ordering::Relaxed)))
+                .collect(),
+            slow_queries: self.slow_queries.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Prometheus text exposition of the pool, its metrics and its write queue
+pub fn render_prometheus(
+    metrics: &MetricsSnapshot,
+    pool: &ConnectionPoolStats,
+    queue: &QueueStats,
+) -> String {
+    let mut out = String::new();
+
+    write_header(
+        &mut out,
+        "tc_db_query_duration_seconds",
+        "histogram",
+        "Time spent running statements",
+    );
+    for (sql, histogram) in &metrics.statements {
+        let label = format!("statement=\"{}\"", escape_label(&statement_label(sql)));
+        write_histogram(&mut out, "tc_db_query_duration_seconds", &label, histogram);
+    }
+
+    write_header(
+        &mut out,
+        "tc_db_acquire_wait_seconds",
+        "histogram",
+        "Time spent waiting for a pooled connection",
+    );
+    write_histogram(
+        &mut out,
+        "tc_db_acquire_wait_seconds",
+        "",
+        &metrics.acquire_wait,
+    );
+
+    write_header(
+        &mut out,
+        "tc_db_errors_total",
+        "counter",
+        "Failed statements by error kind",
+    );
+    for (kind, count) in &metrics.errors {
+        let kind = format!("{:?}", kind).to_lowercase();
+        let _ = writeln!(out, "tc_db_errors_total{{kind=\"{}\"}} {}", kind, count);
+    }
+
+    let values: [(&str, &str, &str, f64); 8] = [
+        (
+            "tc_db_slow_queries_total",
+            "counter",
+            "Statements slower than the slow query threshold",
+            metrics.slow_queries as f64,
+        ),
+        (
+            "tc_db_connections_active",
+            "gauge",
+            "Connections currently handed out",
+            pool.active as f64,
+        ),
+        (
+            "tc_db_connections_created_total",
+            "counter",
+            "Connections opened by the pool",
+            pool.total_created as f64,
+        ),
+        (
+            "tc_db_statement_cache_hits_total",
+            "counter",
+            "Prepared statement cache hits",
+            pool.cache_hits as f64,
+        ),
+        (
+            "tc_db_statement_cache_misses_total",
+            "counter",
+            "Prepared statement cache misses",
+            pool.cache_misses as f64,
+        ),
+        (
+            "tc_db_queue_depth",
+            "gauge",
+            "Operations waiting in the write queue",
+            queue.depth as f64,
+        ),
+        (
+            "tc_db_queue_processed_total",
+            "counter",
+            "Write queue operations that succeeded",
+            queue.processed as f64,
+        ),
+        (
+            "tc_db_queue_failed_total",
+            "counter",
+            "Write queue operations that failed",
+            queue.failed as f64,
+        ),
+    ];
+
+    for (name, kind, help, value) in values {
+        write_header(&mut out, name, kind, help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+
+    out
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &str, histogram: &HistogramSnapshot) {
+    let separator = if labels.is_empty() { "" } else { "," };
+    for (bound, count) in &histogram.buckets {
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"{}\"}} {}",
+            name, labels, separator, bound, count
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+        name, labels, separator, histogram.count
+    );
+
+    let labels = if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    };
+
+    let _ = writeln!(
+        out,
+        "{}_sum{} {}",
+        name,
+        labels,
+        histogram.sum.as_secs_f64()
+    );
+    let _ = writeln!(out, "{}_count{} {}", name, labels, histogram.count);
+}
+
+// Runs of whitespace collapsed, so formatting does not split a statement
+fn normalize_sql(sql: &str) -> String {
+    sql.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Short stable name of a statement for metric labels, whole sql would make
+// huge series names. Grouped work like TRANSACTION keeps its name, sql is
+// hashed and the slow query log prints the hash next to the sql
+pub fn statement_label(sql: &str) -> String {
+    if !sql.contains(char::is_whitespace) {
+        return sql.to_string();
+    }
+
+    let digest = Sha1::digest(sql.as_bytes());
+    digest[..6].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::{
+        ConnectionPoolStats, PoolMetrics, QueueStats, SqlError, SqlErrorKind,
+        metrics::{ERROR_KINDS, error_index, escape_label, statement_label},
+        render_prometheus,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_pool_metrics() {
+        let metrics = PoolMetrics::new(Some(Duration::from_millis(100)));
+        metrics.record_query("SELECT  1", Duration::from_millis(2), None);
+        metrics.record_query("SELECT 1", Duration::from_millis(200), None);
+
+        let timeout = SqlError::new(SqlErrorKind::Timeout, "timed out");
+        metrics.record_query("SELECT 2", Duration::from_millis(30), Some(&timeout));
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.slow_queries, 1);
+        assert_eq!(snapshot.statements.len(), 2);
+
+        let (sql, first) = &snapshot.statements[0];
+        assert_eq!(sql, "SELECT 1");
+        assert_eq!(first.count, 2);
+        assert_eq!(first.sum, Duration::from_millis(202));
+        assert_eq!(first.buckets.iter().find(|b| b.0 == 0.0025).unwrap().1, 1);
+        assert_eq!(first.buckets.last().unwrap().1, 2);
+
+        let errors = snapshot
+            .errors
+            .iter()
+            .find(|(kind, _)| *kind == SqlErrorKind::Timeout)
+            .unwrap();
+        assert_eq!(errors.1, 1);
+
+        for (index, &kind) in ERROR_KINDS.iter().enumerate() {
+            assert_eq!(error_index(kind), index);
+        }
+    }
+
+    #[test]
+    fn test_render_prometheus() {
+        let metrics = PoolMetrics::new(None);
+        metrics.record_query(
+            "SELECT \"name\"\nFROM account",
+            Duration::from_millis(2),
+            None,
+        );
+        metrics.record_query("TRANSACTION", Duration::from_millis(2), None);
+
+        let label = statement_label("SELECT \"name\" FROM account");
+        assert_eq!(label.len(), 12);
+        assert_eq!(statement_label("TRANSACTION"), "TRANSACTION");
+
+        let pool = ConnectionPoolStats {
+            active: 0,
+            total_created: 1,
+            is_shutdown: false,
+            cache_hits: 0,
+            cache_misses: 0,
+            cache_hit_rate: 0.0,
+        };
+        let queue = QueueStats {
+            depth: 0,
+            worker_depths: Vec::new(),
+            processed: 0,
+            failed: 0,
+        };
+
+        let out = render_prometheus(&metrics.snapshot(), &pool, &queue);
+        assert!(out.contains(&format!("statement=\"{}\"", label)));
+        assert!(out.contains("statement=\"TRANSACTION\""));
+        assert!(!out.contains("FROM account"));
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
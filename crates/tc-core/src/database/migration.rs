@@ -1,30 +1,60 @@
-use crate::database::{DatabaseHandle, Result, SqlError, SqlErrorKind};
-use std::{cmp::Ordering, collections::BTreeMap, i64, path::Path};
+use crate::database::{
+    AdvisoryLock, DatabaseHandle, FromRow, QueryParam, Result, SqlError, SqlErrorKind,
+    quote_identifier, quote_qualified_name, split_statements,
+};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, i64,
+    path::Path,
+    time::Duration,
+};
 use tokio::fs;
 
+pub use tc_macros::embed_migrations;
+
 #[derive(Debug, Clone)]
 pub struct Migration {
     pub version: i64,
     pub name: String,
     pub up: String,
     pub down: Option<String>,
+    pub checksum: String,
+    // Off for files with the no-transaction directive, needed by statements
+    // like CREATE INDEX CONCURRENTLY
+    pub transactional: bool,
 }
 
 impl Migration {
     pub fn new(version: i64, name: impl Into<String>, up: impl Into<String>) -> Self {
+        let up = up.into();
         Self {
             version,
             name: name.into(),
-            up: up.into(),
+            checksum: Self::checksum_of(&up),
+            up,
             down: None,
+            transactional: true,
         }
     }
 
+    // Hex sha1 of the up sql. Line endings and surrounding whitespace are
+    // ignored so a checkout on another platform does not count as an edit
+    pub fn checksum_of(up: &str) -> String {
+        let normalized = up.replace("\r\n", "\n");
+        hex::encode(Sha1::digest(normalized.trim().as_bytes()))
+    }
+
     pub fn with_down(mut self, down: impl Into<String>) -> Self {
         self.down = Some(down.into());
         self
     }
 
+    pub fn without_transaction(mut self) -> Self {
+        self.transactional = false;
+        self
+    }
+
     pub fn parse_filename(filename: &str) -> Option<(i64, String)> {
         let stem = filename.strip_suffix(".sql")?;
         let (version_str, name) = stem.split_once('_')?;
@@ -33,6 +63,23 @@ impl Migration {
         Some((version, name.to_string()))
     }
 
+    // R_<name>.sql
+    pub fn parse_repeatable_filename(filename: &str) -> Option<String> {
+        let name = filename.strip_suffix(".sql")?.strip_prefix("R_")?;
+        (!name.is_empty()).then(|| name.to_string())
+    }
+
+    // A line of its own in the header before the first section, e.g.
+    // --#: no-transaction
+    pub fn has_directive(content: &str, directive: &str) -> bool {
+        let marker = format!("--#: {}", directive);
+        content
+            .lines()
+            .map(str::trim)
+            .take_while(|line| !line.starts_with("--#: migration."))
+            .any(|line| line == marker)
+    }
+
     pub fn extract_section(content: &String, section: &str) -> Option<String> {
         let start_marker = format!("--#: {}", section);
         let end_marker = "--#: end";
@@ -51,9 +98,37 @@ impl Migration {
     }
 }
 
+// Applied again whenever its sql changes, for views, functions and the
+// like. Runs after the versioned migrations, in name order
+#[derive(Debug, Clone)]
+pub struct RepeatableMigration {
+    pub name: String,
+    pub up: String,
+    pub checksum: String,
+    pub transactional: bool,
+}
+
+impl RepeatableMigration {
+    pub fn new(name: impl Into<String>, up: impl Into<String>) -> Self {
+        let up = up.into();
+        Self {
+            name: name.into(),
+            checksum: Migration::checksum_of(&up),
+            up,
+            transactional: true,
+        }
+    }
+
+    pub fn without_transaction(mut self) -> Self {
+        self.transactional = false;
+        self
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct MigrationRegistry {
     migrations: BTreeMap<i64, Migration>,
+    repeatables: BTreeMap<String, RepeatableMigration>,
 }
 
 impl MigrationRegistry {
@@ -70,11 +145,7 @@ impl MigrationRegistry {
             )
         })?;
 
-        let mut result = Self {
-            migrations: BTreeMap::new(),
-        };
-
-        let mut files: BTreeMap<i64, (String, String)> = BTreeMap::new();
+        let mut sources = Vec::new();
         while let Some(entry) = entries.next_entry().await.map_err(|e| {
             SqlError::new(
                 SqlErrorKind::Query,
@@ -85,7 +156,8 @@ impl MigrationRegistry {
             let filename = path
                 .file_name()
                 .and_then(|n| n.to_str())
-                .unwrap_or_default();
+                .unwrap_or_default()
+                .to_string();
 
             if !filename.ends_with(".sql") {
                 continue;
@@ -98,6 +170,23 @@ impl MigrationRegistry {
                 )
             })?;
 
+            sources.push((filename, content));
+        }
+
+        Self::from_sources(
+            sources
+                .iter()
+                .map(|(filename, content)| (filename.as_str(), content.as_str())),
+        )
+    }
+
+    // Builds the registry from (filename, content) pairs, the files of a
+    // migrations dir or the ones embedded by embed_migrations!
+    pub fn from_sources<'s>(sources: impl IntoIterator<Item = (&'s str, &'s str)>) -> Result<Self> {
+        let mut result = Self::default();
+
+        let mut files: BTreeMap<i64, (String, String)> = BTreeMap::new();
+        for (filename, content) in sources {
             if content.is_empty() {
                 return Err(SqlError::new(
                     SqlErrorKind::Query,
@@ -105,13 +194,28 @@ impl MigrationRegistry {
                 ));
             }
 
-            if let Some((version, name)) = Migration::parse_filename(&filename) {
-                files.insert(version, (name, content));
+            if let Some(name) = Migration::parse_repeatable_filename(filename) {
+                let content = content.to_string();
+                let Some(up) = Migration::extract_section(&content, "migration.up") else {
+                    return Err(SqlError::new(
+                        SqlErrorKind::Query,
+                        format!("Migration {}, has no 'up' migration content", filename),
+                    ));
+                };
+
+                let mut repeatable = RepeatableMigration::new(name, up);
+                if Migration::has_directive(&content, "no-transaction") {
+                    repeatable = repeatable.without_transaction();
+                }
+
+                result.register_repeatable(repeatable);
+            } else if let Some((version, name)) = Migration::parse_filename(filename) {
+                files.insert(version, (name, content.to_string()));
             } else {
                 return Err(SqlError::new(
                     SqlErrorKind::Query,
                     format!(
-                        "Migration {}, filename is not in correct format <version>_<name>.sql",
+                        "Migration {}, filename is not in correct format <version>_<name>.sql or R_<name>.sql",
                         filename
                     ),
                 ));
@@ -137,6 +241,10 @@ impl MigrationRegistry {
                 migration = migration.with_down(down);
             }
 
+            if Migration::has_directive(&content, "no-transaction") {
+                migration = migration.without_transaction();
+            }
+
             result.register(migration);
         }
 
@@ -147,6 +255,14 @@ impl MigrationRegistry {
         self.migrations.insert(migration.version, migration);
     }
 
+    pub fn register_repeatable(&mut self, repeatable: RepeatableMigration) {
+        self.repeatables.insert(repeatable.name.clone(), repeatable);
+    }
+
+    pub fn repeatables(&self) -> impl Iterator<Item = &RepeatableMigration> {
+        self.repeatables.values()
+    }
+
     pub fn get(&self, version: i64) -> Option<&Migration> {
         self.migrations.get(&version)
     }
@@ -183,6 +299,8 @@ pub struct MigrationReport {
     pub final_version: i64,
     pub applied: Vec<i64>,
     pub reverted: Vec<i64>,
+    // Names of the repeatable migrations that were (re)applied
+    pub repeated: Vec<String>,
 }
 
 impl MigrationReport {
@@ -193,6 +311,7 @@ impl MigrationReport {
             final_version: initial,
             applied: Vec::new(),
             reverted: Vec::new(),
+            repeated: Vec::new(),
         }
     }
 
@@ -204,10 +323,38 @@ impl MigrationReport {
     }
 
     pub fn changes(&self) -> usize {
-        self.applied.len() + self.reverted.len()
+        self.applied.len() + self.reverted.len() + self.repeated.len()
     }
 }
 
+// A single unit of work of a migration run
+#[derive(Debug, Clone, Copy)]
+pub enum MigrationStep<'r> {
+    Apply(&'r Migration),
+    Revert(&'r Migration),
+    Repeat(&'r RepeatableMigration),
+}
+
+impl<'r> MigrationStep<'r> {
+    // The sql the step runs, None for reverts without a down section
+    pub fn sql(&self) -> Option<&'r str> {
+        match self {
+            Self::Apply(m) => Some(&m.up),
+            Self::Revert(m) => m.down.as_deref(),
+            Self::Repeat(r) => Some(&r.up),
+        }
+    }
+
+    pub fn transactional(&self) -> bool {
+        match self {
+            Self::Apply(m) | Self::Revert(m) => m.transactional,
+            Self::Repeat(r) => r.transactional,
+        }
+    }
+}
+
+// Difference between the applied migrations and the migration files.
+// Expected values come from the database, found ones from the files
 #[derive(Debug)]
 pub enum ValidationIssue {
     MissingMigration {
@@ -219,19 +366,58 @@ pub enum ValidationIssue {
         expected: String,
         found: String,
     },
+    ChecksumMismatch {
+        version: i64,
+        name: String,
+        expected: String,
+        found: String,
+    },
 }
 
-#[derive(Debug)]
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingMigration { version, name } => {
+                write!(f, "{}_{} is applied but its file is missing", version, name)
+            }
+            Self::NameMismatch {
+                version,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} was applied as {} but its file is named {}",
+                version, expected, found
+            ),
+            Self::ChecksumMismatch {
+                version,
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}_{} was edited after it was applied (checksum {}, file {})",
+                version, name, expected, found
+            ),
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
 pub struct MigrationRecord {
     pub version: i64,
     pub name: String,
     pub applied_at: chrono::DateTime<chrono::Utc>,
+    // Unset for migrations applied before checksums were recorded
+    pub checksum: Option<String>,
 }
 
 pub struct MigrationMigrator<'a> {
     db: &'a DatabaseHandle,
     registry: &'a MigrationRegistry,
     table_name: String,
+    // How long a run waits for another migrator to finish
+    lock_timeout: Duration,
 }
 
 impl<'a> MigrationMigrator<'a> {
@@ -240,6 +426,7 @@ impl<'a> MigrationMigrator<'a> {
             db,
             registry,
             table_name: "_migrations".to_string(),
+            lock_timeout: Duration::from_secs(60),
         }
     }
 
@@ -248,53 +435,160 @@ impl<'a> MigrationMigrator<'a> {
         self
     }
 
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
     pub async fn init(&self) -> Result<()> {
+        self.locked(self.create_table()).await
+    }
+
+    async fn create_table(&self) -> Result<()> {
         let sql = format!(
             r#"
             CREATE TABLE IF NOT EXISTS {} (
                 version BIGINT PRIMARY KEY,
                 name TEXT NOT NULL,
+                applied_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                checksum TEXT
+            )
+            "#,
+            self.quoted_table()?
+        );
+
+        self.db.execute(&sql, &[]).await?;
+
+        let sql = format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {} (
+                name TEXT PRIMARY KEY,
+                checksum TEXT NOT NULL,
                 applied_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
             )
             "#,
-            self.table_name
+            self.quoted_repeatable_table()?
         );
 
         self.db.execute(&sql, &[]).await?;
-        Ok(())
-    }
 
-    pub async fn initialized(&self) -> Result<bool> {
+        // Tables created before checksums were recorded. Looked up the way
+        // the statements above resolve the name, through the search_path
         let sql = r#"
             SELECT EXISTS (
-                SELECT 1 FROM information_schema.tables
-                WHERE table_schema='public' AND table_name=$1
+                SELECT 1 FROM pg_attribute
+                WHERE attrelid = to_regclass($1) AND attname = 'checksum' AND NOT attisdropped
             )
         "#;
 
-        let exists: bool = self.db.query_scalar(sql, &[&self.table_name]).await?;
+        let table = self.quoted_table()?;
+        let has_checksum: bool = self.db.query_scalar(sql, &[&table]).await?;
+        if !has_checksum {
+            let sql = format!(
+                "ALTER TABLE {} ADD COLUMN checksum TEXT",
+                self.quoted_table()?
+            );
+            self.db.execute(&sql, &[]).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn initialized(&self) -> Result<bool> {
+        let sql = "SELECT to_regclass($1) IS NOT NULL AND to_regclass($2) IS NOT NULL";
+        let tables = [self.quoted_table()?, self.quoted_repeatable_table()?];
+        let exists: bool = self.db.query_scalar(sql, &[&tables[0], &tables[1]]).await?;
         Ok(exists)
     }
 
+    pub fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    // Checksums of the repeatable migrations last applied, keyed by name
+    pub fn repeatable_table(&self) -> String {
+        format!("{}_repeatable", self.table_name)
+    }
+
+    // The names as they go into sql, schema.table names are quoted part by
+    // part. Validated on use, with_table_name takes names from configuration
+    // without checking them
+    fn quoted_table(&self) -> Result<String> {
+        quote_qualified_name(&self.table_name)
+    }
+
+    fn quoted_repeatable_table(&self) -> Result<String> {
+        quote_qualified_name(&self.repeatable_table())
+    }
+
+    // The read methods work before init, a missing table reads as nothing
+    // applied so status and dry runs leave the database untouched
+    async fn table_exists(&self, quoted: &str) -> Result<bool> {
+        let sql = "SELECT to_regclass($1) IS NOT NULL";
+        let name = quoted.to_string();
+        self.db.query_scalar(sql, &[&name]).await
+    }
+
     pub async fn records(&self) -> Result<Vec<MigrationRecord>> {
+        let table = self.quoted_table()?;
+        if !self.table_exists(&table).await? {
+            return Ok(Vec::new());
+        }
+
+        // Tables from before checksums were recorded lack the column until
+        // init adds it, read through jsonb it is null for them
         let sql = format!(
-            "SELECT version, name, applied_at FROM {} ORDER BY version",
-            self.table_name
+            r#"
+            SELECT version, name, applied_at, to_jsonb(m) ->> 'checksum' AS checksum
+            FROM {} m ORDER BY version
+            "#,
+            table
         );
 
-        let rows = self.db.query(&sql, &[]).await?;
-        Ok(rows
-            .iter()
-            .map(|row| MigrationRecord {
-                version: row.get("version"),
-                name: row.get("name"),
-                applied_at: row.get("applied_at"),
-            })
-            .collect())
+        self.db.query_as(&sql, &[]).await
+    }
+
+    pub async fn validate(&self) -> Result<Vec<ValidationIssue>> {
+        let mut issues = Vec::new();
+        for record in self.records().await? {
+            let Some(migration) = self.registry.get(record.version) else {
+                issues.push(ValidationIssue::MissingMigration {
+                    version: record.version,
+                    name: record.name,
+                });
+
+                continue;
+            };
+
+            if migration.name != record.name {
+                issues.push(ValidationIssue::NameMismatch {
+                    version: record.version,
+                    expected: record.name.clone(),
+                    found: migration.name.clone(),
+                });
+            }
+
+            if let Some(checksum) = record.checksum
+                && checksum != migration.checksum
+            {
+                issues.push(ValidationIssue::ChecksumMismatch {
+                    version: record.version,
+                    name: record.name,
+                    expected: checksum,
+                    found: migration.checksum.clone(),
+                });
+            }
+        }
+
+        Ok(issues)
     }
 
     pub async fn current_version(&self) -> Result<Option<i64>> {
-        let sql = format!("SELECT MAX(version) as version FROM {}", self.table_name);
+        let table = self.quoted_table()?;
+        if !self.table_exists(&table).await? {
+            return Ok(None);
+        }
+
+        let sql = format!("SELECT MAX(version) as version FROM {}", table);
         let rows = self.db.query(&sql, &[]).await?;
 
         Ok(rows.first().and_then(|r| r.get("version")))
@@ -309,94 +603,311 @@ impl<'a> MigrationMigrator<'a> {
             .collect())
     }
 
+    // For servers that migrate themselves before accepting connections.
+    // Refuses to run when applied migrations no longer match the registry
+    pub async fn migrate_on_startup(&self) -> Result<MigrationReport> {
+        if !self.initialized().await? {
+            self.init().await?;
+        }
+
+        let issues = self.validate().await?;
+        if let Some(issue) = issues.first() {
+            return Err(SqlError::new(
+                SqlErrorKind::Query,
+                format!(
+                    "{} migration issue(s) in {}, first: {}",
+                    issues.len(),
+                    self.table_name,
+                    issue
+                ),
+            ));
+        }
+
+        self.migrate_pending().await
+    }
+
+    // Repeatable migrations that are new or changed since they last ran
+    pub async fn pending_repeatable(&self) -> Result<Vec<&'a RepeatableMigration>> {
+        let table = self.quoted_repeatable_table()?;
+        let mut applied: HashMap<String, String> = HashMap::new();
+        if self.table_exists(&table).await? {
+            let sql = format!("SELECT name, checksum FROM {}", table);
+            applied = self
+                .db
+                .query(&sql, &[])
+                .await?
+                .iter()
+                .map(|row| (row.get(0), row.get(1)))
+                .collect();
+        }
+
+        Ok(self
+            .registry
+            .repeatables()
+            .filter(|r| applied.get(&r.name) != Some(&r.checksum))
+            .collect())
+    }
+
     pub async fn migrate_pending(&self) -> Result<MigrationReport> {
         self.migrate_to(i64::MAX).await
     }
 
+    // Runs while holding the migration lock, so concurrent migrators wait
+    // for each other instead of applying the same migrations twice
     pub async fn migrate_to(&self, target: i64) -> Result<MigrationReport> {
-        let current = self.current_version().await?.unwrap_or(0);
-        match target.cmp(&current) {
-            Ordering::Greater | Ordering::Equal => self.migrate_up(current, target).await,
-            Ordering::Less => self.migrate_down(current, target).await,
-        }
+        self.locked(self.migrate_locked(target)).await
     }
 
-    async fn migrate_up(&self, current: i64, target: i64) -> Result<MigrationReport> {
+    async fn migrate_locked(&self, target: i64) -> Result<MigrationReport> {
+        let current = self.current_version().await?.unwrap_or(0);
         let mut report = MigrationReport::new(current, target);
-        let pending: Vec<_> = self
-            .registry
-            .all()
-            .filter(|m| m.version > current && m.version <= target)
-            .collect();
 
-        for migration in pending {
-            self.apply_migration(migration).await?;
-            report.applied.push(migration.version);
+        for step in self.plan(current, target).await? {
+            self.run_step_locked(step).await?;
+            match step {
+                MigrationStep::Apply(migration) => report.applied.push(migration.version),
+                MigrationStep::Revert(migration) => report.reverted.push(migration.version),
+                MigrationStep::Repeat(repeatable) => report.repeated.push(repeatable.name.clone()),
+            }
         }
 
         report.final_version = self.current_version().await?.unwrap_or(0);
         Ok(report)
     }
 
-    async fn migrate_down(&self, current: i64, target: i64) -> Result<MigrationReport> {
-        let mut report = MigrationReport::new(current, target);
-        let to_revert: Vec<_> = self
-            .registry
+    // Runs one step of a plan on its own, e.g. to check a migration's down
+    // section by applying and reverting it
+    pub async fn run_step(&self, step: MigrationStep<'_>) -> Result<()> {
+        self.locked(self.run_step_locked(step)).await
+    }
+
+    async fn run_step_locked(&self, step: MigrationStep<'_>) -> Result<()> {
+        match step {
+            MigrationStep::Apply(migration) => self.apply_migration(migration).await,
+            MigrationStep::Revert(migration) => self.revert_migration(migration).await,
+            MigrationStep::Repeat(repeatable) => self.apply_repeatable(repeatable).await,
+        }
+    }
+
+    // What migrating from one version to another runs, in order
+    pub async fn plan(&self, current: i64, target: i64) -> Result<Vec<MigrationStep<'a>>> {
+        let registry = self.registry;
+        if target < current {
+            let migrations = registry
+                .all()
+                .filter(|m| m.version <= current && m.version > target)
+                .collect::<Vec<_>>();
+
+            // Refused up front, reverting the later migrations and then
+            // stopping would leave the database between versions
+            if let Some(migration) = migrations.iter().find(|m| m.down.is_none()) {
+                return Err(missing_down(migration));
+            }
+
+            return Ok(migrations
+                .into_iter()
+                .rev()
+                .map(MigrationStep::Revert)
+                .collect());
+        }
+
+        let mut steps = registry
             .all()
-            .filter(|m| m.version <= current && m.version > target)
-            .collect::<Vec<_>>()
-            .into_iter()
-            .rev()
-            .collect();
+            .filter(|m| m.version > current && m.version <= target)
+            .map(MigrationStep::Apply)
+            .collect::<Vec<_>>();
+
+        // Repeatables are written against the latest schema, so they only
+        // run once every versioned migration is in
+        let latest = registry.all().last().map_or(0, |m| m.version);
+        if target >= latest {
+            steps.extend(
+                self.pending_repeatable()
+                    .await?
+                    .into_iter()
+                    .map(MigrationStep::Repeat),
+            );
+        }
+
+        Ok(steps)
+    }
 
-        for migration in to_revert {
-            self.revert_migration(migration).await?;
-            report.reverted.push(migration.version);
+    pub async fn plan_to(&self, target: i64) -> Result<Vec<MigrationStep<'a>>> {
+        let current = self.current_version().await?.unwrap_or(0);
+        self.plan(current, target).await
+    }
+
+    // Version left after reverting the latest `steps` applied migrations
+    pub async fn down_target(&self, steps: usize) -> Result<i64> {
+        let table = self.quoted_table()?;
+        if !self.table_exists(&table).await? {
+            return Ok(0);
         }
 
-        report.final_version = self.current_version().await?.unwrap_or(0);
+        let sql = format!(
+            "SELECT version FROM {} ORDER BY version DESC OFFSET $1 LIMIT 1",
+            table
+        );
+
+        let rows = self.db.query(&sql, &[&(steps as i64)]).await?;
+        Ok(rows.first().map_or(0, |row| row.get(0)))
+    }
+
+    // Reverts the latest migration and applies it again
+    pub async fn redo(&self) -> Result<MigrationReport> {
+        self.locked(self.redo_locked()).await
+    }
+
+    async fn redo_locked(&self) -> Result<MigrationReport> {
+        let Some(current) = self.current_version().await? else {
+            return Err(SqlError::new(
+                SqlErrorKind::Query,
+                "No applied migration to redo",
+            ));
+        };
+
+        if self
+            .registry
+            .get(current)
+            .and_then(|m| m.down.as_ref())
+            .is_none()
+        {
+            return Err(SqlError::new(
+                SqlErrorKind::Query,
+                format!("Migration {} has no 'down' migration to redo", current),
+            ));
+        }
+
+        let previous = self.down_target(1).await?;
+        let down = self.migrate_locked(previous).await?;
+        let mut report = self.migrate_locked(current).await?;
+        report.initial_version = current;
+        report.reverted = down.reverted;
         Ok(report)
     }
 
-    async fn apply_migration(&self, migration: &Migration) -> Result<()> {
-        tracing::info!(
-            "Applying migration {}: {}",
-            migration.version,
-            migration.name
+    // Records the migrations up to a version as applied without running
+    // them, to adopt a database whose schema already exists
+    pub async fn baseline(&self, version: i64) -> Result<Vec<i64>> {
+        self.locked(self.baseline_locked(version)).await
+    }
+
+    async fn baseline_locked(&self, version: i64) -> Result<Vec<i64>> {
+        if let Some(current) = self.current_version().await? {
+            return Err(SqlError::new(
+                SqlErrorKind::Query,
+                format!(
+                    "{} already has applied migrations up to {}",
+                    self.table_name, current
+                ),
+            ));
+        }
+
+        let sql = format!(
+            "INSERT INTO {} (version, name, checksum) VALUES ($1, $2, $3);",
+            self.quoted_table()?
         );
 
+        let migrations = self.registry.up_to(version).collect::<Vec<_>>();
         self.db
             .transaction(async |tx| {
-                let version = migration.version;
-                let name = migration.name.clone();
-                let up_sql = migration.up.clone();
-                let table = self.table_name.clone();
-
-                for stmt in Self::split_statements(&up_sql) {
-                    let stmt = stmt.trim();
-                    if !stmt.is_empty() {
-                        tx.execute(stmt, &[]).await?;
-                    }
+                for m in &migrations {
+                    tx.execute(&sql, &[&m.version, &m.name, &m.checksum])
+                        .await?;
                 }
 
-                let migration_record_sql =
-                    format!("INSERT INTO {} (version, name) VALUES ($1, $2);", table);
-
-                tx.execute(&migration_record_sql, &[&version, &name])
-                    .await?;
-
                 Ok(())
             })
+            .await?;
+
+        Ok(migrations.iter().map(|m| m.version).collect())
+    }
+
+    // Runs the work while holding the migration lock. A failed release is
+    // only logged, the lock goes away with its connection anyway and the
+    // caller needs the result of the work
+    async fn locked<T>(&self, work: impl Future<Output = Result<T>>) -> Result<T> {
+        let lock = self.lock().await?;
+        let result = work.await;
+        if let Err(e) = lock.release().await {
+            tracing::warn!(
+                "Failed to release the migration lock on {}: {}",
+                self.table_name,
+                e
+            );
+        }
+
+        result
+    }
+
+    async fn lock(&self) -> Result<AdvisoryLock> {
+        self.db
+            .advisory_lock(self.lock_key().await?, self.lock_timeout)
             .await
-            .map_err(|e| e.context(format!("Migration {} failed", migration.version)))?;
+            .map_err(|e| {
+                e.context(format!(
+                    "Another migrator is running against {}",
+                    self.table_name
+                ))
+            })
+    }
 
-        Ok(())
+    // Keyed on the schema qualified table, so _migrations and
+    // public._migrations share a lock. A table without schema resolves the
+    // way the statements using it do: an existing one through the
+    // search_path, a new one to the schema it is created in
+    async fn lock_key(&self) -> Result<i64> {
+        let table = self.quoted_table()?;
+        if self.table_name.contains('.') {
+            return Ok(migration_lock_key(&table));
+        }
+
+        let sql = r#"
+            SELECT COALESCE(
+                (SELECT n.nspname::TEXT FROM pg_class c
+                 JOIN pg_namespace n ON n.oid = c.relnamespace
+                 WHERE c.oid = to_regclass($1)),
+                current_schema()::TEXT
+            )
+        "#;
+
+        // Without a schema to create in there is no table to lock either
+        let schema: Option<String> = self.db.query_scalar(sql, &[&table]).await?;
+        match schema {
+            Some(schema) => Ok(migration_lock_key(&format!(
+                "{}.{}",
+                quote_identifier(&schema)?,
+                table
+            ))),
+            None => Ok(migration_lock_key(&table)),
+        }
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        tracing::info!(
+            "Applying migration {}: {}",
+            migration.version,
+            migration.name
+        );
+
+        let record_sql = format!(
+            "INSERT INTO {} (version, name, checksum) VALUES ($1, $2, $3);",
+            self.quoted_table()?
+        );
+
+        self.run_script(
+            &migration.up,
+            migration.transactional,
+            &record_sql,
+            &[&migration.version, &migration.name, &migration.checksum],
+        )
+        .await
+        .map_err(|e| e.context(format!("Migration {} failed", migration.version)))
     }
 
     async fn revert_migration(&self, migration: &Migration) -> Result<()> {
-        let down_sql = match migration.down.as_ref() {
-            Some(sql) => sql,
-            None => return Ok(()),
+        let Some(down_sql) = migration.down.as_ref() else {
+            return Err(missing_down(migration));
         };
 
         tracing::info!(
@@ -405,31 +916,269 @@ impl<'a> MigrationMigrator<'a> {
             migration.name
         );
 
+        let record_sql = format!("DELETE FROM {} WHERE version = $1;", self.quoted_table()?);
+        self.run_script(
+            down_sql,
+            migration.transactional,
+            &record_sql,
+            &[&migration.version],
+        )
+        .await
+        .map_err(|e| e.context(format!("Revert {} failed", migration.version)))
+    }
+
+    async fn apply_repeatable(&self, repeatable: &RepeatableMigration) -> Result<()> {
+        tracing::info!("Applying repeatable migration {}", repeatable.name);
+
+        let record_sql = format!(
+            r#"
+            INSERT INTO {} (name, checksum) VALUES ($1, $2)
+            ON CONFLICT (name) DO UPDATE SET checksum = EXCLUDED.checksum, applied_at = NOW()
+            "#,
+            self.quoted_repeatable_table()?
+        );
+
+        self.run_script(
+            &repeatable.up,
+            repeatable.transactional,
+            &record_sql,
+            &[&repeatable.name, &repeatable.checksum],
+        )
+        .await
+        .map_err(|e| e.context(format!("Repeatable migration {} failed", repeatable.name)))
+    }
+
+    // Runs the script followed by the statement recording it. Without a
+    // transaction every statement commits on its own, so a failure leaves
+    // the statements before it applied and the script unrecorded
+    async fn run_script(
+        &self,
+        sql: &str,
+        transactional: bool,
+        record_sql: &str,
+        record_params: &[&QueryParam],
+    ) -> Result<()> {
+        if !transactional {
+            for stmt in split_statements(sql) {
+                self.db.query_unprepared(stmt, &[]).await?;
+            }
+
+            self.db.execute(record_sql, record_params).await?;
+            return Ok(());
+        }
+
         self.db
             .transaction(async |tx| {
-                let version = migration.version;
-                let down_sql = down_sql.clone();
-                let table = self.table_name.clone();
-
-                for stmt in Self::split_statements(&down_sql) {
-                    let stmt = stmt.trim();
-                    if !stmt.is_empty() {
-                        tx.execute(stmt, &[]).await?;
-                    }
+                for stmt in split_statements(sql) {
+                    tx.execute(stmt, &[]).await?;
                 }
 
-                let migration_revert_sql = format!("DELETE FROM {} WHERE version = $1;", table);
-                tx.execute(&migration_revert_sql, &[&version]).await?;
-
+                tx.execute(record_sql, record_params).await?;
                 Ok(())
             })
             .await
-            .map_err(|e| e.context(format!("Revert {} failed", migration.version)))?;
+    }
+}
 
-        Ok(())
+fn missing_down(migration: &Migration) -> SqlError {
+    SqlError::new(
+        SqlErrorKind::Query,
+        format!("Migration {} has no down section", migration.version),
+    )
+}
+
+// Stable across processes and builds, every migrator of a table has to
+// agree on it
+fn migration_lock_key(table_name: &str) -> i64 {
+    let digest = Sha1::digest(format!("tc-migrations:{}", table_name).as_bytes());
+    i64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::{
+        Migration, MigrationMigrator, MigrationRegistry, SqlErrorKind,
+        migration::migration_lock_key, test_database,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_migration_checksum() {
+        let migration = Migration::new(1, "create_account", "CREATE TABLE account (id INT);");
+        assert_eq!(migration.checksum.len(), 40);
+        assert_eq!(
+            migration.checksum,
+            Migration::checksum_of("\r\nCREATE TABLE account (id INT);\r\n")
+        );
+        assert_ne!(
+            migration.checksum,
+            Migration::checksum_of("CREATE TABLE account (id BIGINT);")
+        );
+
+        assert_eq!(
+            migration_lock_key("_migrations"),
+            migration_lock_key("_migrations")
+        );
+        assert_ne!(
+            migration_lock_key("_migrations"),
+            migration_lock_key("_world_migrations")
+        );
     }
 
-    fn split_statements(sql: &str) -> Vec<&str> {
-        sql.split(';').filter(|s| !s.trim().is_empty()).collect()
+    #[test]
+    fn test_has_directive() {
+        let header = "--#: no-transaction\n--#: migration.up\nSELECT 1;\n--#: end\n";
+        assert!(Migration::has_directive(header, "no-transaction"));
+
+        let in_section = "--#: migration.up\n--#: no-transaction\nSELECT 1;\n--#: end\n";
+        assert!(!Migration::has_directive(in_section, "no-transaction"));
+        assert!(!Migration::has_directive(header, "no-trans"));
+    }
+
+    #[test]
+    fn test_registry_from_sources() {
+        let registry = MigrationRegistry::from_sources([
+            (
+                "2_add_email.sql",
+                "--#: migration.up\nALTER TABLE account ADD email TEXT;\n--#: end\n",
+            ),
+            (
+                "1_create_account.sql",
+                "--#: migration.up\nCREATE TABLE account (id INT);\n--#: end\n--#: migration.down\nDROP TABLE account;\n--#: end\n",
+            ),
+        ])
+        .unwrap();
+
+        let versions = registry.all().map(|m| m.version).collect::<Vec<_>>();
+        assert_eq!(versions, [1, 2]);
+        assert_eq!(
+            registry.get(1).unwrap().down.as_deref(),
+            Some("DROP TABLE account;")
+        );
+        assert!(registry.get(2).unwrap().down.is_none());
+        assert!(registry.get(1).unwrap().transactional);
+
+        let registry = MigrationRegistry::from_sources([
+            (
+                "3_index.sql",
+                "--#: no-transaction\n--#: migration.up\nCREATE INDEX CONCURRENTLY i ON t (a);\n--#: end\n",
+            ),
+            (
+                "R_names.sql",
+                "--#: migration.up\nCREATE OR REPLACE VIEW names AS SELECT name FROM t;\n--#: end\n",
+            ),
+        ])
+        .unwrap();
+
+        assert!(!registry.get(3).unwrap().transactional);
+        let repeatables = registry.repeatables().collect::<Vec<_>>();
+        assert_eq!(repeatables.len(), 1);
+        assert_eq!(repeatables[0].name, "names");
+        assert!(repeatables[0].transactional);
+
+        assert!(MigrationRegistry::from_sources([("create_account.sql", "x")]).is_err());
+        assert!(MigrationRegistry::from_sources([("1_empty.sql", "")]).is_err());
+        assert!(MigrationRegistry::from_sources([("1_no_up.sql", "SELECT 1;")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_migration_lock_against_database() {
+        let Some(db) = test_database().await else {
+            return;
+        };
+
+        let registry = MigrationRegistry::new();
+        let table = format!("_lock_test_migrations_{}", std::process::id());
+        let qualified = format!("public.{}", table);
+        let first = MigrationMigrator::new(&db, &registry).with_table_name(table.as_str());
+        let second = MigrationMigrator::new(&db, &registry)
+            .with_table_name(qualified.as_str())
+            .with_lock_timeout(Duration::from_millis(200));
+
+        // Both spellings name the same table before and after it exists
+        assert_eq!(
+            first.lock_key().await.unwrap(),
+            second.lock_key().await.unwrap()
+        );
+
+        let lock = first.lock().await.unwrap();
+        let error = second.init().await.unwrap_err();
+        assert_eq!(error.kind, SqlErrorKind::Timeout);
+        lock.release().await.unwrap();
+
+        second.init().await.unwrap();
+        assert!(first.initialized().await.unwrap());
+        assert_eq!(
+            first.lock_key().await.unwrap(),
+            second.lock_key().await.unwrap()
+        );
+
+        let lock = second.lock().await.unwrap();
+        let first = first.with_lock_timeout(Duration::from_millis(200));
+        assert!(first.migrate_pending().await.is_err());
+        lock.release().await.unwrap();
+
+        for table in [second.repeatable_table(), qualified] {
+            db.execute(&format!("DROP TABLE {}", table), &[])
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_down_without_down_section_against_database() {
+        let Some(db) = test_database().await else {
+            return;
+        };
+
+        let prefix = format!("down_test_{}", std::process::id());
+        let mut registry = MigrationRegistry::new();
+        for (version, down) in [(1, true), (2, false), (3, true)] {
+            let table = format!("{}_{}", prefix, version);
+            let migration = Migration::new(
+                version,
+                format!("create_{}", version),
+                format!("CREATE TABLE {} (id INT);", table),
+            );
+            registry.register(match down {
+                true => migration.with_down(format!("DROP TABLE {};", table)),
+                false => migration,
+            });
+        }
+
+        let migrator = MigrationMigrator::new(&db, &registry)
+            .with_table_name(format!("_{}_migrations", prefix));
+        migrator.init().await.unwrap();
+        migrator.migrate_pending().await.unwrap();
+
+        // Migration 3 could be reverted, but nothing runs when the plan
+        // reaches one that can not
+        let target = migrator.down_target(2).await.unwrap();
+        let error = migrator.migrate_to(target).await.unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Migration 2 has no down section")
+        );
+        assert_eq!(migrator.current_version().await.unwrap(), Some(3));
+        assert!(migrator.plan_to(target).await.is_err());
+
+        let report = migrator
+            .migrate_to(migrator.down_target(1).await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(report.reverted, [3]);
+        assert_eq!(migrator.current_version().await.unwrap(), Some(2));
+
+        let mut tables = vec![
+            migrator.table_name().to_string(),
+            migrator.repeatable_table(),
+        ];
+        tables.extend((1..=2).map(|version| format!("{}_{}", prefix, version)));
+        for table in tables {
+            db.execute(&format!("DROP TABLE {}", table), &[])
+                .await
+                .unwrap();
+        }
     }
 }
@@ -1,13 +1,53 @@
 mod cache;
 mod connection;
+mod copy;
 mod db;
 mod error;
+mod ident;
+mod lock;
+mod metrics;
 mod migration;
+mod notify;
 mod pool;
+mod queue;
+mod retry;
+mod router;
+mod row;
+mod splitter;
+mod statements;
+mod tls;
 mod transaction;
 pub use connection::*;
+pub use copy::*;
 pub use db::*;
 pub use error::*;
+pub use ident::*;
+pub use lock::*;
+pub use metrics::*;
 pub use migration::*;
+pub use notify::*;
 pub use pool::*;
+pub use queue::*;
+pub use retry::*;
+pub use router::*;
+pub use row::*;
+pub use splitter::*;
+pub use statements::*;
+pub use tls::*;
 pub use transaction::*;
+
+// Tests against a live server run when this points at a scratch database
+#[cfg(test)]
+fn test_connection() -> Option<String> {
+    std::env::var("TC_TEST_DATABASE_CONNECTION").ok()
+}
+
+#[cfg(test)]
+async fn test_database() -> Option<DatabaseHandle> {
+    let config = PoolConfig {
+        connection_string: test_connection()?,
+        ..Default::default()
+    };
+
+    Some(DatabaseHandle::connect(config).await.unwrap())
+}
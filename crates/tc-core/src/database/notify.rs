@@ -0,0 +1,337 @@
+use crate::database::{
+    ConnectionPool, Result, SqlError, SqlErrorKind, SqlResultExt, quote_identifier,
+};
+use futures::Stream;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+use tokio_postgres::Client;
+
+pub use tokio_postgres::Notification;
+
+// Delay before the listener tries to connect again
+const LISTENER_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+type Subscribers = HashMap<String, Vec<mpsc::UnboundedSender<Notification>>>;
+
+// Notifications sent on a channel. Anything sent while the listener is
+// reconnecting is lost, postgres does not keep notifications around
+pub struct NotificationStream {
+    receiver: mpsc::UnboundedReceiver<Notification>,
+}
+
+impl NotificationStream {
+    pub async fn recv(&mut self) -> Option<Notification> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for NotificationStream {
+    type Item = Notification;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+struct Subscription {
+    channel: String,
+    sender: mpsc::UnboundedSender<Notification>,
+    reply: oneshot::Sender<Result<()>>,
+}
+
+#[derive(Default)]
+struct ListenerState {
+    commands: Option<mpsc::UnboundedSender<Subscription>>,
+    is_shutdown: bool,
+}
+
+// Runs LISTEN for every subscribed channel on one dedicated connection,
+// opened with the first subscription and reopened whenever it drops
+pub struct NotificationListener {
+    pool: Arc<ConnectionPool>,
+    state: Mutex<ListenerState>,
+}
+
+impl NotificationListener {
+    pub fn new(pool: Arc<ConnectionPool>) -> Self {
+        Self {
+            pool,
+            state: Mutex::new(ListenerState::default()),
+        }
+    }
+
+    pub async fn subscribe(&self, channel: &str) -> Result<NotificationStream> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (reply, response) = oneshot::channel();
+        let subscription = Subscription {
+            channel: channel.to_string(),
+            sender,
+            reply,
+        };
+
+        self.commands()?
+            .send(subscription)
+            .map_err(|_| listener_stopped())?;
+        response.await.map_err(|_| listener_stopped())??;
+
+        Ok(NotificationStream { receiver })
+    }
+
+    // Ends every stream handed out so far
+    pub fn shutdown(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.is_shutdown = true;
+        state.commands = None;
+    }
+
+    fn commands(&self) -> Result<mpsc::UnboundedSender<Subscription>> {
+        let mut state = self.state.lock().unwrap();
+        if state.is_shutdown {
+            return Err(SqlError::new(
+                SqlErrorKind::Shutdown,
+                "Notification listener is shut down",
+            ));
+        }
+
+        let commands = state.commands.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(run_listener(self.pool.clone(), receiver));
+            sender
+        });
+
+        Ok(commands.clone())
+    }
+}
+
+fn listener_stopped() -> SqlError {
+    SqlError::new(SqlErrorKind::Shutdown, "Notification listener has stopped")
+}
+
+async fn run_listener(
+    pool: Arc<ConnectionPool>,
+    mut commands: mpsc::UnboundedReceiver<Subscription>,
+) {
+    let mut subscribers = Subscribers::new();
+
+    loop {
+        let (client, mut notifications) = match connect(&pool, &mut subscribers).await {
+            Ok(connected) => connected,
+            Err(e) => {
+                tracing::error!("Notification listener failed to connect: {}", e);
+                if !wait_for_retry(&mut commands, &mut subscribers).await {
+                    return;
+                }
+
+                continue;
+            }
+        };
+
+        loop {
+            tokio::select! {
+                notification = notifications.recv() => match notification {
+                    Some(notification) => dispatch(&client, &mut subscribers, notification).await,
+                    None => {
+                        tracing::warn!("Notification listener lost its connection, reconnecting");
+                        break;
+                    }
+                },
+                command = commands.recv() => match command {
+                    Some(subscription) => subscribe(&client, &mut subscribers, subscription).await,
+                    None => return,
+                },
+            }
+        }
+    }
+}
+
+async fn connect(
+    pool: &ConnectionPool,
+    subscribers: &mut Subscribers,
+) -> Result<(Client, mpsc::UnboundedReceiver<Notification>)> {
+    subscribers.retain(|_, senders| {
+        senders.retain(|s| !s.is_closed());
+        !senders.is_empty()
+    });
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let client = pool.connect_client(Some(sender)).await?;
+    for channel in subscribers.keys() {
+        listen(&client, "LISTEN", channel).await?;
+    }
+
+    Ok((client, receiver))
+}
+
+// Keeps taking subscriptions while disconnected, they are listened to once
+// the connection is back. Returns false when the listener shuts down
+async fn wait_for_retry(
+    commands: &mut mpsc::UnboundedReceiver<Subscription>,
+    subscribers: &mut Subscribers,
+) -> bool {
+    let retry = tokio::time::sleep(LISTENER_RETRY_DELAY);
+    tokio::pin!(retry);
+
+    loop {
+        tokio::select! {
+            _ = &mut retry => return true,
+            command = commands.recv() => match command {
+                Some(subscription) => {
+                    subscribers
+                        .entry(subscription.channel)
+                        .or_default()
+                        .push(subscription.sender);
+                    let _ = subscription.reply.send(Ok(()));
+                }
+                None => return false,
+            },
+        }
+    }
+}
+
+async fn subscribe(client: &Client, subscribers: &mut Subscribers, subscription: Subscription) {
+    let Subscription {
+        channel,
+        sender,
+        reply,
+    } = subscription;
+
+    let result = match subscribers.get_mut(&channel) {
+        Some(senders) => {
+            senders.push(sender);
+            Ok(())
+        }
+        None => match listen(client, "LISTEN", &channel).await {
+            // A dropped connection listens again after reconnecting
+            Err(e) if !e.is_transient() => Err(e),
+            _ => {
+                subscribers.insert(channel, vec![sender]);
+                Ok(())
+            }
+        },
+    };
+
+    let _ = reply.send(result);
+}
+
+async fn dispatch(client: &Client, subscribers: &mut Subscribers, notification: Notification) {
+    let Some(senders) = subscribers.get_mut(notification.channel()) else {
+        return;
+    };
+
+    senders.retain(|s| s.send(notification.clone()).is_ok());
+    if senders.is_empty() {
+        subscribers.remove(notification.channel());
+        if let Err(e) = listen(client, "UNLISTEN", notification.channel()).await {
+            tracing::warn!("Failed to stop listening on a channel: {}", e);
+        }
+    }
+}
+
+async fn listen(client: &Client, command: &str, channel: &str) -> Result<()> {
+    let sql = format!("{} {}", command, quote_channel(channel)?);
+    client
+        .batch_execute(&sql)
+        .await
+        .sql_err(SqlErrorKind::Query)
+        .map_err(|e| e.query(&sql))
+}
+
+// Channels are identifiers in LISTEN but plain strings in pg_notify, quoting
+// keeps both spellings the same channel
+fn quote_channel(channel: &str) -> Result<String> {
+    quote_identifier(channel)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::{
+        DatabaseHandle, NotificationStream, notify::quote_channel, test_database,
+    };
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[test]
+    fn test_quote_channel() {
+        assert_eq!(quote_channel("player_ban").unwrap(), "\"player_ban\"");
+        assert_eq!(quote_channel("Realm Events").unwrap(), "\"Realm Events\"");
+        assert_eq!(quote_channel("a\"b").unwrap(), "\"a\"\"b\"");
+        assert!(quote_channel("").is_err());
+    }
+
+    // Backend running the listener, found by the last command it ran
+    async fn listener_pid(db: &DatabaseHandle, command: &str, except: i32) -> i32 {
+        let command = command.to_string();
+        for _ in 0..100 {
+            let rows = db
+                .query(
+                    "SELECT pid FROM pg_stat_activity WHERE query = $1 AND pid <> $2",
+                    &[&command, &except],
+                )
+                .await
+                .unwrap();
+            if let Some(row) = rows.first() {
+                return row.get(0);
+            }
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        panic!("no backend ran {}", command);
+    }
+
+    // Nothing sent while the listener reconnects is kept, so this sends
+    // until something arrives
+    async fn deliver(db: &DatabaseHandle, stream: &mut NotificationStream, channel: &str) {
+        for _ in 0..100 {
+            db.notify(channel, "payload").await.unwrap();
+            if let Ok(notification) = timeout(Duration::from_millis(100), stream.recv()).await {
+                let notification = notification.unwrap();
+                assert_eq!(
+                    (notification.channel(), notification.payload()),
+                    (channel, "payload")
+                );
+                return;
+            }
+        }
+
+        panic!("nothing delivered on {}", channel);
+    }
+
+    #[tokio::test]
+    async fn test_listener_against_database() {
+        let Some(db) = test_database().await else {
+            return;
+        };
+
+        let channel = format!("notify_test_{}", std::process::id());
+        let listen = format!("LISTEN \"{}\"", channel);
+        let mut stream = db.subscribe(&channel).await.unwrap();
+        deliver(&db, &mut stream, &channel).await;
+
+        // A listener whose backend goes away listens again on a new one
+        let pid = listener_pid(&db, &listen, 0).await;
+        let terminated: bool = db
+            .query_scalar("SELECT pg_terminate_backend($1)", &[&pid])
+            .await
+            .unwrap();
+        assert!(terminated);
+
+        deliver(&db, &mut stream, &channel).await;
+        let pid = listener_pid(&db, &listen, pid).await;
+
+        // Dropped streams are only noticed on the next notification, which
+        // then stops listening on the channel
+        drop(stream);
+        db.notify(&channel, "payload").await.unwrap();
+        let unlisten = format!("UNLISTEN \"{}\"", channel);
+        assert_eq!(listener_pid(&db, &unlisten, 0).await, pid);
+
+        db.shutdown().await;
+    }
+}
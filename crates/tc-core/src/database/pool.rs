@@ -1,4 +1,9 @@
-use crate::database::{ConnectionGuard, PooledConnection, Result, SqlError, SqlResultExt};
+use crate::database::{
+    ConnectionGuard, PoolMetrics, PooledConnection, Result, RetryPolicy, SqlError, SqlResultExt,
+    StatementDef, StatementSet, TlsConfig,
+    tls::{parse_connection_string, pool_connector},
+};
+use futures::{StreamExt, stream};
 use std::{
     collections::VecDeque,
     sync::{
@@ -8,10 +13,14 @@ use std::{
     time::Duration,
 };
 use tokio::{
-    sync::{Mutex, Notify, Semaphore},
+    sync::{Mutex, Notify, Semaphore, mpsc},
     time::{Instant, timeout},
 };
-use tokio_postgres::{Config, NoTls};
+use tokio_postgres::{
+    AsyncMessage, Client, Config, NoTls, Notification, Socket,
+    tls::{MakeTlsConnect, TlsConnect},
+};
+use tokio_postgres_rustls::MakeRustlsConnect;
 
 #[derive(Clone)]
 pub struct PoolConfig {
@@ -23,6 +32,22 @@ pub struct PoolConfig {
     pub health_check_interval: Duration,
     pub idle_timeout: Duration,
     pub statement_cache_capacity: usize,
+    // Prepared on every connection, see with_statements
+    pub statements: Vec<StatementDef>,
+    // Write-behind queue workers and how many operations each one runs on
+    // a connection before handing it back
+    pub queue_workers: usize,
+    pub queue_batch_size: usize,
+    // Retrying of single reads, of single writes including queued ones and
+    // of whole transactions. Writes are not retried unless set here, a write
+    // whose connection dropped may have committed already
+    pub query_retry: RetryPolicy,
+    pub write_retry: RetryPolicy,
+    pub transaction_retry: RetryPolicy,
+    // Statements running at least this long are logged, None disables it
+    pub slow_query_threshold: Option<Duration>,
+    // Certificates for encrypted connections, see TlsConfig
+    pub tls: Option<TlsConfig>,
 }
 
 impl Default for PoolConfig {
@@ -36,10 +61,25 @@ impl Default for PoolConfig {
             health_check_interval: Duration::from_secs(30),
             idle_timeout: Duration::from_secs(600),
             statement_cache_capacity: 100,
+            statements: Vec::new(),
+            queue_workers: 1,
+            queue_batch_size: 64,
+            query_retry: RetryPolicy::default(),
+            write_retry: RetryPolicy::none(),
+            transaction_retry: RetryPolicy::default(),
+            slow_query_threshold: Some(Duration::from_secs(1)),
+            tls: None,
         }
     }
 }
 
+impl PoolConfig {
+    pub fn with_statements<S: StatementSet>(mut self) -> Self {
+        self.statements.extend_from_slice(S::all());
+        self
+    }
+}
+
 #[derive(Debug)]
 pub struct ConnectionPoolStats {
     pub active: usize,
@@ -60,12 +100,32 @@ pub struct ConnectionPool {
     total_created: AtomicUsize,
     total_cache_hits: AtomicU64,
     total_cache_misses: AtomicU64,
+    pub(crate) metrics: PoolMetrics,
+    tls: Option<MakeRustlsConnect>,
 }
 
 impl ConnectionPool {
     pub async fn new(config: PoolConfig) -> Result<Arc<Self>> {
-        let pool = Arc::new(Self {
+        let pool = Self::build(config)?;
+        pool.fill().await?;
+        pool.spawn_health_check();
+        Ok(pool)
+    }
+
+    // Opens no connection up front, the first acquire does. Registered
+    // statements are checked on that connection instead of at startup
+    pub fn new_lazy(config: PoolConfig) -> Result<Arc<Self>> {
+        let pool = Self::build(config)?;
+        pool.spawn_health_check();
+        Ok(pool)
+    }
+
+    fn build(config: PoolConfig) -> Result<Arc<Self>> {
+        let tls = pool_connector(&config)?;
+        Ok(Arc::new(Self {
+            tls,
             sem: Arc::new(Semaphore::new(config.max_connection)),
+            metrics: PoolMetrics::new(config.slow_query_threshold),
             config,
             connections: Arc::new(Mutex::new(VecDeque::new())),
             shutdown: AtomicBool::new(false),
@@ -74,23 +134,38 @@ impl ConnectionPool {
             total_created: AtomicUsize::new(0),
             total_cache_hits: AtomicU64::new(0),
             total_cache_misses: AtomicU64::new(0),
-        });
+        }))
+    }
 
-        for i in 0..pool.config.min_connections {
-            let conn = pool
+    async fn fill(&self) -> Result<()> {
+        for i in 0..self.config.min_connections {
+            let conn = self
                 .create_connection()
                 .await
                 .map_err(|e| e.context(format!("Failed to create initial connection {}", i)))?;
 
-            pool.connections.lock().await.push_back(conn);
+            self.connections.lock().await.push_back(conn);
+        }
+
+        // Statements are checked against the database even when no
+        // connections are kept open, so broken sql fails at startup
+        if self.config.min_connections == 0 && !self.config.statements.is_empty() {
+            let conn = self
+                .create_connection()
+                .await
+                .map_err(|e| e.context("Failed to validate registered statements"))?;
+
+            self.connections.lock().await.push_back(conn);
         }
 
-        let pool_clone = Arc::clone(&pool);
+        Ok(())
+    }
+
+    fn spawn_health_check(self: &Arc<Self>) {
+        let pool_clone = Arc::clone(self);
         tokio::spawn(async move {
             pool_clone.health_check_loop().await;
         });
-
-        Ok(pool)
     }
 
     pub async fn acquire(&'_ self) -> Result<ConnectionGuard<'_>> {
@@ -101,12 +176,15 @@ impl ConnectionPool {
             ));
         }
 
+        let started = Instant::now();
         let permit = timeout(
             self.config.acquire_timeout,
             self.sem.clone().acquire_owned(),
         )
         .await
         .map_err(|_| {
+            // Timed out waits are recorded too, they are the ones that matter
+            self.metrics.record_acquire(started.elapsed());
             SqlError::new(
                 super::SqlErrorKind::Timeout,
                 "Timed out waiting for connection",
@@ -126,11 +204,22 @@ impl ConnectionPool {
         let mut conn = conn.unwrap();
         conn.touch();
 
+        self.metrics.record_acquire(started.elapsed());
         self.active_count.fetch_add(1, Ordering::Relaxed);
         Ok(ConnectionGuard::new(Some(conn), self, permit))
     }
 
     pub fn return_connection(&self, conn: PooledConnection) {
+        self.release(conn, true);
+    }
+
+    // For connections that failed in a way that may have broken them before
+    // the client noticed, they are closed instead of handed out again
+    pub(crate) fn discard_connection(&self, conn: PooledConnection) {
+        self.release(conn, false);
+    }
+
+    fn release(&self, conn: PooledConnection, reuse: bool) {
         let stats = conn.cache_states();
         self.total_cache_hits
             .fetch_add(stats.hits, Ordering::Relaxed);
@@ -140,7 +229,9 @@ impl ConnectionPool {
 
         self.active_count.fetch_sub(1, Ordering::Relaxed);
 
-        if self.shutdown.load(Ordering::Acquire) {
+        // Dropped connections are not handed out again, a retry would only
+        // fail on them once more
+        if !reuse || self.shutdown.load(Ordering::Acquire) || conn.client.is_closed() {
             return;
         }
 
@@ -172,14 +263,42 @@ impl ConnectionPool {
     }
 
     async fn create_connection(&self) -> Result<PooledConnection> {
-        let config: Config = self
-            .config
-            .connection_string
-            .parse()
-            .sql_err(super::SqlErrorKind::Connection)
-            .map_err(|e| e.context("Invalid connection string"))?;
+        let client = self.connect_client(None).await?;
+        let mut conn = PooledConnection::new(client, self.config.statement_cache_capacity);
+        conn.prepare_statements(&self.config.statements, self.config.query_timeout)
+            .await?;
 
-        let (client, connection) = timeout(self.config.acquire_timeout, config.connect(NoTls))
+        self.total_created.fetch_add(1, Ordering::Relaxed);
+        Ok(conn)
+    }
+
+    // Opens a connection outside of the pool. Notifications received on it
+    // are forwarded to the given channel, which closes with the connection
+    pub(crate) async fn connect_client(
+        &self,
+        notifications: Option<mpsc::UnboundedSender<Notification>>,
+    ) -> Result<Client> {
+        let (config, _) = parse_connection_string(&self.config.connection_string)?;
+
+        match &self.tls {
+            Some(tls) => self.connect_with(&config, tls.clone(), notifications).await,
+            None => self.connect_with(&config, NoTls, notifications).await,
+        }
+    }
+
+    async fn connect_with<T>(
+        &self,
+        config: &Config,
+        tls: T,
+        notifications: Option<mpsc::UnboundedSender<Notification>>,
+    ) -> Result<Client>
+    where
+        T: MakeTlsConnect<Socket>,
+        T::Stream: Send + 'static,
+        T::TlsConnect: Send,
+        <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+    {
+        let (client, connection) = timeout(self.config.acquire_timeout, config.connect(tls))
             .await
             .map_err(|_| {
                 SqlError::new(super::SqlErrorKind::Timeout, "Connection attempt timed out")
@@ -187,17 +306,34 @@ impl ConnectionPool {
             .sql_err(super::SqlErrorKind::Connection)
             .map_err(|e| e.context("Failed to establish database connection"))?;
 
+        let Some(notifications) = notifications else {
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    tracing::error!("Connection error: {}", e)
+                }
+            });
+
+            return Ok(client);
+        };
+
         tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                tracing::error!("Connection error: {}", e)
+            let mut connection = connection;
+            let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+            while let Some(message) = messages.next().await {
+                match message {
+                    Ok(AsyncMessage::Notification(n)) => {
+                        let _ = notifications.send(n);
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        tracing::error!("Connection error: {}", e);
+                        break;
+                    }
+                }
             }
         });
 
-        self.total_created.fetch_add(1, Ordering::Relaxed);
-        Ok(PooledConnection::new(
-            client,
-            self.config.statement_cache_capacity,
-        ))
+        Ok(client)
     }
 
     async fn health_check_loop(self: Arc<Self>) {
@@ -0,0 +1,572 @@
+use crate::database::{
+    ConnectionGuard, ConnectionPool, QueryParam, Result, SqlError, SqlErrorKind, SqlResultExt,
+    StatementDef, StatementSet,
+};
+use futures::FutureExt;
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    panic::AssertUnwindSafe,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{Instant, timeout},
+};
+use tokio_postgres::{Row, Statement, types::ToSql};
+
+pub type QueueParam = Box<dyn ToSql + Send + Sync>;
+pub type QueryCallback = Box<dyn FnOnce(Result<Vec<Row>>) + Send>;
+
+// Delay before retrying a batch when no connection could be acquired
+const QUEUE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub enum QueuedSql {
+    Raw(String),
+    Prepared(&'static StatementDef),
+}
+
+impl From<&str> for QueuedSql {
+    fn from(sql: &str) -> Self {
+        Self::Raw(sql.to_string())
+    }
+}
+
+impl From<String> for QueuedSql {
+    fn from(sql: String) -> Self {
+        Self::Raw(sql)
+    }
+}
+
+impl<S: StatementSet> From<S> for QueuedSql {
+    fn from(statement: S) -> Self {
+        Self::Prepared(statement.definition())
+    }
+}
+
+impl QueuedSql {
+    fn sql(&self) -> &str {
+        match self {
+            Self::Raw(sql) => sql,
+            Self::Prepared(def) => def.sql,
+        }
+    }
+}
+
+// Statement with owned parameters so it can outlive the caller
+pub struct QueuedStatement {
+    sql: QueuedSql,
+    params: Vec<QueueParam>,
+}
+
+impl QueuedStatement {
+    pub fn new(sql: impl Into<QueuedSql>) -> Self {
+        Self {
+            sql: sql.into(),
+            params: Vec::new(),
+        }
+    }
+
+    pub fn param(mut self, value: impl ToSql + Send + Sync + 'static) -> Self {
+        self.params.push(Box::new(value));
+        self
+    }
+}
+
+enum QueuedKind {
+    Execute(QueuedStatement),
+    Transaction(Vec<QueuedStatement>),
+    Query(QueuedStatement),
+}
+
+// Work handed to the queue. Operations sharing an ordering key run one
+// after another in the order they were queued, others may run in parallel
+pub struct QueuedOperation {
+    kind: QueuedKind,
+    callback: Option<QueryCallback>,
+    key: Option<u64>,
+}
+
+impl QueuedOperation {
+    pub fn execute(statement: QueuedStatement) -> Self {
+        Self {
+            kind: QueuedKind::Execute(statement),
+            callback: None,
+            key: None,
+        }
+    }
+
+    // Statements applied together or not at all
+    pub fn transaction(statements: Vec<QueuedStatement>) -> Self {
+        Self {
+            kind: QueuedKind::Transaction(statements),
+            callback: None,
+            key: None,
+        }
+    }
+
+    // The callback runs on the queue worker once the rows are in
+    pub fn query(
+        statement: QueuedStatement,
+        callback: impl FnOnce(Result<Vec<Row>>) + Send + 'static,
+    ) -> Self {
+        Self {
+            kind: QueuedKind::Query(statement),
+            callback: Some(Box::new(callback)),
+            key: None,
+        }
+    }
+
+    pub fn ordered_by(mut self, key: impl Hash) -> Self {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.key = Some(hasher.finish());
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueStats {
+    pub depth: usize,
+    pub worker_depths: Vec<usize>,
+    pub processed: u64,
+    pub failed: u64,
+}
+
+#[derive(Default)]
+struct QueueCounters {
+    processed: AtomicU64,
+    failed: AtomicU64,
+}
+
+struct QueueWorker {
+    sender: mpsc::UnboundedSender<QueuedOperation>,
+    depth: Arc<AtomicUsize>,
+}
+
+// Write-behind queue in front of the pool. Each worker owns a channel and
+// runs what it receives in batches on a single connection, replaced when it
+// breaks. Transient failures are retried with the pool's query retry
+// policy, so like execute a retried write runs at least once
+pub struct WriteQueue {
+    workers: Mutex<Vec<QueueWorker>>,
+    depths: Vec<Arc<AtomicUsize>>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    counters: Arc<QueueCounters>,
+    next_worker: AtomicUsize,
+    // Set by shutdown, workers stop waiting for a connection after it
+    acquire_deadline: Arc<OnceLock<Instant>>,
+}
+
+impl WriteQueue {
+    pub fn new(pool: Arc<ConnectionPool>) -> Self {
+        let count = pool.config.queue_workers.max(1);
+        let batch_size = pool.config.queue_batch_size.max(1);
+        let counters = Arc::new(QueueCounters::default());
+        let acquire_deadline = Arc::new(OnceLock::new());
+
+        let mut workers = Vec::with_capacity(count);
+        let mut handles = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let depth = Arc::new(AtomicUsize::new(0));
+            handles.push(tokio::spawn(run_worker(
+                pool.clone(),
+                receiver,
+                depth.clone(),
+                counters.clone(),
+                acquire_deadline.clone(),
+                batch_size,
+            )));
+
+            workers.push(QueueWorker { sender, depth });
+        }
+
+        Self {
+            depths: workers.iter().map(|w| w.depth.clone()).collect(),
+            workers: Mutex::new(workers),
+            handles: Mutex::new(handles),
+            counters,
+            next_worker: AtomicUsize::new(0),
+            acquire_deadline,
+        }
+    }
+
+    pub fn enqueue(&self, operation: QueuedOperation) -> Result<()> {
+        let workers = self.workers.lock().unwrap();
+        if workers.is_empty() {
+            return Err(SqlError::new(
+                SqlErrorKind::Shutdown,
+                "Write queue is shut down",
+            ));
+        }
+
+        let index = match operation.key {
+            Some(key) => worker_for_key(key, workers.len()),
+            None => self.next_worker.fetch_add(1, Ordering::Relaxed) % workers.len(),
+        };
+
+        let worker = &workers[index];
+        worker.depth.fetch_add(1, Ordering::Relaxed);
+        worker.sender.send(operation).map_err(|_| {
+            worker.depth.fetch_sub(1, Ordering::Relaxed);
+            SqlError::new(SqlErrorKind::Shutdown, "Write queue worker has stopped")
+        })
+    }
+
+    // Stops accepting operations and waits for everything queued so far.
+    // Workers that can not get a connection give up after the pool's
+    // acquire timeout, failing what is left instead of hanging
+    pub async fn shutdown(&self, acquire_timeout: Duration) {
+        let _ = self.acquire_deadline.set(Instant::now() + acquire_timeout);
+        self.workers.lock().unwrap().clear();
+
+        let handles = std::mem::take(&mut *self.handles.lock().unwrap());
+        for handle in handles {
+            if let Err(e) = handle.await {
+                tracing::error!("Write queue worker failed: {}", e);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let worker_depths = self
+            .depths
+            .iter()
+            .map(|d| d.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+
+        QueueStats {
+            depth: worker_depths.iter().sum(),
+            worker_depths,
+            processed: self.counters.processed.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn worker_for_key(key: u64, workers: usize) -> usize {
+    (key % workers as u64) as usize
+}
+
+async fn run_worker(
+    pool: Arc<ConnectionPool>,
+    mut receiver: mpsc::UnboundedReceiver<QueuedOperation>,
+    depth: Arc<AtomicUsize>,
+    counters: Arc<QueueCounters>,
+    acquire_deadline: Arc<OnceLock<Instant>>,
+    batch_size: usize,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+
+    while receiver.recv_many(&mut batch, batch_size).await > 0 {
+        let mut conn = None;
+        for operation in batch.drain(..) {
+            let result = run_operation(&pool, &mut conn, operation, &acquire_deadline).await;
+
+            match result {
+                Ok(()) => counters.processed.fetch_add(1, Ordering::Relaxed),
+                Err(e) => {
+                    tracing::error!("Queued database operation failed: {}", e);
+                    counters.failed.fetch_add(1, Ordering::Relaxed)
+                }
+            };
+
+            depth.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Waits for a connection as long as the queue runs, after shutdown started
+// only until the deadline
+async fn acquire<'p>(
+    pool: &'p ConnectionPool,
+    acquire_deadline: &OnceLock<Instant>,
+) -> Result<ConnectionGuard<'p>> {
+    loop {
+        match pool.acquire().await {
+            Ok(conn) => return Ok(conn),
+            Err(e) if e.kind == SqlErrorKind::Shutdown => return Err(e),
+            Err(e) if acquire_deadline.get().is_some_and(|d| Instant::now() >= *d) => {
+                return Err(e.context("Write queue gave up on a connection at shutdown"));
+            }
+            Err(e) => {
+                tracing::error!("Write queue failed to acquire a connection: {}", e);
+                tokio::time::sleep(QUEUE_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn run_operation<'p>(
+    pool: &'p ConnectionPool,
+    conn: &mut Option<ConnectionGuard<'p>>,
+    operation: QueuedOperation,
+    acquire_deadline: &OnceLock<Instant>,
+) -> Result<()> {
+    let result = run_with_retry(pool, conn, &operation.kind, acquire_deadline).await;
+
+    match operation.callback {
+        // A panicking callback must not take the worker and the rest of
+        // its queue down with it
+        Some(callback) => AssertUnwindSafe(async move { callback(result) })
+            .catch_unwind()
+            .await
+            .map_err(|_| SqlError::new(SqlErrorKind::Panic, "Queued query callback panicked")),
+        None => result.map(|_| ()),
+    }
+}
+
+// A connection that failed in a way that may have broken it is dropped,
+// the retry and the rest of the batch run on a new one
+async fn run_with_retry<'p>(
+    pool: &'p ConnectionPool,
+    conn: &mut Option<ConnectionGuard<'p>>,
+    kind: &QueuedKind,
+    acquire_deadline: &OnceLock<Instant>,
+) -> Result<Vec<Row>> {
+    let retry = &pool.config.write_retry;
+    let query_timeout = pool.config.query_timeout;
+    let mut attempt = 1;
+
+    loop {
+        let guard = match conn {
+            Some(guard) => guard,
+            None => conn.insert(acquire(pool, acquire_deadline).await?),
+        };
+
+        let error = match run_once(guard, kind, query_timeout).await {
+            Ok(rows) => return Ok(rows),
+            Err(e) => e,
+        };
+
+        let broken = error.is_transient()
+            || error.kind == SqlErrorKind::Timeout
+            || guard.client().is_closed();
+        if broken && let Some(guard) = conn.take() {
+            guard.discard();
+        }
+
+        if !retry.should_retry(&error, attempt) {
+            return Err(error);
+        }
+
+        retry.wait("queued operation", attempt, &error).await;
+        attempt += 1;
+    }
+}
+
+async fn run_once(
+    conn: &mut ConnectionGuard<'_>,
+    kind: &QueuedKind,
+    query_timeout: Duration,
+) -> Result<Vec<Row>> {
+    match kind {
+        QueuedKind::Execute(statement) => {
+            let stmt = prepare(conn, &statement.sql, query_timeout).await?;
+            timeout(
+                query_timeout,
+                conn.client().execute(&stmt, &params(statement)),
+            )
+            .await
+            .map_err(|_| SqlError::new(SqlErrorKind::Timeout, "Queued statement timed out"))?
+            .with_query(statement.sql.sql())?;
+
+            Ok(Vec::new())
+        }
+        QueuedKind::Query(statement) => {
+            let stmt = prepare(conn, &statement.sql, query_timeout).await?;
+            timeout(
+                query_timeout,
+                conn.client().query(&stmt, &params(statement)),
+            )
+            .await
+            .map_err(|_| SqlError::new(SqlErrorKind::Timeout, "Queued query timed out"))?
+            .with_query(statement.sql.sql())
+        }
+        QueuedKind::Transaction(statements) => {
+            let mut prepared = Vec::with_capacity(statements.len());
+            for statement in statements {
+                prepared.push(prepare(conn, &statement.sql, query_timeout).await?);
+            }
+
+            let tx = conn
+                .client_mut()
+                .transaction()
+                .await
+                .sql_err(SqlErrorKind::Transaction)
+                .map_err(|e| e.context("Failed to begin queued transaction"))?;
+
+            for (statement, stmt) in statements.iter().zip(&prepared) {
+                timeout(query_timeout, tx.execute(stmt, &params(statement)))
+                    .await
+                    .map_err(|_| {
+                        SqlError::new(SqlErrorKind::Timeout, "Queued transaction timed out")
+                    })?
+                    .with_query(statement.sql.sql())
+                    .map_err(|e| e.context("Queued transaction rolled back"))?;
+            }
+
+            tx.commit()
+                .await
+                .sql_err(SqlErrorKind::Transaction)
+                .map_err(|e| e.context("Failed to commit queued transaction"))?;
+
+            Ok(Vec::new())
+        }
+    }
+}
+
+async fn prepare(
+    conn: &mut ConnectionGuard<'_>,
+    sql: &QueuedSql,
+    query_timeout: Duration,
+) -> Result<Statement> {
+    match sql {
+        QueuedSql::Raw(sql) => conn.conn_mut().prepare_cached(sql, query_timeout).await,
+        QueuedSql::Prepared(def) => conn.conn().statement(def),
+    }
+}
+
+fn params(statement: &QueuedStatement) -> Vec<&QueryParam> {
+    statement
+        .params
+        .iter()
+        .map(|p| p.as_ref() as &QueryParam)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::{
+        DatabaseHandle, PoolConfig, QueuedOperation, QueuedStatement, RetryPolicy,
+        queue::worker_for_key, test_connection,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_queue_ordering_key() {
+        let first = QueuedOperation::execute(QueuedStatement::new("SELECT 1")).ordered_by(42u32);
+        let second = QueuedOperation::execute(QueuedStatement::new("SELECT 2").param(5i32))
+            .ordered_by(42u32);
+        let unkeyed = QueuedOperation::execute(QueuedStatement::new("SELECT 3"));
+
+        assert_eq!(first.key, second.key);
+        assert!(unkeyed.key.is_none());
+
+        let key = first.key.unwrap();
+        for workers in 1..8 {
+            assert_eq!(
+                worker_for_key(key, workers),
+                worker_for_key(second.key.unwrap(), workers)
+            );
+            assert!(worker_for_key(key, workers) < workers);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_shutdown_without_database() {
+        let db = DatabaseHandle::connect_lazy(PoolConfig {
+            connection_string: "postgres://postgres@127.0.0.1:1/tc".to_string(),
+            min_connections: 0,
+            acquire_timeout: Duration::from_millis(500),
+            ..Default::default()
+        })
+        .unwrap();
+
+        db.enqueue(QueuedOperation::execute(QueuedStatement::new("SELECT 1")))
+            .unwrap();
+
+        // Gives up on the connection instead of hanging
+        tokio::time::timeout(Duration::from_secs(10), db.shutdown())
+            .await
+            .unwrap();
+
+        let stats = db.queue_stats();
+        assert_eq!((stats.processed, stats.failed, stats.depth), (0, 1, 0));
+        assert!(
+            db.enqueue(QueuedOperation::execute(QueuedStatement::new("SELECT 1")))
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queue_against_database() {
+        let Some(connection_string) = test_connection() else {
+            return;
+        };
+
+        let config = PoolConfig {
+            connection_string,
+            queue_workers: 4,
+            write_retry: RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_millis(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let table = format!("queue_test_{}", std::process::id());
+        let check = DatabaseHandle::connect(config.clone()).await.unwrap();
+        check
+            .execute(
+                &format!("CREATE TABLE {} (id SERIAL, key INT, seq INT)", table),
+                &[],
+            )
+            .await
+            .unwrap();
+
+        let db = DatabaseHandle::connect(config).await.unwrap();
+        let insert = format!("INSERT INTO {} (key, seq) VALUES ($1, $2)", table);
+        for seq in 0..50 {
+            for key in 0..8 {
+                let statement = QueuedStatement::new(insert.as_str()).param(key).param(seq);
+                db.enqueue(QueuedOperation::execute(statement).ordered_by(key))
+                    .unwrap();
+            }
+
+            // A failing operation and one that drops its own connection
+            // must not take the operations after them along
+            if seq == 10 {
+                let missing = QueuedStatement::new("INSERT INTO queue_test_missing VALUES (1)");
+                db.enqueue(QueuedOperation::execute(missing).ordered_by(3))
+                    .unwrap();
+            }
+            if seq == 20 {
+                let kill = QueuedStatement::new("SELECT pg_terminate_backend(pg_backend_pid())");
+                db.enqueue(QueuedOperation::execute(kill).ordered_by(5))
+                    .unwrap();
+            }
+        }
+
+        // Shutdown flushes everything queued
+        db.shutdown().await;
+        let stats = db.queue_stats();
+        assert_eq!((stats.processed, stats.failed, stats.depth), (400, 2, 0));
+
+        let rows = check
+            .query(&format!("SELECT key, seq FROM {} ORDER BY id", table), &[])
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 400);
+
+        let mut last = [-1; 8];
+        for row in rows {
+            let (key, seq): (i32, i32) = (row.get(0), row.get(1));
+            assert_eq!(seq, last[key as usize] + 1, "key {} out of order", key);
+            last[key as usize] = seq;
+        }
+
+        check
+            .execute(&format!("DROP TABLE {}", table), &[])
+            .await
+            .unwrap();
+        check.shutdown().await;
+    }
+}
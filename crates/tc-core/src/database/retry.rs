@@ -0,0 +1,126 @@
+use crate::database::{Result, SqlError};
+use rand::Rng;
+use std::time::Duration;
+
+// How often and how fast failed work is tried again. Only errors for which
+// SqlError::is_transient holds are retried. A write whose connection drops
+// may still have committed, so PoolConfig::write_retry defaults to
+// RetryPolicy::none(). Pools whose writes are idempotent can opt in, those
+// writes then run at least once, not exactly once
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // Attempts in total, 1 disables retrying
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    // Fraction of each delay that is randomized so clients failing together
+    // do not retry together
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            multiplier: 2.0,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn should_retry(&self, error: &SqlError, attempt: u32) -> bool {
+        attempt < self.max_attempts && error.is_transient()
+    }
+
+    // Delay after the given failed attempt, counting from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = (self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_backoff.as_secs_f64());
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 - jitter * rand::rng().random::<f64>();
+        Duration::from_secs_f64(delay * factor)
+    }
+
+    pub(crate) async fn run<F, Fut, T>(&self, context: &str, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Err(e) if self.should_retry(&e, attempt) => {
+                    self.wait(context, attempt, &e).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    pub(crate) async fn wait(&self, context: &str, attempt: u32, error: &SqlError) {
+        let delay = self.backoff(attempt);
+        tracing::warn!(
+            "Retrying {} in {:?} after transient error (attempt {}/{}): {}",
+            context,
+            delay,
+            attempt,
+            self.max_attempts,
+            error
+        );
+
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::{RetryPolicy, SqlError, SqlErrorKind};
+    use std::time::Duration;
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(500));
+
+        let jittered = RetryPolicy {
+            jitter: 0.5,
+            ..policy.clone()
+        };
+        for _ in 0..100 {
+            let delay = jittered.backoff(2);
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let transient = SqlError::with_source(SqlErrorKind::Connection, io);
+        let query = SqlError::new(SqlErrorKind::Query, "syntax error");
+        assert!(policy.should_retry(&transient, 4));
+        assert!(!policy.should_retry(&transient, 5));
+        assert!(!policy.should_retry(&query, 1));
+        assert!(!RetryPolicy::none().should_retry(&transient, 1));
+    }
+}
@@ -0,0 +1,418 @@
+use crate::database::{DatabaseHandle, PoolConfig, Result, SqlError, SqlErrorKind};
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::task::AbortHandle;
+use tokio_postgres::error::SqlState;
+
+// A primary with optional read replicas, each with its own pool
+#[derive(Clone)]
+pub struct DatabaseConfig {
+    pub primary: PoolConfig,
+    pub replicas: Vec<PoolConfig>,
+    // How often replicas are checked, unhealthy ones get no reads until a
+    // check passes again
+    pub replica_check_interval: Duration,
+}
+
+impl DatabaseConfig {
+    pub fn new(primary: PoolConfig) -> Self {
+        Self {
+            primary,
+            replicas: Vec::new(),
+            replica_check_interval: Duration::from_secs(5),
+        }
+    }
+
+    pub fn with_replica(mut self, replica: PoolConfig) -> Self {
+        self.replicas.push(replica);
+        self
+    }
+}
+
+struct Replica {
+    handle: DatabaseHandle,
+    healthy: AtomicBool,
+}
+
+impl Replica {
+    fn mark_unhealthy(&self, database: &str, index: usize, error: &SqlError) {
+        if self.healthy.swap(false, Ordering::Relaxed) {
+            tracing::warn!(
+                "Replica {} of database {} is unhealthy, reading from the primary: {}",
+                index,
+                database,
+                error
+            );
+        }
+    }
+}
+
+// One logical database. Writes and transactions go to the primary, reads
+// may be served by a replica
+pub struct RoutedDatabase {
+    name: String,
+    primary: DatabaseHandle,
+    replicas: Vec<Arc<Replica>>,
+    next_replica: AtomicUsize,
+    // Stopped on shutdown or drop, it keeps the replica pools alive
+    replica_check: Option<AbortHandle>,
+}
+
+impl RoutedDatabase {
+    pub async fn connect(name: impl Into<String>, config: DatabaseConfig) -> Result<Self> {
+        let name = name.into();
+        let primary = DatabaseHandle::connect(config.primary)
+            .await
+            .map_err(|e| e.context(format!("Failed to connect to database {}", name)))?;
+
+        // A replica that is down at startup only costs its reads, it starts
+        // unhealthy and the replica check brings it back
+        let mut replicas = Vec::with_capacity(config.replicas.len());
+        for (i, replica) in config.replicas.into_iter().enumerate() {
+            let replica = match DatabaseHandle::connect(replica.clone()).await {
+                Ok(handle) => (handle, true),
+                Err(e) => {
+                    tracing::warn!(
+                        "Replica {} of database {} is unreachable, reading from the primary: {}",
+                        i,
+                        name,
+                        e
+                    );
+                    (DatabaseHandle::connect_lazy(replica)?, false)
+                }
+            };
+
+            replicas.push(replica);
+        }
+
+        Ok(Self::with_handles(
+            name,
+            primary,
+            replicas,
+            config.replica_check_interval,
+        ))
+    }
+
+    fn with_handles(
+        name: String,
+        primary: DatabaseHandle,
+        replicas: Vec<(DatabaseHandle, bool)>,
+        check_interval: Duration,
+    ) -> Self {
+        let replicas = replicas
+            .into_iter()
+            .map(|(handle, healthy)| {
+                Arc::new(Replica {
+                    handle,
+                    healthy: AtomicBool::new(healthy),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let replica_check = (!replicas.is_empty()).then(|| {
+            tokio::spawn(check_replicas(
+                name.clone(),
+                replicas.clone(),
+                check_interval,
+            ))
+            .abort_handle()
+        });
+
+        Self {
+            name,
+            primary,
+            replicas,
+            next_replica: AtomicUsize::new(0),
+            replica_check,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn primary(&self) -> &DatabaseHandle {
+        &self.primary
+    }
+
+    // Handle for read only work, the next healthy replica or the primary
+    // when there is none
+    pub fn reader(&self) -> &DatabaseHandle {
+        self.healthy_replica()
+            .map(|(_, replica)| &replica.handle)
+            .unwrap_or(&self.primary)
+    }
+
+    // Runs the read on a replica and again on the primary if the replica
+    // fails with a transient error or times out
+    pub async fn read<'a, F, Fut, T>(&'a self, f: F) -> Result<T>
+    where
+        F: Fn(&'a DatabaseHandle) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        if let Some((index, replica)) = self.healthy_replica() {
+            match f(&replica.handle).await {
+                Err(e) if is_replica_failure(&e) => replica.mark_unhealthy(&self.name, index, &e),
+                result => return result,
+            }
+        }
+
+        f(&self.primary).await
+    }
+
+    pub fn healthy_replicas(&self) -> usize {
+        self.replicas
+            .iter()
+            .filter(|r| r.healthy.load(Ordering::Relaxed))
+            .count()
+    }
+
+    pub async fn shutdown(&self) {
+        if let Some(replica_check) = &self.replica_check {
+            replica_check.abort();
+        }
+
+        for replica in &self.replicas {
+            replica.handle.shutdown().await;
+        }
+
+        self.primary.shutdown().await;
+    }
+
+    fn healthy_replica(&self) -> Option<(usize, &Replica)> {
+        let count = self.replicas.len();
+        let start = self.next_replica.fetch_add(1, Ordering::Relaxed);
+        (0..count)
+            .map(|offset| (start + offset) % count)
+            .map(|index| (index, self.replicas[index].as_ref()))
+            .find(|(_, replica)| replica.healthy.load(Ordering::Relaxed))
+    }
+}
+
+impl Drop for RoutedDatabase {
+    fn drop(&mut self) {
+        if let Some(replica_check) = &self.replica_check {
+            replica_check.abort();
+        }
+    }
+}
+
+// Timeouts count too, an overloaded replica is as good as a dead one. That
+// covers waiting for a connection, the client side query timeout and the
+// server's statement_timeout
+fn is_replica_failure(error: &SqlError) -> bool {
+    error.is_transient()
+        || error.kind == SqlErrorKind::Timeout
+        || error.sqlstate() == Some(&SqlState::QUERY_CANCELED)
+}
+
+async fn check_replicas(name: String, replicas: Vec<Arc<Replica>>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        for (index, replica) in replicas.iter().enumerate() {
+            match replica.handle.query_scalar::<i32>("SELECT 1", &[]).await {
+                Ok(_) => {
+                    if !replica.healthy.swap(true, Ordering::Relaxed) {
+                        tracing::info!("Replica {} of database {} is healthy again", index, name);
+                    }
+                }
+                Err(e) => replica.mark_unhealthy(&name, index, &e),
+            }
+        }
+    }
+}
+
+// Named databases of one process, e.g. auth, characters and world. Cheap to
+// clone so it can live in shared server state
+#[derive(Clone, Default)]
+pub struct DatabaseSet {
+    databases: HashMap<String, Arc<RoutedDatabase>>,
+}
+
+impl DatabaseSet {
+    pub async fn connect<N, I>(configs: I) -> Result<Self>
+    where
+        N: Into<String>,
+        I: IntoIterator<Item = (N, DatabaseConfig)>,
+    {
+        let mut set = Self::default();
+        for (name, config) in configs {
+            let database = RoutedDatabase::connect(name, config).await?;
+            set.insert(database);
+        }
+
+        Ok(set)
+    }
+
+    pub fn insert(&mut self, database: RoutedDatabase) {
+        self.databases
+            .insert(database.name.clone(), Arc::new(database));
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Arc<RoutedDatabase>> {
+        self.databases
+            .get(name)
+            .ok_or_else(|| SqlError::new(SqlErrorKind::Pool, format!("Unknown database {}", name)))
+    }
+
+    pub fn primary(&self, name: &str) -> Result<&DatabaseHandle> {
+        Ok(self.get(name)?.primary())
+    }
+
+    pub fn reader(&self, name: &str) -> Result<&DatabaseHandle> {
+        Ok(self.get(name)?.reader())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.databases.keys().map(|name| name.as_str())
+    }
+
+    pub async fn shutdown(&self) {
+        for database in self.databases.values() {
+            database.shutdown().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::{
+        DatabaseConfig, DatabaseHandle, DatabaseSet, PoolConfig, Result, RoutedDatabase, SqlError,
+        SqlErrorKind,
+    };
+    use std::{io, ptr, sync::Arc, time::Duration};
+
+    // Nothing listens on port 1, so handles work without a server until
+    // they are used
+    fn unreachable(min_connections: usize) -> PoolConfig {
+        PoolConfig {
+            connection_string: "postgres://postgres@127.0.0.1:1/tc".to_string(),
+            min_connections,
+            ..Default::default()
+        }
+    }
+
+    fn routed(replicas: &[bool]) -> RoutedDatabase {
+        let handle = || DatabaseHandle::connect_lazy(unreachable(0)).unwrap();
+        RoutedDatabase::with_handles(
+            "auth".to_string(),
+            handle(),
+            replicas
+                .iter()
+                .map(|&healthy| (handle(), healthy))
+                .collect(),
+            Duration::from_secs(3600),
+        )
+    }
+
+    #[test]
+    fn test_database_set_lookup() {
+        let set = DatabaseSet::default();
+        let err = set.primary("world").err().unwrap();
+        assert_eq!(err.kind, SqlErrorKind::Pool);
+        assert_eq!(set.names().count(), 0);
+
+        let config = DatabaseConfig::new(PoolConfig::default())
+            .with_replica(PoolConfig::default())
+            .with_replica(PoolConfig::default());
+        assert_eq!(config.replicas.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reader_routing() {
+        let db = routed(&[true, true]);
+        let first = db.reader();
+        let second = db.reader();
+        assert!(!ptr::eq(first, second));
+        assert!(!ptr::eq(first, db.primary()));
+        assert!(ptr::eq(db.reader(), first));
+
+        let error = SqlError::new(SqlErrorKind::Timeout, "replica down");
+        db.replicas[0].mark_unhealthy("auth", 0, &error);
+        assert_eq!(db.healthy_replicas(), 1);
+        for _ in 0..3 {
+            assert!(ptr::eq(db.reader(), &db.replicas[1].handle));
+        }
+
+        db.replicas[1].mark_unhealthy("auth", 1, &error);
+        assert!(ptr::eq(db.reader(), db.primary()));
+    }
+
+    #[tokio::test]
+    async fn test_read_failover() {
+        let db = routed(&[true]);
+        let primary = db.primary();
+        let value = db
+            .read(|handle| async move {
+                if ptr::eq(handle, primary) {
+                    Ok(1)
+                } else {
+                    let dropped = io::Error::from(io::ErrorKind::ConnectionReset);
+                    Err(SqlError::with_source(SqlErrorKind::Connection, dropped))
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(value, 1);
+        assert_eq!(db.healthy_replicas(), 0);
+
+        // A replica too slow to answer is given up on as well
+        let db = routed(&[true]);
+        let primary = db.primary();
+        let value = db
+            .read(|handle| async move {
+                if ptr::eq(handle, primary) {
+                    Ok(1)
+                } else {
+                    Err(SqlError::new(
+                        SqlErrorKind::Timeout,
+                        "Query execution timed out",
+                    ))
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(value, 1);
+        assert_eq!(db.healthy_replicas(), 0);
+
+        // Errors of the query itself are not the replica's fault
+        let db = routed(&[true]);
+        let result: Result<i32> = db
+            .read(|_| async { Err(SqlError::new(SqlErrorKind::Query, "syntax error")) })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(db.healthy_replicas(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_drop_stops_replica_check() {
+        let db = routed(&[true]);
+        let replica = Arc::downgrade(&db.replicas[0]);
+        drop(db);
+
+        for _ in 0..100 {
+            if replica.upgrade().is_none() {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        panic!("replica check outlived its database");
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_replica_starts_unhealthy() {
+        let config = DatabaseConfig::new(unreachable(0)).with_replica(unreachable(1));
+        let db = RoutedDatabase::connect("auth", config).await.unwrap();
+        assert_eq!(db.healthy_replicas(), 0);
+        assert!(ptr::eq(db.reader(), db.primary()));
+    }
+}
@@ -0,0 +1,233 @@
+use crate::{
+    crypto::defines::{PasswordVerifier, Salt},
+    database::{Result, SqlError, SqlErrorKind},
+};
+use std::fmt::Display;
+use tokio_postgres::{
+    row::RowIndex,
+    types::{FromSql, Type},
+};
+
+pub use tc_macros::FromRow;
+pub use tokio_postgres::Row;
+
+// Builds a value out of a single result row, usually through
+// #[derive(FromRow)]
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self>;
+}
+
+// Column lookup by name or by position
+pub trait RowColumn: RowIndex + Display + Copy {
+    fn exists(&self, row: &Row) -> bool;
+}
+
+impl RowColumn for &str {
+    fn exists(&self, row: &Row) -> bool {
+        row.columns().iter().any(|c| c.name() == *self)
+    }
+}
+
+impl RowColumn for usize {
+    fn exists(&self, row: &Row) -> bool {
+        *self < row.len()
+    }
+}
+
+pub fn row_column<T, I>(row: &Row, column: I) -> Result<T>
+where
+    T: for<'a> FromSql<'a>,
+    I: RowColumn,
+{
+    row.try_get(column).map_err(|e| {
+        SqlError::with_source(SqlErrorKind::Query, e)
+            .context(format!("Failed to read column {}", column))
+    })
+}
+
+// Same as row_column but a column missing from the result gives the
+// default value instead of an error
+pub fn row_column_optional<T, I>(row: &Row, column: I) -> Result<T>
+where
+    T: for<'a> FromSql<'a> + Default,
+    I: RowColumn,
+{
+    if !column.exists(row) {
+        return Ok(T::default());
+    }
+
+    row_column(row, column)
+}
+
+pub(crate) fn rows_as<T: FromRow>(rows: &[Row], sql: &str) -> Result<Vec<T>> {
+    rows.iter()
+        .map(|row| T::from_row(row).map_err(|e| e.query(sql)))
+        .collect()
+}
+
+pub(crate) fn single_row(rows: Vec<Row>, sql: &str) -> Result<Row> {
+    match rows.len() {
+        0 => Err(SqlError::new(SqlErrorKind::Query, "Expected a single row, got none").query(sql)),
+        1 => Ok(rows.into_iter().next().unwrap()),
+        n => Err(SqlError::new(
+            SqlErrorKind::Query,
+            format!("Expected a single row, got {}", n),
+        )
+        .query(sql)),
+    }
+}
+
+pub(crate) fn single_row_as<T: FromRow>(rows: Vec<Row>, sql: &str) -> Result<Option<T>> {
+    match rows.len() {
+        0 => Ok(None),
+        1 => T::from_row(&rows[0]).map(Some).map_err(|e| e.query(sql)),
+        n => Err(SqlError::new(
+            SqlErrorKind::Query,
+            format!("Expected at most a single row, got {}", n),
+        )
+        .query(sql)),
+    }
+}
+
+// Keys are stored as their little endian bytes in BYTEA columns
+macro_rules! impl_from_sql_key {
+    ($name: ty) => {
+        impl<'a> FromSql<'a> for $name {
+            fn from_sql(
+                ty: &Type,
+                raw: &'a [u8],
+            ) -> std::result::Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+                let bytes = <&[u8] as FromSql>::from_sql(ty, raw)?;
+                let key = <[u8; <$name>::SIZE]>::try_from(bytes).map_err(|_| {
+                    format!(
+                        "Expected {} bytes for {}, got {}",
+                        <$name>::SIZE,
+                        stringify!($name),
+                        bytes.len()
+                    )
+                })?;
+
+                Ok(Self::from_bytes_le(&key))
+            }
+
+            fn accepts(ty: &Type) -> bool {
+                <&[u8] as FromSql>::accepts(ty)
+            }
+        }
+    };
+}
+
+impl_from_sql_key!(Salt);
+impl_from_sql_key!(PasswordVerifier);
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        crypto::defines::{PasswordVerifier, Salt},
+        database::{FromRow, QueryParam, SqlErrorKind, test_database},
+    };
+    use tokio_postgres::types::{FromSql, Type};
+
+    #[derive(FromRow)]
+    struct Account {
+        id: i64,
+        #[row(rename = "username")]
+        name: String,
+        #[row(optional)]
+        email: Option<String>,
+        #[row(optional)]
+        logins: i32,
+        #[row(flatten)]
+        credentials: Credentials,
+    }
+
+    #[derive(FromRow)]
+    struct Credentials {
+        salt: Salt,
+        verifier: PasswordVerifier,
+    }
+
+    #[test]
+    fn test_key_from_bytea() {
+        let salt = Salt::randomized();
+        let bytes = salt.as_bytes_le();
+
+        let read = Salt::from_sql(&Type::BYTEA, &bytes).unwrap();
+        assert_eq!(read, salt);
+
+        assert!(Salt::from_sql(&Type::BYTEA, &bytes[1..]).is_err());
+        assert!(<PasswordVerifier as FromSql>::accepts(&Type::BYTEA));
+        assert!(!<PasswordVerifier as FromSql>::accepts(&Type::TEXT));
+    }
+
+    #[tokio::test]
+    async fn test_from_row_against_database() {
+        let Some(db) = test_database().await else {
+            return;
+        };
+
+        let salt = Salt::randomized();
+        let verifier = PasswordVerifier::from_bytes_le(&[7; PasswordVerifier::SIZE]);
+        let keys: [&QueryParam; 2] = [
+            &salt.as_bytes_le().to_vec(),
+            &verifier.as_bytes_le().to_vec(),
+        ];
+
+        // Optional columns missing from the result take their default
+        let account: Account = db
+            .query_one_as(
+                "SELECT 7::BIGINT AS id, 'alice' AS username, $1::BYTEA AS salt, $2::BYTEA AS verifier",
+                &keys,
+            )
+            .await
+            .unwrap();
+        assert_eq!((account.id, account.name.as_str()), (7, "alice"));
+        assert_eq!((account.email, account.logins), (None, 0));
+        assert_eq!(account.credentials.salt, salt);
+        assert_eq!(
+            account.credentials.verifier.as_bytes_le(),
+            verifier.as_bytes_le()
+        );
+
+        // Present optional columns are read, NULL included
+        let accounts: Vec<Account> = db
+            .query_as(
+                "SELECT id, 'bob' AS username, email, 3 AS logins, $1::BYTEA AS salt, $2::BYTEA AS verifier \
+                 FROM (VALUES (1::BIGINT, 'bob@example.org'), (2, NULL)) AS a (id, email) ORDER BY id",
+                &keys,
+            )
+            .await
+            .unwrap();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].email.as_deref(), Some("bob@example.org"));
+        assert_eq!(accounts[0].logins, 3);
+        assert_eq!(accounts[1].email, None);
+
+        // Renamed columns are looked up by their new name only
+        let error = db
+            .query_one_as::<Account>(
+                "SELECT 1::BIGINT AS id, 'carol' AS name, $1::BYTEA AS salt, $2::BYTEA AS verifier",
+                &keys,
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind, SqlErrorKind::Query);
+        assert!(format!("{:#}", error).contains("username"));
+
+        // A missing column that is not optional is an error, flattened
+        // fields included
+        let error = db
+            .query_one_as::<Account>(
+                "SELECT 1::BIGINT AS id, 'carol' AS username, $1::BYTEA AS salt",
+                &keys[..1],
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind, SqlErrorKind::Query);
+        assert!(format!("{:#}", error).contains("verifier"));
+
+        db.shutdown().await;
+    }
+}
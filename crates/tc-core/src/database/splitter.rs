@@ -0,0 +1,234 @@
+// Splits a sql script into its statements on top level semicolons. String
+// literals, quoted identifiers, dollar quoted bodies and comments are
+// skipped, so function bodies and DO blocks stay in one piece. Statements
+// made up only of comments are dropped.
+//
+// Bodies written as BEGIN ATOMIC ... END are not understood, quote the
+// function body with $$ instead
+pub fn split_statements(sql: &str) -> Vec<&str> {
+    let bytes = sql.as_bytes();
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut has_code = false;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        let next = bytes.get(i + 1).copied();
+
+        match (c, next) {
+            (b'-', Some(b'-')) => {
+                i = skip_line_comment(bytes, i);
+                continue;
+            }
+            (b'/', Some(b'*')) => {
+                i = skip_block_comment(bytes, i);
+                continue;
+            }
+            (b';', _) => {
+                if has_code {
+                    statements.push(sql[start..i].trim());
+                }
+
+                start = i + 1;
+                has_code = false;
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        has_code |= !c.is_ascii_whitespace();
+        i = match c {
+            b'\'' => skip_quoted(bytes, i, b'\'', is_escape_string(bytes, i)),
+            b'"' => skip_quoted(bytes, i, b'"', false),
+            b'$' => skip_dollar_quoted(bytes, i).unwrap_or(i + 1),
+            _ => i + 1,
+        };
+    }
+
+    if has_code {
+        statements.push(sql[start..].trim());
+    }
+
+    statements
+}
+
+fn is_identifier_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'$' || b >= 0x80
+}
+
+fn skip_line_comment(bytes: &[u8], start: usize) -> usize {
+    bytes[start..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(bytes.len(), |offset| start + offset)
+}
+
+// Block comments nest in postgres
+fn skip_block_comment(bytes: &[u8], start: usize) -> usize {
+    let mut depth = 0;
+    let mut i = start;
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1)) {
+            (b'/', Some(b'*')) => {
+                depth += 1;
+                i += 2;
+            }
+            (b'*', Some(b'/')) => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => i += 1,
+        }
+    }
+
+    bytes.len()
+}
+
+// E'...' strings allow backslash escapes, including \'
+fn is_escape_string(bytes: &[u8], quote: usize) -> bool {
+    quote > 0
+        && matches!(bytes[quote - 1], b'E' | b'e')
+        && (quote < 2 || !is_identifier_byte(bytes[quote - 2]))
+}
+
+// Doubled quotes stand for the quote itself
+fn skip_quoted(bytes: &[u8], start: usize, quote: u8, backslash_escapes: bool) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        let b = bytes[i];
+        if backslash_escapes && b == b'\\' {
+            i += 2;
+        } else if b == quote {
+            if bytes.get(i + 1) == Some(&quote) {
+                i += 2;
+            } else {
+                return i + 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+
+    bytes.len()
+}
+
+// $$ ... $$ or $tag$ ... $tag$. None when the dollar sign does not open a
+// quote, as in $1 parameters or identifiers containing a dollar sign
+fn skip_dollar_quoted(bytes: &[u8], start: usize) -> Option<usize> {
+    if start > 0 && is_identifier_byte(bytes[start - 1]) {
+        return None;
+    }
+
+    let mut end = start + 1;
+    let first = *bytes.get(end)?;
+    if first != b'$' {
+        if !(first.is_ascii_alphabetic() || first == b'_' || first >= 0x80) {
+            return None;
+        }
+
+        while end < bytes.len() && bytes[end] != b'$' {
+            if !is_identifier_byte(bytes[end]) {
+                return None;
+            }
+
+            end += 1;
+        }
+
+        if end == bytes.len() {
+            return None;
+        }
+    }
+
+    let tag = &bytes[start..=end];
+    let body = end + 1;
+    Some(
+        bytes[body..]
+            .windows(tag.len())
+            .position(|window| window == tag)
+            .map_or(bytes.len(), |offset| body + offset + tag.len()),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::split_statements;
+
+    #[test]
+    fn test_split_statements() {
+        let corpus: &[(&str, &[&str])] = &[
+            ("SELECT 1; SELECT 2;", &["SELECT 1", "SELECT 2"]),
+            ("SELECT 1;\n\n  ;;SELECT 2", &["SELECT 1", "SELECT 2"]),
+            (
+                "INSERT INTO t VALUES ('a;b', 'it''s; here');",
+                &["INSERT INTO t VALUES ('a;b', 'it''s; here')"],
+            ),
+            (
+                "SELECT E'\\';', 1; SELECT 2",
+                &["SELECT E'\\';', 1", "SELECT 2"],
+            ),
+            (
+                "SELECT 'ends with \\'; SELECT 2",
+                &["SELECT 'ends with \\'", "SELECT 2"],
+            ),
+            (
+                "CREATE TABLE \"odd;name\" (\"a\"\"b;\" INT); SELECT 1",
+                &["CREATE TABLE \"odd;name\" (\"a\"\"b;\" INT)", "SELECT 1"],
+            ),
+            (
+                "-- first; still a comment\nSELECT 1; -- trailing; comment",
+                &["-- first; still a comment\nSELECT 1"],
+            ),
+            (
+                "/* outer /* nested; */ still; comment */ SELECT 1; SELECT 2",
+                &[
+                    "/* outer /* nested; */ still; comment */ SELECT 1",
+                    "SELECT 2",
+                ],
+            ),
+            (
+                "CREATE FUNCTION f() RETURNS INT AS $$ BEGIN RETURN 1; END; $$ LANGUAGE plpgsql; SELECT f();",
+                &[
+                    "CREATE FUNCTION f() RETURNS INT AS $$ BEGIN RETURN 1; END; $$ LANGUAGE plpgsql",
+                    "SELECT f()",
+                ],
+            ),
+            (
+                "DO $body$ BEGIN PERFORM '$$;'; RAISE NOTICE 'x;'; END $body$; SELECT 1",
+                &[
+                    "DO $body$ BEGIN PERFORM '$$;'; RAISE NOTICE 'x;'; END $body$",
+                    "SELECT 1",
+                ],
+            ),
+            (
+                "CREATE FUNCTION g() RETURNS TRIGGER AS $fn$ BEGIN $$;$$; END $fn$ LANGUAGE plpgsql; SELECT 2",
+                &[
+                    "CREATE FUNCTION g() RETURNS TRIGGER AS $fn$ BEGIN $$;$$; END $fn$ LANGUAGE plpgsql",
+                    "SELECT 2",
+                ],
+            ),
+            (
+                "PREPARE p AS SELECT $1; SELECT a$b FROM t; SELECT 3",
+                &["PREPARE p AS SELECT $1", "SELECT a$b FROM t", "SELECT 3"],
+            ),
+            (
+                "SELECT 'unterminated; SELECT 2",
+                &["SELECT 'unterminated; SELECT 2"],
+            ),
+            ("-- only a comment; here\n/* and; this */", &[]),
+            (
+                "SELECT 'ü;'; SELECT \"ñ\"",
+                &["SELECT 'ü;'", "SELECT \"ñ\""],
+            ),
+            ("", &[]),
+        ];
+
+        for (sql, expected) in corpus {
+            assert_eq!(&split_statements(sql), expected, "splitting {:?}", sql);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use crate::database::{Result, SqlError, SqlErrorKind};
+use std::{collections::HashMap, fmt::Debug};
+use tokio_postgres::{Statement, types::Type};
+
+pub use tokio_postgres::types::Type as SqlType;
+
+// A statement known up front. Every pooled connection prepares all of the
+// registered statements when it is opened
+#[derive(Debug, Clone)]
+pub struct StatementDef {
+    pub name: &'static str,
+    pub sql: &'static str,
+    pub params: &'static [Type],
+}
+
+// Implemented by statement enums declared with define_statements!
+pub trait StatementSet: Copy + Debug + Send + Sync + 'static {
+    fn all() -> &'static [StatementDef];
+    fn definition(&self) -> &'static StatementDef;
+}
+
+pub(crate) fn find_statement(
+    statements: &HashMap<&'static str, Statement>,
+    def: &StatementDef,
+) -> Result<Statement> {
+    statements.get(def.name).cloned().ok_or_else(|| {
+        SqlError::new(
+            SqlErrorKind::Query,
+            format!("Statement {} is not registered with the pool", def.name),
+        )
+        .query(def.sql)
+    })
+}
+
+// Declares an enum of statement ids along with their sql and parameter
+// types:
+//
+// define_statements! {
+//     pub enum LoginStatements {
+//         SelAccountByName = ("SELECT id FROM account WHERE username=$1", [SqlType::VARCHAR]),
+//     }
+// }
+#[macro_export]
+macro_rules! define_statements {
+    (
+        $vis: vis enum $name: ident {
+            $($variant: ident = ($sql: expr, [$($param: expr),* $(,)?])),* $(,)?
+        }
+    ) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        $vis enum $name {
+            $($variant),*
+        }
+
+        impl $name {
+            const DEFINITIONS: &'static [$crate::database::StatementDef] = &[
+                $($crate::database::StatementDef {
+                    name: concat!(stringify!($name), "::", stringify!($variant)),
+                    sql: $sql,
+                    params: &[$($param),*],
+                }),*
+            ];
+        }
+
+        impl $crate::database::StatementSet for $name {
+            fn all() -> &'static [$crate::database::StatementDef] {
+                Self::DEFINITIONS
+            }
+
+            fn definition(&self) -> &'static $crate::database::StatementDef {
+                &Self::DEFINITIONS[*self as usize]
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::{SqlType, StatementSet};
+
+    crate::define_statements! {
+        enum TestStatements {
+            First = ("SELECT 1", []),
+            Second = ("SELECT $1::BIGINT, $2::TEXT", [SqlType::INT8, SqlType::TEXT]),
+        }
+    }
+
+    #[test]
+    fn test_statement_definitions() {
+        assert_eq!(TestStatements::all().len(), 2);
+
+        let second = TestStatements::Second.definition();
+        assert_eq!(second.name, "TestStatements::Second");
+        assert_eq!(second.sql, "SELECT $1::BIGINT, $2::TEXT");
+        assert_eq!(second.params, &[SqlType::INT8, SqlType::TEXT]);
+        assert!(TestStatements::First.definition().params.is_empty());
+    }
+}
@@ -0,0 +1,544 @@
+use crate::database::{PoolConfig, Result, SqlError, SqlErrorKind, SqlResultExt};
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    crypto::{
+        CryptoProvider, WebPkiSupportedAlgorithms, ring, verify_tls12_signature,
+        verify_tls13_signature,
+    },
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+};
+use std::{borrow::Cow, path::PathBuf, sync::Arc};
+use tokio_postgres::{Config, config::SslMode};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+// sslmode values tokio-postgres does not know, connected to as require
+const VERIFY_MODES: [(&str, TlsVerification); 2] = [
+    ("sslmode=verify-ca", TlsVerification::Ca),
+    ("sslmode=verify-full", TlsVerification::Full),
+];
+
+// How much of the server certificate is checked, following sslmode like
+// libpq. require only encrypts, verify-ca checks that a trusted CA signed
+// the certificate and verify-full also that it was issued for the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVerification {
+    None,
+    Ca,
+    Full,
+}
+
+// Certificates used for encrypted connections. Whether TLS is used at all
+// follows sslmode in the connection string: disable never, prefer when the
+// server supports it and require, verify-ca and verify-full always
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    // PEM bundle of trusted roots, the Mozilla roots when unset
+    pub ca_cert: Option<PathBuf>,
+    // PEM certificate chain and key for certificate authentication
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub fn with_ca_cert(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ca_cert = Some(path.into());
+        self
+    }
+
+    pub fn with_client_cert(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.client_cert = Some(cert.into());
+        self.client_key = Some(key.into());
+        self
+    }
+
+    pub fn connector(&self, verification: TlsVerification) -> Result<MakeRustlsConnect> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| tls_error(e, "Unsupported TLS protocol versions"))?;
+
+        let builder = match verification {
+            TlsVerification::None => {
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(NoVerifier(
+                        provider.signature_verification_algorithms,
+                    )))
+            }
+            TlsVerification::Ca => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AnyHostnameVerifier(
+                    self.verifier(provider)?,
+                ))),
+            TlsVerification::Full => builder.with_webpki_verifier(self.verifier(provider)?),
+        };
+
+        let config = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let key = PrivateKeyDer::from_pem_file(key).map_err(|e| {
+                    tls_error(e, format!("Failed to read client key {}", key.display()))
+                })?;
+
+                builder
+                    .with_client_auth_cert(read_certs(cert)?, key)
+                    .map_err(|e| tls_error(e, "Invalid client certificate"))?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => {
+                return Err(SqlError::new(
+                    SqlErrorKind::Connection,
+                    "Client certificate and key must be set together",
+                ));
+            }
+        };
+
+        Ok(MakeRustlsConnect::new(config))
+    }
+
+    fn verifier(&self, provider: Arc<CryptoProvider>) -> Result<Arc<WebPkiServerVerifier>> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_cert {
+            Some(path) => {
+                for cert in read_certs(path)? {
+                    roots.add(cert).map_err(|e| {
+                        tls_error(e, format!("Invalid CA certificate in {}", path.display()))
+                    })?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+
+        WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider)
+            .build()
+            .map_err(|e| tls_error(e, "Failed to build certificate verifier"))
+    }
+}
+
+// tokio-postgres only parses sslmode disable, prefer and require. The verify
+// modes are taken out here and the rest is parsed as require
+pub(crate) fn parse_connection_string(
+    connection_string: &str,
+) -> Result<(Config, TlsVerification)> {
+    let (connection_string, verification) = VERIFY_MODES
+        .iter()
+        .find(|(mode, _)| connection_string.contains(mode))
+        .map(|&(mode, verification)| {
+            let replaced = connection_string.replace(mode, "sslmode=require");
+            (Cow::Owned(replaced), verification)
+        })
+        .unwrap_or((Cow::Borrowed(connection_string), TlsVerification::None));
+
+    let config = connection_string
+        .parse()
+        .sql_err(SqlErrorKind::Connection)
+        .map_err(|e| e.context("Invalid connection string"))?;
+
+    Ok((config, verification))
+}
+
+// TLS connector for the pool, None when connections stay unencrypted
+pub(crate) fn pool_connector(config: &PoolConfig) -> Result<Option<MakeRustlsConnect>> {
+    let (parsed, verification) = parse_connection_string(&config.connection_string)?;
+
+    match (parsed.get_ssl_mode(), &config.tls) {
+        (SslMode::Disable, _) => Ok(None),
+        (_, Some(tls)) => tls.connector(verification).map(Some),
+        (SslMode::Require, None) => TlsConfig::default().connector(verification).map(Some),
+        _ => Ok(None),
+    }
+}
+
+fn read_certs(path: &PathBuf) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| tls_error(e, format!("Failed to read certificates {}", path.display())))?;
+
+    if certs.is_empty() {
+        return Err(SqlError::new(
+            SqlErrorKind::Connection,
+            format!("No certificates found in {}", path.display()),
+        ));
+    }
+
+    Ok(certs)
+}
+
+fn tls_error(
+    error: impl std::error::Error + Send + Sync + 'static,
+    context: impl Into<String>,
+) -> SqlError {
+    SqlError::with_source(SqlErrorKind::Connection, error).context(context.into())
+}
+
+// Accepts any certificate for sslmode=require, the handshake signatures are
+// still checked against it
+#[derive(Debug)]
+struct NoVerifier(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
+
+// Full chain verification that accepts certificates issued for another name
+#[derive(Debug)]
+struct AnyHostnameVerifier(Arc<WebPkiServerVerifier>);
+
+impl ServerCertVerifier for AnyHostnameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        match self
+            .0
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+        {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) => Ok(ServerCertVerified::assertion()),
+            result => result,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::{
+        DatabaseHandle, PoolConfig, SqlErrorKind, TlsConfig, TlsVerification, test_connection,
+        tls::{parse_connection_string, pool_connector},
+    };
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use ring::{
+        rand::SystemRandom,
+        signature::{ECDSA_P256_SHA256_ASN1_SIGNING, EcdsaKeyPair, KeyPair},
+    };
+    use rustls::{
+        ServerConfig,
+        crypto::ring::default_provider,
+        pki_types::{CertificateDer, PrivateKeyDer},
+    };
+    use std::{
+        str::FromStr,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_postgres::config::{Host, SslMode};
+    use tokio_rustls::TlsAcceptor;
+    use x509_cert::{
+        Certificate, TbsCertificate, Version,
+        der::{
+            Encode,
+            asn1::{Any, BitString, Ia5String, OctetString},
+            oid::db::{rfc5280::ID_CE_SUBJECT_ALT_NAME, rfc5912},
+        },
+        ext::{
+            Extension,
+            pkix::{SubjectAltName, name::GeneralName},
+        },
+        name::Name,
+        serial_number::SerialNumber,
+        spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned},
+        time::{Time, Validity},
+    };
+
+    #[test]
+    fn test_pool_connector_follows_sslmode() {
+        let config = |connection_string: &str, tls: Option<TlsConfig>| PoolConfig {
+            connection_string: connection_string.to_string(),
+            tls,
+            ..Default::default()
+        };
+
+        let tls = Some(TlsConfig::default());
+        assert!(pool_connector(&config("host=db", None)).unwrap().is_none());
+        assert!(
+            pool_connector(&config("host=db sslmode=require", None))
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            pool_connector(&config("host=db sslmode=verify-full", None))
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            pool_connector(&config("host=db", tls.clone()))
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            pool_connector(&config("host=db sslmode=disable", tls))
+                .unwrap()
+                .is_none()
+        );
+
+        let missing_key = TlsConfig {
+            client_cert: Some("client.crt".into()),
+            ..Default::default()
+        };
+        assert!(missing_key.connector(TlsVerification::Full).is_err());
+    }
+
+    #[test]
+    fn test_parse_verify_modes() {
+        let modes = [
+            ("host=db", SslMode::Prefer, TlsVerification::None),
+            (
+                "host=db sslmode=require",
+                SslMode::Require,
+                TlsVerification::None,
+            ),
+            (
+                "host=db sslmode=verify-ca",
+                SslMode::Require,
+                TlsVerification::Ca,
+            ),
+            (
+                "postgres://tc@db/auth?sslmode=verify-full",
+                SslMode::Require,
+                TlsVerification::Full,
+            ),
+        ];
+
+        for (connection_string, ssl_mode, verification) in modes {
+            let (config, parsed) = parse_connection_string(connection_string).unwrap();
+            assert_eq!(config.get_ssl_mode(), ssl_mode, "{}", connection_string);
+            assert_eq!(parsed, verification, "{}", connection_string);
+        }
+
+        let (config, _) = parse_connection_string("postgres://tc@db/auth").unwrap();
+        assert_eq!(config.get_dbname(), Some("auth"));
+        assert!(parse_connection_string("host=db sslmode=verify").is_err());
+    }
+
+    // Self-signed certificate for localhost and its key
+    fn self_signed() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let rng = SystemRandom::new();
+        let algorithm = &ECDSA_P256_SHA256_ASN1_SIGNING;
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(algorithm, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(algorithm, pkcs8.as_ref(), &rng).unwrap();
+
+        let signature_algorithm = AlgorithmIdentifierOwned {
+            oid: rfc5912::ECDSA_WITH_SHA_256,
+            parameters: None,
+        };
+        let name = Name::from_str("CN=localhost").unwrap();
+        let now = SystemTime::now();
+        let alt_names = SubjectAltName(vec![GeneralName::DnsName(
+            Ia5String::new("localhost").unwrap(),
+        )]);
+
+        let tbs_certificate = TbsCertificate {
+            version: Version::V3,
+            serial_number: SerialNumber::new(&[1]).unwrap(),
+            signature: signature_algorithm.clone(),
+            issuer: name.clone(),
+            validity: Validity {
+                not_before: Time::try_from(now - Duration::from_secs(3600)).unwrap(),
+                not_after: Time::try_from(now + Duration::from_secs(86400)).unwrap(),
+            },
+            subject: name,
+            subject_public_key_info: SubjectPublicKeyInfoOwned {
+                algorithm: AlgorithmIdentifierOwned {
+                    oid: rfc5912::ID_EC_PUBLIC_KEY,
+                    parameters: Some(Any::encode_from(&rfc5912::SECP_256_R_1).unwrap()),
+                },
+                subject_public_key: BitString::from_bytes(key.public_key().as_ref()).unwrap(),
+            },
+            issuer_unique_id: None,
+            subject_unique_id: None,
+            extensions: Some(vec![Extension {
+                extn_id: ID_CE_SUBJECT_ALT_NAME,
+                critical: false,
+                extn_value: OctetString::new(alt_names.to_der().unwrap()).unwrap(),
+            }]),
+        };
+
+        let signature = key.sign(&rng, &tbs_certificate.to_der().unwrap()).unwrap();
+        let certificate = Certificate {
+            tbs_certificate,
+            signature_algorithm,
+            signature: BitString::from_bytes(signature.as_ref()).unwrap(),
+        };
+
+        (
+            CertificateDer::from(certificate.to_der().unwrap()),
+            PrivateKeyDer::Pkcs8(pkcs8.as_ref().to_vec().into()),
+        )
+    }
+
+    // Answers postgres' TLS request with the certificate and passes what it
+    // decrypts on to a server that runs without TLS
+    async fn tls_proxy(
+        target: (String, u16),
+        cert: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+    ) -> u16 {
+        let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let (acceptor, target) = (acceptor.clone(), target.clone());
+                tokio::spawn(async move {
+                    // SSLRequest is a length of 8 and the request code
+                    let mut request = [0; 8];
+                    client.read_exact(&mut request).await?;
+                    client.write_all(b"S").await?;
+
+                    let mut client = acceptor.accept(client).await?;
+                    let mut server = TcpStream::connect(target).await?;
+                    tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+                    Ok::<_, std::io::Error>(())
+                });
+            }
+        });
+
+        port
+    }
+
+    #[tokio::test]
+    async fn test_sslmode_against_database() {
+        let Some(connection_string) = test_connection() else {
+            return;
+        };
+
+        let (config, _) = parse_connection_string(&connection_string).unwrap();
+        let Some(Host::Tcp(host)) = config.get_hosts().first() else {
+            return;
+        };
+        let target = (
+            host.clone(),
+            config.get_ports().first().copied().unwrap_or(5432),
+        );
+        let user = config.get_user().unwrap();
+        let mut options = format!(
+            "hostaddr=127.0.0.1 user={} dbname={}",
+            user,
+            config.get_dbname().unwrap_or(user)
+        );
+        if let Some(password) = config.get_password() {
+            options += &format!(" password={}", String::from_utf8_lossy(password));
+        }
+
+        let (cert, key) = self_signed();
+        let ca_cert = std::env::temp_dir().join(format!("tc_tls_test_{}.pem", std::process::id()));
+        let pem = format!(
+            "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+            STANDARD.encode(&cert)
+        );
+        std::fs::write(&ca_cert, pem).unwrap();
+
+        let port = tls_proxy(target, cert, key).await;
+        let trusted = Some(TlsConfig::default().with_ca_cert(&ca_cert));
+        let connect = async |host: &str, sslmode: &str, tls: Option<TlsConfig>| {
+            let config = PoolConfig {
+                connection_string: format!(
+                    "host={} port={} {} sslmode={}",
+                    host, port, options, sslmode
+                ),
+                min_connections: 1,
+                tls,
+                ..Default::default()
+            };
+
+            let db = DatabaseHandle::connect(config).await?;
+            let value = db.query_scalar::<i32>("SELECT 1", &[]).await;
+            db.shutdown().await;
+            value
+        };
+
+        // require encrypts without looking at the certificate
+        assert_eq!(connect("localhost", "require", None).await.unwrap(), 1);
+
+        // verify-full needs a trusted certificate issued for the host,
+        // verify-ca only the trusted certificate
+        let untrusted = connect("localhost", "verify-full", None).await.unwrap_err();
+        assert_eq!(untrusted.kind, SqlErrorKind::Connection);
+        assert_eq!(
+            connect("localhost", "verify-full", trusted.clone())
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(
+            connect("127.0.0.1", "verify-full", trusted.clone())
+                .await
+                .is_err()
+        );
+        assert_eq!(connect("127.0.0.1", "verify-ca", trusted).await.unwrap(), 1);
+
+        std::fs::remove_file(ca_cert).unwrap();
+    }
+}
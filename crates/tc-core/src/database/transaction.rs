@@ -1,19 +1,104 @@
-use std::time::Duration;
+use futures::future::try_join_all;
+use std::{collections::HashMap, time::Duration};
 use tokio::{sync::RwLock, time::timeout};
-use tokio_postgres::{Row, Statement, Transaction as PgTransaction};
+use tokio_postgres::{IsolationLevel, Row, Statement, Transaction as PgTransaction};
 
 use crate::database::{
-    QueryParam, Result, SqlError, SqlErrorKind, SqlResultExt,
+    FromRow, NOTIFY_SQL, QueryParam, Result, SqlError, SqlErrorKind, SqlResultExt, StatementSet,
     cache::{CacheStats, PreparedStatementCache},
+    row::{rows_as, single_row_as},
+    statements::find_statement,
 };
 
+pub use tokio_postgres::IsolationLevel as SqlIsolationLevel;
+
+// Server defaults apply for anything not set, see transaction_with
+#[derive(Debug, Clone, Default)]
+pub struct TransactionOptions {
+    pub isolation: Option<IsolationLevel>,
+    pub read_only: bool,
+    // Only has an effect on serializable read only transactions, which then
+    // wait for a safe snapshot instead of risking serialization failures
+    pub deferrable: bool,
+}
+
+impl TransactionOptions {
+    pub fn serializable() -> Self {
+        Self {
+            isolation: Some(IsolationLevel::Serializable),
+            ..Default::default()
+        }
+    }
+
+    pub fn repeatable_read() -> Self {
+        Self {
+            isolation: Some(IsolationLevel::RepeatableRead),
+            ..Default::default()
+        }
+    }
+
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    pub fn deferrable(mut self) -> Self {
+        self.deferrable = true;
+        self
+    }
+}
+
 pub struct TransactionContext<'a> {
     pub tx: &'a PgTransaction<'a>,
     pub cache: &'a RwLock<PreparedStatementCache>,
+    pub statements: &'a HashMap<&'static str, Statement>,
     pub query_timeout: Duration,
+    // Savepoints currently open around this context
+    pub(crate) depth: u32,
 }
 
 impl<'a> TransactionContext<'a> {
+    // Runs the closure inside a savepoint. An error rolls back only what
+    // the closure did and is returned, the surrounding transaction can go
+    // on and still commit
+    pub async fn savepoint<F, T>(&self, f: F) -> Result<T>
+    where
+        F: for<'c> AsyncFnOnce(TransactionContext<'c>) -> Result<T>,
+    {
+        let name = format!("tc_savepoint_{}", self.depth + 1);
+        self.savepoint_command(&format!("SAVEPOINT {}", name))
+            .await?;
+
+        let ctx = TransactionContext {
+            tx: self.tx,
+            cache: self.cache,
+            statements: self.statements,
+            query_timeout: self.query_timeout,
+            depth: self.depth + 1,
+        };
+
+        match f(ctx).await {
+            Ok(result) => {
+                self.savepoint_command(&format!("RELEASE SAVEPOINT {}", name))
+                    .await?;
+                Ok(result)
+            }
+            Err(e) => {
+                self.savepoint_command(&format!("ROLLBACK TO SAVEPOINT {}", name))
+                    .await?;
+                Err(e.context("Savepoint rolled back"))
+            }
+        }
+    }
+
+    async fn savepoint_command(&self, sql: &str) -> Result<()> {
+        timeout(self.query_timeout, self.tx.batch_execute(sql))
+            .await
+            .map_err(|_| SqlError::new(SqlErrorKind::Timeout, "Savepoint timed out"))?
+            .sql_err(SqlErrorKind::Transaction)
+            .map_err(|e| e.query(sql))
+    }
+
     pub async fn query(&self, sql: &str, params: &[&QueryParam]) -> Result<Vec<Row>> {
         let stmt = self.prepare_cached(sql).await?;
         timeout(self.query_timeout, self.tx.query(&stmt, params))
@@ -50,6 +135,25 @@ impl<'a> TransactionContext<'a> {
         })
     }
 
+    pub async fn query_as<T: FromRow>(&self, sql: &str, params: &[&QueryParam]) -> Result<Vec<T>> {
+        let rows = self.query(sql, params).await?;
+        rows_as(&rows, sql)
+    }
+
+    pub async fn query_one_as<T: FromRow>(&self, sql: &str, params: &[&QueryParam]) -> Result<T> {
+        let row = self.query_single(sql, params).await?;
+        T::from_row(&row).map_err(|e| e.query(sql))
+    }
+
+    pub async fn query_opt_as<T: FromRow>(
+        &self,
+        sql: &str,
+        params: &[&QueryParam],
+    ) -> Result<Option<T>> {
+        let rows = self.query(sql, params).await?;
+        single_row_as(rows, sql)
+    }
+
     pub async fn execute(&self, sql: &str, params: &[&QueryParam]) -> Result<u64> {
         let stmt = self.prepare_cached(sql).await?;
         timeout(self.query_timeout, self.tx.execute(&stmt, params))
@@ -59,6 +163,77 @@ impl<'a> TransactionContext<'a> {
             .map_err(|e| e.query(sql).context("Transaction execute failed"))
     }
 
+    // Pipelined like DatabaseHandle::execute_batch, atomic as part of the
+    // transaction
+    pub async fn execute_batch(&self, statements: &[(&str, &[&QueryParam])]) -> Result<Vec<u64>> {
+        let mut prepared = Vec::with_capacity(statements.len());
+        for (sql, _) in statements {
+            prepared.push(self.prepare_cached(sql).await?);
+        }
+
+        let pending = statements
+            .iter()
+            .zip(&prepared)
+            .map(|((sql, params), stmt)| async move {
+                self.tx.execute(stmt, params).await.with_query(sql)
+            });
+
+        timeout(self.query_timeout, try_join_all(pending))
+            .await
+            .map_err(|_| SqlError::new(SqlErrorKind::Timeout, "Transaction batch timed out"))?
+            .map_err(|e| e.context("Transaction batch failed"))
+    }
+
+    pub async fn query_statement<S: StatementSet>(
+        &self,
+        statement: S,
+        params: &[&QueryParam],
+    ) -> Result<Vec<Row>> {
+        let def = statement.definition();
+        let stmt = find_statement(self.statements, def)?;
+        timeout(self.query_timeout, self.tx.query(&stmt, params))
+            .await
+            .map_err(|_| SqlError::new(SqlErrorKind::Timeout, "Transaction query timed out"))?
+            .sql_err(SqlErrorKind::Query)
+            .map_err(|e| {
+                e.query(def.sql)
+                    .context(format!("Transaction statement {} failed", def.name))
+            })
+    }
+
+    pub async fn query_statement_as<S: StatementSet, T: FromRow>(
+        &self,
+        statement: S,
+        params: &[&QueryParam],
+    ) -> Result<Vec<T>> {
+        let rows = self.query_statement(statement, params).await?;
+        rows_as(&rows, statement.definition().sql)
+    }
+
+    pub async fn execute_statement<S: StatementSet>(
+        &self,
+        statement: S,
+        params: &[&QueryParam],
+    ) -> Result<u64> {
+        let def = statement.definition();
+        let stmt = find_statement(self.statements, def)?;
+        timeout(self.query_timeout, self.tx.execute(&stmt, params))
+            .await
+            .map_err(|_| SqlError::new(SqlErrorKind::Timeout, "Transaction execute timed out"))?
+            .sql_err(SqlErrorKind::Query)
+            .map_err(|e| {
+                e.query(def.sql)
+                    .context(format!("Transaction statement {} failed", def.name))
+            })
+    }
+
+    // Delivered to subscribers only once the transaction commits
+    pub async fn notify(&self, channel: &str, payload: &str) -> Result<()> {
+        let (channel, payload) = (channel.to_string(), payload.to_string());
+        self.execute(NOTIFY_SQL, &[&channel, &payload]).await?;
+        Ok(())
+    }
+
     pub async fn cache_stats(&self) -> CacheStats {
         self.cache.read().await.stats()
     }
@@ -81,3 +256,131 @@ impl<'a> TransactionContext<'a> {
         Ok(stmt)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::database::{SqlErrorKind, TransactionOptions, test_database};
+    use tokio_postgres::error::SqlState;
+
+    #[tokio::test]
+    async fn test_savepoint_against_database() {
+        let Some(db) = test_database().await else {
+            return;
+        };
+
+        let table = format!("savepoint_test_{}", std::process::id());
+        db.execute(&format!("CREATE TABLE {} (id INT PRIMARY KEY)", table), &[])
+            .await
+            .unwrap();
+
+        let insert = format!("INSERT INTO {} VALUES ($1)", table);
+        let insert = insert.as_str();
+        db.transaction(async |ctx| {
+            ctx.execute(insert, &[&1]).await?;
+
+            ctx.savepoint(async |outer| {
+                // Rolling back to a savepoint only works under its own name
+                assert_eq!(outer.depth, 1);
+                outer
+                    .execute("ROLLBACK TO SAVEPOINT tc_savepoint_1", &[])
+                    .await?;
+                outer.execute(insert, &[&2]).await?;
+
+                // A failed statement aborts the transaction, rolling back the
+                // savepoint it ran in makes it usable again
+                let nested = outer
+                    .savepoint(async |inner| {
+                        assert_eq!(inner.depth, 2);
+                        inner
+                            .execute("ROLLBACK TO SAVEPOINT tc_savepoint_2", &[])
+                            .await?;
+                        inner.execute(insert, &[&3]).await?;
+                        inner.execute(insert, &[&1]).await
+                    })
+                    .await;
+                let error = nested.unwrap_err();
+                assert_eq!(error.sqlstate(), Some(&SqlState::UNIQUE_VIOLATION));
+
+                outer.execute(insert, &[&4]).await
+            })
+            .await?;
+
+            let failed = ctx
+                .savepoint(async |inner| {
+                    inner.execute(insert, &[&5]).await?;
+                    inner.query_scalar::<i32>("SELECT 1 / 0", &[]).await
+                })
+                .await;
+            assert_eq!(failed.unwrap_err().kind, SqlErrorKind::Query);
+
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        let ids: Vec<i32> = db
+            .query(&format!("SELECT id FROM {} ORDER BY id", table), &[])
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(ids, [1, 2, 4]);
+
+        db.execute(&format!("DROP TABLE {}", table), &[])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transaction_options_against_database() {
+        let Some(db) = test_database().await else {
+            return;
+        };
+
+        let settings = async |options: TransactionOptions| {
+            db.transaction_with(options, async |ctx| {
+                let isolation = ctx.query_scalar("SHOW transaction_isolation", &[]).await?;
+                let read_only = ctx.query_scalar("SHOW transaction_read_only", &[]).await?;
+                Ok::<(String, String), _>((isolation, read_only))
+            })
+            .await
+            .unwrap()
+        };
+
+        assert_eq!(
+            settings(TransactionOptions::repeatable_read()).await,
+            ("repeatable read".to_string(), "off".to_string())
+        );
+        assert_eq!(
+            settings(TransactionOptions::serializable().read_only()).await,
+            ("serializable".to_string(), "on".to_string())
+        );
+
+        let table = format!("read_only_test_{}", std::process::id());
+        db.execute(&format!("CREATE TABLE {} (id INT)", table), &[])
+            .await
+            .unwrap();
+
+        let insert = format!("INSERT INTO {} VALUES (1)", table);
+        let insert = insert.as_str();
+        let error = db
+            .transaction_with(TransactionOptions::default().read_only(), async |ctx| {
+                ctx.execute(insert, &[]).await
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind, SqlErrorKind::Query);
+        assert_eq!(error.sqlstate(), Some(&SqlState::READ_ONLY_SQL_TRANSACTION));
+
+        let count: i64 = db
+            .query_scalar(&format!("SELECT COUNT(*) FROM {}", table), &[])
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+
+        db.execute(&format!("DROP TABLE {}", table), &[])
+            .await
+            .unwrap();
+    }
+}
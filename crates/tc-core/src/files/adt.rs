@@ -0,0 +1,537 @@
+use crate::files::reader::{ByteReader, chunks, string_at};
+use anyhow::{Result, anyhow};
+
+pub const ADT_CHUNKS_PER_SIDE: usize = 16;
+pub const ADT_CHUNK_COUNT: usize = ADT_CHUNKS_PER_SIDE * ADT_CHUNKS_PER_SIDE;
+pub const ADT_CELLS_PER_CHUNK: usize = 8;
+pub const ADT_CHUNK_HEIGHT_COUNT: usize = 145;
+
+pub const MCNK_FLAG_LIQUID_RIVER: u32 = 0x04;
+pub const MCNK_FLAG_LIQUID_OCEAN: u32 = 0x08;
+pub const MCNK_FLAG_LIQUID_MAGMA: u32 = 0x10;
+pub const MCNK_FLAG_LIQUID_SLIME: u32 = 0x20;
+
+const MCLQ_FLAG_FISHABLE: u8 = 0x40;
+const MCLQ_FLAG_FATIGUE: u8 = 0x80;
+
+const MCNK_HEADER_SIZE: usize = 128;
+const CHUNK_HEADER_SIZE: usize = 8;
+const MH2O_INSTANCE_SIZE: usize = 24;
+const MDDF_ENTRY_SIZE: usize = 36;
+const MODF_ENTRY_SIZE: usize = 64;
+
+// M2 placement from the MDDF chunk, position and rotation (degrees) are in
+// the client's placement coordinate system
+#[derive(Debug, Clone)]
+pub struct AdtDoodadPlacement {
+    pub name_id: u32,
+    pub unique_id: u32,
+    pub position: [f32; 3],
+    pub rotation: [f32; 3],
+    // Fixed point scale where 1024 is 1.0
+    pub scale: u16,
+    pub flags: u16,
+}
+
+impl AdtDoodadPlacement {
+    pub fn scale(&self) -> f32 {
+        self.scale as f32 / 1024.0
+    }
+
+    pub fn parse_all(data: &[u8]) -> Result<Vec<Self>> {
+        let mut reader = ByteReader::new(data);
+        let mut placements = Vec::with_capacity(data.len() / MDDF_ENTRY_SIZE);
+
+        while reader.remaining() >= MDDF_ENTRY_SIZE {
+            placements.push(Self {
+                name_id: reader.read_u32()?,
+                unique_id: reader.read_u32()?,
+                position: reader.read_vec3()?,
+                rotation: reader.read_vec3()?,
+                scale: reader.read_u16()?,
+                flags: reader.read_u16()?,
+            });
+        }
+
+        Ok(placements)
+    }
+}
+
+// WMO placement from the MODF chunk of an ADT or a WMO only WDT
+#[derive(Debug, Clone)]
+pub struct AdtWmoPlacement {
+    pub name_id: u32,
+    pub unique_id: u32,
+    pub position: [f32; 3],
+    pub rotation: [f32; 3],
+    pub extents_min: [f32; 3],
+    pub extents_max: [f32; 3],
+    pub flags: u16,
+    pub doodad_set: u16,
+    pub name_set: u16,
+}
+
+impl AdtWmoPlacement {
+    pub fn parse_all(data: &[u8]) -> Result<Vec<Self>> {
+        let mut reader = ByteReader::new(data);
+        let mut placements = Vec::with_capacity(data.len() / MODF_ENTRY_SIZE);
+
+        while reader.remaining() >= MODF_ENTRY_SIZE {
+            let placement = Self {
+                name_id: reader.read_u32()?,
+                unique_id: reader.read_u32()?,
+                position: reader.read_vec3()?,
+                rotation: reader.read_vec3()?,
+                extents_min: reader.read_vec3()?,
+                extents_max: reader.read_vec3()?,
+                flags: reader.read_u16()?,
+                doodad_set: reader.read_u16()?,
+                name_set: reader.read_u16()?,
+            };
+
+            reader.skip(2)?;
+            placements.push(placement);
+        }
+
+        Ok(placements)
+    }
+}
+
+// Resolves a string block (MMDX, MWMO) through its offset table (MMID, MWID)
+// so placements can index names by their name_id directly
+pub fn resolve_names(names: &[u8], offsets: &[u8]) -> Result<Vec<String>> {
+    let mut reader = ByteReader::new(offsets);
+    let mut resolved = Vec::with_capacity(offsets.len() / 4);
+
+    while reader.remaining() >= 4 {
+        let offset = reader.read_u32()? as usize;
+        resolved.push(
+            string_at(names, offset)
+                .ok_or_else(|| anyhow!("Name offset {} is outside of the string block", offset))?,
+        );
+    }
+
+    Ok(resolved)
+}
+
+// Liquid stored in the pre-WotLK MCLQ sub chunk of a map chunk, still
+// present in a number of 3.3.5a tiles that were never converted to MH2O
+#[derive(Debug, Clone)]
+pub struct AdtLegacyLiquid {
+    pub min_height: f32,
+    pub max_height: f32,
+    pub heights: [f32; 81],
+    pub flags: [u8; 64],
+}
+
+impl AdtLegacyLiquid {
+    // Cells with the low nibble fully set are not covered by liquid
+    pub fn has_cell(&self, x: usize, y: usize) -> bool {
+        self.flags[y * 8 + x] & 0x0F != 0x0F
+    }
+
+    pub fn is_fishable(&self, x: usize, y: usize) -> bool {
+        self.flags[y * 8 + x] & MCLQ_FLAG_FISHABLE != 0
+    }
+
+    // Deep water that drains fatigue while swimming in it
+    pub fn is_fatigue(&self, x: usize, y: usize) -> bool {
+        self.flags[y * 8 + x] & MCLQ_FLAG_FATIGUE != 0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AdtMapChunk {
+    pub flags: u32,
+    pub index_x: u32,
+    pub index_y: u32,
+    pub area_id: u32,
+    pub holes: u16,
+    pub position: [f32; 3],
+    // Interleaved 9x9 outer and 8x8 inner vertices relative to position[2]
+    pub heights: Option<Box<[f32; ADT_CHUNK_HEIGHT_COUNT]>>,
+    pub legacy_liquid: Option<AdtLegacyLiquid>,
+}
+
+impl AdtMapChunk {
+    fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < MCNK_HEADER_SIZE {
+            return Err(anyhow!("MCNK chunk is smaller than its header"));
+        }
+
+        let mut reader = ByteReader::new(data);
+        let flags = reader.read_u32()?;
+        let index_x = reader.read_u32()?;
+        let index_y = reader.read_u32()?;
+
+        reader.seek(0x14)?;
+        let ofs_height = reader.read_u32()? as usize;
+
+        reader.seek(0x34)?;
+        let area_id = reader.read_u32()?;
+        reader.skip(4)?;
+        let holes = reader.read_u16()?;
+
+        reader.seek(0x60)?;
+        let ofs_liquid = reader.read_u32()? as usize;
+        let size_liquid = reader.read_u32()? as usize;
+        let position = reader.read_vec3()?;
+
+        // Sub chunk offsets are relative to the MCNK chunk header which the
+        // chunk iterator already consumed, so skipping the sub chunk's own
+        // header lands exactly on the offset value within data
+        let heights = if ofs_height >= CHUNK_HEADER_SIZE {
+            let mut reader = ByteReader::new(data);
+            reader.seek(ofs_height)?;
+
+            let mut heights = Box::new([0f32; ADT_CHUNK_HEIGHT_COUNT]);
+            for height in heights.iter_mut() {
+                *height = reader.read_f32()?;
+            }
+
+            Some(heights)
+        } else {
+            None
+        };
+
+        let legacy_liquid = if ofs_liquid >= CHUNK_HEADER_SIZE && size_liquid > CHUNK_HEADER_SIZE {
+            let mut reader = ByteReader::new(data);
+            reader.seek(ofs_liquid)?;
+
+            let min_height = reader.read_f32()?;
+            let max_height = reader.read_f32()?;
+            let mut heights = [0f32; 81];
+            for height in heights.iter_mut() {
+                reader.skip(4)?;
+                *height = reader.read_f32()?;
+            }
+
+            let flags = reader.read_array::<64>()?;
+            Some(AdtLegacyLiquid {
+                min_height,
+                max_height,
+                heights,
+                flags,
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            flags,
+            index_x,
+            index_y,
+            area_id,
+            holes,
+            position,
+            heights,
+            legacy_liquid,
+        })
+    }
+
+    // Height of the outer (9x9) vertex at x, y including the chunk base height
+    pub fn outer_height(&self, x: usize, y: usize) -> f32 {
+        let base = self.position[2];
+        match &self.heights {
+            Some(heights) => base + heights[y * 17 + x],
+            None => base,
+        }
+    }
+
+    // Height of the inner (8x8) vertex at the center of cell x, y
+    pub fn inner_height(&self, x: usize, y: usize) -> f32 {
+        let base = self.position[2];
+        match &self.heights {
+            Some(heights) => base + heights[y * 17 + 9 + x],
+            None => base,
+        }
+    }
+
+    pub fn has_hole(&self, cell_x: usize, cell_y: usize) -> bool {
+        // Low resolution holes cover 2x2 cells per bit
+        let bit = (cell_y / 2) * 4 + cell_x / 2;
+        self.holes & (1 << bit) != 0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AdtLiquidInstance {
+    pub liquid_type: u16,
+    pub vertex_format: u16,
+    pub min_height: f32,
+    pub max_height: f32,
+    pub x_offset: u8,
+    pub y_offset: u8,
+    pub width: u8,
+    pub height: u8,
+    // Row major bit per covered cell, bits outside width * height are unused
+    pub exists: u64,
+    // (width + 1) * (height + 1) vertex heights when the format carries them
+    pub heights: Option<Vec<f32>>,
+}
+
+impl AdtLiquidInstance {
+    pub fn has_cell(&self, x: usize, y: usize) -> bool {
+        let bit = y * self.width as usize + x;
+        self.exists & (1 << bit) != 0
+    }
+
+    pub fn vertex_height(&self, x: usize, y: usize) -> f32 {
+        match &self.heights {
+            Some(heights) => heights[y * (self.width as usize + 1) + x],
+            None => self.min_height,
+        }
+    }
+
+    fn parse(data: &[u8], offset: usize) -> Result<Self> {
+        let mut reader = ByteReader::new(data);
+        reader.seek(offset)?;
+
+        let liquid_type = reader.read_u16()?;
+        let vertex_format = reader.read_u16()?;
+        let min_height = reader.read_f32()?;
+        let max_height = reader.read_f32()?;
+        let x_offset = reader.read_u8()?;
+        let y_offset = reader.read_u8()?;
+        let width = reader.read_u8()?;
+        let height = reader.read_u8()?;
+        let ofs_exists = reader.read_u32()? as usize;
+        let ofs_vertex = reader.read_u32()? as usize;
+
+        if x_offset as usize + width as usize > ADT_CELLS_PER_CHUNK
+            || y_offset as usize + height as usize > ADT_CELLS_PER_CHUNK
+        {
+            return Err(anyhow!("MH2O instance extends outside of its chunk"));
+        }
+
+        let cell_count = width as usize * height as usize;
+        let exists = if ofs_exists != 0 {
+            let mut reader = ByteReader::new(data);
+            reader.seek(ofs_exists)?;
+
+            let mut mask = 0u64;
+            for i in 0..cell_count.div_ceil(8) {
+                mask |= (reader.read_u8()? as u64) << (i * 8);
+            }
+
+            mask
+        } else if cell_count == 64 {
+            u64::MAX
+        } else {
+            (1u64 << cell_count) - 1
+        };
+
+        // Format 2 only stores depth values, everything else starts with a
+        // float height per vertex
+        let heights = if ofs_vertex != 0 && vertex_format != 2 {
+            let mut reader = ByteReader::new(data);
+            reader.seek(ofs_vertex)?;
+
+            let count = (width as usize + 1) * (height as usize + 1);
+            let mut heights = Vec::with_capacity(count);
+            for _ in 0..count {
+                heights.push(reader.read_f32()?);
+            }
+
+            Some(heights)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            liquid_type,
+            vertex_format,
+            min_height,
+            max_height,
+            x_offset,
+            y_offset,
+            width,
+            height,
+            exists,
+            heights,
+        })
+    }
+}
+
+// Per chunk MH2O cell masks, row major with a bit per cell of the 8x8 grid
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AdtLiquidAttributes {
+    pub fishable: u64,
+    // Cells causing fatigue while swimming
+    pub deep: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct AdtFile {
+    pub version: u32,
+    pub chunks: Vec<AdtMapChunk>,
+    // MH2O liquid layers per map chunk, indexed like chunks
+    pub liquids: Vec<Vec<AdtLiquidInstance>>,
+    pub liquid_attributes: Vec<AdtLiquidAttributes>,
+    // M2 and WMO file names indexed by the placements name_id
+    pub doodad_names: Vec<String>,
+    pub wmo_names: Vec<String>,
+    pub doodads: Vec<AdtDoodadPlacement>,
+    pub wmos: Vec<AdtWmoPlacement>,
+}
+
+impl AdtFile {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut version = 0;
+        let mut map_chunks = Vec::with_capacity(ADT_CHUNK_COUNT);
+        let mut liquids = vec![Vec::new(); ADT_CHUNK_COUNT];
+        let mut liquid_attributes = vec![AdtLiquidAttributes::default(); ADT_CHUNK_COUNT];
+        let (mut mmdx, mut mmid, mut mwmo, mut mwid) = (&[][..], &[][..], &[][..], &[][..]);
+        let mut doodads = Vec::new();
+        let mut wmos = Vec::new();
+
+        for chunk in chunks(data) {
+            let chunk = chunk?;
+            match &chunk.id {
+                b"MVER" => version = ByteReader::new(chunk.data).read_u32()?,
+                b"MCNK" => map_chunks.push(AdtMapChunk::parse(chunk.data)?),
+                b"MH2O" => (liquids, liquid_attributes) = Self::parse_mh2o(chunk.data)?,
+                b"MMDX" => mmdx = chunk.data,
+                b"MMID" => mmid = chunk.data,
+                b"MWMO" => mwmo = chunk.data,
+                b"MWID" => mwid = chunk.data,
+                b"MDDF" => doodads = AdtDoodadPlacement::parse_all(chunk.data)?,
+                b"MODF" => wmos = AdtWmoPlacement::parse_all(chunk.data)?,
+                _ => {}
+            }
+        }
+
+        if map_chunks.len() != ADT_CHUNK_COUNT {
+            return Err(anyhow!(
+                "ADT contains {} map chunks, expected {}",
+                map_chunks.len(),
+                ADT_CHUNK_COUNT
+            ));
+        }
+
+        map_chunks.sort_by_key(|c| (c.index_y, c.index_x));
+        Ok(Self {
+            version,
+            chunks: map_chunks,
+            liquids,
+            liquid_attributes,
+            doodad_names: resolve_names(mmdx, mmid)?,
+            wmo_names: resolve_names(mwmo, mwid)?,
+            doodads,
+            wmos,
+        })
+    }
+
+    pub fn chunk(&self, x: usize, y: usize) -> &AdtMapChunk {
+        &self.chunks[y * ADT_CHUNKS_PER_SIDE + x]
+    }
+
+    pub fn liquid(&self, x: usize, y: usize) -> &[AdtLiquidInstance] {
+        &self.liquids[y * ADT_CHUNKS_PER_SIDE + x]
+    }
+
+    pub fn liquid_attributes(&self, x: usize, y: usize) -> AdtLiquidAttributes {
+        self.liquid_attributes[y * ADT_CHUNKS_PER_SIDE + x]
+    }
+
+    fn parse_mh2o(data: &[u8]) -> Result<(Vec<Vec<AdtLiquidInstance>>, Vec<AdtLiquidAttributes>)> {
+        let mut reader = ByteReader::new(data);
+        let mut liquids = Vec::with_capacity(ADT_CHUNK_COUNT);
+        let mut attributes = Vec::with_capacity(ADT_CHUNK_COUNT);
+
+        for _ in 0..ADT_CHUNK_COUNT {
+            let ofs_instances = reader.read_u32()? as usize;
+            let layer_count = reader.read_u32()? as usize;
+            let ofs_attributes = reader.read_u32()? as usize;
+
+            // Chunks with liquid but without attributes count as fishable
+            // everywhere and deep nowhere
+            attributes.push(if ofs_attributes != 0 {
+                let mut reader = ByteReader::new(data);
+                reader.seek(ofs_attributes)?;
+                AdtLiquidAttributes {
+                    fishable: reader.read_u64()?,
+                    deep: reader.read_u64()?,
+                }
+            } else if layer_count != 0 {
+                AdtLiquidAttributes {
+                    fishable: u64::MAX,
+                    deep: 0,
+                }
+            } else {
+                AdtLiquidAttributes::default()
+            });
+
+            let mut layers = Vec::with_capacity(layer_count);
+            if ofs_instances != 0 {
+                for layer in 0..layer_count {
+                    layers.push(AdtLiquidInstance::parse(
+                        data,
+                        ofs_instances + layer * MH2O_INSTANCE_SIZE,
+                    )?);
+                }
+            }
+
+            liquids.push(layers);
+        }
+
+        Ok((liquids, attributes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::files::adt::{ADT_CHUNK_COUNT, AdtFile};
+
+    // MH2O with a full 8x8 instance in chunk 0, attributes only for chunk 0
+    // and a layer without attributes in chunk 1
+    fn mh2o() -> Vec<u8> {
+        let header_size = ADT_CHUNK_COUNT * 12;
+        let ofs_instance = header_size as u32;
+        let ofs_attributes = ofs_instance + 2 * 24;
+
+        let mut data = Vec::new();
+        for chunk in 0..ADT_CHUNK_COUNT {
+            let (instances, count, attributes) = match chunk {
+                0 => (ofs_instance, 1, ofs_attributes),
+                1 => (ofs_instance + 24, 1, 0),
+                _ => (0, 0, 0),
+            };
+
+            data.extend(instances.to_le_bytes());
+            data.extend((count as u32).to_le_bytes());
+            data.extend(attributes.to_le_bytes());
+        }
+
+        for _ in 0..2 {
+            data.extend(2u16.to_le_bytes());
+            data.extend(0u16.to_le_bytes());
+            data.extend(10f32.to_le_bytes());
+            data.extend(10f32.to_le_bytes());
+            data.extend([0, 0, 8, 8]);
+            data.extend([0; 8]);
+        }
+
+        data.extend(0x00FFu64.to_le_bytes());
+        data.extend(0xFF00u64.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_mh2o_attributes() {
+        let (liquids, attributes) = AdtFile::parse_mh2o(&mh2o()).unwrap();
+
+        assert_eq!(liquids[0].len(), 1);
+        assert_eq!(liquids[0][0].liquid_type, 2);
+        assert_eq!(liquids[0][0].exists, u64::MAX);
+        assert_eq!(attributes[0].fishable, 0x00FF);
+        assert_eq!(attributes[0].deep, 0xFF00);
+
+        assert_eq!(attributes[1].fishable, u64::MAX);
+        assert_eq!(attributes[1].deep, 0);
+
+        assert!(liquids[2].is_empty());
+        assert_eq!(attributes[2].fishable, 0);
+        assert_eq!(attributes[2].deep, 0);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::files::reader::{ByteReader, string_at};
+use anyhow::{Result, anyhow};
+
+const DBC_MAGIC: &[u8; 4] = b"WDBC";
+const DBC_HEADER_SIZE: usize = 20;
+
+pub struct DbcFile {
+    record_count: usize,
+    field_count: usize,
+    record_size: usize,
+    records: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl DbcFile {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader::new(data);
+        if &reader.read_array::<4>()? != DBC_MAGIC {
+            return Err(anyhow!("Not a DBC file, missing WDBC magic"));
+        }
+
+        let record_count = reader.read_u32()? as usize;
+        let field_count = reader.read_u32()? as usize;
+        let record_size = reader.read_u32()? as usize;
+        let string_size = reader.read_u32()? as usize;
+
+        if field_count * 4 > record_size {
+            return Err(anyhow!(
+                "DBC declares {} fields but records are only {} bytes",
+                field_count,
+                record_size
+            ));
+        }
+
+        let records = reader.read_bytes(record_count * record_size)?.to_vec();
+        let strings = reader.read_bytes(string_size)?.to_vec();
+        debug_assert_eq!(reader.position(), DBC_HEADER_SIZE + records.len() + strings.len());
+
+        Ok(Self {
+            record_count,
+            field_count,
+            record_size,
+            records,
+            strings,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.record_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.record_count == 0
+    }
+
+    pub fn field_count(&self) -> usize {
+        self.field_count
+    }
+
+    pub fn record(&self, index: usize) -> Option<DbcRecord<'_>> {
+        if index >= self.record_count {
+            return None;
+        }
+
+        let start = index * self.record_size;
+        Some(DbcRecord {
+            data: &self.records[start..start + self.record_size],
+            strings: &self.strings,
+        })
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = DbcRecord<'_>> {
+        (0..self.record_count).filter_map(|i| self.record(i))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct DbcRecord<'a> {
+    data: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl DbcRecord<'_> {
+    pub fn get_u32(&self, field: usize) -> u32 {
+        let offset = field * 4;
+        self.data
+            .get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .unwrap_or_default()
+    }
+
+    pub fn get_i32(&self, field: usize) -> i32 {
+        self.get_u32(field) as i32
+    }
+
+    pub fn get_f32(&self, field: usize) -> f32 {
+        f32::from_bits(self.get_u32(field))
+    }
+
+    pub fn get_string(&self, field: usize) -> String {
+        string_at(self.strings, self.get_u32(field) as usize).unwrap_or_default()
+    }
+}
@@ -0,0 +1,105 @@
+use crate::files::reader::ByteReader;
+use anyhow::{Result, anyhow};
+
+const M2_MAGIC: &[u8; 4] = b"MD20";
+const M2_COLLISION_TRIANGLES_OFFSET: usize = 0xD8;
+const M2_COLLISION_VERTICES_OFFSET: usize = 0xE0;
+
+// Only the simplified collision mesh of an M2 is parsed, the render
+// geometry lives in separate skin files and is never needed server side
+#[derive(Debug, Clone, Default)]
+pub struct M2Collision {
+    pub vertices: Vec<[f32; 3]>,
+    pub indices: Vec<u16>,
+}
+
+impl M2Collision {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader::new(data);
+        if &reader.read_array::<4>()? != M2_MAGIC {
+            return Err(anyhow!("File is not an MD20 model"));
+        }
+
+        reader.seek(M2_COLLISION_TRIANGLES_OFFSET)?;
+        let index_count = reader.read_u32()? as usize;
+        let index_offset = reader.read_u32()? as usize;
+
+        reader.seek(M2_COLLISION_VERTICES_OFFSET)?;
+        let vertex_count = reader.read_u32()? as usize;
+        let vertex_offset = reader.read_u32()? as usize;
+
+        let mut indices = Vec::with_capacity(index_count);
+        reader.seek(index_offset)?;
+        for _ in 0..index_count {
+            indices.push(reader.read_u16()?);
+        }
+
+        let mut vertices = Vec::with_capacity(vertex_count);
+        reader.seek(vertex_offset)?;
+        for _ in 0..vertex_count {
+            vertices.push(reader.read_vec3()?);
+        }
+
+        Ok(Self { vertices, indices })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.len() < 3 || self.vertices.is_empty()
+    }
+}
+
+// ADTs still reference doodads by their pre-WotLK .mdx/.mdl names
+pub fn m2_path(name: &str) -> String {
+    let lower = name.to_ascii_lowercase();
+    if lower.ends_with(".mdx") || lower.ends_with(".mdl") {
+        format!("{}.m2", &name[..name.len() - 4])
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::files::m2::{M2Collision, m2_path};
+
+    // MD20 header with the collision arrays right after it
+    fn md20(vertices: &[[f32; 3]], indices: &[u16]) -> Vec<u8> {
+        let mut data = vec![0u8; 0x130];
+        data[..4].copy_from_slice(b"MD20");
+
+        let index_offset = data.len();
+        data.extend(indices.iter().flat_map(|i| i.to_le_bytes()));
+        let vertex_offset = data.len();
+        data.extend(vertices.iter().flatten().flat_map(|v| v.to_le_bytes()));
+
+        for (at, value) in [
+            (0xD8, indices.len()),
+            (0xDC, index_offset),
+            (0xE0, vertices.len()),
+            (0xE4, vertex_offset),
+        ] {
+            data[at..at + 4].copy_from_slice(&(value as u32).to_le_bytes());
+        }
+
+        data
+    }
+
+    #[test]
+    fn test_m2_collision() {
+        let vertices = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 2.0]];
+        let collision = M2Collision::parse(&md20(&vertices, &[0, 1, 2])).unwrap();
+        assert_eq!(collision.vertices, vertices);
+        assert_eq!(collision.indices, [0, 1, 2]);
+        assert!(!collision.is_empty());
+
+        assert!(M2Collision::parse(&md20(&[], &[])).unwrap().is_empty());
+        assert!(M2Collision::parse(b"MD21").is_err());
+    }
+
+    #[test]
+    fn test_m2_path() {
+        assert_eq!(m2_path("World\\Tree01.MDX"), "World\\Tree01.m2");
+        assert_eq!(m2_path("world\\rock.mdl"), "world\\rock.m2");
+        assert_eq!(m2_path("world\\rock.m2"), "world\\rock.m2");
+    }
+}
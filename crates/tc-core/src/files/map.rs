@@ -0,0 +1,612 @@
+use crate::files::reader::ByteReader;
+use anyhow::{Result, anyhow};
+
+pub const MAP_MAGIC: &[u8; 4] = b"MAPS";
+pub const MAP_VERSION: u32 = 1;
+
+pub const MAP_TILE_SIZE: f32 = 533.333_3;
+pub const MAP_TILES_PER_SIDE: usize = 64;
+pub const MAP_CHUNKS_PER_TILE: usize = 16;
+pub const MAP_CELLS_PER_TILE: usize = 128;
+pub const MAP_V9_SIZE: usize = MAP_CELLS_PER_TILE + 1;
+pub const MAP_V8_SIZE: usize = MAP_CELLS_PER_TILE;
+pub const MAP_CHUNK_COUNT: usize = MAP_CHUNKS_PER_TILE * MAP_CHUNKS_PER_TILE;
+
+pub const MAP_LIQUID_WATER: u8 = 0x01;
+pub const MAP_LIQUID_OCEAN: u8 = 0x02;
+pub const MAP_LIQUID_MAGMA: u8 = 0x04;
+pub const MAP_LIQUID_SLIME: u8 = 0x08;
+pub const MAP_LIQUID_DARK_WATER: u8 = 0x10;
+
+const AREA_MAGIC: &[u8; 4] = b"AREA";
+const HEIGHT_MAGIC: &[u8; 4] = b"MHGT";
+const LIQUID_MAGIC: &[u8; 4] = b"MLIQ";
+const HOLES_MAGIC: &[u8; 4] = b"HOLE";
+
+const AREA_FLAG_UNIFORM: u16 = 0x01;
+
+const HEIGHT_FLAG_FLAT: u32 = 0x01;
+const HEIGHT_FLAG_INT16: u32 = 0x02;
+const HEIGHT_FLAG_INT8: u32 = 0x04;
+
+const LIQUID_FLAG_UNIFORM_TYPE: u16 = 0x01;
+const LIQUID_FLAG_UNIFORM_LEVEL: u16 = 0x02;
+
+const HEADER_SIZE: usize = 4 + 4 + 4 + 8 * 4;
+
+pub fn map_file_name(map_id: u32, tile_x: usize, tile_y: usize) -> String {
+    format!("{:04}_{:02}_{:02}.map", map_id, tile_x, tile_y)
+}
+
+// Tile containing a world position, using the same x, y order as the ADT
+// and map file names. World x runs along the tile y axis and both axes
+// decrease away from the client's map origin
+pub fn world_to_tile(x: f32, y: f32) -> (usize, usize) {
+    let half = (MAP_TILES_PER_SIDE / 2) as f32;
+    let max = (MAP_TILES_PER_SIDE - 1) as f32;
+    let tile_x = (half - y / MAP_TILE_SIZE).floor().clamp(0.0, max);
+    let tile_y = (half - x / MAP_TILE_SIZE).floor().clamp(0.0, max);
+
+    (tile_x as usize, tile_y as usize)
+}
+
+// World x, y of the tile's first v9 vertex. Moving along the tile's x axis
+// decreases world y and moving along its y axis decreases world x
+pub fn tile_world_origin(tile_x: usize, tile_y: usize) -> (f32, f32) {
+    let half = (MAP_TILES_PER_SIDE / 2) as f32;
+    (
+        (half - tile_y as f32) * MAP_TILE_SIZE,
+        (half - tile_x as f32) * MAP_TILE_SIZE,
+    )
+}
+
+// Thresholds used when writing heights, a tile whose height range fits
+// within a limit is quantized to that integer size instead of floats
+#[derive(Debug, Clone, Copy)]
+pub struct MapHeightEncoding {
+    pub allow_int: bool,
+    pub int8_limit: f32,
+    pub int16_limit: f32,
+    pub flat_limit: f32,
+    pub flat_liquid_limit: f32,
+}
+
+impl Default for MapHeightEncoding {
+    fn default() -> Self {
+        Self {
+            allow_int: true,
+            int8_limit: 2.0,
+            int16_limit: 2048.0,
+            flat_limit: 0.005,
+            flat_liquid_limit: 0.001,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapAreaData {
+    Uniform(u16),
+    Grid(Box<[u16; MAP_CHUNK_COUNT]>),
+}
+
+impl MapAreaData {
+    pub fn area_at(&self, chunk_x: usize, chunk_y: usize) -> u16 {
+        match self {
+            Self::Uniform(area) => *area,
+            Self::Grid(areas) => areas[chunk_y * MAP_CHUNKS_PER_TILE + chunk_x],
+        }
+    }
+}
+
+// Terrain heights of a tile, v9 holds the 129x129 cell corners and v8 the
+// 128x128 cell centers, both row major with rows running along tile y
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapHeightData {
+    pub v9: Vec<f32>,
+    pub v8: Vec<f32>,
+}
+
+impl MapHeightData {
+    pub fn flat(height: f32) -> Self {
+        Self {
+            v9: vec![height; MAP_V9_SIZE * MAP_V9_SIZE],
+            v8: vec![height; MAP_V8_SIZE * MAP_V8_SIZE],
+        }
+    }
+
+    pub fn v9(&self, x: usize, y: usize) -> f32 {
+        self.v9[y * MAP_V9_SIZE + x]
+    }
+
+    pub fn v8(&self, x: usize, y: usize) -> f32 {
+        self.v8[y * MAP_V8_SIZE + x]
+    }
+
+    pub fn min_max(&self) -> (f32, f32) {
+        self.v9
+            .iter()
+            .chain(self.v8.iter())
+            .fold((f32::MAX, f32::MIN), |(min, max), h| {
+                (min.min(*h), max.max(*h))
+            })
+    }
+}
+
+// Liquid of a tile. Types and flags are per map chunk while levels cover
+// the vertex rectangle offset_x, offset_y, width, height of the v9 grid
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapLiquidData {
+    pub types: Box<[u16; MAP_CHUNK_COUNT]>,
+    pub flags: Box<[u8; MAP_CHUNK_COUNT]>,
+    pub offset_x: u8,
+    pub offset_y: u8,
+    pub width: u8,
+    pub height: u8,
+    pub levels: Vec<f32>,
+}
+
+impl MapLiquidData {
+    pub fn level_at(&self, x: usize, y: usize) -> Option<f32> {
+        let (ox, oy) = (self.offset_x as usize, self.offset_y as usize);
+        if x < ox || y < oy || x >= ox + self.width as usize || y >= oy + self.height as usize {
+            return None;
+        }
+
+        Some(self.levels[(y - oy) * self.width as usize + (x - ox)])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapTile {
+    pub build: u32,
+    pub area: MapAreaData,
+    pub height: MapHeightData,
+    pub liquid: Option<MapLiquidData>,
+    pub holes: Option<Box<[u16; MAP_CHUNK_COUNT]>>,
+}
+
+impl MapTile {
+    // Holes cover 2x2 cells per bit of their map chunk
+    pub fn is_hole(&self, cell_x: usize, cell_y: usize) -> bool {
+        let Some(holes) = &self.holes else {
+            return false;
+        };
+
+        let chunk = (cell_y / 8) * MAP_CHUNKS_PER_TILE + cell_x / 8;
+        let bit = ((cell_y % 8) / 2) * 4 + (cell_x % 8) / 2;
+        holes[chunk] & (1 << bit) != 0
+    }
+
+    pub fn read(data: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader::new(data);
+        if &reader.read_array::<4>()? != MAP_MAGIC {
+            return Err(anyhow!("Not a map file, missing MAPS magic"));
+        }
+
+        let version = reader.read_u32()?;
+        if version != MAP_VERSION {
+            return Err(anyhow!(
+                "Map file version {} is not supported, expected {}",
+                version,
+                MAP_VERSION
+            ));
+        }
+
+        let build = reader.read_u32()?;
+        let mut sections = [(0usize, 0usize); 4];
+        for section in sections.iter_mut() {
+            *section = (reader.read_u32()? as usize, reader.read_u32()? as usize);
+        }
+
+        let section = |(offset, size): (usize, usize)| -> Result<Option<&[u8]>> {
+            if size == 0 {
+                return Ok(None);
+            }
+
+            data.get(offset..offset + size)
+                .map(Some)
+                .ok_or_else(|| anyhow!("Map file section at {} is truncated", offset))
+        };
+
+        let area = match section(sections[0])? {
+            Some(data) => Self::read_area(data)?,
+            None => MapAreaData::Uniform(0),
+        };
+
+        let height = match section(sections[1])? {
+            Some(data) => Self::read_height(data)?,
+            None => MapHeightData::flat(0.0),
+        };
+
+        let liquid = section(sections[2])?.map(Self::read_liquid).transpose()?;
+
+        let holes = section(sections[3])?
+            .map(|data| -> Result<_> {
+                let mut reader = ByteReader::new(data);
+                Self::expect_magic(&mut reader, HOLES_MAGIC)?;
+                Self::read_u16_grid(&mut reader)
+            })
+            .transpose()?;
+
+        Ok(Self {
+            build,
+            area,
+            height,
+            liquid,
+            holes,
+        })
+    }
+
+    pub fn write(&self, encoding: &MapHeightEncoding) -> Vec<u8> {
+        let area = self.write_area();
+        let height = self.write_height(encoding);
+        let liquid = self.write_liquid(encoding);
+        let holes = self.holes.as_ref().map(|holes| {
+            let mut out = HOLES_MAGIC.to_vec();
+            for hole in holes.iter() {
+                out.extend_from_slice(&hole.to_le_bytes());
+            }
+
+            out
+        });
+
+        let mut out = Vec::with_capacity(HEADER_SIZE + area.len() + height.len());
+        out.extend_from_slice(MAP_MAGIC);
+        out.extend_from_slice(&MAP_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.build.to_le_bytes());
+
+        let mut offset = HEADER_SIZE;
+        let sections = [Some(&area), Some(&height), liquid.as_ref(), holes.as_ref()];
+        for section in sections {
+            let size = section.map(|s| s.len()).unwrap_or_default();
+            let start = if size > 0 { offset } else { 0 };
+
+            out.extend_from_slice(&(start as u32).to_le_bytes());
+            out.extend_from_slice(&(size as u32).to_le_bytes());
+            offset += size;
+        }
+
+        for section in sections.into_iter().flatten() {
+            out.extend_from_slice(section);
+        }
+
+        out
+    }
+
+    fn write_area(&self) -> Vec<u8> {
+        let mut out = AREA_MAGIC.to_vec();
+        let uniform = match &self.area {
+            MapAreaData::Uniform(area) => Some(*area),
+            MapAreaData::Grid(areas) if areas.iter().all(|a| *a == areas[0]) => Some(areas[0]),
+            MapAreaData::Grid(_) => None,
+        };
+
+        match (uniform, &self.area) {
+            (Some(area), _) => {
+                out.extend_from_slice(&AREA_FLAG_UNIFORM.to_le_bytes());
+                out.extend_from_slice(&area.to_le_bytes());
+            }
+            (None, MapAreaData::Grid(areas)) => {
+                out.extend_from_slice(&0u16.to_le_bytes());
+                out.extend_from_slice(&0u16.to_le_bytes());
+                for area in areas.iter() {
+                    out.extend_from_slice(&area.to_le_bytes());
+                }
+            }
+            (None, MapAreaData::Uniform(_)) => unreachable!(),
+        }
+
+        out
+    }
+
+    fn write_height(&self, encoding: &MapHeightEncoding) -> Vec<u8> {
+        let (min, max) = self.height.min_max();
+        let range = max - min;
+
+        let mut flags = 0;
+        if range < encoding.flat_limit {
+            flags |= HEIGHT_FLAG_FLAT;
+        } else if encoding.allow_int && range < encoding.int8_limit {
+            flags |= HEIGHT_FLAG_INT8;
+        } else if encoding.allow_int && range < encoding.int16_limit {
+            flags |= HEIGHT_FLAG_INT16;
+        }
+
+        let mut out = HEIGHT_MAGIC.to_vec();
+        out.extend_from_slice(&flags.to_le_bytes());
+        out.extend_from_slice(&min.to_le_bytes());
+        out.extend_from_slice(&max.to_le_bytes());
+
+        let values = self.height.v9.iter().chain(self.height.v8.iter());
+        if flags & HEIGHT_FLAG_INT8 != 0 {
+            let step = u8::MAX as f32 / range;
+            out.extend(values.map(|h| ((h - min) * step + 0.5) as u8));
+        } else if flags & HEIGHT_FLAG_INT16 != 0 {
+            let step = u16::MAX as f32 / range;
+            for h in values {
+                out.extend_from_slice(&(((h - min) * step + 0.5) as u16).to_le_bytes());
+            }
+        } else if flags & HEIGHT_FLAG_FLAT == 0 {
+            for h in values {
+                out.extend_from_slice(&h.to_le_bytes());
+            }
+        }
+
+        out
+    }
+
+    fn write_liquid(&self, encoding: &MapHeightEncoding) -> Option<Vec<u8>> {
+        let liquid = self.liquid.as_ref()?;
+
+        let uniform_type = liquid.types.iter().all(|t| *t == liquid.types[0])
+            && liquid.flags.iter().all(|f| *f == liquid.flags[0]);
+
+        let (min, max) = liquid
+            .levels
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(min, max), h| {
+                (min.min(*h), max.max(*h))
+            });
+
+        let uniform_level = liquid.levels.is_empty() || max - min < encoding.flat_liquid_limit;
+
+        let mut flags = 0;
+        if uniform_type {
+            flags |= LIQUID_FLAG_UNIFORM_TYPE;
+        }
+        if uniform_level {
+            flags |= LIQUID_FLAG_UNIFORM_LEVEL;
+        }
+
+        let mut out = LIQUID_MAGIC.to_vec();
+        out.extend_from_slice(&flags.to_le_bytes());
+        out.extend_from_slice(&liquid.types[0].to_le_bytes());
+        out.push(liquid.flags[0]);
+        out.push(liquid.offset_x);
+        out.push(liquid.offset_y);
+        out.push(liquid.width);
+        out.push(liquid.height);
+        out.push(0);
+        out.extend_from_slice(
+            &liquid
+                .levels
+                .first()
+                .copied()
+                .unwrap_or_default()
+                .to_le_bytes(),
+        );
+
+        if !uniform_type {
+            for t in liquid.types.iter() {
+                out.extend_from_slice(&t.to_le_bytes());
+            }
+            out.extend_from_slice(&liquid.flags[..]);
+        }
+
+        if !uniform_level {
+            for level in &liquid.levels {
+                out.extend_from_slice(&level.to_le_bytes());
+            }
+        }
+
+        Some(out)
+    }
+
+    fn expect_magic(reader: &mut ByteReader, magic: &[u8; 4]) -> Result<()> {
+        let found = reader.read_array::<4>()?;
+        if &found != magic {
+            return Err(anyhow!(
+                "Expected map section {}, found {}",
+                String::from_utf8_lossy(magic),
+                String::from_utf8_lossy(&found)
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn read_u16_grid(reader: &mut ByteReader) -> Result<Box<[u16; MAP_CHUNK_COUNT]>> {
+        let mut grid = Box::new([0u16; MAP_CHUNK_COUNT]);
+        for value in grid.iter_mut() {
+            *value = reader.read_u16()?;
+        }
+
+        Ok(grid)
+    }
+
+    fn read_area(data: &[u8]) -> Result<MapAreaData> {
+        let mut reader = ByteReader::new(data);
+        Self::expect_magic(&mut reader, AREA_MAGIC)?;
+
+        let flags = reader.read_u16()?;
+        let area = reader.read_u16()?;
+        if flags & AREA_FLAG_UNIFORM != 0 {
+            return Ok(MapAreaData::Uniform(area));
+        }
+
+        Ok(MapAreaData::Grid(Self::read_u16_grid(&mut reader)?))
+    }
+
+    fn read_height(data: &[u8]) -> Result<MapHeightData> {
+        let mut reader = ByteReader::new(data);
+        Self::expect_magic(&mut reader, HEIGHT_MAGIC)?;
+
+        let flags = reader.read_u32()?;
+        let min = reader.read_f32()?;
+        let max = reader.read_f32()?;
+        if flags & HEIGHT_FLAG_FLAT != 0 {
+            return Ok(MapHeightData::flat(min));
+        }
+
+        let count = MAP_V9_SIZE * MAP_V9_SIZE + MAP_V8_SIZE * MAP_V8_SIZE;
+        let mut values = Vec::with_capacity(count);
+        if flags & HEIGHT_FLAG_INT8 != 0 {
+            let step = (max - min) / u8::MAX as f32;
+            for _ in 0..count {
+                values.push(min + reader.read_u8()? as f32 * step);
+            }
+        } else if flags & HEIGHT_FLAG_INT16 != 0 {
+            let step = (max - min) / u16::MAX as f32;
+            for _ in 0..count {
+                values.push(min + reader.read_u16()? as f32 * step);
+            }
+        } else {
+            for _ in 0..count {
+                values.push(reader.read_f32()?);
+            }
+        }
+
+        let v8 = values.split_off(MAP_V9_SIZE * MAP_V9_SIZE);
+        Ok(MapHeightData { v9: values, v8 })
+    }
+
+    fn read_liquid(data: &[u8]) -> Result<MapLiquidData> {
+        let mut reader = ByteReader::new(data);
+        Self::expect_magic(&mut reader, LIQUID_MAGIC)?;
+
+        let flags = reader.read_u16()?;
+        let liquid_type = reader.read_u16()?;
+        let liquid_flags = reader.read_u8()?;
+        let offset_x = reader.read_u8()?;
+        let offset_y = reader.read_u8()?;
+        let width = reader.read_u8()?;
+        let height = reader.read_u8()?;
+        reader.skip(1)?;
+        let level = reader.read_f32()?;
+
+        let (types, flag_grid) = if flags & LIQUID_FLAG_UNIFORM_TYPE != 0 {
+            (
+                Box::new([liquid_type; MAP_CHUNK_COUNT]),
+                Box::new([liquid_flags; MAP_CHUNK_COUNT]),
+            )
+        } else {
+            let types = Self::read_u16_grid(&mut reader)?;
+            let flag_grid = Box::new(reader.read_array::<MAP_CHUNK_COUNT>()?);
+            (types, flag_grid)
+        };
+
+        let count = width as usize * height as usize;
+        let levels = if flags & LIQUID_FLAG_UNIFORM_LEVEL != 0 {
+            vec![level; count]
+        } else {
+            let mut levels = Vec::with_capacity(count);
+            for _ in 0..count {
+                levels.push(reader.read_f32()?);
+            }
+
+            levels
+        };
+
+        Ok(MapLiquidData {
+            types,
+            flags: flag_grid,
+            offset_x,
+            offset_y,
+            width,
+            height,
+            levels,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::files::map::{
+        MAP_CHUNK_COUNT, MAP_LIQUID_WATER, MAP_V8_SIZE, MAP_V9_SIZE, MapAreaData, MapHeightData,
+        MapHeightEncoding, MapLiquidData, MapTile,
+    };
+
+    fn sloped_heights(range: f32) -> MapHeightData {
+        let v9 = (0..MAP_V9_SIZE * MAP_V9_SIZE)
+            .map(|i| (i % MAP_V9_SIZE) as f32 / MAP_V9_SIZE as f32 * range)
+            .collect();
+        let v8 = (0..MAP_V8_SIZE * MAP_V8_SIZE)
+            .map(|i| (i % MAP_V8_SIZE) as f32 / MAP_V9_SIZE as f32 * range)
+            .collect();
+
+        MapHeightData { v9, v8 }
+    }
+
+    fn tile(height: MapHeightData) -> MapTile {
+        MapTile {
+            build: 12340,
+            area: MapAreaData::Uniform(12),
+            height,
+            liquid: None,
+            holes: None,
+        }
+    }
+
+    fn max_error(a: &MapHeightData, b: &MapHeightData) -> f32 {
+        a.v9.iter()
+            .chain(a.v8.iter())
+            .zip(b.v9.iter().chain(b.v8.iter()))
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn test_map_tile_roundtrip_float() {
+        let encoding = MapHeightEncoding {
+            allow_int: false,
+            ..Default::default()
+        };
+
+        let tile = tile(sloped_heights(5000.0));
+        let read = MapTile::read(&tile.write(&encoding)).unwrap();
+
+        assert_eq!(tile, read);
+    }
+
+    #[test]
+    fn test_map_tile_roundtrip_int8() {
+        let tile = tile(sloped_heights(1.5));
+        let bytes = tile.write(&MapHeightEncoding::default());
+        let read = MapTile::read(&bytes).unwrap();
+
+        assert!(bytes.len() < (MAP_V9_SIZE * MAP_V9_SIZE + MAP_V8_SIZE * MAP_V8_SIZE) * 2);
+        assert!(max_error(&tile.height, &read.height) <= 1.5 / 255.0);
+    }
+
+    #[test]
+    fn test_map_tile_roundtrip_int16() {
+        let tile = tile(sloped_heights(1000.0));
+        let read = MapTile::read(&tile.write(&MapHeightEncoding::default())).unwrap();
+
+        assert!(max_error(&tile.height, &read.height) <= 1000.0 / 65535.0);
+    }
+
+    #[test]
+    fn test_map_tile_roundtrip_sections() {
+        let mut areas = Box::new([3u16; MAP_CHUNK_COUNT]);
+        areas[17] = 40;
+
+        let mut holes = Box::new([0u16; MAP_CHUNK_COUNT]);
+        holes[5] = 0x0300;
+
+        let mut types = Box::new([0u16; MAP_CHUNK_COUNT]);
+        types[1] = 1;
+        let mut flags = Box::new([0u8; MAP_CHUNK_COUNT]);
+        flags[1] = MAP_LIQUID_WATER;
+
+        let tile = MapTile {
+            build: 12340,
+            area: MapAreaData::Grid(areas),
+            height: MapHeightData::flat(-12.5),
+            liquid: Some(MapLiquidData {
+                types,
+                flags,
+                offset_x: 8,
+                offset_y: 0,
+                width: 9,
+                height: 2,
+                levels: (0..18).map(|i| i as f32).collect(),
+            }),
+            holes: Some(holes),
+        };
+
+        let read = MapTile::read(&tile.write(&MapHeightEncoding::default())).unwrap();
+        assert_eq!(tile, read);
+        assert_eq!(Some(10.0), read.liquid.unwrap().level_at(9, 1));
+    }
+}
@@ -0,0 +1,10 @@
+pub mod adt;
+pub mod dbc;
+pub mod m2;
+pub mod map;
+pub mod mpq;
+pub mod navmesh;
+pub mod reader;
+pub mod vmap;
+pub mod wdt;
+pub mod wmo;
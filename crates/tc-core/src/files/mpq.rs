@@ -0,0 +1,655 @@
+use crate::files::reader::ByteReader;
+use anyhow::{Context, Result, anyhow};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+const MPQ_MAGIC: &[u8; 4] = b"MPQ\x1A";
+const MPQ_USER_DATA_MAGIC: &[u8; 4] = b"MPQ\x1B";
+const MPQ_HEADER_SEARCH_STEP: u64 = 0x200;
+
+const HASH_ENTRY_EMPTY: u32 = 0xFFFF_FFFF;
+const HASH_ENTRY_DELETED: u32 = 0xFFFF_FFFE;
+
+const HASH_TYPE_TABLE_OFFSET: u32 = 0;
+const HASH_TYPE_NAME_A: u32 = 1;
+const HASH_TYPE_NAME_B: u32 = 2;
+const HASH_TYPE_FILE_KEY: u32 = 3;
+
+const FILE_IMPLODE: u32 = 0x0000_0100;
+const FILE_COMPRESS: u32 = 0x0000_0200;
+const FILE_ENCRYPTED: u32 = 0x0001_0000;
+const FILE_FIX_KEY: u32 = 0x0002_0000;
+const FILE_SINGLE_UNIT: u32 = 0x0100_0000;
+const FILE_DELETE_MARKER: u32 = 0x0200_0000;
+const FILE_SECTOR_CRC: u32 = 0x0400_0000;
+const FILE_EXISTS: u32 = 0x8000_0000;
+
+const COMPRESSION_ZLIB: u8 = 0x02;
+const COMPRESSION_BZIP2: u8 = 0x10;
+
+fn crypt_table() -> &'static [u32; 0x500] {
+    static TABLE: OnceLock<[u32; 0x500]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0u32; 0x500];
+        let mut seed: u32 = 0x0010_0001;
+
+        for index1 in 0..0x100 {
+            let mut index2 = index1;
+            for _ in 0..5 {
+                seed = (seed * 125 + 3) % 0x2A_AAAB;
+                let temp1 = (seed & 0xFFFF) << 0x10;
+                seed = (seed * 125 + 3) % 0x2A_AAAB;
+                let temp2 = seed & 0xFFFF;
+
+                table[index2] = temp1 | temp2;
+                index2 += 0x100;
+            }
+        }
+
+        table
+    })
+}
+
+// Hashes a file name the way the archive hash table expects it, names are
+// case insensitive and use backslashes as separators
+pub fn hash_string(name: &str, hash_type: u32) -> u32 {
+    let table = crypt_table();
+    let mut seed1: u32 = 0x7FED_7FED;
+    let mut seed2: u32 = 0xEEEE_EEEE;
+
+    for byte in name.bytes() {
+        let ch = match byte.to_ascii_uppercase() {
+            b'/' => b'\\',
+            b => b,
+        } as u32;
+
+        seed1 = table[((hash_type << 8) + ch) as usize] ^ seed1.wrapping_add(seed2);
+        seed2 = ch
+            .wrapping_add(seed1)
+            .wrapping_add(seed2)
+            .wrapping_add(seed2 << 5)
+            .wrapping_add(3);
+    }
+
+    seed1
+}
+
+fn decrypt_block(data: &mut [u8], mut key: u32) {
+    let table = crypt_table();
+    let mut seed: u32 = 0xEEEE_EEEE;
+
+    for word in data.chunks_exact_mut(4) {
+        seed = seed.wrapping_add(table[0x400 + (key & 0xFF) as usize]);
+        let value = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        let ch = value ^ key.wrapping_add(seed);
+
+        key = ((!key) << 0x15).wrapping_add(0x1111_1111) | (key >> 0x0B);
+        seed = ch
+            .wrapping_add(seed)
+            .wrapping_add(seed << 5)
+            .wrapping_add(3);
+
+        word.copy_from_slice(&ch.to_le_bytes());
+    }
+}
+
+fn file_key(name: &str, block: &BlockEntry) -> u32 {
+    let base_name = name.rsplit(['\\', '/']).next().unwrap_or(name);
+    let mut key = hash_string(base_name, HASH_TYPE_FILE_KEY);
+    if block.flags & FILE_FIX_KEY != 0 {
+        key = key.wrapping_add(block.file_pos as u32) ^ block.file_size;
+    }
+
+    key
+}
+
+fn decompress(data: &[u8], expected_size: usize) -> Result<Vec<u8>> {
+    let (mask, payload) = data
+        .split_first()
+        .ok_or_else(|| anyhow!("Compressed sector is empty"))?;
+
+    let mut output = Vec::with_capacity(expected_size);
+    match *mask {
+        COMPRESSION_ZLIB => {
+            flate2::read::ZlibDecoder::new(payload)
+                .read_to_end(&mut output)
+                .context("Failed to inflate zlib sector")?;
+        }
+        COMPRESSION_BZIP2 => {
+            bzip2::read::BzDecoder::new(payload)
+                .read_to_end(&mut output)
+                .context("Failed to decompress bzip2 sector")?;
+        }
+        other => return Err(anyhow!("Unsupported MPQ compression mask 0x{:02X}", other)),
+    }
+
+    Ok(output)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HashEntry {
+    name_a: u32,
+    name_b: u32,
+    block_index: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BlockEntry {
+    file_pos: u64,
+    compressed_size: u32,
+    file_size: u32,
+    flags: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct MpqHeader {
+    pub header_size: u32,
+    pub archive_size: u32,
+    pub format_version: u16,
+    pub sector_size: u32,
+    pub hash_table_pos: u64,
+    pub block_table_pos: u64,
+    pub hash_table_size: u32,
+    pub block_table_size: u32,
+    pub hi_block_table_pos: u64,
+}
+
+pub struct MpqArchive {
+    path: PathBuf,
+    file: Mutex<File>,
+    archive_offset: u64,
+    header: MpqHeader,
+    hash_table: Vec<HashEntry>,
+    block_table: Vec<BlockEntry>,
+}
+
+impl MpqArchive {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+        let (archive_offset, header) = Self::find_header(&mut file)
+            .with_context(|| format!("{} is not a valid MPQ archive", path.display()))?;
+
+        let hash_table = {
+            let raw = Self::read_table(
+                &mut file,
+                archive_offset + header.hash_table_pos,
+                header.hash_table_size,
+                "(hash table)",
+            )?;
+
+            let mut reader = ByteReader::new(&raw);
+            let mut entries = Vec::with_capacity(header.hash_table_size as usize);
+            for _ in 0..header.hash_table_size {
+                let name_a = reader.read_u32()?;
+                let name_b = reader.read_u32()?;
+                let _locale = reader.read_u16()?;
+                let _platform = reader.read_u16()?;
+                let block_index = reader.read_u32()?;
+
+                entries.push(HashEntry {
+                    name_a,
+                    name_b,
+                    block_index,
+                });
+            }
+
+            entries
+        };
+
+        let block_table = {
+            let raw = Self::read_table(
+                &mut file,
+                archive_offset + header.block_table_pos,
+                header.block_table_size,
+                "(block table)",
+            )?;
+
+            let hi_positions = if header.hi_block_table_pos != 0 {
+                let mut bytes = vec![0u8; header.block_table_size as usize * 2];
+                file.seek(SeekFrom::Start(archive_offset + header.hi_block_table_pos))?;
+                file.read_exact(&mut bytes)?;
+
+                bytes
+                    .chunks_exact(2)
+                    .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                    .collect()
+            } else {
+                vec![0u16; header.block_table_size as usize]
+            };
+
+            let mut reader = ByteReader::new(&raw);
+            let mut entries = Vec::with_capacity(header.block_table_size as usize);
+            for hi in hi_positions {
+                let file_pos = reader.read_u32()? as u64 | ((hi as u64) << 32);
+                entries.push(BlockEntry {
+                    file_pos,
+                    compressed_size: reader.read_u32()?,
+                    file_size: reader.read_u32()?,
+                    flags: reader.read_u32()?,
+                });
+            }
+
+            entries
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            archive_offset,
+            header,
+            hash_table,
+            block_table,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn header(&self) -> &MpqHeader {
+        &self.header
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.find_block(name).is_some()
+    }
+
+    // Returns true when the archive explicitly marks a file as removed, used
+    // by patch archives to hide files from the archives below them
+    pub fn is_deleted(&self, name: &str) -> bool {
+        self.find_block_raw(name)
+            .is_some_and(|b| b.flags & FILE_DELETE_MARKER != 0)
+    }
+
+    pub fn read_file(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let block = match self.find_block(name) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+
+        self.read_block(name, &block)
+            .with_context(|| format!("Failed to read {} from {}", name, self.path.display()))
+            .map(Some)
+    }
+
+    // File names listed in the archive's (listfile), archives without one
+    // can still be read but their contents cannot be enumerated
+    pub fn list_files(&self) -> Result<Vec<String>> {
+        let listfile = match self.read_file("(listfile)")? {
+            Some(data) => data,
+            None => return Ok(Vec::new()),
+        };
+
+        Ok(String::from_utf8_lossy(&listfile)
+            .split(['\r', '\n', ';'])
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect())
+    }
+
+    fn find_header(file: &mut File) -> Result<(u64, MpqHeader)> {
+        let file_len = file.metadata()?.len();
+        let mut offset = 0u64;
+
+        while offset + 32 <= file_len {
+            let mut magic = [0u8; 4];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut magic)?;
+
+            if &magic == MPQ_USER_DATA_MAGIC {
+                let mut bytes = [0u8; 8];
+                file.read_exact(&mut bytes)?;
+                let header_offset = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+
+                offset += header_offset as u64;
+                continue;
+            }
+
+            if &magic == MPQ_MAGIC {
+                let mut bytes = [0u8; 44];
+                file.read_exact(&mut bytes[..28])?;
+
+                let mut reader = ByteReader::new(&bytes);
+                let header_size = reader.read_u32()?;
+                let archive_size = reader.read_u32()?;
+                let format_version = reader.read_u16()?;
+                let sector_shift = reader.read_u16()?;
+                let hash_table_pos = reader.read_u32()? as u64;
+                let block_table_pos = reader.read_u32()? as u64;
+                let hash_table_size = reader.read_u32()?;
+                let block_table_size = reader.read_u32()?;
+
+                let mut header = MpqHeader {
+                    header_size,
+                    archive_size,
+                    format_version,
+                    sector_size: 512 << sector_shift,
+                    hash_table_pos,
+                    block_table_pos,
+                    hash_table_size,
+                    block_table_size,
+                    hi_block_table_pos: 0,
+                };
+
+                if format_version >= 1 && header_size >= 44 {
+                    file.read_exact(&mut bytes[28..40])?;
+                    let mut reader = ByteReader::new(&bytes[28..40]);
+                    header.hi_block_table_pos = reader.read_u64()?;
+                    header.hash_table_pos |= (reader.read_u16()? as u64) << 32;
+                    header.block_table_pos |= (reader.read_u16()? as u64) << 32;
+                }
+
+                return Ok((offset, header));
+            }
+
+            offset += MPQ_HEADER_SEARCH_STEP;
+        }
+
+        Err(anyhow!("MPQ header not found"))
+    }
+
+    fn read_table(file: &mut File, pos: u64, entries: u32, key_name: &str) -> Result<Vec<u8>> {
+        let mut raw = vec![0u8; entries as usize * 16];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut raw)
+            .with_context(|| format!("Failed to read MPQ {}", key_name))?;
+
+        decrypt_block(&mut raw, hash_string(key_name, HASH_TYPE_FILE_KEY));
+        Ok(raw)
+    }
+
+    fn find_block_raw(&self, name: &str) -> Option<BlockEntry> {
+        if self.hash_table.is_empty() {
+            return None;
+        }
+
+        let mask = self.hash_table.len() - 1;
+        let start = hash_string(name, HASH_TYPE_TABLE_OFFSET) as usize & mask;
+        let name_a = hash_string(name, HASH_TYPE_NAME_A);
+        let name_b = hash_string(name, HASH_TYPE_NAME_B);
+
+        let mut index = start;
+        loop {
+            let entry = &self.hash_table[index];
+            if entry.block_index == HASH_ENTRY_EMPTY {
+                return None;
+            }
+
+            if entry.block_index != HASH_ENTRY_DELETED
+                && entry.name_a == name_a
+                && entry.name_b == name_b
+            {
+                return self.block_table.get(entry.block_index as usize).copied();
+            }
+
+            index = (index + 1) & mask;
+            if index == start {
+                return None;
+            }
+        }
+    }
+
+    fn find_block(&self, name: &str) -> Option<BlockEntry> {
+        self.find_block_raw(name).filter(|b| {
+            b.flags & FILE_EXISTS != 0 && b.flags & FILE_DELETE_MARKER == 0
+        })
+    }
+
+    fn read_raw(&self, pos: u64, size: usize) -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; size];
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| anyhow!("MPQ file handle lock poisoned"))?;
+
+        file.seek(SeekFrom::Start(self.archive_offset + pos))?;
+        file.read_exact(&mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    fn read_block(&self, name: &str, block: &BlockEntry) -> Result<Vec<u8>> {
+        if block.flags & FILE_IMPLODE != 0 {
+            return Err(anyhow!("PKWARE imploded files are not supported"));
+        }
+
+        let key = if block.flags & FILE_ENCRYPTED != 0 {
+            Some(file_key(name, block))
+        } else {
+            None
+        };
+
+        let file_size = block.file_size as usize;
+        let compressed = block.flags & FILE_COMPRESS != 0;
+
+        if block.flags & FILE_SINGLE_UNIT != 0 {
+            let mut data = self.read_raw(block.file_pos, block.compressed_size as usize)?;
+            if let Some(key) = key {
+                decrypt_block(&mut data, key);
+            }
+
+            if compressed && data.len() < file_size {
+                return decompress(&data, file_size);
+            }
+
+            data.truncate(file_size);
+            return Ok(data);
+        }
+
+        let sector_size = self.header.sector_size as usize;
+        let sector_count = file_size.div_ceil(sector_size);
+        let mut output = Vec::with_capacity(file_size);
+
+        if !compressed {
+            let mut data = self.read_raw(block.file_pos, file_size)?;
+            if let Some(key) = key {
+                for (i, sector) in data.chunks_mut(sector_size).enumerate() {
+                    decrypt_block(sector, key.wrapping_add(i as u32));
+                }
+            }
+
+            return Ok(data);
+        }
+
+        let table_entries = sector_count + 1 + usize::from(block.flags & FILE_SECTOR_CRC != 0);
+        let mut table = self.read_raw(block.file_pos, table_entries * 4)?;
+        if let Some(key) = key {
+            decrypt_block(&mut table, key.wrapping_sub(1));
+        }
+
+        let offsets: Vec<usize> = table
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .collect();
+
+        for sector in 0..sector_count {
+            let (start, end) = (offsets[sector], offsets[sector + 1]);
+            if end < start {
+                return Err(anyhow!("Sector {} has an invalid offset table entry", sector));
+            }
+
+            let mut data = self.read_raw(block.file_pos + start as u64, end - start)?;
+            if let Some(key) = key {
+                decrypt_block(&mut data, key.wrapping_add(sector as u32));
+            }
+
+            let expected = sector_size.min(file_size - sector * sector_size);
+            if data.len() < expected {
+                output.extend(decompress(&data, expected)?);
+            } else {
+                output.extend_from_slice(&data[..expected]);
+            }
+        }
+
+        Ok(output)
+    }
+}
+
+// An ordered set of archives where archives opened later take priority,
+// mirroring how the client layers patch archives over the base data
+#[derive(Default)]
+pub struct MpqArchiveSet {
+    archives: Vec<MpqArchive>,
+}
+
+impl MpqArchiveSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Opens the archives of a 3.3.5a client Data directory in load order. The
+    // locale is detected from the locale sub directory when one is not given
+    pub fn open_client_data(data_dir: impl AsRef<Path>, locale: Option<&str>) -> Result<Self> {
+        let data_dir = data_dir.as_ref();
+        let locale = match locale {
+            Some(locale) => locale.to_string(),
+            None => Self::detect_locale(data_dir)?,
+        };
+
+        let locale_dir = data_dir.join(&locale);
+        let mut candidates = vec![
+            locale_dir.join(format!("locale-{}.MPQ", locale)),
+            data_dir.join("common.MPQ"),
+            data_dir.join("common-2.MPQ"),
+            data_dir.join("expansion.MPQ"),
+            data_dir.join("lichking.MPQ"),
+            locale_dir.join(format!("expansion-locale-{}.MPQ", locale)),
+            locale_dir.join(format!("lichking-locale-{}.MPQ", locale)),
+            data_dir.join("patch.MPQ"),
+        ];
+
+        for i in 2..=9 {
+            candidates.push(data_dir.join(format!("patch-{}.MPQ", i)));
+        }
+
+        candidates.push(locale_dir.join(format!("patch-{}.MPQ", locale)));
+        for i in 2..=9 {
+            candidates.push(locale_dir.join(format!("patch-{}-{}.MPQ", locale, i)));
+        }
+
+        let mut set = Self::new();
+        for path in candidates.into_iter().filter(|p| p.is_file()) {
+            tracing::info!("Opening archive {}", path.display());
+            set.push(MpqArchive::open(&path)?);
+        }
+
+        if set.archives.is_empty() {
+            return Err(anyhow!("No MPQ archives found in {}", data_dir.display()));
+        }
+
+        Ok(set)
+    }
+
+    pub fn push(&mut self, archive: MpqArchive) {
+        self.archives.push(archive);
+    }
+
+    pub fn archives(&self) -> &[MpqArchive] {
+        &self.archives
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        for archive in self.archives.iter().rev() {
+            if archive.contains(name) {
+                return true;
+            }
+
+            if archive.is_deleted(name) {
+                return false;
+            }
+        }
+
+        false
+    }
+
+    pub fn read_file(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        for archive in self.archives.iter().rev() {
+            if let Some(data) = archive.read_file(name)? {
+                return Ok(Some(data));
+            }
+
+            if archive.is_deleted(name) {
+                return Ok(None);
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn read_required(&self, name: &str) -> Result<Vec<u8>> {
+        self.read_file(name)?
+            .ok_or_else(|| anyhow!("{} was not found in any archive", name))
+    }
+
+    // Union of every archive's listfile, sorted and without duplicates
+    pub fn list_files(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for archive in &self.archives {
+            names.extend(archive.list_files()?);
+        }
+
+        names.sort_by_key(|n| n.to_ascii_lowercase());
+        names.dedup_by(|a, b| a.eq_ignore_ascii_case(b));
+
+        Ok(names)
+    }
+
+    fn detect_locale(data_dir: &Path) -> Result<String> {
+        let entries = std::fs::read_dir(data_dir)
+            .with_context(|| format!("Failed to read {}", data_dir.display()))?;
+
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.len() == 4
+                && entry.path().is_dir()
+                && entry
+                    .path()
+                    .join(format!("locale-{}.MPQ", name))
+                    .is_file()
+            {
+                return Ok(name);
+            }
+        }
+
+        Err(anyhow!(
+            "Could not detect client locale in {}",
+            data_dir.display()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::files::mpq::{HASH_TYPE_FILE_KEY, decrypt_block, hash_string};
+
+    #[test]
+    fn test_hash_string_table_keys() {
+        assert_eq!(0xC3AF3770, hash_string("(hash table)", HASH_TYPE_FILE_KEY));
+        assert_eq!(0xEC83B3A3, hash_string("(block table)", HASH_TYPE_FILE_KEY));
+    }
+
+    #[test]
+    fn test_hash_string_ignores_case_and_separator() {
+        assert_eq!(
+            hash_string("DBFilesClient\\Map.dbc", 1),
+            hash_string("dbfilesclient/map.DBC", 1)
+        );
+    }
+
+    #[test]
+    fn test_decrypt_block_leaves_trailing_bytes() {
+        let mut data = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        decrypt_block(&mut data, 0xDEADBEEF);
+
+        assert_ne!([0x01, 0x02, 0x03, 0x04], data[..4]);
+        assert_eq!([0x05, 0x06], data[4..]);
+    }
+}
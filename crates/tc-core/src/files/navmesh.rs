@@ -0,0 +1,392 @@
+use crate::{
+    files::{map::MAP_TILES_PER_SIDE, reader::ByteReader},
+    math::Vec3,
+};
+use anyhow::{Result, anyhow};
+
+pub const NAVMESH_VERSION: u32 = 1;
+
+const TILE_MAGIC: &[u8; 4] = b"NAVT";
+
+// Area ids, zero marks unwalkable space and never ends up in a tile
+pub const NAV_AREA_GROUND: u8 = 1;
+pub const NAV_AREA_WATER: u8 = 2;
+pub const NAV_AREA_MAGMA: u8 = 3;
+pub const NAV_AREA_SLIME: u8 = 4;
+
+pub const NAV_POLY_FLAG_WALK: u16 = 0x01;
+pub const NAV_POLY_FLAG_SWIM: u16 = 0x02;
+
+// Polygon edge neighbors. Zero is a solid edge, values below the border
+// bit are an index into the tile's polygons plus one and border edges
+// carry the tile side they touch in the low bits
+pub const NAV_NEIGHBOR_NONE: u32 = 0;
+pub const NAV_NEIGHBOR_BORDER: u32 = 0x8000_0000;
+
+// Tile sides in world space, in the order of their border values
+pub const NAV_SIDE_POS_X: u32 = 0;
+pub const NAV_SIDE_POS_Y: u32 = 1;
+pub const NAV_SIDE_NEG_X: u32 = 2;
+pub const NAV_SIDE_NEG_Y: u32 = 3;
+
+pub fn navmesh_tile_file_name(map_id: u32, tile_x: usize, tile_y: usize) -> String {
+    format!("{:04}_{:02}_{:02}.mmtile", map_id, tile_x, tile_y)
+}
+
+// Tile across the given side and the side of it facing back. World x grows
+// towards lower tile y and world y towards lower tile x
+pub fn navmesh_neighbor_tile(
+    tile_x: usize,
+    tile_y: usize,
+    side: u32,
+) -> Option<((usize, usize), u32)> {
+    let (x, y) = match side {
+        NAV_SIDE_POS_X => (Some(tile_x), tile_y.checked_sub(1)),
+        NAV_SIDE_POS_Y => (tile_x.checked_sub(1), Some(tile_y)),
+        NAV_SIDE_NEG_X => (Some(tile_x), Some(tile_y + 1)),
+        NAV_SIDE_NEG_Y => (Some(tile_x + 1), Some(tile_y)),
+        _ => return None,
+    };
+
+    let (x, y) = (
+        x.filter(|x| *x < MAP_TILES_PER_SIDE)?,
+        y.filter(|y| *y < MAP_TILES_PER_SIDE)?,
+    );
+    Some(((x, y), (side + 2) % 4))
+}
+
+// Agent the tile was built for, kept so the runtime can tell tiles built
+// with different settings apart
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NavMeshParams {
+    pub walkable_height: f32,
+    pub walkable_radius: f32,
+    pub walkable_climb: f32,
+    pub cell_size: f32,
+    pub cell_height: f32,
+}
+
+// Convex polygon, counter clockwise seen from above. Edge i runs from
+// vertex i to vertex i + 1 and neighbors[i] tells what lies across it
+#[derive(Debug, Clone, PartialEq)]
+pub struct NavPoly {
+    pub vertices: Vec<u32>,
+    pub neighbors: Vec<u32>,
+    pub area: u8,
+    pub flags: u16,
+}
+
+impl NavPoly {
+    pub fn neighbor(&self, edge: usize) -> Option<usize> {
+        let value = self.neighbors[edge];
+        (value != NAV_NEIGHBOR_NONE && value & NAV_NEIGHBOR_BORDER == 0).then(|| value as usize - 1)
+    }
+
+    pub fn border_side(&self, edge: usize) -> Option<u32> {
+        let value = self.neighbors[edge];
+        (value & NAV_NEIGHBOR_BORDER != 0).then_some(value & !NAV_NEIGHBOR_BORDER)
+    }
+}
+
+// Triangles following the surface below a polygon more closely than the
+// polygon itself, used for height queries
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NavPolyDetail {
+    pub vertices: Vec<Vec3>,
+    pub triangles: Vec<[u8; 3]>,
+}
+
+// Hand placed link between two points the mesh itself does not connect,
+// like jumping down a ledge or taking an elevator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NavOffMeshConnection {
+    pub start: Vec3,
+    pub end: Vec3,
+    pub radius: f32,
+    pub bidirectional: bool,
+    pub area: u8,
+    pub flags: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NavMeshTile {
+    pub map_id: u32,
+    pub tile_x: u32,
+    pub tile_y: u32,
+    pub params: NavMeshParams,
+    pub vertices: Vec<Vec3>,
+    pub polys: Vec<NavPoly>,
+    // One entry per polygon
+    pub details: Vec<NavPolyDetail>,
+    pub off_mesh_connections: Vec<NavOffMeshConnection>,
+}
+
+impl NavMeshTile {
+    pub fn read(data: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader::new(data);
+        let found = reader.read_array::<4>()?;
+        if &found != TILE_MAGIC {
+            return Err(anyhow!("Not a navmesh tile, missing NAVT magic"));
+        }
+
+        let version = reader.read_u32()?;
+        if version != NAVMESH_VERSION {
+            return Err(anyhow!(
+                "Navmesh tile version {} is not supported, expected {}",
+                version,
+                NAVMESH_VERSION
+            ));
+        }
+
+        let map_id = reader.read_u32()?;
+        let tile_x = reader.read_u32()?;
+        let tile_y = reader.read_u32()?;
+        let params = NavMeshParams {
+            walkable_height: reader.read_f32()?,
+            walkable_radius: reader.read_f32()?,
+            walkable_climb: reader.read_f32()?,
+            cell_size: reader.read_f32()?,
+            cell_height: reader.read_f32()?,
+        };
+
+        let vertex_count = reader.read_u32()? as usize;
+        let mut vertices = Vec::with_capacity(vertex_count);
+        for _ in 0..vertex_count {
+            vertices.push(Vec3::from_array(reader.read_vec3()?));
+        }
+
+        let poly_count = reader.read_u32()? as usize;
+        let mut polys = Vec::with_capacity(poly_count);
+        for _ in 0..poly_count {
+            let area = reader.read_u8()?;
+            let flags = reader.read_u16()?;
+            let count = reader.read_u8()? as usize;
+            if count < 3 {
+                return Err(anyhow!("Navmesh polygon with {} vertices", count));
+            }
+
+            let mut poly = NavPoly {
+                vertices: Vec::with_capacity(count),
+                neighbors: Vec::with_capacity(count),
+                area,
+                flags,
+            };
+
+            for _ in 0..count {
+                let vertex = reader.read_u32()?;
+                if vertex as usize >= vertex_count {
+                    return Err(anyhow!("Navmesh polygon references a vertex out of range"));
+                }
+
+                poly.vertices.push(vertex);
+            }
+
+            for _ in 0..count {
+                let neighbor = reader.read_u32()?;
+                if neighbor & NAV_NEIGHBOR_BORDER == 0 && neighbor as usize > poly_count {
+                    return Err(anyhow!(
+                        "Navmesh polygon references a neighbor out of range"
+                    ));
+                }
+
+                poly.neighbors.push(neighbor);
+            }
+
+            polys.push(poly);
+        }
+
+        let mut details = Vec::with_capacity(poly_count);
+        for _ in 0..poly_count {
+            let vertex_count = reader.read_u8()? as usize;
+            let mut detail = NavPolyDetail::default();
+            for _ in 0..vertex_count {
+                detail.vertices.push(Vec3::from_array(reader.read_vec3()?));
+            }
+
+            let triangle_count = reader.read_u16()? as usize;
+            for _ in 0..triangle_count {
+                let triangle = reader.read_array::<3>()?;
+                if triangle.iter().any(|i| *i as usize >= vertex_count) {
+                    return Err(anyhow!("Detail triangle references a vertex out of range"));
+                }
+
+                detail.triangles.push(triangle);
+            }
+
+            details.push(detail);
+        }
+
+        let connection_count = reader.read_u32()? as usize;
+        let mut off_mesh_connections = Vec::with_capacity(connection_count);
+        for _ in 0..connection_count {
+            off_mesh_connections.push(NavOffMeshConnection {
+                start: Vec3::from_array(reader.read_vec3()?),
+                end: Vec3::from_array(reader.read_vec3()?),
+                radius: reader.read_f32()?,
+                bidirectional: reader.read_u8()? != 0,
+                area: reader.read_u8()?,
+                flags: reader.read_u16()?,
+            });
+        }
+
+        Ok(Self {
+            map_id,
+            tile_x,
+            tile_y,
+            params,
+            vertices,
+            polys,
+            details,
+            off_mesh_connections,
+        })
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(TILE_MAGIC);
+        out.extend_from_slice(&NAVMESH_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.map_id.to_le_bytes());
+        out.extend_from_slice(&self.tile_x.to_le_bytes());
+        out.extend_from_slice(&self.tile_y.to_le_bytes());
+        for value in [
+            self.params.walkable_height,
+            self.params.walkable_radius,
+            self.params.walkable_climb,
+            self.params.cell_size,
+            self.params.cell_height,
+        ] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+
+        out.extend_from_slice(&(self.vertices.len() as u32).to_le_bytes());
+        for vertex in &self.vertices {
+            write_vec3(&mut out, *vertex);
+        }
+
+        out.extend_from_slice(&(self.polys.len() as u32).to_le_bytes());
+        for poly in &self.polys {
+            out.push(poly.area);
+            out.extend_from_slice(&poly.flags.to_le_bytes());
+            out.push(poly.vertices.len() as u8);
+            for value in poly.vertices.iter().chain(&poly.neighbors) {
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        for detail in &self.details {
+            out.push(detail.vertices.len() as u8);
+            for vertex in &detail.vertices {
+                write_vec3(&mut out, *vertex);
+            }
+
+            out.extend_from_slice(&(detail.triangles.len() as u16).to_le_bytes());
+            for triangle in &detail.triangles {
+                out.extend_from_slice(triangle);
+            }
+        }
+
+        out.extend_from_slice(&(self.off_mesh_connections.len() as u32).to_le_bytes());
+        for connection in &self.off_mesh_connections {
+            write_vec3(&mut out, connection.start);
+            write_vec3(&mut out, connection.end);
+            out.extend_from_slice(&connection.radius.to_le_bytes());
+            out.push(connection.bidirectional as u8);
+            out.push(connection.area);
+            out.extend_from_slice(&connection.flags.to_le_bytes());
+        }
+
+        out
+    }
+}
+
+fn write_vec3(out: &mut Vec<u8>, v: Vec3) {
+    for value in v.to_array() {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::files::navmesh::{
+        NAV_AREA_GROUND, NAV_NEIGHBOR_BORDER, NAV_POLY_FLAG_WALK, NAV_SIDE_NEG_X, NAV_SIDE_POS_X,
+        NavMeshParams, NavMeshTile, NavOffMeshConnection, NavPoly, NavPolyDetail,
+        navmesh_neighbor_tile,
+    };
+    use crate::math::Vec3;
+
+    #[test]
+    fn test_navmesh_tile_roundtrip() {
+        let vertices = vec![
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(4.0, 0.0, 1.0),
+            Vec3::new(4.0, 4.0, 1.5),
+            Vec3::new(0.0, 4.0, 1.5),
+            Vec3::new(8.0, 0.0, 1.0),
+            Vec3::new(8.0, 4.0, 1.5),
+        ];
+
+        let tile = NavMeshTile {
+            map_id: 1,
+            tile_x: 32,
+            tile_y: 48,
+            params: NavMeshParams {
+                walkable_height: 2.0,
+                walkable_radius: 0.6,
+                walkable_climb: 1.0,
+                cell_size: 0.5,
+                cell_height: 0.25,
+            },
+            polys: vec![
+                NavPoly {
+                    vertices: vec![0, 1, 2, 3],
+                    neighbors: vec![0, 2, 0, NAV_NEIGHBOR_BORDER | NAV_SIDE_NEG_X],
+                    area: NAV_AREA_GROUND,
+                    flags: NAV_POLY_FLAG_WALK,
+                },
+                NavPoly {
+                    vertices: vec![1, 4, 5, 2],
+                    neighbors: vec![0, 0, 0, 1],
+                    area: NAV_AREA_GROUND,
+                    flags: NAV_POLY_FLAG_WALK,
+                },
+            ],
+            details: vec![
+                NavPolyDetail {
+                    vertices: vertices[..4].to_vec(),
+                    triangles: vec![[0, 1, 2], [0, 2, 3]],
+                },
+                NavPolyDetail::default(),
+            ],
+            vertices,
+            off_mesh_connections: vec![NavOffMeshConnection {
+                start: Vec3::new(1.0, 1.0, 1.0),
+                end: Vec3::new(7.0, 3.0, 1.5),
+                radius: 1.0,
+                bidirectional: true,
+                area: NAV_AREA_GROUND,
+                flags: NAV_POLY_FLAG_WALK,
+            }],
+        };
+
+        let read = NavMeshTile::read(&tile.write()).unwrap();
+        assert_eq!(read, tile);
+        assert_eq!(read.polys[0].neighbor(1), Some(1));
+        assert_eq!(read.polys[0].neighbor(3), None);
+        assert_eq!(read.polys[0].border_side(3), Some(NAV_SIDE_NEG_X));
+        assert_eq!(read.polys[1].neighbor(3), Some(0));
+    }
+
+    #[test]
+    fn test_navmesh_neighbor_tile() {
+        assert_eq!(
+            navmesh_neighbor_tile(32, 48, NAV_SIDE_POS_X),
+            Some(((32, 47), NAV_SIDE_NEG_X))
+        );
+        assert_eq!(
+            navmesh_neighbor_tile(32, 48, NAV_SIDE_NEG_X),
+            Some(((32, 49), NAV_SIDE_POS_X))
+        );
+        assert_eq!(navmesh_neighbor_tile(32, 0, NAV_SIDE_POS_X), None);
+        assert_eq!(navmesh_neighbor_tile(32, 63, NAV_SIDE_NEG_X), None);
+    }
+}
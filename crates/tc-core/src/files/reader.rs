@@ -0,0 +1,181 @@
+use anyhow::{Result, anyhow};
+
+// Little endian cursor over an in memory file, every client file format
+// we parse is little endian so there is no need for a generic byte order
+pub struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.pos)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    pub fn seek(&mut self, pos: usize) -> Result<()> {
+        if pos > self.data.len() {
+            return Err(anyhow!(
+                "Seek to {} is past the end of the buffer ({} bytes)",
+                pos,
+                self.data.len()
+            ));
+        }
+
+        self.pos = pos;
+        Ok(())
+    }
+
+    pub fn skip(&mut self, count: usize) -> Result<()> {
+        self.seek(self.pos + count)
+    }
+
+    pub fn read_bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let end = self.pos + count;
+        if end > self.data.len() {
+            return Err(anyhow!(
+                "Unexpected end of data reading {} bytes at offset {} ({} bytes)",
+                count,
+                self.pos,
+                self.data.len()
+            ));
+        }
+
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+
+        Ok(bytes)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let bytes = self.read_bytes(N)?;
+        Ok(<[u8; N]>::try_from(bytes).unwrap())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_i16(&mut self) -> Result<i16> {
+        Ok(i16::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_vec3(&mut self) -> Result<[f32; 3]> {
+        Ok([self.read_f32()?, self.read_f32()?, self.read_f32()?])
+    }
+}
+
+// A single IFF style chunk from a client file. The identifier is stored
+// reversed on disk ("REVM" for MVER), it is flipped back while reading so
+// callers can compare against the readable name
+pub struct Chunk<'a> {
+    pub id: [u8; 4],
+    pub data: &'a [u8],
+}
+
+impl Chunk<'_> {
+    pub fn is(&self, id: &[u8; 4]) -> bool {
+        &self.id == id
+    }
+}
+
+pub struct ChunkIter<'a> {
+    reader: ByteReader<'a>,
+}
+
+impl<'a> Iterator for ChunkIter<'a> {
+    type Item = Result<Chunk<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.reader.remaining() < 8 {
+            return None;
+        }
+
+        let chunk = (|| {
+            let mut id = self.reader.read_array::<4>()?;
+            id.reverse();
+
+            let size = self.reader.read_u32()? as usize;
+            let data = self.reader.read_bytes(size).map_err(|e| {
+                e.context(format!(
+                    "Chunk {} is truncated",
+                    String::from_utf8_lossy(&id)
+                ))
+            })?;
+
+            Ok(Chunk { id, data })
+        })();
+
+        if chunk.is_err() {
+            self.reader.pos = self.reader.data.len();
+        }
+
+        Some(chunk)
+    }
+}
+
+pub fn chunks(data: &[u8]) -> ChunkIter<'_> {
+    ChunkIter {
+        reader: ByteReader::new(data),
+    }
+}
+
+// Reads a block of null separated strings (MWMO, MMDX, ...) keyed by the
+// byte offset of each string so the offset tables (MWID, MMID) resolve directly
+pub fn string_block(data: &[u8]) -> Vec<(u32, String)> {
+    let mut strings = Vec::new();
+    let mut start = 0;
+
+    for (i, byte) in data.iter().enumerate() {
+        if *byte == 0 {
+            if i > start {
+                strings.push((
+                    start as u32,
+                    String::from_utf8_lossy(&data[start..i]).into_owned(),
+                ));
+            }
+
+            start = i + 1;
+        }
+    }
+
+    strings
+}
+
+pub fn string_at(data: &[u8], offset: usize) -> Option<String> {
+    let slice = data.get(offset..)?;
+    let end = slice.iter().position(|b| *b == 0).unwrap_or(slice.len());
+
+    Some(String::from_utf8_lossy(&slice[..end]).into_owned())
+}
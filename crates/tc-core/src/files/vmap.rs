@@ -0,0 +1,464 @@
+use crate::{
+    files::{
+        map::{MAP_CELLS_PER_TILE, MAP_TILE_SIZE},
+        reader::ByteReader,
+    },
+    math::{Aabb, Mat3, Vec3},
+};
+use anyhow::{Result, anyhow};
+
+pub const VMAP_VERSION: u32 = 1;
+
+// World coordinate of the corner between the four center tiles, the client
+// places everything relative to the opposite corner of the map
+pub const VMAP_MAP_CENTER: f32 = 32.0 * MAP_TILE_SIZE;
+
+// WMO liquid tiles are the same size as a map cell
+pub const VMAP_LIQUID_TILE_SIZE: f32 = MAP_TILE_SIZE / MAP_CELLS_PER_TILE as f32;
+
+const MODEL_MAGIC: &[u8; 4] = b"VMOD";
+const PLACEMENT_MAGIC: &[u8; 4] = b"VMPS";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmapModelKind {
+    M2,
+    Wmo,
+}
+
+impl VmapModelKind {
+    fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::M2),
+            1 => Ok(Self::Wmo),
+            _ => Err(anyhow!("Unknown vmap model kind {}", value)),
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Self::M2 => 0,
+            Self::Wmo => 1,
+        }
+    }
+}
+
+pub fn vmap_model_file_name(model_path: &str) -> String {
+    let name = model_path.to_ascii_lowercase().replace(['\\', '/'], "_");
+
+    format!("{}.vmo", name)
+}
+
+pub fn vmap_tile_file_name(map_id: u32, tile_x: usize, tile_y: usize) -> String {
+    format!("{:04}_{:02}_{:02}.vmtile", map_id, tile_x, tile_y)
+}
+
+// Placements that are not bound to a tile, the global WMO of instance maps
+pub fn vmap_map_file_name(map_id: u32) -> String {
+    format!("{:04}.vmmap", map_id)
+}
+
+// Converts a client placement position (MDDF/MODF) to world coordinates
+pub fn placement_position(position: [f32; 3]) -> Vec3 {
+    Vec3::new(
+        VMAP_MAP_CENTER - position[2],
+        VMAP_MAP_CENTER - position[0],
+        position[1],
+    )
+}
+
+// Converts a client placement rotation in degrees to a model to world
+// rotation. The client rotates around its own axes which map onto the world
+// axes mirrored on x and y, hence the final flip
+pub fn placement_rotation(rotation: [f32; 3]) -> Mat3 {
+    let local = Mat3::from_euler_zyx(
+        rotation[1].to_radians(),
+        rotation[0].to_radians(),
+        rotation[2].to_radians(),
+    );
+
+    Mat3::from_rows([[-1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 1.0]]) * local
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmapLiquid {
+    // LiquidType.dbc id
+    pub liquid_type: u32,
+    pub tiles_x: u32,
+    pub tiles_y: u32,
+    pub corner: Vec3,
+    // (tiles_x + 1) * (tiles_y + 1) vertex heights
+    pub heights: Vec<f32>,
+    pub flags: Vec<u8>,
+}
+
+impl VmapLiquid {
+    pub fn has_tile(&self, x: u32, y: u32) -> bool {
+        self.flags[(y * self.tiles_x + x) as usize] & 0x0F != 0x0F
+    }
+
+    pub fn height(&self, x: u32, y: u32) -> f32 {
+        self.heights[(y * (self.tiles_x + 1) + x) as usize]
+    }
+}
+
+// Collision geometry of a single WMO group or of a whole M2 model, in
+// model space
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmapGroup {
+    pub flags: u32,
+    pub group_id: u32,
+    pub bounds: Aabb,
+    pub vertices: Vec<Vec3>,
+    pub triangles: Vec<[u32; 3]>,
+    pub liquid: Option<VmapLiquid>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmapModel {
+    pub kind: VmapModelKind,
+    pub flags: u32,
+    // WMOAreaTable.dbc root id, zero for M2 models
+    pub root_id: u32,
+    pub bounds: Aabb,
+    pub groups: Vec<VmapGroup>,
+}
+
+impl VmapModel {
+    pub fn read(data: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader::new(data);
+        expect_header(&mut reader, MODEL_MAGIC)?;
+
+        let kind = VmapModelKind::from_u8(reader.read_u8()?)?;
+        let flags = reader.read_u32()?;
+        let root_id = reader.read_u32()?;
+        let bounds = read_aabb(&mut reader)?;
+
+        let group_count = reader.read_u32()? as usize;
+        let mut groups = Vec::with_capacity(group_count);
+        for _ in 0..group_count {
+            let flags = reader.read_u32()?;
+            let group_id = reader.read_u32()?;
+            let bounds = read_aabb(&mut reader)?;
+
+            let vertex_count = reader.read_u32()? as usize;
+            let mut vertices = Vec::with_capacity(vertex_count);
+            for _ in 0..vertex_count {
+                vertices.push(Vec3::from_array(reader.read_vec3()?));
+            }
+
+            let triangle_count = reader.read_u32()? as usize;
+            let mut triangles = Vec::with_capacity(triangle_count);
+            for _ in 0..triangle_count {
+                let triangle = [reader.read_u32()?, reader.read_u32()?, reader.read_u32()?];
+                if triangle.iter().any(|i| *i as usize >= vertex_count) {
+                    return Err(anyhow!("Triangle references a vertex out of range"));
+                }
+
+                triangles.push(triangle);
+            }
+
+            let liquid = if reader.read_u8()? != 0 {
+                let liquid_type = reader.read_u32()?;
+                let tiles_x = reader.read_u32()?;
+                let tiles_y = reader.read_u32()?;
+                let corner = Vec3::from_array(reader.read_vec3()?);
+
+                let mut heights = Vec::new();
+                for _ in 0..(tiles_x + 1) * (tiles_y + 1) {
+                    heights.push(reader.read_f32()?);
+                }
+
+                let flags = reader.read_bytes((tiles_x * tiles_y) as usize)?.to_vec();
+                Some(VmapLiquid {
+                    liquid_type,
+                    tiles_x,
+                    tiles_y,
+                    corner,
+                    heights,
+                    flags,
+                })
+            } else {
+                None
+            };
+
+            groups.push(VmapGroup {
+                flags,
+                group_id,
+                bounds,
+                vertices,
+                triangles,
+                liquid,
+            });
+        }
+
+        Ok(Self {
+            kind,
+            flags,
+            root_id,
+            bounds,
+            groups,
+        })
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MODEL_MAGIC);
+        out.extend_from_slice(&VMAP_VERSION.to_le_bytes());
+        out.push(self.kind.as_u8());
+        out.extend_from_slice(&self.flags.to_le_bytes());
+        out.extend_from_slice(&self.root_id.to_le_bytes());
+        write_aabb(&mut out, &self.bounds);
+
+        out.extend_from_slice(&(self.groups.len() as u32).to_le_bytes());
+        for group in &self.groups {
+            out.extend_from_slice(&group.flags.to_le_bytes());
+            out.extend_from_slice(&group.group_id.to_le_bytes());
+            write_aabb(&mut out, &group.bounds);
+
+            out.extend_from_slice(&(group.vertices.len() as u32).to_le_bytes());
+            for vertex in &group.vertices {
+                write_vec3(&mut out, *vertex);
+            }
+
+            out.extend_from_slice(&(group.triangles.len() as u32).to_le_bytes());
+            for index in group.triangles.iter().flatten() {
+                out.extend_from_slice(&index.to_le_bytes());
+            }
+
+            match &group.liquid {
+                Some(liquid) => {
+                    out.push(1);
+                    out.extend_from_slice(&liquid.liquid_type.to_le_bytes());
+                    out.extend_from_slice(&liquid.tiles_x.to_le_bytes());
+                    out.extend_from_slice(&liquid.tiles_y.to_le_bytes());
+                    write_vec3(&mut out, liquid.corner);
+                    for height in &liquid.heights {
+                        out.extend_from_slice(&height.to_le_bytes());
+                    }
+                    out.extend_from_slice(&liquid.flags);
+                }
+                None => out.push(0),
+            }
+        }
+
+        out
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.groups.iter().map(|g| g.triangles.len()).sum()
+    }
+}
+
+// A model instance in the world. Model space points are taken to world
+// space with rotation * (point * scale) + position
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmapPlacement {
+    // Unique per instance, the same placement is listed by every tile it
+    // is referenced from
+    pub id: u64,
+    pub kind: VmapModelKind,
+    pub flags: u16,
+    // Placement name set, used as the adt id for WMOAreaTable lookups
+    pub name_set: u16,
+    pub model: String,
+    pub position: Vec3,
+    pub rotation: Mat3,
+    pub scale: f32,
+    pub bounds: Aabb,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VmapPlacementSet {
+    pub placements: Vec<VmapPlacement>,
+}
+
+impl VmapPlacementSet {
+    pub fn read(data: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader::new(data);
+        expect_header(&mut reader, PLACEMENT_MAGIC)?;
+
+        let count = reader.read_u32()? as usize;
+        let mut placements = Vec::with_capacity(count);
+        for _ in 0..count {
+            let id = reader.read_u64()?;
+            let kind = VmapModelKind::from_u8(reader.read_u8()?)?;
+            let flags = reader.read_u16()?;
+            let name_set = reader.read_u16()?;
+
+            let name_length = reader.read_u16()? as usize;
+            let model = String::from_utf8(reader.read_bytes(name_length)?.to_vec())?;
+
+            let position = Vec3::from_array(reader.read_vec3()?);
+            let mut rotation = [0f32; 9];
+            for value in rotation.iter_mut() {
+                *value = reader.read_f32()?;
+            }
+
+            let scale = reader.read_f32()?;
+            let bounds = read_aabb(&mut reader)?;
+
+            placements.push(VmapPlacement {
+                id,
+                kind,
+                flags,
+                name_set,
+                model,
+                position,
+                rotation: Mat3::from_array(rotation),
+                scale,
+                bounds,
+            });
+        }
+
+        Ok(Self { placements })
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(PLACEMENT_MAGIC);
+        out.extend_from_slice(&VMAP_VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.placements.len() as u32).to_le_bytes());
+
+        for placement in &self.placements {
+            out.extend_from_slice(&placement.id.to_le_bytes());
+            out.push(placement.kind.as_u8());
+            out.extend_from_slice(&placement.flags.to_le_bytes());
+            out.extend_from_slice(&placement.name_set.to_le_bytes());
+            out.extend_from_slice(&(placement.model.len() as u16).to_le_bytes());
+            out.extend_from_slice(placement.model.as_bytes());
+            write_vec3(&mut out, placement.position);
+            for value in placement.rotation.to_array() {
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            out.extend_from_slice(&placement.scale.to_le_bytes());
+            write_aabb(&mut out, &placement.bounds);
+        }
+
+        out
+    }
+}
+
+fn expect_header(reader: &mut ByteReader, magic: &[u8; 4]) -> Result<()> {
+    let found = reader.read_array::<4>()?;
+    if &found != magic {
+        return Err(anyhow!(
+            "Invalid vmap file magic {:?}, expected {:?}",
+            String::from_utf8_lossy(&found),
+            String::from_utf8_lossy(magic)
+        ));
+    }
+
+    let version = reader.read_u32()?;
+    if version != VMAP_VERSION {
+        return Err(anyhow!(
+            "Unsupported vmap version {}, expected {}",
+            version,
+            VMAP_VERSION
+        ));
+    }
+
+    Ok(())
+}
+
+fn read_aabb(reader: &mut ByteReader) -> Result<Aabb> {
+    Ok(Aabb::new(
+        Vec3::from_array(reader.read_vec3()?),
+        Vec3::from_array(reader.read_vec3()?),
+    ))
+}
+
+fn write_vec3(out: &mut Vec<u8>, v: Vec3) {
+    for value in v.to_array() {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn write_aabb(out: &mut Vec<u8>, bounds: &Aabb) {
+    write_vec3(out, bounds.min);
+    write_vec3(out, bounds.max);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::files::vmap::{
+        VMAP_MAP_CENTER, VmapGroup, VmapLiquid, VmapModel, VmapModelKind, VmapPlacement,
+        VmapPlacementSet, placement_position, placement_rotation,
+    };
+    use crate::math::{Aabb, Mat3, Vec3};
+
+    fn model() -> VmapModel {
+        let vertices = vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.5),
+        ];
+
+        VmapModel {
+            kind: VmapModelKind::Wmo,
+            flags: 0x4,
+            root_id: 42,
+            bounds: Aabb::from_points(vertices.iter().copied()),
+            groups: vec![VmapGroup {
+                flags: 0x2000,
+                group_id: 7,
+                bounds: Aabb::from_points(vertices.iter().copied()),
+                vertices,
+                triangles: vec![[0, 1, 2]],
+                liquid: Some(VmapLiquid {
+                    liquid_type: 13,
+                    tiles_x: 1,
+                    tiles_y: 2,
+                    corner: Vec3::new(-1.0, -2.0, 0.0),
+                    heights: vec![0.0, 0.1, 0.2, 0.3, 0.4, 0.5],
+                    flags: vec![0, 0x0F],
+                }),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_vmap_model_roundtrip() {
+        let model = model();
+        let read = VmapModel::read(&model.write()).unwrap();
+
+        assert_eq!(read, model);
+        assert!(read.groups[0].liquid.as_ref().unwrap().has_tile(0, 0));
+        assert!(!read.groups[0].liquid.as_ref().unwrap().has_tile(0, 1));
+    }
+
+    #[test]
+    fn test_vmap_placement_roundtrip() {
+        let set = VmapPlacementSet {
+            placements: vec![VmapPlacement {
+                id: 1 << 62 | 1234,
+                kind: VmapModelKind::M2,
+                flags: 2,
+                name_set: 3,
+                model: "world_generic_tree.m2.vmo".to_string(),
+                position: Vec3::new(1.0, 2.0, 3.0),
+                rotation: Mat3::rotation_z(1.0),
+                scale: 1.5,
+                bounds: Aabb::new(Vec3::ZERO, Vec3::ONE),
+            }],
+        };
+
+        assert_eq!(VmapPlacementSet::read(&set.write()).unwrap(), set);
+    }
+
+    #[test]
+    fn test_vmap_placement_transform() {
+        // The client's origin is the far corner of the map
+        let position = placement_position([0.0, 10.0, 0.0]);
+        assert_eq!(position, Vec3::new(VMAP_MAP_CENTER, VMAP_MAP_CENTER, 10.0));
+
+        // Without rotation the model axes are mirrored on x and y
+        let rotation = placement_rotation([0.0, 0.0, 0.0]);
+        let v = rotation * Vec3::new(1.0, 2.0, 3.0);
+        assert_eq!(v, Vec3::new(-1.0, -2.0, 3.0));
+
+        // Yaw of 90 degrees turns the model x axis onto the world -y axis
+        let rotation = placement_rotation([0.0, 90.0, 0.0]);
+        let v = rotation * Vec3::new(1.0, 0.0, 0.0);
+        assert!((v - Vec3::new(0.0, -1.0, 0.0)).length() < 1e-5);
+    }
+}
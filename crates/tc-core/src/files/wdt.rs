@@ -0,0 +1,79 @@
+use crate::files::{
+    adt::AdtWmoPlacement,
+    reader::{ByteReader, chunks, string_at},
+};
+use anyhow::{Result, anyhow};
+
+pub const WDT_TILES_PER_SIDE: usize = 64;
+
+// Map is a single global WMO (instances, capitals) placed by the WDT itself
+pub const MPHD_FLAG_GLOBAL_WMO: u32 = 0x01;
+
+const MAIN_FLAG_HAS_ADT: u32 = 0x01;
+
+#[derive(Debug, Clone)]
+pub struct WdtFile {
+    pub flags: u32,
+    tiles: Vec<u32>,
+    // Global WMO name and placement, only present on WMO based maps
+    pub wmo_name: Option<String>,
+    pub wmo: Option<AdtWmoPlacement>,
+}
+
+impl WdtFile {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut flags = 0;
+        let mut tiles = None;
+        let mut wmo_name = None;
+        let mut wmo = None;
+
+        for chunk in chunks(data) {
+            let chunk = chunk?;
+            match &chunk.id {
+                b"MPHD" => flags = ByteReader::new(chunk.data).read_u32()?,
+                b"MAIN" => {
+                    let mut reader = ByteReader::new(chunk.data);
+                    let mut entries = Vec::with_capacity(WDT_TILES_PER_SIDE * WDT_TILES_PER_SIDE);
+                    for _ in 0..WDT_TILES_PER_SIDE * WDT_TILES_PER_SIDE {
+                        entries.push(reader.read_u32()?);
+                        reader.skip(4)?;
+                    }
+
+                    tiles = Some(entries);
+                }
+                b"MWMO" => wmo_name = string_at(chunk.data, 0).filter(|n| !n.is_empty()),
+                b"MODF" => wmo = AdtWmoPlacement::parse_all(chunk.data)?.into_iter().next(),
+                _ => {}
+            }
+        }
+
+        let tiles = tiles.ok_or_else(|| anyhow!("WDT has no MAIN chunk"))?;
+        Ok(Self {
+            flags,
+            tiles,
+            wmo_name,
+            wmo,
+        })
+    }
+
+    // Tile coordinates follow the ADT file naming, <map>_<x>_<y>.adt
+    pub fn has_tile(&self, x: usize, y: usize) -> bool {
+        x < WDT_TILES_PER_SIDE
+            && y < WDT_TILES_PER_SIDE
+            && self.tiles[y * WDT_TILES_PER_SIDE + x] & MAIN_FLAG_HAS_ADT != 0
+    }
+
+    pub fn tiles(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (0..WDT_TILES_PER_SIDE)
+            .flat_map(|y| (0..WDT_TILES_PER_SIDE).map(move |x| (x, y)))
+            .filter(|(x, y)| self.has_tile(*x, *y))
+    }
+}
+
+pub fn wdt_path(map_directory: &str) -> String {
+    format!("World\\Maps\\{0}\\{0}.wdt", map_directory)
+}
+
+pub fn adt_path(map_directory: &str, x: usize, y: usize) -> String {
+    format!("World\\Maps\\{0}\\{0}_{1}_{2}.adt", map_directory, x, y)
+}
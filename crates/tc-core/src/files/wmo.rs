@@ -0,0 +1,475 @@
+use crate::files::reader::{ByteReader, chunks, string_at};
+use anyhow::{Result, anyhow};
+
+// Root flag telling that group liquid values are LiquidType.dbc ids instead
+// of the legacy per group liquid enumeration
+pub const WMO_ROOT_FLAG_LIQUID_TYPE_ID: u16 = 0x04;
+
+pub const WMO_GROUP_FLAG_OUTDOOR: u32 = 0x08;
+pub const WMO_GROUP_FLAG_INDOOR: u32 = 0x2000;
+pub const WMO_GROUP_FLAG_OCEAN: u32 = 0x80000;
+
+const MOPY_FLAG_DETAIL: u8 = 0x04;
+const MOPY_FLAG_COLLISION: u8 = 0x08;
+const MOPY_FLAG_RENDER: u8 = 0x20;
+
+const MOGP_HEADER_SIZE: usize = 0x44;
+const MODS_ENTRY_SIZE: usize = 32;
+const MODD_ENTRY_SIZE: usize = 40;
+const MLIQ_VERTEX_SIZE: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct WmoDoodadSet {
+    pub name: String,
+    pub start: u32,
+    pub count: u32,
+}
+
+// Doodad placed inside a WMO, position and rotation are relative to the WMO
+#[derive(Debug, Clone)]
+pub struct WmoDoodad {
+    pub name: String,
+    pub position: [f32; 3],
+    // Quaternion as x, y, z, w
+    pub rotation: [f32; 4],
+    pub scale: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct WmoRoot {
+    pub group_count: u32,
+    pub wmo_id: u32,
+    pub bounds_min: [f32; 3],
+    pub bounds_max: [f32; 3],
+    pub flags: u16,
+    pub doodad_sets: Vec<WmoDoodadSet>,
+    pub doodads: Vec<WmoDoodad>,
+}
+
+impl WmoRoot {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut header = None;
+        let mut doodad_sets = Vec::new();
+        let mut doodad_names = &[][..];
+        let mut doodad_defs = &[][..];
+
+        for chunk in chunks(data) {
+            let chunk = chunk?;
+            match &chunk.id {
+                b"MOHD" => header = Some(chunk.data),
+                b"MODS" => {
+                    let mut reader = ByteReader::new(chunk.data);
+                    while reader.remaining() >= MODS_ENTRY_SIZE {
+                        let name = reader.read_bytes(20)?;
+                        doodad_sets.push(WmoDoodadSet {
+                            name: string_at(name, 0).unwrap_or_default(),
+                            start: reader.read_u32()?,
+                            count: reader.read_u32()?,
+                        });
+                        reader.skip(4)?;
+                    }
+                }
+                b"MODN" => doodad_names = chunk.data,
+                b"MODD" => doodad_defs = chunk.data,
+                _ => {}
+            }
+        }
+
+        let header = header.ok_or_else(|| anyhow!("WMO root has no MOHD chunk"))?;
+        let mut reader = ByteReader::new(header);
+        reader.skip(4)?;
+        let group_count = reader.read_u32()?;
+        reader.seek(0x20)?;
+        let wmo_id = reader.read_u32()?;
+        let bounds_min = reader.read_vec3()?;
+        let bounds_max = reader.read_vec3()?;
+        let flags = reader.read_u16()?;
+
+        let mut reader = ByteReader::new(doodad_defs);
+        let mut doodads = Vec::with_capacity(doodad_defs.len() / MODD_ENTRY_SIZE);
+        while reader.remaining() >= MODD_ENTRY_SIZE {
+            // Upper 8 bits of the name offset are flags
+            let name_offset = (reader.read_u32()? & 0x00FF_FFFF) as usize;
+            let position = reader.read_vec3()?;
+            let rotation = [
+                reader.read_f32()?,
+                reader.read_f32()?,
+                reader.read_f32()?,
+                reader.read_f32()?,
+            ];
+            let scale = reader.read_f32()?;
+            reader.skip(4)?;
+
+            doodads.push(WmoDoodad {
+                name: string_at(doodad_names, name_offset).unwrap_or_default(),
+                position,
+                rotation,
+                scale,
+            });
+        }
+
+        Ok(Self {
+            group_count,
+            wmo_id,
+            bounds_min,
+            bounds_max,
+            flags,
+            doodad_sets,
+            doodads,
+        })
+    }
+
+    // Doodads shown for a placement along with their index in the doodad
+    // list, set 0 is always shown in addition to the placement's set
+    pub fn doodads_in_set(&self, set: u16) -> impl Iterator<Item = (usize, &WmoDoodad)> + '_ {
+        let mut sets = vec![0usize];
+        if set != 0 {
+            sets.push(set as usize);
+        }
+
+        sets.into_iter()
+            .filter_map(|s| self.doodad_sets.get(s))
+            .flat_map(|s| {
+                let start = (s.start as usize).min(self.doodads.len());
+                let end = (start + s.count as usize).min(self.doodads.len());
+                (start..end).map(|i| (i, &self.doodads[i]))
+            })
+    }
+}
+
+// Liquid plane of a WMO group, heights are per vertex on a grid of
+// (tiles_x + 1) * (tiles_y + 1) starting at corner
+#[derive(Debug, Clone)]
+pub struct WmoLiquid {
+    pub tiles_x: u32,
+    pub tiles_y: u32,
+    pub corner: [f32; 3],
+    pub material: u16,
+    pub heights: Vec<f32>,
+    pub flags: Vec<u8>,
+}
+
+impl WmoLiquid {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader::new(data);
+        let verts_x = reader.read_u32()?;
+        let verts_y = reader.read_u32()?;
+        let tiles_x = reader.read_u32()?;
+        let tiles_y = reader.read_u32()?;
+        let corner = reader.read_vec3()?;
+        let material = reader.read_u16()?;
+
+        // Every vertex starts with 4 bytes of flow or texture data, the
+        // height always follows
+        let mut heights = Vec::with_capacity((verts_x * verts_y) as usize);
+        for _ in 0..verts_x * verts_y {
+            reader.skip(MLIQ_VERTEX_SIZE - 4)?;
+            heights.push(reader.read_f32()?);
+        }
+
+        let flags = reader.read_bytes((tiles_x * tiles_y) as usize)?.to_vec();
+        if verts_x != tiles_x + 1 || verts_y != tiles_y + 1 {
+            return Err(anyhow!(
+                "MLIQ vertex grid {}x{} does not match tile grid {}x{}",
+                verts_x,
+                verts_y,
+                tiles_x,
+                tiles_y
+            ));
+        }
+
+        Ok(Self {
+            tiles_x,
+            tiles_y,
+            corner,
+            material,
+            heights,
+            flags,
+        })
+    }
+
+    // Tiles with the low nibble fully set are not covered by liquid
+    pub fn has_tile(&self, x: u32, y: u32) -> bool {
+        self.flags[(y * self.tiles_x + x) as usize] & 0x0F != 0x0F
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WmoGroup {
+    pub flags: u32,
+    pub bounds_min: [f32; 3],
+    pub bounds_max: [f32; 3],
+    pub group_liquid: u32,
+    // WMOAreaTable.dbc group id
+    pub group_id: u32,
+    pub vertices: Vec<[f32; 3]>,
+    pub indices: Vec<u16>,
+    // Material flags and id per triangle
+    pub materials: Vec<(u8, u8)>,
+    pub liquid: Option<WmoLiquid>,
+}
+
+impl WmoGroup {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mogp = chunks(data)
+            .find(|c| c.as_ref().map_or(true, |c| c.is(b"MOGP")))
+            .ok_or_else(|| anyhow!("WMO group has no MOGP chunk"))??;
+
+        if mogp.data.len() < MOGP_HEADER_SIZE {
+            return Err(anyhow!("MOGP chunk is smaller than its header"));
+        }
+
+        let mut reader = ByteReader::new(mogp.data);
+        reader.seek(0x08)?;
+        let flags = reader.read_u32()?;
+        let bounds_min = reader.read_vec3()?;
+        let bounds_max = reader.read_vec3()?;
+        reader.seek(0x34)?;
+        let group_liquid = reader.read_u32()?;
+        let group_id = reader.read_u32()?;
+
+        let mut group = Self {
+            flags,
+            bounds_min,
+            bounds_max,
+            group_liquid,
+            group_id,
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            materials: Vec::new(),
+            liquid: None,
+        };
+
+        for chunk in chunks(&mogp.data[MOGP_HEADER_SIZE..]) {
+            let chunk = chunk?;
+            let mut reader = ByteReader::new(chunk.data);
+            match &chunk.id {
+                b"MOPY" => {
+                    while reader.remaining() >= 2 {
+                        group.materials.push((reader.read_u8()?, reader.read_u8()?));
+                    }
+                }
+                b"MOVI" => {
+                    while reader.remaining() >= 2 {
+                        group.indices.push(reader.read_u16()?);
+                    }
+                }
+                b"MOVT" => {
+                    while reader.remaining() >= 12 {
+                        group.vertices.push(reader.read_vec3()?);
+                    }
+                }
+                b"MLIQ" => group.liquid = Some(WmoLiquid::parse(chunk.data)?),
+                _ => {}
+            }
+        }
+
+        Ok(group)
+    }
+
+    // Triangles that block movement and line of sight, render only detail
+    // geometry is skipped
+    pub fn collision_triangles(&self) -> impl Iterator<Item = [u16; 3]> + '_ {
+        self.indices
+            .chunks_exact(3)
+            .zip(self.materials.iter())
+            .filter(|(_, (flags, _))| {
+                let render = flags & MOPY_FLAG_RENDER != 0 && flags & MOPY_FLAG_DETAIL == 0;
+                flags & MOPY_FLAG_COLLISION != 0 || render
+            })
+            .map(|(tri, _)| [tri[0], tri[1], tri[2]])
+    }
+
+    // LiquidType.dbc id of the group liquid, older WMOs store a small
+    // enumeration that is mapped onto the basic liquid types
+    pub fn liquid_type(&self, root_flags: u16) -> u32 {
+        let liquid = if root_flags & WMO_ROOT_FLAG_LIQUID_TYPE_ID != 0 {
+            self.group_liquid
+        } else if self.group_liquid == 15 {
+            0
+        } else {
+            self.group_liquid + 1
+        };
+
+        if liquid == 0 || liquid >= 21 {
+            return liquid;
+        }
+
+        match (liquid - 1) & 3 {
+            0 if self.flags & WMO_GROUP_FLAG_OCEAN != 0 => 14,
+            0 => 13,
+            1 => 14,
+            2 => 19,
+            _ => 20,
+        }
+    }
+}
+
+// Group files sit next to the root as <root>_000.wmo, <root>_001.wmo, ...
+pub fn wmo_group_path(root_path: &str, index: u32) -> String {
+    let stem = root_path
+        .strip_suffix(".wmo")
+        .or_else(|| root_path.strip_suffix(".WMO"))
+        .unwrap_or(root_path);
+
+    format!("{}_{:03}.wmo", stem, index)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::files::wmo::{
+        WMO_GROUP_FLAG_OCEAN, WMO_ROOT_FLAG_LIQUID_TYPE_ID, WmoGroup, WmoRoot, wmo_group_path,
+    };
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut out = id.iter().rev().copied().collect::<Vec<_>>();
+        out.extend((data.len() as u32).to_le_bytes());
+        out.extend(data);
+        out
+    }
+
+    fn floats(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn doodad(name_offset: u32, position: [f32; 3], scale: f32) -> Vec<u8> {
+        let mut data = (name_offset | 0x0100_0000).to_le_bytes().to_vec();
+        data.extend(floats(&position));
+        data.extend(floats(&[0.0, 0.0, 0.0, 1.0, scale]));
+        data.extend([0; 4]);
+        data
+    }
+
+    fn doodad_set(name: &str, start: u32, count: u32) -> Vec<u8> {
+        let mut data = vec![0u8; 20];
+        data[..name.len()].copy_from_slice(name.as_bytes());
+        data.extend(start.to_le_bytes());
+        data.extend(count.to_le_bytes());
+        data.extend([0; 4]);
+        data
+    }
+
+    fn root() -> Vec<u8> {
+        let mut mohd = vec![0u8; 0x40];
+        mohd[4..8].copy_from_slice(&2u32.to_le_bytes());
+        mohd[0x20..0x24].copy_from_slice(&77u32.to_le_bytes());
+        mohd[0x24..0x3C].copy_from_slice(&floats(&[-1.0, -2.0, -3.0, 1.0, 2.0, 3.0]));
+        mohd[0x3C..0x3E].copy_from_slice(&WMO_ROOT_FLAG_LIQUID_TYPE_ID.to_le_bytes());
+
+        let sets = [
+            doodad_set("Set_$DefaultGlobal", 0, 1),
+            doodad_set("Chairs", 1, 2),
+        ];
+        let doodads = [
+            doodad(0, [1.0, 2.0, 3.0], 1.0),
+            doodad(9, [4.0, 5.0, 6.0], 0.5),
+            doodad(9, [7.0, 8.0, 9.0], 2.0),
+        ];
+
+        [
+            chunk(b"MVER", &17u32.to_le_bytes()),
+            chunk(b"MOHD", &mohd),
+            chunk(b"MODS", &sets.concat()),
+            chunk(b"MODN", b"lamp.mdx\0chair.m2\0"),
+            chunk(b"MODD", &doodads.concat()),
+        ]
+        .concat()
+    }
+
+    // Two triangles, only the first collides, and a 1x1 liquid
+    fn group() -> Vec<u8> {
+        let mut header = vec![0u8; 0x44];
+        header[0x08..0x0C].copy_from_slice(&0x2000u32.to_le_bytes());
+        header[0x0C..0x24].copy_from_slice(&floats(&[0.0, 0.0, 0.0, 1.0, 1.0, 1.0]));
+        header[0x34..0x38].copy_from_slice(&13u32.to_le_bytes());
+        header[0x38..0x3C].copy_from_slice(&5u32.to_le_bytes());
+
+        let mut mliq = [2u32, 2, 1, 1].map(u32::to_le_bytes).concat();
+        mliq.extend(floats(&[0.0, 0.0, 0.5]));
+        mliq.extend(0u16.to_le_bytes());
+        for height in [0.5f32, 0.5, 0.6, 0.6] {
+            mliq.extend([0; 4]);
+            mliq.extend(height.to_le_bytes());
+        }
+        mliq.push(0);
+
+        let vertices = floats(&[0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 1.0, 1.0, 1.0]);
+        let indices = [0u16, 1, 2, 1, 3, 2].map(u16::to_le_bytes).concat();
+        header.extend(
+            [
+                chunk(b"MOPY", &[0x08, 0, 0x24, 0]),
+                chunk(b"MOVI", &indices),
+                chunk(b"MOVT", &vertices),
+                chunk(b"MLIQ", &mliq),
+            ]
+            .concat(),
+        );
+
+        [
+            chunk(b"MVER", &17u32.to_le_bytes()),
+            chunk(b"MOGP", &header),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_wmo_root() {
+        let root = WmoRoot::parse(&root()).unwrap();
+        assert_eq!(root.group_count, 2);
+        assert_eq!(root.wmo_id, 77);
+        assert_eq!(root.bounds_min, [-1.0, -2.0, -3.0]);
+        assert_eq!(root.bounds_max, [1.0, 2.0, 3.0]);
+        assert_eq!(root.flags, WMO_ROOT_FLAG_LIQUID_TYPE_ID);
+        assert_eq!(root.doodad_sets[1].name, "Chairs");
+        assert_eq!(root.doodads[0].name, "lamp.mdx");
+        assert_eq!(root.doodads[2].name, "chair.m2");
+        assert_eq!(root.doodads[2].position, [7.0, 8.0, 9.0]);
+        assert_eq!(root.doodads[2].rotation, [0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(root.doodads[2].scale, 2.0);
+
+        // The default set is always included
+        let indexes = |set| root.doodads_in_set(set).map(|(i, _)| i).collect::<Vec<_>>();
+        assert_eq!(indexes(0), [0]);
+        assert_eq!(indexes(1), [0, 1, 2]);
+        assert_eq!(indexes(9), [0]);
+
+        assert!(WmoRoot::parse(&[]).is_err());
+    }
+
+    #[test]
+    fn test_wmo_group() {
+        let group = WmoGroup::parse(&group()).unwrap();
+        assert_eq!(group.flags, 0x2000);
+        assert_eq!(group.group_id, 5);
+        assert_eq!(group.vertices.len(), 4);
+        assert_eq!(group.collision_triangles().collect::<Vec<_>>(), [[0, 1, 2]]);
+
+        let liquid = group.liquid.as_ref().unwrap();
+        assert_eq!((liquid.tiles_x, liquid.tiles_y), (1, 1));
+        assert_eq!(liquid.corner, [0.0, 0.0, 0.5]);
+        assert_eq!(liquid.heights, [0.5, 0.5, 0.6, 0.6]);
+        assert!(liquid.has_tile(0, 0));
+    }
+
+    #[test]
+    fn test_wmo_liquid_type() {
+        let mut group = WmoGroup::parse(&group()).unwrap();
+        assert_eq!(group.liquid_type(WMO_ROOT_FLAG_LIQUID_TYPE_ID), 13);
+
+        // The legacy enumeration is offset by one and 15 means no liquid
+        group.group_liquid = 0;
+        assert_eq!(group.liquid_type(0), 13);
+        group.flags |= WMO_GROUP_FLAG_OCEAN;
+        assert_eq!(group.liquid_type(0), 14);
+        group.group_liquid = 2;
+        assert_eq!(group.liquid_type(0), 19);
+        group.group_liquid = 15;
+        assert_eq!(group.liquid_type(0), 0);
+    }
+
+    #[test]
+    fn test_wmo_group_path() {
+        assert_eq!(wmo_group_path("World\\Inn.wmo", 3), "World\\Inn_003.wmo");
+        assert_eq!(wmo_group_path("World\\Inn.WMO", 12), "World\\Inn_012.wmo");
+    }
+}
@@ -1,6 +1,11 @@
+// Lets code generated by tc-macros refer to tc_core from inside the crate
+extern crate self as tc_core;
+
 pub mod crypto;
 pub mod database;
 pub mod files;
 pub mod math;
+pub mod navmesh;
 pub mod platform;
 pub mod server;
+pub mod vmap;
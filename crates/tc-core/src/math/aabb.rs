@@ -0,0 +1,242 @@
+use crate::math::{Mat3, Vec3};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Default for Aabb {
+    fn default() -> Self {
+        Self::EMPTY
+    }
+}
+
+impl Aabb {
+    // Inverted bounds that become valid as soon as a point is added
+    pub const EMPTY: Self = Self {
+        min: Vec3::MAX,
+        max: Vec3::MIN,
+    };
+
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self { min, max }
+    }
+
+    pub fn from_points(points: impl IntoIterator<Item = Vec3>) -> Self {
+        let mut bounds = Self::EMPTY;
+        for point in points {
+            bounds.add_point(point);
+        }
+
+        bounds
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn add_point(&mut self, point: Vec3) {
+        self.min = self.min.min(point);
+        self.max = self.max.max(point);
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn extent(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+
+        let e = self.extent();
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+
+    pub fn longest_axis(&self) -> usize {
+        let e = self.extent();
+        if e.x >= e.y && e.x >= e.z {
+            0
+        } else if e.y >= e.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    pub fn contains(&self, point: Vec3) -> bool {
+        point.x >= self.min.x
+            && point.x <= self.max.x
+            && point.y >= self.min.y
+            && point.y <= self.max.y
+            && point.z >= self.min.z
+            && point.z <= self.max.z
+    }
+
+    pub fn contains_xy(&self, point: Vec3) -> bool {
+        point.x >= self.min.x
+            && point.x <= self.max.x
+            && point.y >= self.min.y
+            && point.y <= self.max.y
+    }
+
+    pub fn intersects(&self, other: &Self) -> bool {
+        self.min.x <= other.max.x
+            && self.max.x >= other.min.x
+            && self.min.y <= other.max.y
+            && self.max.y >= other.min.y
+            && self.min.z <= other.max.z
+            && self.max.z >= other.min.z
+    }
+
+    pub fn corners(&self) -> [Vec3; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            Vec3::new(a.x, a.y, a.z),
+            Vec3::new(b.x, a.y, a.z),
+            Vec3::new(a.x, b.y, a.z),
+            Vec3::new(b.x, b.y, a.z),
+            Vec3::new(a.x, a.y, b.z),
+            Vec3::new(b.x, a.y, b.z),
+            Vec3::new(a.x, b.y, b.z),
+            Vec3::new(b.x, b.y, b.z),
+        ]
+    }
+
+    // Bounds of this box after rotating, scaling and translating it
+    pub fn transform(&self, rotation: &Mat3, scale: f32, position: Vec3) -> Self {
+        Self::from_points(
+            self.corners()
+                .into_iter()
+                .map(|c| *rotation * (c * scale) + position),
+        )
+    }
+
+    // Slab test returning the entry and exit distance along the ray, where
+    // inv_dir is the per component reciprocal of the ray direction
+    pub fn ray_intersection(&self, origin: Vec3, inv_dir: Vec3, max_t: f32) -> Option<(f32, f32)> {
+        let mut t_min = 0.0f32;
+        let mut t_max = max_t;
+
+        for axis in 0..3 {
+            let t1 = (self.min[axis] - origin[axis]) * inv_dir[axis];
+            let t2 = (self.max[axis] - origin[axis]) * inv_dir[axis];
+
+            // NaN appears when a ray parallel to the slab lies exactly on
+            // one of its planes, treat it as inside so axis aligned rays
+            // still hit
+            if t1.is_nan() || t2.is_nan() {
+                continue;
+            }
+
+            t_min = t_min.max(t1.min(t2));
+            t_max = t_max.min(t1.max(t2));
+
+            if t_min > t_max {
+                return None;
+            }
+        }
+
+        Some((t_min, t_max))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::math::{Aabb, Mat3, Vec3};
+    use std::f32::consts::FRAC_PI_2;
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1e-4
+    }
+
+    #[test]
+    fn test_aabb_from_points() {
+        assert!(Aabb::EMPTY.is_empty());
+        assert!(Aabb::from_points([]).is_empty());
+        assert_eq!(Aabb::EMPTY.surface_area(), 0.0);
+
+        let bounds = Aabb::from_points([
+            Vec3::new(1.0, -2.0, 3.0),
+            Vec3::new(-1.0, 4.0, 0.0),
+            Vec3::new(0.0, 0.0, 5.0),
+        ]);
+        assert_eq!(bounds.min, Vec3::new(-1.0, -2.0, 0.0));
+        assert_eq!(bounds.max, Vec3::new(1.0, 4.0, 5.0));
+        assert_eq!(bounds.center(), Vec3::new(0.0, 1.0, 2.5));
+        assert_eq!(bounds.longest_axis(), 1);
+        assert_eq!(
+            bounds.surface_area(),
+            2.0 * (2.0 * 6.0 + 6.0 * 5.0 + 5.0 * 2.0)
+        );
+
+        let merged = bounds.merge(&Aabb::new(Vec3::splat(2.0), Vec3::splat(3.0)));
+        assert_eq!(merged.max, Vec3::new(3.0, 4.0, 5.0));
+        assert_eq!(Aabb::EMPTY.merge(&bounds), bounds);
+
+        assert!(bounds.contains(Vec3::new(1.0, 4.0, 5.0)));
+        assert!(!bounds.contains(Vec3::new(0.0, 0.0, 6.0)));
+        assert!(bounds.contains_xy(Vec3::new(0.0, 0.0, 6.0)));
+        assert!(bounds.intersects(&merged));
+        assert!(!bounds.intersects(&Aabb::new(Vec3::splat(6.0), Vec3::splat(7.0))));
+    }
+
+    #[test]
+    fn test_aabb_transform() {
+        let bounds = Aabb::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(2.0, 1.0, 1.0));
+
+        // Quarter turn around z, doubled and moved
+        let rotation = Mat3::rotation_z(FRAC_PI_2);
+        let moved = bounds.transform(&rotation, 2.0, Vec3::new(10.0, 0.0, 5.0));
+        assert!(close(moved.min, Vec3::new(8.0, 0.0, 5.0)));
+        assert!(close(moved.max, Vec3::new(10.0, 4.0, 7.0)));
+
+        // An eighth turn grows the bounds to contain the rotated corners
+        let rotation = Mat3::rotation_z(FRAC_PI_2 / 2.0);
+        let unit = Aabb::new(Vec3::splat(-1.0), Vec3::splat(1.0));
+        let turned = unit.transform(&rotation, 1.0, Vec3::ZERO);
+        let half = 2.0f32.sqrt();
+        assert!(close(turned.min, Vec3::new(-half, -half, -1.0)));
+        assert!(close(turned.max, Vec3::new(half, half, 1.0)));
+    }
+
+    #[test]
+    fn test_aabb_ray_intersection() {
+        let bounds = Aabb::new(Vec3::new(2.0, -1.0, -1.0), Vec3::new(4.0, 1.0, 1.0));
+        let along_x = Vec3::new(1.0, 0.0, 0.0).recip();
+
+        assert_eq!(
+            bounds.ray_intersection(Vec3::ZERO, along_x, 10.0),
+            Some((2.0, 4.0))
+        );
+        assert_eq!(bounds.ray_intersection(Vec3::ZERO, along_x, 1.0), None);
+        assert_eq!(
+            bounds.ray_intersection(Vec3::new(0.0, 2.0, 0.0), along_x, 10.0),
+            None
+        );
+
+        // Rays lying on a face still hit
+        assert_eq!(
+            bounds.ray_intersection(Vec3::new(0.0, 1.0, 0.0), along_x, 10.0),
+            Some((2.0, 4.0))
+        );
+
+        // Starting inside enters at 0
+        assert_eq!(
+            bounds.ray_intersection(Vec3::new(3.0, 0.0, 0.0), along_x, 10.0),
+            Some((0.0, 1.0))
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use crate::math::Vec3;
+use std::ops::Mul;
+
+// Row major 3x3 matrix, only ever used for rotations so the inverse is
+// taken as the transpose
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mat3 {
+    pub rows: [[f32; 3]; 3],
+}
+
+impl Default for Mat3 {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl Mat3 {
+    pub const IDENTITY: Self = Self {
+        rows: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+    };
+
+    pub fn rotation_x(angle: f32) -> Self {
+        let (s, c) = angle.sin_cos();
+        Self {
+            rows: [[1.0, 0.0, 0.0], [0.0, c, -s], [0.0, s, c]],
+        }
+    }
+
+    pub fn rotation_y(angle: f32) -> Self {
+        let (s, c) = angle.sin_cos();
+        Self {
+            rows: [[c, 0.0, s], [0.0, 1.0, 0.0], [-s, 0.0, c]],
+        }
+    }
+
+    pub fn rotation_z(angle: f32) -> Self {
+        let (s, c) = angle.sin_cos();
+        Self {
+            rows: [[c, -s, 0.0], [s, c, 0.0], [0.0, 0.0, 1.0]],
+        }
+    }
+
+    // Rz(z) * Ry(y) * Rx(x), angles in radians
+    pub fn from_euler_zyx(z: f32, y: f32, x: f32) -> Self {
+        Self::rotation_z(z) * Self::rotation_y(y) * Self::rotation_x(x)
+    }
+
+    pub fn from_quaternion(x: f32, y: f32, z: f32, w: f32) -> Self {
+        let (xx, yy, zz) = (x * x, y * y, z * z);
+        let (xy, xz, yz) = (x * y, x * z, y * z);
+        let (wx, wy, wz) = (w * x, w * y, w * z);
+
+        Self {
+            rows: [
+                [1.0 - 2.0 * (yy + zz), 2.0 * (xy - wz), 2.0 * (xz + wy)],
+                [2.0 * (xy + wz), 1.0 - 2.0 * (xx + zz), 2.0 * (yz - wx)],
+                [2.0 * (xz - wy), 2.0 * (yz + wx), 1.0 - 2.0 * (xx + yy)],
+            ],
+        }
+    }
+
+    pub fn from_rows(rows: [[f32; 3]; 3]) -> Self {
+        Self { rows }
+    }
+
+    pub fn transpose(&self) -> Self {
+        let r = &self.rows;
+        Self {
+            rows: [
+                [r[0][0], r[1][0], r[2][0]],
+                [r[0][1], r[1][1], r[2][1]],
+                [r[0][2], r[1][2], r[2][2]],
+            ],
+        }
+    }
+
+    pub fn to_array(&self) -> [f32; 9] {
+        let r = &self.rows;
+        [
+            r[0][0], r[0][1], r[0][2], r[1][0], r[1][1], r[1][2], r[2][0], r[2][1], r[2][2],
+        ]
+    }
+
+    pub fn from_array(v: [f32; 9]) -> Self {
+        Self {
+            rows: [[v[0], v[1], v[2]], [v[3], v[4], v[5]], [v[6], v[7], v[8]]],
+        }
+    }
+}
+
+impl Mul<Vec3> for Mat3 {
+    type Output = Vec3;
+
+    fn mul(self, v: Vec3) -> Vec3 {
+        let r = &self.rows;
+        Vec3::new(
+            r[0][0] * v.x + r[0][1] * v.y + r[0][2] * v.z,
+            r[1][0] * v.x + r[1][1] * v.y + r[1][2] * v.z,
+            r[2][0] * v.x + r[2][1] * v.y + r[2][2] * v.z,
+        )
+    }
+}
+
+impl Mul for Mat3 {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        let mut rows = [[0.0; 3]; 3];
+        for (i, row) in rows.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..3).map(|k| self.rows[i][k] * other.rows[k][j]).sum();
+            }
+        }
+
+        Self { rows }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::math::{Mat3, Vec3};
+    use std::f32::consts::FRAC_PI_2;
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1e-5
+    }
+
+    fn close_mat(a: Mat3, b: Mat3) -> bool {
+        a.to_array()
+            .iter()
+            .zip(b.to_array())
+            .all(|(a, b)| (a - b).abs() < 1e-5)
+    }
+
+    #[test]
+    fn test_rotations() {
+        let x = Vec3::new(1.0, 0.0, 0.0);
+        let y = Vec3::new(0.0, 1.0, 0.0);
+        let z = Vec3::new(0.0, 0.0, 1.0);
+
+        assert!(close(Mat3::rotation_z(FRAC_PI_2) * x, y));
+        assert!(close(Mat3::rotation_x(FRAC_PI_2) * y, z));
+        assert!(close(Mat3::rotation_y(FRAC_PI_2) * z, x));
+
+        // Applied right to left, x first
+        let euler = Mat3::from_euler_zyx(FRAC_PI_2, 0.0, FRAC_PI_2);
+        assert!(close(euler * y, z));
+        assert!(close(euler * z, x));
+    }
+
+    #[test]
+    fn test_quaternion_matches_euler() {
+        let (s, c) = (FRAC_PI_2 / 2.0).sin_cos();
+        assert!(close_mat(
+            Mat3::from_quaternion(0.0, 0.0, s, c),
+            Mat3::rotation_z(FRAC_PI_2)
+        ));
+        assert!(close_mat(
+            Mat3::from_quaternion(s, 0.0, 0.0, c),
+            Mat3::rotation_x(FRAC_PI_2)
+        ));
+        assert!(close_mat(
+            Mat3::from_quaternion(0.0, 0.0, 0.0, 1.0),
+            Mat3::IDENTITY
+        ));
+    }
+
+    #[test]
+    fn test_transpose_inverts_rotation() {
+        let rotation = Mat3::from_euler_zyx(0.3, -1.2, 2.0);
+        assert!(close_mat(rotation * rotation.transpose(), Mat3::IDENTITY));
+
+        let v = Vec3::new(1.0, 2.0, 3.0);
+        assert!(close(rotation.transpose() * (rotation * v), v));
+        assert_eq!(Mat3::from_array(rotation.to_array()), rotation);
+    }
+}
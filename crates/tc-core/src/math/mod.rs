@@ -1 +1,6 @@
-
+mod aabb;
+mod matrix;
+mod vector;
+pub use aabb::*;
+pub use matrix::*;
+pub use vector::*;
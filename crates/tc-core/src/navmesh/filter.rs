@@ -0,0 +1,68 @@
+pub const NAV_MAX_AREAS: usize = 8;
+
+// Decides which polygons a query may use and how expensive they are to
+// cross. Costs multiply the distance travelled within an area
+#[derive(Debug, Clone, PartialEq)]
+pub struct NavQueryFilter {
+    area_costs: [f32; NAV_MAX_AREAS],
+    include_flags: u16,
+    exclude_flags: u16,
+}
+
+impl Default for NavQueryFilter {
+    fn default() -> Self {
+        Self {
+            area_costs: [1.0; NAV_MAX_AREAS],
+            include_flags: u16::MAX,
+            exclude_flags: 0,
+        }
+    }
+}
+
+impl NavQueryFilter {
+    pub fn with_area_cost(mut self, area: u8, cost: f32) -> Self {
+        if let Some(value) = self.area_costs.get_mut(area as usize) {
+            *value = cost.max(0.0);
+        }
+
+        self
+    }
+
+    // Polygons of the area are never entered
+    pub fn without_area(self, area: u8) -> Self {
+        self.with_area_cost(area, f32::INFINITY)
+    }
+
+    pub fn with_include_flags(mut self, flags: u16) -> Self {
+        self.include_flags = flags;
+        self
+    }
+
+    pub fn with_exclude_flags(mut self, flags: u16) -> Self {
+        self.exclude_flags = flags;
+        self
+    }
+
+    pub fn area_cost(&self, area: u8) -> f32 {
+        self.area_costs
+            .get(area as usize)
+            .copied()
+            .unwrap_or(f32::INFINITY)
+    }
+
+    pub fn passes(&self, area: u8, flags: u16) -> bool {
+        self.area_cost(area).is_finite()
+            && flags & self.include_flags != 0
+            && flags & self.exclude_flags == 0
+    }
+
+    // Cheapest cost per distance, scales the search heuristic so it never
+    // overestimates
+    pub(crate) fn min_cost(&self) -> f32 {
+        self.area_costs
+            .iter()
+            .copied()
+            .filter(|c| c.is_finite())
+            .fold(1.0, f32::min)
+    }
+}
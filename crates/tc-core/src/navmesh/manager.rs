@@ -0,0 +1,558 @@
+use crate::{
+    math::Vec3,
+    navmesh::{NavLinkKind, NavMap, NavPolyRef, NavQueryFilter, path, tile::closest_on_segment_xy},
+};
+use rand::Rng;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NavPathKind {
+    // Reaches the end point over the navmesh
+    Complete,
+    // Ends at the reachable point closest to the end point
+    Partial,
+    // Cut short at the maximum path length
+    Truncated,
+    // Tiles around the start or end are missing, the path is the direct
+    // line between them
+    Straight,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NavPath {
+    pub points: Vec<Vec3>,
+    pub kind: NavPathKind,
+}
+
+impl NavPath {
+    pub fn length(&self) -> f32 {
+        self.points.windows(2).map(|w| w[0].distance(w[1])).sum()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NavPathOptions {
+    // Half size of the box searched for polygons around the start and end
+    pub extents: Vec3,
+    // Polygons the search may visit before settling for a partial path
+    pub max_nodes: usize,
+    pub max_length: Option<f32>,
+}
+
+impl Default for NavPathOptions {
+    fn default() -> Self {
+        Self {
+            extents: Vec3::new(3.0, 3.0, 5.0),
+            max_nodes: 4096,
+            max_length: None,
+        }
+    }
+}
+
+// Entry point for path queries against generated navmeshes. Maps and their
+// tiles are loaded the first time a query touches them
+pub struct NavMeshManager {
+    directory: PathBuf,
+    maps: RwLock<HashMap<u32, Arc<NavMap>>>,
+}
+
+impl NavMeshManager {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            maps: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn map(&self, map_id: u32) -> Arc<NavMap> {
+        if let Some(map) = self.maps.read().unwrap().get(&map_id) {
+            return Arc::clone(map);
+        }
+
+        let mut maps = self.maps.write().unwrap();
+        Arc::clone(
+            maps.entry(map_id)
+                .or_insert_with(|| Arc::new(NavMap::new(self.directory.clone(), map_id))),
+        )
+    }
+
+    // Loads a tile ahead of the first query, returns whether it exists
+    pub fn load_tile(&self, map_id: u32, x: usize, y: usize) -> bool {
+        self.map(map_id).tile(x, y).is_some()
+    }
+
+    pub fn unload_tile(&self, map_id: u32, x: usize, y: usize) {
+        if let Some(map) = self.maps.read().unwrap().get(&map_id) {
+            map.unload_tile(x, y);
+        }
+    }
+
+    pub fn unload_map(&self, map_id: u32) {
+        self.maps.write().unwrap().remove(&map_id);
+    }
+
+    pub fn loaded_tile_count(&self, map_id: u32) -> usize {
+        self.maps
+            .read()
+            .unwrap()
+            .get(&map_id)
+            .map_or(0, |m| m.loaded_tile_count())
+    }
+
+    // Fraction along the segment where it leaves the navmesh, None when it
+    // can be walked in a straight line
+    pub fn raycast(
+        &self,
+        map_id: u32,
+        start: Vec3,
+        end: Vec3,
+        filter: &NavQueryFilter,
+    ) -> Option<f32> {
+        path::raycast(&self.map(map_id), start, end, filter)
+    }
+
+    pub fn find_nearest_poly(
+        &self,
+        map_id: u32,
+        point: Vec3,
+        extents: Vec3,
+        filter: &NavQueryFilter,
+    ) -> Option<(NavPolyRef, Vec3)> {
+        self.map(map_id).find_nearest_poly(point, extents, filter)
+    }
+
+    // Path from start to end. Returns None when either point is away from
+    // every usable polygon of a loaded tile
+    pub fn find_path(
+        &self,
+        map_id: u32,
+        start: Vec3,
+        end: Vec3,
+        filter: &NavQueryFilter,
+        options: &NavPathOptions,
+    ) -> Option<NavPath> {
+        let map = self.map(map_id);
+        if map.tile_at(start).is_none() || map.tile_at(end).is_none() {
+            let mut points = vec![start, end];
+            let kind = match options.max_length {
+                Some(max) if path::truncate(&mut points, max) => NavPathKind::Truncated,
+                _ => NavPathKind::Straight,
+            };
+
+            return Some(NavPath { points, kind });
+        }
+
+        let start = map.find_nearest_poly(start, options.extents, filter)?;
+        let end = map.find_nearest_poly(end, options.extents, filter)?;
+        let corridor = path::find_corridor(&map, start, end, filter, options.max_nodes)?;
+
+        let (target, kind) = if corridor.complete {
+            (end.1, NavPathKind::Complete)
+        } else {
+            let last = *corridor.polys.last().unwrap();
+            let tile = map.tile(last.tile.0, last.tile.1)?;
+            (tile.closest_point(last.poly, end.1), NavPathKind::Partial)
+        };
+
+        let points = path::string_pull(start.1, target, &corridor.links);
+        let mut points = path::shortcut(&map, &corridor, &points, filter);
+        let kind = match options.max_length {
+            Some(max) if path::truncate(&mut points, max) => NavPathKind::Truncated,
+            _ => kind,
+        };
+
+        Some(NavPath { points, kind })
+    }
+
+    // Random point on the navmesh reachable from center without leaving the
+    // radius. Polygons are picked weighted by their area
+    pub fn random_point_in_radius(
+        &self,
+        map_id: u32,
+        center: Vec3,
+        radius: f32,
+        filter: &NavQueryFilter,
+        rng: &mut impl Rng,
+    ) -> Option<Vec3> {
+        let map = self.map(map_id);
+        let extents = Vec3::new(radius, radius, radius.max(5.0));
+        let (start, start_point) = map.find_nearest_poly(center, extents, filter)?;
+
+        let mut visited = HashSet::from([start]);
+        let mut queue = VecDeque::from([start]);
+        let mut chosen = None;
+        let mut total_area = 0.0;
+
+        while let Some(poly) = queue.pop_front() {
+            let Some(tile) = map.tile(poly.tile.0, poly.tile.1) else {
+                continue;
+            };
+
+            let area = tile.area_xy(poly.poly);
+            total_area += area;
+            if rng.random::<f32>() * total_area <= area {
+                chosen = Some(poly);
+            }
+
+            for link in map.links(&tile, poly.poly) {
+                if visited.contains(&link.to) {
+                    continue;
+                }
+
+                // Off-mesh links never count as staying inside the radius
+                let (_, distance) = closest_on_segment_xy(center, link.right, link.left);
+                let within = link.kind == NavLinkKind::Edge && distance <= radius;
+
+                let passes = map.tile(link.to.tile.0, link.to.tile.1).is_some_and(|t| {
+                    let target = t.poly(link.to.poly);
+                    filter.passes(target.area, target.flags)
+                });
+
+                if within && passes {
+                    visited.insert(link.to);
+                    queue.push_back(link.to);
+                }
+            }
+        }
+
+        let chosen = chosen?;
+        let tile = map.tile(chosen.tile.0, chosen.tile.1)?;
+        let within = |p: Vec3| (p.x - center.x).hypot(p.y - center.y) <= radius;
+
+        // Polygons reaching past the radius are sampled again a few times
+        // before settling for their point closest to the center
+        for _ in 0..16 {
+            let point = tile.random_point(chosen.poly, rng);
+            if within(point) {
+                return Some(point);
+            }
+        }
+
+        let point = tile.closest_point(chosen.poly, center);
+        Some(if within(point) { point } else { start_point })
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use crate::files::map::MAP_TILE_SIZE;
+    use crate::files::navmesh::{
+        NAV_AREA_GROUND, NAV_AREA_WATER, NAV_NEIGHBOR_BORDER, NAV_NEIGHBOR_NONE,
+        NAV_POLY_FLAG_SWIM, NAV_POLY_FLAG_WALK, NAV_SIDE_NEG_X, NAV_SIDE_NEG_Y, NAV_SIDE_POS_X,
+        NAV_SIDE_POS_Y, NavMeshParams, NavMeshTile, NavOffMeshConnection, NavPoly, NavPolyDetail,
+        navmesh_tile_file_name,
+    };
+    use crate::math::Vec3;
+    use crate::navmesh::{NavMeshManager, NavPathKind, NavPathOptions, NavQueryFilter};
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::path::{Path, PathBuf};
+
+    pub const CELL: f32 = MAP_TILE_SIZE / 8.0;
+
+    // Point in cell units from the low corner of tile 32, 32. Tile 33, 32
+    // continues below at negative y
+    pub fn at(x: f32, y: f32) -> Vec3 {
+        Vec3::new(-MAP_TILE_SIZE + x * CELL, -MAP_TILE_SIZE + y * CELL, 0.0)
+    }
+
+    pub fn close(a: Vec3, b: Vec3) -> bool {
+        a.distance(b) < 1e-2
+    }
+
+    // Flat tile of 8x8 square polygons, one per character. Rows run along
+    // y, '.' is ground, 'w' water and '#' left out
+    fn write_tile(
+        directory: &Path,
+        map_id: u32,
+        tile: (usize, usize),
+        rows: [&str; 8],
+        off_mesh_connections: Vec<NavOffMeshConnection>,
+    ) {
+        let offset = (tile.0 - 32) as f32 * -8.0;
+        let cells = rows.map(|r| r.as_bytes());
+        let mut index = [[0u32; 8]; 8];
+        let mut count = 0;
+        for y in 0..8 {
+            for x in 0..8 {
+                if cells[y][x] != b'#' {
+                    count += 1;
+                    index[y][x] = count;
+                }
+            }
+        }
+
+        let vertices = (0..81)
+            .map(|i| at((i % 9) as f32, (i / 9) as f32 + offset))
+            .collect::<Vec<_>>();
+        let neighbor = |x: i32, y: i32, side: u32| {
+            if !(0..8).contains(&x) || !(0..8).contains(&y) {
+                NAV_NEIGHBOR_BORDER | side
+            } else if index[y as usize][x as usize] == 0 {
+                NAV_NEIGHBOR_NONE
+            } else {
+                index[y as usize][x as usize]
+            }
+        };
+
+        let mut polys = Vec::new();
+        for y in 0..8i32 {
+            for x in 0..8i32 {
+                let water = match cells[y as usize][x as usize] {
+                    b'#' => continue,
+                    c => c == b'w',
+                };
+
+                let corner = (y * 9 + x) as u32;
+                polys.push(NavPoly {
+                    vertices: vec![corner, corner + 1, corner + 10, corner + 9],
+                    neighbors: vec![
+                        neighbor(x, y - 1, NAV_SIDE_NEG_Y),
+                        neighbor(x + 1, y, NAV_SIDE_POS_X),
+                        neighbor(x, y + 1, NAV_SIDE_POS_Y),
+                        neighbor(x - 1, y, NAV_SIDE_NEG_X),
+                    ],
+                    area: if water {
+                        NAV_AREA_WATER
+                    } else {
+                        NAV_AREA_GROUND
+                    },
+                    flags: if water {
+                        NAV_POLY_FLAG_SWIM
+                    } else {
+                        NAV_POLY_FLAG_WALK
+                    },
+                });
+            }
+        }
+
+        let data = NavMeshTile {
+            map_id,
+            tile_x: tile.0 as u32,
+            tile_y: tile.1 as u32,
+            params: NavMeshParams {
+                walkable_height: 2.0,
+                walkable_radius: 0.6,
+                walkable_climb: 1.0,
+                cell_size: 0.5,
+                cell_height: 0.25,
+            },
+            vertices,
+            details: vec![NavPolyDetail::default(); polys.len()],
+            polys,
+            off_mesh_connections,
+        };
+
+        std::fs::write(
+            directory.join(navmesh_tile_file_name(map_id, tile.0, tile.1)),
+            data.write(),
+        )
+        .unwrap();
+    }
+
+    // Map 1 has a wall with a gap on the right and a closed off area at the
+    // top reached through an off-mesh link, map 2 is the same without it.
+    // Map 3 is two open tiles next to each other and map 4 mixes in water
+    pub fn setup(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("tc-navmesh-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let walls = [
+            "........", "........", "######..", "........", "........", "########", "........",
+            "........",
+        ];
+        let link = NavOffMeshConnection {
+            start: at(7.5, 4.5),
+            end: at(7.5, 6.5),
+            radius: 1.0,
+            bidirectional: false,
+            area: NAV_AREA_GROUND,
+            flags: NAV_POLY_FLAG_WALK,
+        };
+        write_tile(&directory, 1, (32, 32), walls, vec![link]);
+        write_tile(&directory, 2, (32, 32), walls, Vec::new());
+
+        let open = ["........"; 8];
+        write_tile(&directory, 3, (32, 32), open, Vec::new());
+        write_tile(&directory, 3, (33, 32), open, Vec::new());
+
+        let water = [
+            "........", "........", "www###..", "........", "........", "........", "........",
+            "........",
+        ];
+        write_tile(&directory, 4, (32, 32), water, Vec::new());
+
+        directory
+    }
+
+    #[test]
+    fn test_navmesh_path_around_walls() {
+        let directory = setup("walls");
+        let navmesh = NavMeshManager::new(&directory);
+        let filter = NavQueryFilter::default();
+        let options = NavPathOptions::default();
+
+        let path = navmesh
+            .find_path(1, at(1.5, 0.5), at(1.5, 4.5), &filter, &options)
+            .unwrap();
+        assert_eq!(path.kind, NavPathKind::Complete);
+        assert_eq!(path.points.len(), 4);
+        assert!(close(path.points[1], at(6.0, 2.0)));
+        assert!(close(path.points[2], at(6.0, 3.0)));
+        assert!(close(path.points[3], at(1.5, 4.5)));
+
+        // The closed off area is only reached through the off-mesh link
+        let path = navmesh
+            .find_path(1, at(1.5, 0.5), at(1.5, 6.5), &filter, &options)
+            .unwrap();
+        assert_eq!(path.kind, NavPathKind::Complete);
+        assert!(path.points.iter().any(|p| close(*p, at(7.5, 4.5))));
+        assert!(path.points.iter().any(|p| close(*p, at(7.5, 6.5))));
+
+        let path = navmesh
+            .find_path(2, at(1.5, 0.5), at(1.5, 6.5), &filter, &options)
+            .unwrap();
+        assert_eq!(path.kind, NavPathKind::Partial);
+        assert!((path.points.last().unwrap().y - at(0.0, 5.0).y).abs() < 1e-2);
+
+        // Points inside walls are not on the mesh
+        assert!(
+            navmesh
+                .find_path(1, at(1.5, 2.5), at(1.5, 4.5), &filter, &options)
+                .is_none()
+        );
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_navmesh_path_area_costs() {
+        let directory = setup("costs");
+        let navmesh = NavMeshManager::new(&directory);
+        let options = NavPathOptions::default();
+        let (start, end) = (at(1.5, 0.5), at(1.5, 4.5));
+
+        let path = navmesh
+            .find_path(4, start, end, &NavQueryFilter::default(), &options)
+            .unwrap();
+        assert_eq!(path.points.len(), 2);
+
+        for filter in [
+            NavQueryFilter::default().without_area(NAV_AREA_WATER),
+            NavQueryFilter::default().with_area_cost(NAV_AREA_WATER, 10.0),
+            NavQueryFilter::default().with_exclude_flags(NAV_POLY_FLAG_SWIM),
+        ] {
+            let path = navmesh.find_path(4, start, end, &filter, &options).unwrap();
+            assert_eq!(path.kind, NavPathKind::Complete);
+            assert!(close(path.points[1], at(6.0, 2.0)));
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_navmesh_path_across_tiles() {
+        let directory = setup("tiles");
+        let navmesh = NavMeshManager::new(&directory);
+        let filter = NavQueryFilter::default();
+        let (start, end) = (at(4.0, 0.5), at(4.0, -1.5));
+
+        let path = navmesh
+            .find_path(3, start, end, &filter, &NavPathOptions::default())
+            .unwrap();
+        assert_eq!(path.kind, NavPathKind::Complete);
+        assert_eq!(path.points.len(), 2);
+        assert_eq!(navmesh.loaded_tile_count(3), 2);
+
+        let options = NavPathOptions {
+            max_length: Some(50.0),
+            ..Default::default()
+        };
+        let path = navmesh.find_path(3, start, end, &filter, &options).unwrap();
+        assert_eq!(path.kind, NavPathKind::Truncated);
+        assert!((path.length() - 50.0).abs() < 1e-2);
+
+        // Tiles without navmesh fall back to the direct line
+        let far = at(4.0, -9.5);
+        let path = navmesh
+            .find_path(3, start, far, &filter, &NavPathOptions::default())
+            .unwrap();
+        assert_eq!(path.kind, NavPathKind::Straight);
+        assert_eq!(path.points, vec![start, far]);
+
+        // The direct line is cut at max_length as well
+        let path = navmesh.find_path(3, start, far, &filter, &options).unwrap();
+        assert_eq!(path.kind, NavPathKind::Truncated);
+        assert_eq!(path.points.len(), 2);
+        assert_eq!(path.points[0], start);
+        assert!((path.length() - 50.0).abs() < 1e-2);
+
+        navmesh.unload_tile(3, 33, 32);
+        assert_eq!(navmesh.loaded_tile_count(3), 1);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_navmesh_path_node_limit() {
+        let directory = setup("nodes");
+        let navmesh = NavMeshManager::new(&directory);
+        let filter = NavQueryFilter::default();
+        let (start, end) = (at(0.5, 0.5), at(7.5, 7.5));
+
+        // Too few nodes to get across the tile, the path heads towards the
+        // end as far as the search got
+        let options = NavPathOptions {
+            max_nodes: 6,
+            ..Default::default()
+        };
+        let path = navmesh.find_path(3, start, end, &filter, &options).unwrap();
+        assert_eq!(path.kind, NavPathKind::Partial);
+        let last = *path.points.last().unwrap();
+        assert!(last.distance(end) < start.distance(end) - CELL);
+        assert!(last.distance(end) > CELL);
+
+        let path = navmesh
+            .find_path(3, start, end, &filter, &NavPathOptions::default())
+            .unwrap();
+        assert_eq!(path.kind, NavPathKind::Complete);
+        assert_eq!(path.points, vec![start, end]);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_navmesh_random_point() {
+        let directory = setup("random");
+        let navmesh = NavMeshManager::new(&directory);
+        let filter = NavQueryFilter::default();
+        let center = at(4.0, 4.0);
+
+        let sample = |seed: u64| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..50)
+                .map(|_| {
+                    navmesh
+                        .random_point_in_radius(1, center, 40.0, &filter, &mut rng)
+                        .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let points = sample(7);
+        assert_eq!(points, sample(7));
+        for point in &points {
+            assert!((point.x - center.x).hypot(point.y - center.y) <= 40.0);
+            let (_, nearest) = navmesh
+                .find_nearest_poly(1, *point, Vec3::new(0.1, 0.1, 0.1), &filter)
+                .unwrap();
+            assert!(close(nearest, *point));
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
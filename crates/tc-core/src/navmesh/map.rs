@@ -0,0 +1,422 @@
+use crate::{
+    files::map::world_to_tile,
+    files::navmesh::{
+        NAV_SIDE_NEG_X, NAV_SIDE_POS_X, NavMeshTile, navmesh_neighbor_tile, navmesh_tile_file_name,
+    },
+    math::{Aabb, Vec3},
+    navmesh::{NavQueryFilter, NavTile},
+};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+// Border edges closer than this along their side are not linked
+const MIN_PORTAL_WIDTH: f32 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NavPolyRef {
+    pub tile: (usize, usize),
+    pub poly: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NavLinkKind {
+    // Shared edge between two polygons, crossed anywhere along the portal
+    Edge,
+    // Off-mesh connection entered at the portal and left at end
+    OffMesh { end: Vec3, area: u8 },
+}
+
+// Way from one polygon into another. Left and right are the portal ends
+// as seen when crossing it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NavLink {
+    pub to: NavPolyRef,
+    pub left: Vec3,
+    pub right: Vec3,
+    pub kind: NavLinkKind,
+}
+
+impl NavLink {
+    // Where a path entering the target polygon through this link arrives
+    pub fn entry(&self) -> Vec3 {
+        match self.kind {
+            NavLinkKind::Edge => self.left.lerp(self.right, 0.5),
+            NavLinkKind::OffMesh { end, .. } => end,
+        }
+    }
+}
+
+// Loaded tiles by coordinates, None for tiles without a file
+type TileSlots = HashMap<(usize, usize), Option<Arc<NavTile>>>;
+
+// Navmesh tiles of a single map, loaded the first time a query reaches
+// them. Missing tile files are recorded so they are not looked up again
+pub struct NavMap {
+    map_id: u32,
+    directory: PathBuf,
+    tiles: RwLock<TileSlots>,
+    // Off-mesh links leaving each polygon, resolved when their tile loads
+    off_mesh: RwLock<HashMap<NavPolyRef, Vec<NavLink>>>,
+}
+
+impl NavMap {
+    pub fn new(directory: PathBuf, map_id: u32) -> Self {
+        Self {
+            map_id,
+            directory,
+            tiles: RwLock::new(HashMap::new()),
+            off_mesh: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn map_id(&self) -> u32 {
+        self.map_id
+    }
+
+    pub fn loaded_tile_count(&self) -> usize {
+        self.tiles
+            .read()
+            .unwrap()
+            .values()
+            .filter(|t| t.is_some())
+            .count()
+    }
+
+    pub fn tile(&self, x: usize, y: usize) -> Option<Arc<NavTile>> {
+        if let Some(tile) = self.tiles.read().unwrap().get(&(x, y)) {
+            return tile.clone();
+        }
+
+        // Reading happens outside of the lock, only the thread inserting
+        // the tile resolves its off-mesh connections
+        let tile = match self.read_tile(x, y) {
+            Ok(tile) => tile.map(Arc::new),
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to load navmesh tile {} {} of map {}: {:#}",
+                    x,
+                    y,
+                    self.map_id,
+                    e
+                );
+                None
+            }
+        };
+
+        {
+            let mut tiles = self.tiles.write().unwrap();
+            if let Some(existing) = tiles.get(&(x, y)) {
+                return existing.clone();
+            }
+
+            tiles.insert((x, y), tile.clone());
+        }
+
+        if let Some(tile) = &tile {
+            self.link_off_mesh(tile);
+        }
+
+        tile
+    }
+
+    pub fn tile_at(&self, point: Vec3) -> Option<Arc<NavTile>> {
+        let (x, y) = world_to_tile(point.x, point.y);
+        self.tile(x, y)
+    }
+
+    fn read_tile(&self, x: usize, y: usize) -> Result<Option<NavTile>> {
+        let path = self
+            .directory
+            .join(navmesh_tile_file_name(self.map_id, x, y));
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let data =
+            std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let tile = NavMeshTile::read(&data)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        Ok(Some(NavTile::new(x, y, tile)))
+    }
+
+    pub fn unload_tile(&self, x: usize, y: usize) {
+        self.tiles.write().unwrap().remove(&(x, y));
+
+        let mut off_mesh = self.off_mesh.write().unwrap();
+        off_mesh.retain(|from, _| from.tile != (x, y));
+        for links in off_mesh.values_mut() {
+            links.retain(|l| l.to.tile != (x, y));
+        }
+    }
+
+    fn link_off_mesh(&self, tile: &NavTile) {
+        let filter = NavQueryFilter::default();
+        let mut links = Vec::new();
+
+        for connection in &tile.data.off_mesh_connections {
+            let extents = Vec3::new(
+                connection.radius,
+                connection.radius,
+                tile.data.params.walkable_climb.max(connection.radius),
+            );
+
+            let Some((start, start_point)) =
+                self.find_nearest_in_tile(tile, connection.start, extents, &filter)
+            else {
+                continue;
+            };
+
+            let Some((end, end_point)) = self.find_nearest_poly(connection.end, extents, &filter)
+            else {
+                continue;
+            };
+
+            links.push((
+                start,
+                NavLink {
+                    to: end,
+                    left: start_point,
+                    right: start_point,
+                    kind: NavLinkKind::OffMesh {
+                        end: end_point,
+                        area: connection.area,
+                    },
+                },
+            ));
+
+            if connection.bidirectional {
+                links.push((
+                    end,
+                    NavLink {
+                        to: start,
+                        left: end_point,
+                        right: end_point,
+                        kind: NavLinkKind::OffMesh {
+                            end: start_point,
+                            area: connection.area,
+                        },
+                    },
+                ));
+            }
+        }
+
+        let mut off_mesh = self.off_mesh.write().unwrap();
+        for (from, link) in links {
+            off_mesh.entry(from).or_default().push(link);
+        }
+    }
+
+    fn find_nearest_in_tile(
+        &self,
+        tile: &NavTile,
+        point: Vec3,
+        extents: Vec3,
+        filter: &NavQueryFilter,
+    ) -> Option<(NavPolyRef, Vec3)> {
+        let bounds = Aabb::new(point - extents, point + extents);
+        let mut best: Option<(u32, Vec3, f32)> = None;
+
+        tile.query(&bounds, |poly| {
+            let data = tile.poly(poly);
+            if !filter.passes(data.area, data.flags) {
+                return;
+            }
+
+            let closest = tile.closest_point(poly, point);
+            if !bounds.contains(closest) {
+                return;
+            }
+
+            // Ties go to the lower index so results do not depend on the
+            // order the tree visits polygons in
+            let distance = closest.distance(point);
+            if best.is_none_or(|(p, _, d)| distance < d || (distance == d && poly < p)) {
+                best = Some((poly, closest, distance));
+            }
+        });
+
+        best.map(|(poly, closest, _)| {
+            (
+                NavPolyRef {
+                    tile: (tile.x, tile.y),
+                    poly,
+                },
+                closest,
+            )
+        })
+    }
+
+    // Nearest polygon with a surface point inside the box of the given half
+    // extents around the point
+    pub fn find_nearest_poly(
+        &self,
+        point: Vec3,
+        extents: Vec3,
+        filter: &NavQueryFilter,
+    ) -> Option<(NavPolyRef, Vec3)> {
+        let (ax, ay) = world_to_tile(point.x - extents.x, point.y - extents.y);
+        let (bx, by) = world_to_tile(point.x + extents.x, point.y + extents.y);
+
+        let mut best: Option<(NavPolyRef, Vec3, f32)> = None;
+        for x in ax.min(bx)..=ax.max(bx) {
+            for y in ay.min(by)..=ay.max(by) {
+                let Some(tile) = self.tile(x, y) else {
+                    continue;
+                };
+
+                if let Some((poly, closest)) =
+                    self.find_nearest_in_tile(&tile, point, extents, filter)
+                {
+                    let distance = closest.distance(point);
+                    if best.is_none_or(|(_, _, d)| distance < d) {
+                        best = Some((poly, closest, distance));
+                    }
+                }
+            }
+        }
+
+        best.map(|(poly, closest, _)| (poly, closest))
+    }
+
+    // Usable polygon under the point, preferring the one the direction
+    // towards ahead leads into when the point lies on an edge
+    pub fn poly_at(&self, point: Vec3, ahead: Vec3, filter: &NavQueryFilter) -> Option<NavPolyRef> {
+        let tile = self.tile_at(point)?;
+        let pad = Vec3::new(0.01, 0.01, 0.0);
+        let mut candidates = Vec::new();
+        tile.query(&Aabb::new(point - pad, point + pad), |poly| {
+            let data = tile.poly(poly);
+            if filter.passes(data.area, data.flags) && tile.contains_xy(poly, point) {
+                candidates.push(poly);
+            }
+        });
+
+        candidates.sort_unstable();
+        let poly = candidates
+            .iter()
+            .find(|p| tile.contains_xy(**p, ahead))
+            .or(candidates.first())?;
+
+        Some(NavPolyRef {
+            tile: (tile.x, tile.y),
+            poly: *poly,
+        })
+    }
+
+    // Every way out of a polygon: shared edges within the tile, edges on the
+    // tile border matched against the neighboring tile and off-mesh links
+    pub fn links(&self, tile: &NavTile, poly: u32) -> Vec<NavLink> {
+        let data = tile.poly(poly);
+        let mut links = Vec::new();
+
+        for edge in 0..data.vertices.len() {
+            let (a, b) = tile.edge(poly, edge);
+            if let Some(neighbor) = data.neighbor(edge) {
+                links.push(NavLink {
+                    to: NavPolyRef {
+                        tile: (tile.x, tile.y),
+                        poly: neighbor as u32,
+                    },
+                    left: b,
+                    right: a,
+                    kind: NavLinkKind::Edge,
+                });
+            } else if let Some(side) = data.border_side(edge) {
+                self.border_links(tile, side, a, b, &mut links);
+            }
+        }
+
+        let from = NavPolyRef {
+            tile: (tile.x, tile.y),
+            poly,
+        };
+        if let Some(off_mesh) = self.off_mesh.read().unwrap().get(&from) {
+            links.extend_from_slice(off_mesh);
+        }
+
+        links
+    }
+
+    fn border_links(&self, tile: &NavTile, side: u32, a: Vec3, b: Vec3, links: &mut Vec<NavLink>) {
+        let Some(((nx, ny), back)) = navmesh_neighbor_tile(tile.x, tile.y, side) else {
+            return;
+        };
+
+        let Some(neighbor) = self.tile(nx, ny) else {
+            return;
+        };
+
+        // Edges on the x sides run along y and the other way around
+        let axis = if side == NAV_SIDE_POS_X || side == NAV_SIDE_NEG_X {
+            1
+        } else {
+            0
+        };
+        let climb = tile.data.params.walkable_climb;
+        let pad = Vec3::new(0.1, 0.1, climb);
+        let bounds = Aabb::from_points([a, b]);
+        let bounds = Aabb::new(bounds.min - pad, bounds.max + pad);
+
+        let mut candidates = Vec::new();
+        neighbor.query(&bounds, |poly| candidates.push(poly));
+        candidates.sort_unstable();
+
+        let along = |p: Vec3| p[axis];
+        let at = |from: Vec3, to: Vec3, value: f32| {
+            let span = along(to) - along(from);
+            let t = if span.abs() > 0.0 {
+                (value - along(from)) / span
+            } else {
+                0.0
+            };
+            from.lerp(to, t.clamp(0.0, 1.0))
+        };
+
+        for poly in candidates {
+            let data = neighbor.poly(poly);
+            for edge in 0..data.vertices.len() {
+                if data.border_side(edge) != Some(back) {
+                    continue;
+                }
+
+                let (c, d) = neighbor.edge(poly, edge);
+                let low = along(a).min(along(b)).max(along(c).min(along(d)));
+                let high = along(a).max(along(b)).min(along(c).max(along(d)));
+                if high - low < MIN_PORTAL_WIDTH {
+                    continue;
+                }
+
+                let (ours_low, ours_high) = (at(a, b, low), at(a, b, high));
+                let (theirs_low, theirs_high) = (at(c, d, low), at(c, d, high));
+                if (ours_low.z - theirs_low.z).abs() > climb
+                    || (ours_high.z - theirs_high.z).abs() > climb
+                {
+                    continue;
+                }
+
+                // Left is the end closer to b, the edge runs right to left
+                // when seen from inside the polygon
+                let (left, right) = if along(b) >= along(a) {
+                    (ours_high, ours_low)
+                } else {
+                    (ours_low, ours_high)
+                };
+
+                links.push(NavLink {
+                    to: NavPolyRef {
+                        tile: (nx, ny),
+                        poly,
+                    },
+                    left,
+                    right,
+                    kind: NavLinkKind::Edge,
+                });
+            }
+        }
+    }
+}
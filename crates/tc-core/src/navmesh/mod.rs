@@ -0,0 +1,9 @@
+mod filter;
+mod manager;
+mod map;
+mod path;
+mod tile;
+pub use filter::*;
+pub use manager::*;
+pub use map::*;
+pub use tile::NavTile;
@@ -0,0 +1,538 @@
+use crate::{
+    math::Vec3,
+    navmesh::{
+        NAV_MAX_AREAS, NavLink, NavLinkKind, NavMap, NavPolyRef, NavQueryFilter,
+        tile::{closest_on_segment_xy, cross_xy},
+    },
+};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+};
+
+// Keeps the heuristic slightly below the true distance so ties are broken
+// towards nodes that already came further
+const HEURISTIC_SCALE: f32 = 0.999;
+
+// Polygons a raycast walks through before giving up
+const MAX_RAYCAST_STEPS: usize = 4096;
+
+// Distance used to tell which side of a portal a point lies on
+const RAYCAST_EPSILON: f32 = 1e-3;
+
+struct Node {
+    poly: NavPolyRef,
+    position: Vec3,
+    area: u8,
+    cost: f32,
+    parent: Option<usize>,
+    // Link the node was entered through
+    link: Option<NavLink>,
+    closed: bool,
+}
+
+// Polygons from start to end along with the link entering each of them
+pub struct Corridor {
+    pub polys: Vec<NavPolyRef>,
+    pub links: Vec<NavLink>,
+    pub complete: bool,
+}
+
+// A* over polygons with nodes placed at portal midpoints. When the goal is
+// out of reach or the node budget runs out the corridor ends at the node
+// closest to the goal
+pub fn find_corridor(
+    map: &NavMap,
+    start: (NavPolyRef, Vec3),
+    end: (NavPolyRef, Vec3),
+    filter: &NavQueryFilter,
+    max_nodes: usize,
+) -> Option<Corridor> {
+    let heuristic = |p: Vec3| p.distance(end.1) * filter.min_cost() * HEURISTIC_SCALE;
+    let start_tile = map.tile(start.0.tile.0, start.0.tile.1)?;
+
+    let mut nodes = vec![Node {
+        poly: start.0,
+        position: start.1,
+        area: start_tile.poly(start.0.poly).area,
+        cost: 0.0,
+        parent: None,
+        link: None,
+        closed: false,
+    }];
+    let mut lookup = HashMap::from([(start.0, 0usize)]);
+
+    // Ordered by estimated total cost, then by insertion so equal costs
+    // always expand in the same order
+    let mut open = BinaryHeap::new();
+    let mut sequence = 0u32;
+    open.push(Reverse((heuristic(start.1).to_bits(), sequence, 0usize)));
+
+    let mut best = (0usize, heuristic(start.1));
+    let mut found = None;
+
+    while let Some(Reverse((_, _, index))) = open.pop() {
+        if nodes[index].closed {
+            continue;
+        }
+
+        nodes[index].closed = true;
+        let current = nodes[index].poly;
+        if current == end.0 {
+            found = Some(index);
+            break;
+        }
+
+        let Some(tile) = map.tile(current.tile.0, current.tile.1) else {
+            continue;
+        };
+
+        for link in map.links(&tile, current.poly) {
+            if Some(link.to) == nodes[index].parent.map(|p| nodes[p].poly) {
+                continue;
+            }
+
+            let Some(target_tile) = map.tile(link.to.tile.0, link.to.tile.1) else {
+                continue;
+            };
+
+            let target = target_tile.poly(link.to.poly);
+            if !filter.passes(target.area, target.flags) {
+                continue;
+            }
+
+            let from = &nodes[index];
+            let entry = link.entry();
+            let mut cost = from.cost
+                + match link.kind {
+                    NavLinkKind::Edge => {
+                        from.position.distance(entry) * filter.area_cost(from.area)
+                    }
+                    NavLinkKind::OffMesh { area, .. } => {
+                        from.position.distance(link.left) * filter.area_cost(from.area)
+                            + link.left.distance(entry) * filter.area_cost(area)
+                    }
+                };
+
+            if link.to == end.0 {
+                cost += entry.distance(end.1) * filter.area_cost(target.area);
+            }
+
+            if !cost.is_finite() {
+                continue;
+            }
+
+            let target_index = match lookup.get(&link.to) {
+                Some(existing) if nodes[*existing].cost <= cost => continue,
+                Some(existing) => *existing,
+                None => {
+                    if nodes.len() >= max_nodes {
+                        continue;
+                    }
+
+                    nodes.push(Node {
+                        poly: link.to,
+                        position: entry,
+                        area: target.area,
+                        cost: 0.0,
+                        parent: None,
+                        link: None,
+                        closed: false,
+                    });
+                    lookup.insert(link.to, nodes.len() - 1);
+                    nodes.len() - 1
+                }
+            };
+
+            let node = &mut nodes[target_index];
+            node.position = entry;
+            node.cost = cost;
+            node.parent = Some(index);
+            node.link = Some(link);
+            node.closed = false;
+
+            let estimate = if link.to == end.0 {
+                0.0
+            } else {
+                heuristic(entry)
+            };
+            if estimate < best.1 {
+                best = (target_index, estimate);
+            }
+
+            sequence += 1;
+            open.push(Reverse((
+                (cost + estimate).to_bits(),
+                sequence,
+                target_index,
+            )));
+        }
+    }
+
+    let complete = found.is_some();
+    let mut index = Some(found.unwrap_or(best.0));
+    let mut polys = Vec::new();
+    let mut links = Vec::new();
+    while let Some(i) = index {
+        polys.push(nodes[i].poly);
+        if let Some(link) = nodes[i].link {
+            links.push(link);
+        }
+
+        index = nodes[i].parent;
+    }
+
+    polys.reverse();
+    links.reverse();
+    Some(Corridor {
+        polys,
+        links,
+        complete,
+    })
+}
+
+// Shortest path through a corridor with the funnel algorithm. Off-mesh
+// links split the corridor, the path runs to their start point and
+// continues from their end
+pub fn string_pull(start: Vec3, end: Vec3, links: &[NavLink]) -> Vec<Vec3> {
+    let mut points = vec![start];
+    let mut portals = Vec::new();
+    let mut from = start;
+
+    for link in links {
+        match link.kind {
+            NavLinkKind::Edge => portals.push((link.left, link.right)),
+            NavLinkKind::OffMesh { end, .. } => {
+                funnel(from, link.left, &portals, &mut points);
+                push_point(&mut points, end);
+                portals.clear();
+                from = end;
+            }
+        }
+    }
+
+    funnel(from, end, &portals, &mut points);
+    points
+}
+
+fn push_point(points: &mut Vec<Vec3>, point: Vec3) {
+    if points.last().is_none_or(|last| last.distance(point) > 1e-4) {
+        points.push(point);
+    }
+}
+
+// Appends the corners between start and end through the portals, end
+// included and start left out
+fn funnel(start: Vec3, end: Vec3, portals: &[(Vec3, Vec3)], points: &mut Vec<Vec3>) {
+    let mut all = Vec::with_capacity(portals.len() + 1);
+    all.extend_from_slice(portals);
+    all.push((end, end));
+
+    let (mut apex, mut left, mut right) = (start, start, start);
+    let (mut left_index, mut right_index) = (0usize, 0usize);
+    let mut i = 0;
+
+    while i < all.len() {
+        let (portal_left, portal_right) = all[i];
+
+        // Tighten the right side, crossing the left side makes the left
+        // end a corner of the path
+        if cross_xy(apex, right, portal_right) >= 0.0 {
+            if apex == right || cross_xy(apex, left, portal_right) < 0.0 {
+                right = portal_right;
+                right_index = i;
+            } else {
+                push_point(points, left);
+                apex = left;
+                right = apex;
+                right_index = left_index;
+                i = left_index + 1;
+                continue;
+            }
+        }
+
+        if cross_xy(apex, left, portal_left) <= 0.0 {
+            if apex == left || cross_xy(apex, right, portal_left) > 0.0 {
+                left = portal_left;
+                left_index = i;
+            } else {
+                push_point(points, right);
+                apex = right;
+                left = apex;
+                left_index = right_index;
+                i = right_index + 1;
+                continue;
+            }
+        }
+
+        i += 1;
+    }
+
+    push_point(points, end);
+}
+
+// Cuts a path once it is max_length long, returns whether anything was cut
+pub fn truncate(points: &mut Vec<Vec3>, max_length: f32) -> bool {
+    let mut length = 0.0;
+    for i in 1..points.len() {
+        let segment = points[i - 1].distance(points[i]);
+        if length + segment > max_length {
+            let t = (max_length - length) / segment;
+            points[i] = points[i - 1].lerp(points[i], t);
+            points.truncate(i + 1);
+            return true;
+        }
+
+        length += segment;
+    }
+
+    false
+}
+
+// Walks the mesh from start towards end in the xy plane. Returns None when
+// the segment stays on usable polygons all the way, otherwise the fraction
+// along it where it leaves them
+pub fn raycast(map: &NavMap, start: Vec3, end: Vec3, filter: &NavQueryFilter) -> Option<f32> {
+    let delta = end - start;
+    let length = delta.x.hypot(delta.y);
+    if length < RAYCAST_EPSILON {
+        return None;
+    }
+
+    let step = Vec3::new(delta.x, delta.y, 0.0) * (RAYCAST_EPSILON / length);
+    let Some(mut poly) = map.poly_at(start, start + step, filter) else {
+        return Some(0.0);
+    };
+
+    let mut t = 0.0f32;
+    for _ in 0..MAX_RAYCAST_STEPS {
+        let tile = map.tile(poly.tile.0, poly.tile.1)?;
+        if tile.contains_xy(poly.poly, end) {
+            return None;
+        }
+
+        // Leave through the edge the segment crosses first on its way out
+        let count = tile.poly(poly.poly).vertices.len();
+        let exit = (0..count)
+            .filter_map(|edge| {
+                let (a, b) = tile.edge(poly.poly, edge);
+                let from = cross_xy(a, b, start);
+                let change = cross_xy(a, b, end) - from;
+                (change < 0.0).then_some(-from / change)
+            })
+            .fold(f32::MAX, f32::min);
+
+        if exit == f32::MAX {
+            return None;
+        }
+
+        t = t.max(exit);
+        let point = start + delta * t;
+        let ahead = point + step;
+        let next = map
+            .links(&tile, poly.poly)
+            .into_iter()
+            .filter(|l| {
+                l.kind == NavLinkKind::Edge
+                    && closest_on_segment_xy(point, l.right, l.left).1 < RAYCAST_EPSILON
+            })
+            .filter_map(|l| {
+                let target = map.tile(l.to.tile.0, l.to.tile.1)?;
+                let data = target.poly(l.to.poly);
+                filter
+                    .passes(data.area, data.flags)
+                    .then(|| (l.to, target.contains_xy(l.to.poly, ahead)))
+            })
+            .max_by_key(|(_, ahead)| *ahead);
+
+        match next {
+            Some((to, _)) => poly = to,
+            None => return Some(t),
+        }
+    }
+
+    Some(t)
+}
+
+// Drops corners the path can skip by walking straight, which corridors
+// zigzagging over evenly sized polygons leave behind. Shortcuts stay within
+// the areas the corridor went through so costlier areas it avoided are not
+// crossed after all
+pub fn shortcut(
+    map: &NavMap,
+    corridor: &Corridor,
+    points: &[Vec3],
+    filter: &NavQueryFilter,
+) -> Vec<Vec3> {
+    let mut areas = [false; NAV_MAX_AREAS];
+    for poly in &corridor.polys {
+        if let Some(tile) = map.tile(poly.tile.0, poly.tile.1) {
+            let area = tile.poly(poly.poly).area as usize;
+            if area < NAV_MAX_AREAS {
+                areas[area] = true;
+            }
+        }
+    }
+
+    let filter = (0..NAV_MAX_AREAS)
+        .filter(|area| !areas[*area])
+        .fold(filter.clone(), |f, area| f.without_area(area as u8));
+
+    let mut result = vec![points[0]];
+    let mut i = 0;
+    while i + 1 < points.len() {
+        let next = (i + 2..points.len())
+            .rev()
+            .find(|j| raycast(map, points[i], points[*j], &filter).is_none())
+            .unwrap_or(i + 1);
+
+        result.push(points[next]);
+        i = next;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use crate::math::Vec3;
+    use crate::navmesh::{
+        NavLink, NavLinkKind, NavMap, NavPolyRef, NavQueryFilter,
+        manager::test::{at, close, setup},
+        path::{find_corridor, raycast, shortcut, string_pull, truncate},
+    };
+
+    fn edge(left: Vec3, right: Vec3) -> NavLink {
+        NavLink {
+            to: NavPolyRef {
+                tile: (0, 0),
+                poly: 0,
+            },
+            left,
+            right,
+            kind: NavLinkKind::Edge,
+        }
+    }
+
+    fn nearest(map: &NavMap, point: Vec3) -> (NavPolyRef, Vec3) {
+        map.find_nearest_poly(point, Vec3::new(1.0, 1.0, 1.0), &NavQueryFilter::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_string_pull() {
+        let start = Vec3::new(0.0, 0.0, 0.0);
+
+        // Both portals are wide enough for the direct line
+        let straight = [
+            edge(Vec3::new(1.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.0)),
+            edge(Vec3::new(2.0, 1.0, 0.0), Vec3::new(2.0, -1.0, 0.0)),
+        ];
+        let end = Vec3::new(3.0, 0.5, 0.0);
+        assert_eq!(string_pull(start, end, &straight), [start, end]);
+
+        // Turning left around the corner at 1, 1
+        let corner = [
+            edge(Vec3::new(1.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.0)),
+            edge(Vec3::new(1.0, 1.0, 0.0), Vec3::new(3.0, 1.0, 0.0)),
+        ];
+        let end = Vec3::new(2.0, 5.0, 0.0);
+        assert_eq!(
+            string_pull(start, end, &corner),
+            [start, Vec3::new(1.0, 1.0, 0.0), end]
+        );
+
+        // Off-mesh links are walked to their start and left at their end
+        let jump = NavLink {
+            kind: NavLinkKind::OffMesh {
+                end: Vec3::new(2.0, 4.0, 3.0),
+                area: 1,
+            },
+            ..edge(Vec3::new(2.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0))
+        };
+        assert_eq!(
+            string_pull(start, end, &[jump]),
+            [
+                start,
+                Vec3::new(2.0, 0.0, 0.0),
+                Vec3::new(2.0, 4.0, 3.0),
+                end
+            ]
+        );
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut points = vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(3.0, 0.0, 0.0),
+            Vec3::new(3.0, 4.0, 0.0),
+        ];
+        assert!(!truncate(&mut points.clone(), 7.0));
+
+        assert!(truncate(&mut points, 5.0));
+        assert_eq!(
+            points,
+            [
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(3.0, 0.0, 0.0),
+                Vec3::new(3.0, 2.0, 0.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_corridor() {
+        let directory = setup("corridor");
+        let map = NavMap::new(directory.clone(), 3);
+        let filter = NavQueryFilter::default();
+        let start = nearest(&map, at(0.5, 0.5));
+        let end = nearest(&map, at(3.5, 0.5));
+
+        // One polygon per cell, each entered through its shared edge
+        let corridor = find_corridor(&map, start, end, &filter, 4096).unwrap();
+        assert!(corridor.complete);
+        assert_eq!(corridor.polys.len(), 4);
+        assert_eq!(corridor.links.len(), 3);
+        assert_eq!(corridor.polys[0], start.0);
+        assert_eq!(corridor.polys[3], end.0);
+        assert!(corridor.links.iter().all(|l| l.kind == NavLinkKind::Edge));
+        assert!(close(corridor.links[0].entry(), at(1.0, 0.5)));
+
+        // Out of nodes the corridor ends at the polygon closest to the end
+        let corridor = find_corridor(&map, start, end, &filter, 3).unwrap();
+        assert!(!corridor.complete);
+        assert_eq!(corridor.polys.len(), 2);
+        assert_eq!(corridor.polys[0], start.0);
+        assert_eq!(corridor.links.len(), 1);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_raycast_and_shortcut() {
+        let directory = setup("raycast");
+        let walls = NavMap::new(directory.clone(), 2);
+        let open = NavMap::new(directory.clone(), 3);
+        let filter = NavQueryFilter::default();
+
+        // The wall row starts at y 2, 1.5 cells into the 4 cell segment
+        let (start, end) = (at(1.5, 0.5), at(1.5, 4.5));
+        let hit = raycast(&walls, start, end, &filter).unwrap();
+        assert!((hit - 0.375).abs() < 1e-3);
+        assert_eq!(raycast(&open, start, end, &filter), None);
+        assert_eq!(raycast(&walls, at(6.5, 0.5), at(6.5, 4.5), &filter), None);
+
+        // A zigzag over open ground collapses to its end points
+        let start = nearest(&open, at(0.5, 0.5));
+        let end = nearest(&open, at(4.5, 0.5));
+        let corridor = find_corridor(&open, start, end, &filter, 4096).unwrap();
+        let zigzag = [at(0.5, 0.5), at(2.0, 1.0), at(3.0, 0.2), at(4.5, 0.5)];
+        assert_eq!(
+            shortcut(&open, &corridor, &zigzag, &filter),
+            [zigzag[0], zigzag[3]]
+        );
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{
+    files::navmesh::{NavMeshTile, NavPoly},
+    math::{Aabb, Vec3},
+    vmap::Bvh,
+};
+use rand::Rng;
+
+// Twice the signed area of the triangle in the xy plane, positive when c
+// lies to the left of a to b
+pub(crate) fn cross_xy(a: Vec3, b: Vec3, c: Vec3) -> f32 {
+    (b.x - a.x) * (c.y - a.y) - (c.x - a.x) * (b.y - a.y)
+}
+
+// Closest point to p on the segment a to b in the xy plane with the height
+// interpolated along it, along with the xy distance
+pub(crate) fn closest_on_segment_xy(p: Vec3, a: Vec3, b: Vec3) -> (Vec3, f32) {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    let length = dx * dx + dy * dy;
+    let t = if length > 0.0 {
+        (((p.x - a.x) * dx + (p.y - a.y) * dy) / length).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+
+    let point = a.lerp(b, t);
+    let distance = ((p.x - point.x).powi(2) + (p.y - point.y).powi(2)).sqrt();
+    (point, distance)
+}
+
+// Height of the triangle at x, y when the point lies inside it in the xy
+// plane
+fn triangle_height(a: Vec3, b: Vec3, c: Vec3, x: f32, y: f32) -> Option<f32> {
+    let p = Vec3::new(x, y, 0.0);
+    let area = cross_xy(a, b, c);
+    if area.abs() < 1e-6 {
+        return None;
+    }
+
+    let u = cross_xy(b, c, p) / area;
+    let v = cross_xy(c, a, p) / area;
+    let w = 1.0 - u - v;
+    let epsilon = -1e-4;
+    (u >= epsilon && v >= epsilon && w >= epsilon).then_some(a.z * u + b.z * v + c.z * w)
+}
+
+// Loaded navmesh tile with a tree over its polygons for spatial lookups
+pub struct NavTile {
+    pub x: usize,
+    pub y: usize,
+    pub data: NavMeshTile,
+    poly_bounds: Vec<Aabb>,
+    bvh: Bvh,
+}
+
+impl NavTile {
+    pub fn new(x: usize, y: usize, data: NavMeshTile) -> Self {
+        // Boxes reach a climb above and below so queries for points a step
+        // off the surface still find the polygon
+        let climb = Vec3::new(0.0, 0.0, data.params.walkable_climb);
+        let poly_bounds = data
+            .polys
+            .iter()
+            .zip(&data.details)
+            .map(|(poly, detail)| {
+                let bounds = Aabb::from_points(
+                    poly.vertices
+                        .iter()
+                        .map(|v| data.vertices[*v as usize])
+                        .chain(detail.vertices.iter().copied()),
+                );
+                Aabb::new(bounds.min - climb, bounds.max + climb)
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            x,
+            y,
+            bvh: Bvh::build(&poly_bounds),
+            poly_bounds,
+            data,
+        }
+    }
+
+    pub fn poly_count(&self) -> usize {
+        self.data.polys.len()
+    }
+
+    pub fn poly(&self, poly: u32) -> &NavPoly {
+        &self.data.polys[poly as usize]
+    }
+
+    pub fn poly_bounds(&self, poly: u32) -> &Aabb {
+        &self.poly_bounds[poly as usize]
+    }
+
+    pub fn vertex(&self, poly: u32, index: usize) -> Vec3 {
+        let poly = self.poly(poly);
+        self.data.vertices[poly.vertices[index % poly.vertices.len()] as usize]
+    }
+
+    // Start and end of an edge, walking counter clockwise
+    pub fn edge(&self, poly: u32, edge: usize) -> (Vec3, Vec3) {
+        (self.vertex(poly, edge), self.vertex(poly, edge + 1))
+    }
+
+    pub fn query(&self, bounds: &Aabb, visit: impl FnMut(u32)) {
+        self.bvh.query_aabb(bounds, visit);
+    }
+
+    pub fn contains_xy(&self, poly: u32, point: Vec3) -> bool {
+        let count = self.poly(poly).vertices.len();
+        (0..count).all(|i| {
+            let (a, b) = self.edge(poly, i);
+            cross_xy(a, b, point) >= -1e-4
+        })
+    }
+
+    // Surface height at x, y, taken from the detail mesh when it covers
+    // the point and from the polygon itself otherwise
+    pub fn height_at(&self, poly: u32, x: f32, y: f32) -> f32 {
+        let detail = &self.data.details[poly as usize];
+        for triangle in &detail.triangles {
+            let [a, b, c] = triangle.map(|i| detail.vertices[i as usize]);
+            if let Some(height) = triangle_height(a, b, c, x, y) {
+                return height;
+            }
+        }
+
+        let count = self.poly(poly).vertices.len();
+        let first = self.vertex(poly, 0);
+        for i in 1..count - 1 {
+            let (b, c) = self.edge(poly, i);
+            if let Some(height) = triangle_height(first, b, c, x, y) {
+                return height;
+            }
+        }
+
+        // Outside of the polygon, use the nearest point on its outline
+        (0..count)
+            .map(|i| {
+                let (a, b) = self.edge(poly, i);
+                closest_on_segment_xy(Vec3::new(x, y, 0.0), a, b)
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or(first.z, |(p, _)| p.z)
+    }
+
+    pub fn closest_point(&self, poly: u32, point: Vec3) -> Vec3 {
+        if self.contains_xy(poly, point) {
+            return Vec3::new(point.x, point.y, self.height_at(poly, point.x, point.y));
+        }
+
+        let count = self.poly(poly).vertices.len();
+        let (closest, _) = (0..count)
+            .map(|i| {
+                let (a, b) = self.edge(poly, i);
+                closest_on_segment_xy(point, a, b)
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+
+        Vec3::new(
+            closest.x,
+            closest.y,
+            self.height_at(poly, closest.x, closest.y),
+        )
+    }
+
+    pub fn area_xy(&self, poly: u32) -> f32 {
+        let count = self.poly(poly).vertices.len();
+        let first = self.vertex(poly, 0);
+        (1..count - 1)
+            .map(|i| {
+                let (b, c) = self.edge(poly, i);
+                cross_xy(first, b, c) * 0.5
+            })
+            .sum()
+    }
+
+    // Uniformly distributed point inside a polygon, picking a triangle of
+    // its fan by area first
+    pub fn random_point(&self, poly: u32, rng: &mut impl Rng) -> Vec3 {
+        let count = self.poly(poly).vertices.len();
+        let first = self.vertex(poly, 0);
+        let total = self.area_xy(poly);
+
+        let mut target = rng.random::<f32>() * total;
+        let mut triangle = self.edge(poly, count - 2);
+        for i in 1..count - 1 {
+            let (b, c) = self.edge(poly, i);
+            let area = cross_xy(first, b, c) * 0.5;
+            if target <= area {
+                triangle = (b, c);
+                break;
+            }
+
+            target -= area;
+        }
+
+        let (mut u, mut v) = (rng.random::<f32>(), rng.random::<f32>());
+        if u + v > 1.0 {
+            (u, v) = (1.0 - u, 1.0 - v);
+        }
+
+        let (b, c) = triangle;
+        let point = first + (b - first) * u + (c - first) * v;
+        Vec3::new(point.x, point.y, self.height_at(poly, point.x, point.y))
+    }
+}
@@ -0,0 +1,319 @@
+use crate::math::{Aabb, Vec3};
+
+const LEAF_SIZE: usize = 4;
+
+#[derive(Debug, Clone)]
+struct BvhNode {
+    bounds: Aabb,
+    // Leaves cover items[start..start + count], interior nodes keep their
+    // left child right after themselves and the right child at start
+    start: u32,
+    count: u32,
+}
+
+// Bounding volume hierarchy over a set of boxes, used both for the model
+// instances of a map and for the triangles of a single model
+#[derive(Debug, Clone, Default)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    items: Vec<u32>,
+    // Item boxes in the same order as items, leaves test them one by one
+    // before handing an item to the caller
+    item_bounds: Vec<Aabb>,
+}
+
+impl Bvh {
+    pub fn build(bounds: &[Aabb]) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(bounds.len().max(1) * 2),
+            items: (0..bounds.len() as u32).collect(),
+            item_bounds: Vec::new(),
+        };
+
+        if !bounds.is_empty() {
+            let centers = bounds.iter().map(|b| b.center()).collect::<Vec<_>>();
+            bvh.build_node(bounds, &centers, 0, bounds.len());
+        }
+
+        bvh.item_bounds = bvh.items.iter().map(|i| bounds[*i as usize]).collect();
+        bvh
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::EMPTY, |n| n.bounds)
+    }
+
+    fn build_node(&mut self, bounds: &[Aabb], centers: &[Vec3], start: usize, end: usize) -> usize {
+        let items = &mut self.items[start..end];
+        let node_bounds = items
+            .iter()
+            .fold(Aabb::EMPTY, |acc, i| acc.merge(&bounds[*i as usize]));
+
+        let index = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds: node_bounds,
+            start: start as u32,
+            count: (end - start) as u32,
+        });
+
+        if end - start <= LEAF_SIZE {
+            return index;
+        }
+
+        // Split at the middle of the centers along their widest axis, items
+        // with identical centers fall back to an even split
+        let center_bounds = Aabb::from_points(items.iter().map(|i| centers[*i as usize]));
+        let axis = center_bounds.longest_axis();
+        let split = center_bounds.center()[axis];
+
+        let mut mid = 0;
+        for i in 0..items.len() {
+            if centers[items[i] as usize][axis] < split {
+                items.swap(i, mid);
+                mid += 1;
+            }
+        }
+
+        if mid == 0 || mid == items.len() {
+            items.sort_by(|a, b| centers[*a as usize][axis].total_cmp(&centers[*b as usize][axis]));
+            mid = items.len() / 2;
+        }
+
+        let mid = start + mid;
+        self.build_node(bounds, centers, start, mid);
+        let right = self.build_node(bounds, centers, mid, end);
+
+        self.nodes[index].start = right as u32;
+        self.nodes[index].count = 0;
+        index
+    }
+
+    // Walks every item whose box the ray enters before max_distance. The
+    // callback may shorten max_distance when it finds a hit and returns
+    // whether it did, with stop_at_first the walk ends at the first hit
+    pub fn intersect_ray(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: &mut f32,
+        stop_at_first: bool,
+        mut intersect: impl FnMut(u32, &mut f32) -> bool,
+    ) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let inv_dir = direction.recip();
+        let mut hit = false;
+        let mut stack = vec![0usize];
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if node
+                .bounds
+                .ray_intersection(origin, inv_dir, *max_distance)
+                .is_none()
+            {
+                continue;
+            }
+
+            if node.count > 0 {
+                let range = node.start as usize..(node.start + node.count) as usize;
+                for (item, bounds) in self.items[range.clone()]
+                    .iter()
+                    .zip(&self.item_bounds[range])
+                {
+                    if bounds
+                        .ray_intersection(origin, inv_dir, *max_distance)
+                        .is_none()
+                    {
+                        continue;
+                    }
+
+                    if intersect(*item, max_distance) {
+                        hit = true;
+                        if stop_at_first {
+                            return true;
+                        }
+                    }
+                }
+
+                continue;
+            }
+
+            // Visit the nearer child first so hits shorten the ray early
+            let (left, right) = (index + 1, node.start as usize);
+            let near_left = match (
+                self.nodes[left]
+                    .bounds
+                    .ray_intersection(origin, inv_dir, *max_distance),
+                self.nodes[right]
+                    .bounds
+                    .ray_intersection(origin, inv_dir, *max_distance),
+            ) {
+                (Some((l, _)), Some((r, _))) => l <= r,
+                (Some(_), None) => {
+                    stack.push(left);
+                    continue;
+                }
+                (None, Some(_)) => {
+                    stack.push(right);
+                    continue;
+                }
+                (None, None) => continue,
+            };
+
+            if near_left {
+                stack.push(right);
+                stack.push(left);
+            } else {
+                stack.push(left);
+                stack.push(right);
+            }
+        }
+
+        hit
+    }
+
+    // Calls visit for every item whose box contains the point
+    pub fn query_point(&self, point: Vec3, mut visit: impl FnMut(u32)) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut stack = vec![0usize];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !node.bounds.contains(point) {
+                continue;
+            }
+
+            if node.count > 0 {
+                let range = node.start as usize..(node.start + node.count) as usize;
+                for (item, bounds) in self.items[range.clone()]
+                    .iter()
+                    .zip(&self.item_bounds[range])
+                {
+                    if bounds.contains(point) {
+                        visit(*item);
+                    }
+                }
+            } else {
+                stack.push(index + 1);
+                stack.push(node.start as usize);
+            }
+        }
+    }
+
+    // Calls visit for every item whose box overlaps the given box
+    pub fn query_aabb(&self, bounds: &Aabb, mut visit: impl FnMut(u32)) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut stack = vec![0usize];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !node.bounds.intersects(bounds) {
+                continue;
+            }
+
+            if node.count > 0 {
+                let range = node.start as usize..(node.start + node.count) as usize;
+                for (item, item_bounds) in self.items[range.clone()]
+                    .iter()
+                    .zip(&self.item_bounds[range])
+                {
+                    if item_bounds.intersects(bounds) {
+                        visit(*item);
+                    }
+                }
+            } else {
+                stack.push(index + 1);
+                stack.push(node.start as usize);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::math::{Aabb, Vec3};
+    use crate::vmap::Bvh;
+
+    fn boxes() -> Vec<Aabb> {
+        (0..100)
+            .map(|i| {
+                let min = Vec3::new((i % 10) as f32 * 2.0, (i / 10) as f32 * 2.0, 0.0);
+                Aabb::new(min, min + Vec3::ONE)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bvh_ray_matches_brute_force() {
+        let boxes = boxes();
+        let bvh = Bvh::build(&boxes);
+        let origin = Vec3::new(-1.0, 4.5, 0.5);
+        let direction = Vec3::new(1.0, 0.0, 0.0);
+
+        let mut visited = Vec::new();
+        let mut max_distance = 100.0;
+        bvh.intersect_ray(origin, direction, &mut max_distance, false, |i, _| {
+            visited.push(i);
+            false
+        });
+        visited.sort();
+
+        let expected = (0..boxes.len() as u32)
+            .filter(|i| {
+                boxes[*i as usize]
+                    .ray_intersection(origin, direction.recip(), 100.0)
+                    .is_some()
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(visited, expected);
+        assert_eq!(expected, (20..30).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_bvh_ray_shortens_distance() {
+        let boxes = boxes();
+        let bvh = Bvh::build(&boxes);
+
+        // Reporting a hit at each box entry must end at the nearest box
+        let origin = Vec3::new(-1.0, 4.5, 0.5);
+        let inv_dir = Vec3::new(1.0, 0.0, 0.0).recip();
+        let mut max_distance = 100.0;
+        let hit = bvh.intersect_ray(
+            origin,
+            Vec3::new(1.0, 0.0, 0.0),
+            &mut max_distance,
+            false,
+            |i, max| match boxes[i as usize].ray_intersection(origin, inv_dir, *max) {
+                Some((t, _)) => {
+                    *max = t;
+                    true
+                }
+                None => false,
+            },
+        );
+
+        assert!(hit);
+        assert_eq!(max_distance, 1.0);
+    }
+
+    #[test]
+    fn test_bvh_query_point() {
+        let bvh = Bvh::build(&boxes());
+        let mut found = Vec::new();
+        bvh.query_point(Vec3::new(4.5, 2.5, 0.5), |i| found.push(i));
+
+        assert_eq!(found, vec![12]);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{
+    files::vmap::VmapPlacement,
+    math::{Aabb, Mat3, Vec3},
+    vmap::{GroupModel, WorldModel},
+};
+use std::sync::Arc;
+
+// A placed model. Queries arrive in world space and are moved into the
+// model's space with the inverse placement transform
+pub struct ModelInstance {
+    pub id: u64,
+    pub flags: u16,
+    pub name_set: u16,
+    pub position: Vec3,
+    pub bounds: Aabb,
+    pub model: Arc<WorldModel>,
+    rotation: Mat3,
+    inv_rotation: Mat3,
+    scale: f32,
+    inv_scale: f32,
+}
+
+impl ModelInstance {
+    pub fn new(placement: &VmapPlacement, model: Arc<WorldModel>) -> Self {
+        let scale = if placement.scale > 0.0 {
+            placement.scale
+        } else {
+            1.0
+        };
+
+        Self {
+            id: placement.id,
+            flags: placement.flags,
+            name_set: placement.name_set,
+            position: placement.position,
+            bounds: placement.bounds,
+            model,
+            rotation: placement.rotation,
+            inv_rotation: placement.rotation.transpose(),
+            scale,
+            inv_scale: 1.0 / scale,
+        }
+    }
+
+    pub fn to_model_space(&self, point: Vec3) -> Vec3 {
+        self.inv_rotation * (point - self.position) * self.inv_scale
+    }
+
+    pub fn to_world_space(&self, point: Vec3) -> Vec3 {
+        self.rotation * (point * self.scale) + self.position
+    }
+
+    // Direction is expected to be normalized, distances are in world units
+    pub fn intersect_ray(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: &mut f32,
+        stop_at_first: bool,
+    ) -> bool {
+        let model_origin = self.to_model_space(origin);
+        let model_direction = self.inv_rotation * direction;
+        let mut distance = *max_distance * self.inv_scale;
+
+        if self
+            .model
+            .intersect_ray(model_origin, model_direction, &mut distance, stop_at_first)
+        {
+            *max_distance = distance * self.scale;
+            return true;
+        }
+
+        false
+    }
+
+    // Group containing the point with the distance down to its floor
+    pub fn locate(&self, point: Vec3) -> Option<(&GroupModel, f32)> {
+        if !self.bounds.contains(point) {
+            return None;
+        }
+
+        let down = self.inv_rotation * Vec3::new(0.0, 0.0, -1.0);
+        self.model
+            .locate(self.to_model_space(point), down)
+            .map(|(group, distance)| (group, distance * self.scale))
+    }
+
+    // Liquid type and world space surface height at a point inside group
+    pub fn liquid_level(&self, group: &GroupModel, point: Vec3) -> Option<(u32, f32)> {
+        let model_point = self.to_model_space(point);
+        let (liquid_type, level) = group.liquid_level(model_point)?;
+        let surface = self.to_world_space(Vec3::new(model_point.x, model_point.y, level));
+
+        Some((liquid_type, surface.z))
+    }
+}
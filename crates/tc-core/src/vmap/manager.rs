@@ -0,0 +1,335 @@
+use crate::{
+    files::{map::world_to_tile, wmo::WMO_GROUP_FLAG_INDOOR},
+    math::Vec3,
+    vmap::{MapTree, ModelStore},
+};
+use anyhow::Result;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VmapAreaInfo {
+    // Keys into WMOAreaTable.dbc
+    pub root_id: u32,
+    pub adt_id: u16,
+    pub group_id: u32,
+    pub flags: u32,
+    pub floor_z: f32,
+}
+
+impl VmapAreaInfo {
+    pub fn is_indoor(&self) -> bool {
+        self.flags & WMO_GROUP_FLAG_INDOOR != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VmapLiquidInfo {
+    // LiquidType.dbc id
+    pub liquid_type: u32,
+    pub level: f32,
+    pub floor_z: f32,
+}
+
+// Entry point for collision queries against extracted models. Maps and
+// their tiles are loaded the first time a query touches them, queries on
+// maps without vmap data behave as if nothing is in the way
+pub struct VmapManager {
+    directory: PathBuf,
+    models: ModelStore,
+    maps: RwLock<HashMap<u32, Option<Arc<MapTree>>>>,
+}
+
+impl VmapManager {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        let directory = directory.into();
+        Self {
+            models: ModelStore::new(directory.clone()),
+            directory,
+            maps: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn map(&self, map_id: u32) -> Option<Arc<MapTree>> {
+        if let Some(map) = self.maps.read().unwrap().get(&map_id) {
+            return map.clone();
+        }
+
+        let mut maps = self.maps.write().unwrap();
+        maps.entry(map_id)
+            .or_insert_with(
+                || match MapTree::open(&self.models, &self.directory, map_id) {
+                    Ok(map) => map.map(Arc::new),
+                    Err(e) => {
+                        tracing::warn!("Failed to load vmap for map {}: {:#}", map_id, e);
+                        None
+                    }
+                },
+            )
+            .clone()
+    }
+
+    // Map with every tile between the two points loaded
+    fn map_with_tiles(&self, map_id: u32, a: Vec3, b: Vec3) -> Option<Arc<MapTree>> {
+        let map = self.map(map_id)?;
+        let (ax, ay) = world_to_tile(a.x, a.y);
+        let (bx, by) = world_to_tile(b.x, b.y);
+        let tiles =
+            (ax.min(bx)..=ax.max(bx)).flat_map(|x| (ay.min(by)..=ay.max(by)).map(move |y| (x, y)));
+
+        if let Err(e) = map.load_tiles(&self.models, tiles) {
+            tracing::warn!("Failed to load vmap tiles for map {}: {:#}", map_id, e);
+        }
+
+        Some(map)
+    }
+
+    pub fn load_tile(&self, map_id: u32, x: usize, y: usize) -> Result<()> {
+        match self.map(map_id) {
+            Some(map) => map.load_tiles(&self.models, [(x, y)]),
+            None => Ok(()),
+        }
+    }
+
+    pub fn unload_tile(&self, map_id: u32, x: usize, y: usize) {
+        if let Some(map) = self.maps.read().unwrap().get(&map_id).cloned().flatten() {
+            map.unload_tile(x, y);
+        }
+    }
+
+    pub fn unload_map(&self, map_id: u32) {
+        self.maps.write().unwrap().remove(&map_id);
+    }
+
+    pub fn loaded_model_count(&self) -> usize {
+        self.models.loaded()
+    }
+
+    pub fn is_in_line_of_sight(&self, map_id: u32, a: Vec3, b: Vec3) -> bool {
+        let distance = a.distance(b);
+        if distance < f32::EPSILON {
+            return true;
+        }
+
+        let Some(map) = self.map_with_tiles(map_id, a, b) else {
+            return true;
+        };
+
+        let mut max_distance = distance;
+        !map.intersect_ray(a, (b - a) / distance, &mut max_distance, true)
+    }
+
+    // First model surface at or below z, searching at most max_search down
+    pub fn get_height(&self, map_id: u32, x: f32, y: f32, z: f32, max_search: f32) -> Option<f32> {
+        let origin = Vec3::new(x, y, z);
+        let map = self.map_with_tiles(map_id, origin, origin)?;
+
+        let mut distance = max_search;
+        map.intersect_ray(origin, Vec3::new(0.0, 0.0, -1.0), &mut distance, false)
+            .then_some(z - distance)
+    }
+
+    // Point along the segment from a to b just before the first model
+    // surface, or b itself when nothing is in the way
+    pub fn get_obstacle_hit(&self, map_id: u32, a: Vec3, b: Vec3, offset: f32) -> Vec3 {
+        let distance = a.distance(b);
+        let Some(map) = self
+            .map_with_tiles(map_id, a, b)
+            .filter(|_| distance >= f32::EPSILON)
+        else {
+            return b;
+        };
+
+        let direction = (b - a) / distance;
+        let mut max_distance = distance;
+        if map.intersect_ray(a, direction, &mut max_distance, false) {
+            a + direction * (max_distance - offset).max(0.0)
+        } else {
+            b
+        }
+    }
+
+    pub fn get_area_info(&self, map_id: u32, x: f32, y: f32, z: f32) -> Option<VmapAreaInfo> {
+        let point = Vec3::new(x, y, z);
+        let map = self.map_with_tiles(map_id, point, point)?;
+
+        map.locate(point, |instance, group, distance| VmapAreaInfo {
+            root_id: instance.model.root_id,
+            adt_id: instance.name_set,
+            group_id: group.group_id,
+            flags: group.flags,
+            floor_z: z - distance,
+        })
+    }
+
+    pub fn get_liquid_level(&self, map_id: u32, x: f32, y: f32, z: f32) -> Option<VmapLiquidInfo> {
+        let point = Vec3::new(x, y, z);
+        let map = self.map_with_tiles(map_id, point, point)?;
+
+        map.locate(point, |instance, group, distance| {
+            instance
+                .liquid_level(group, point)
+                .map(|(liquid_type, level)| VmapLiquidInfo {
+                    liquid_type,
+                    level,
+                    floor_z: z - distance,
+                })
+        })
+        .flatten()
+    }
+
+    pub fn is_indoor(&self, map_id: u32, x: f32, y: f32, z: f32) -> bool {
+        self.get_area_info(map_id, x, y, z)
+            .is_some_and(|info| info.is_indoor())
+    }
+
+    pub fn is_outdoor(&self, map_id: u32, x: f32, y: f32, z: f32) -> bool {
+        !self.is_indoor(map_id, x, y, z)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::files::vmap::{
+        VmapGroup, VmapLiquid, VmapModel, VmapModelKind, VmapPlacement, VmapPlacementSet,
+        vmap_map_file_name, vmap_tile_file_name,
+    };
+    use crate::math::{Aabb, Mat3, Vec3};
+    use crate::vmap::VmapManager;
+    use std::path::PathBuf;
+
+    // A 10x10 room with its floor at z 0, a wall across x 5 and a pool of
+    // water at height 2 covering the first two liquid tiles
+    fn room() -> VmapModel {
+        let vertices = vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(10.0, 0.0, 0.0),
+            Vec3::new(10.0, 10.0, 0.0),
+            Vec3::new(0.0, 10.0, 0.0),
+            Vec3::new(5.0, 0.0, 0.0),
+            Vec3::new(5.0, 10.0, 0.0),
+            Vec3::new(5.0, 10.0, 10.0),
+            Vec3::new(5.0, 0.0, 10.0),
+        ];
+        let bounds = Aabb::from_points(vertices.iter().copied());
+
+        VmapModel {
+            kind: VmapModelKind::Wmo,
+            flags: 0,
+            root_id: 42,
+            bounds,
+            groups: vec![VmapGroup {
+                flags: 0x2000,
+                group_id: 7,
+                bounds,
+                vertices,
+                triangles: vec![[0, 1, 2], [0, 2, 3], [4, 5, 6], [4, 6, 7]],
+                liquid: Some(VmapLiquid {
+                    liquid_type: 13,
+                    tiles_x: 1,
+                    tiles_y: 2,
+                    corner: Vec3::ZERO,
+                    heights: vec![2.0; 6],
+                    flags: vec![0, 0],
+                }),
+            }],
+        }
+    }
+
+    fn setup(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("tc-vmap-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        std::fs::write(directory.join("room.vmo"), room().write()).unwrap();
+        std::fs::write(
+            directory.join(vmap_map_file_name(1)),
+            VmapPlacementSet::default().write(),
+        )
+        .unwrap();
+
+        let position = Vec3::new(100.0, 100.0, 50.0);
+        let placement = VmapPlacement {
+            id: 1,
+            kind: VmapModelKind::Wmo,
+            flags: 0,
+            name_set: 3,
+            model: "room.vmo".to_string(),
+            position,
+            rotation: Mat3::IDENTITY,
+            scale: 1.0,
+            bounds: room().bounds.transform(&Mat3::IDENTITY, 1.0, position),
+        };
+
+        std::fs::write(
+            directory.join(vmap_tile_file_name(1, 31, 31)),
+            VmapPlacementSet {
+                placements: vec![placement],
+            }
+            .write(),
+        )
+        .unwrap();
+
+        directory
+    }
+
+    #[test]
+    fn test_vmap_line_of_sight() {
+        let directory = setup("los");
+        let vmaps = VmapManager::new(&directory);
+
+        let a = Vec3::new(101.0, 102.0, 51.0);
+        assert!(!vmaps.is_in_line_of_sight(1, a, Vec3::new(109.0, 102.0, 51.0)));
+        assert!(vmaps.is_in_line_of_sight(1, a, Vec3::new(104.0, 102.0, 51.0)));
+        assert!(vmaps.is_in_line_of_sight(1, a, Vec3::new(109.0, 102.0, 71.0)));
+        assert_eq!(vmaps.loaded_model_count(), 1);
+
+        // Maps that were never extracted never block
+        assert!(vmaps.is_in_line_of_sight(2, a, Vec3::new(109.0, 102.0, 51.0)));
+
+        let hit = vmaps.get_obstacle_hit(1, a, Vec3::new(109.0, 102.0, 51.0), 0.5);
+        assert!((hit - Vec3::new(104.5, 102.0, 51.0)).length() < 1e-4);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_vmap_height() {
+        let directory = setup("height");
+        let vmaps = VmapManager::new(&directory);
+
+        let height = vmaps.get_height(1, 102.0, 102.0, 55.0, 10.0).unwrap();
+        assert!((height - 50.0).abs() < 1e-4);
+
+        assert_eq!(vmaps.get_height(1, 102.0, 102.0, 55.0, 2.0), None);
+        assert_eq!(vmaps.get_height(1, 120.0, 102.0, 55.0, 10.0), None);
+        assert_eq!(vmaps.get_height(2, 102.0, 102.0, 55.0, 10.0), None);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_vmap_area_and_liquid() {
+        let directory = setup("area");
+        let vmaps = VmapManager::new(&directory);
+
+        let info = vmaps.get_area_info(1, 102.0, 102.0, 51.0).unwrap();
+        assert_eq!((info.root_id, info.adt_id, info.group_id), (42, 3, 7));
+        assert!((info.floor_z - 50.0).abs() < 1e-4);
+        assert!(vmaps.is_indoor(1, 102.0, 102.0, 51.0));
+        assert!(vmaps.is_outdoor(1, 120.0, 102.0, 51.0));
+
+        let liquid = vmaps.get_liquid_level(1, 102.0, 102.0, 51.0).unwrap();
+        assert_eq!(liquid.liquid_type, 13);
+        assert!((liquid.level - 52.0).abs() < 1e-4);
+        assert_eq!(vmaps.get_liquid_level(1, 106.0, 102.0, 51.0), None);
+
+        vmaps.unload_tile(1, 31, 31);
+        assert_eq!(vmaps.loaded_model_count(), 0);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
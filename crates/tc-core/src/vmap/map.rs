@@ -0,0 +1,217 @@
+use crate::{
+    files::vmap::{VmapModelKind, VmapPlacementSet, vmap_map_file_name, vmap_tile_file_name},
+    math::{Aabb, Vec3},
+    vmap::{Bvh, GroupModel, ModelInstance, ModelStore},
+};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+#[derive(Default)]
+struct MapTreeState {
+    // Placement ids referenced by each loaded tile, None marks the map's
+    // global placements
+    tiles: HashMap<Option<(usize, usize)>, Vec<u64>>,
+    // Instances with the number of loaded tiles referencing them
+    instances: HashMap<u64, (usize, Arc<ModelInstance>)>,
+    tree: Vec<Arc<ModelInstance>>,
+    bvh: Bvh,
+}
+
+impl MapTreeState {
+    fn rebuild(&mut self) {
+        self.tree = self
+            .instances
+            .values()
+            .map(|(_, i)| Arc::clone(i))
+            .collect();
+        let bounds = self.tree.iter().map(|i| i.bounds).collect::<Vec<_>>();
+        self.bvh = Bvh::build(&bounds);
+    }
+}
+
+// Model instances of a single map, loaded one tile at a time. Placements
+// spanning several tiles are listed by each of them and shared by id
+pub struct MapTree {
+    map_id: u32,
+    directory: PathBuf,
+    state: RwLock<MapTreeState>,
+}
+
+impl MapTree {
+    // Returns None when the map was never extracted
+    pub fn open(models: &ModelStore, directory: &Path, map_id: u32) -> Result<Option<Self>> {
+        let path = directory.join(vmap_map_file_name(map_id));
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let tree = Self {
+            map_id,
+            directory: directory.to_path_buf(),
+            state: RwLock::new(MapTreeState::default()),
+        };
+
+        {
+            let mut state = tree.state.write().unwrap();
+            tree.load_placements(&mut state, models, None, &path)?;
+            state.rebuild();
+        }
+
+        Ok(Some(tree))
+    }
+
+    pub fn map_id(&self) -> u32 {
+        self.map_id
+    }
+
+    pub fn instance_count(&self) -> usize {
+        self.state.read().unwrap().instances.len()
+    }
+
+    pub fn is_tile_loaded(&self, x: usize, y: usize) -> bool {
+        self.state.read().unwrap().tiles.contains_key(&Some((x, y)))
+    }
+
+    // Loads every tile in the list that is not loaded yet. Tiles without a
+    // file are recorded as empty so they are not looked up again
+    pub fn load_tiles(
+        &self,
+        models: &ModelStore,
+        tiles: impl IntoIterator<Item = (usize, usize)>,
+    ) -> Result<()> {
+        let missing = {
+            let state = self.state.read().unwrap();
+            tiles
+                .into_iter()
+                .filter(|t| !state.tiles.contains_key(&Some(*t)))
+                .collect::<Vec<_>>()
+        };
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let mut state = self.state.write().unwrap();
+        let mut result = Ok(());
+        for (x, y) in missing {
+            if state.tiles.contains_key(&Some((x, y))) {
+                continue;
+            }
+
+            let path = self.directory.join(vmap_tile_file_name(self.map_id, x, y));
+            if !path.exists() {
+                state.tiles.insert(Some((x, y)), Vec::new());
+                continue;
+            }
+
+            if let Err(e) = self.load_placements(&mut state, models, Some((x, y)), &path) {
+                state.tiles.insert(Some((x, y)), Vec::new());
+                result = Err(e);
+            }
+        }
+
+        state.rebuild();
+        result
+    }
+
+    pub fn unload_tile(&self, x: usize, y: usize) {
+        let mut state = self.state.write().unwrap();
+        let Some(ids) = state.tiles.remove(&Some((x, y))) else {
+            return;
+        };
+
+        for id in ids {
+            if let Some((references, _)) = state.instances.get_mut(&id) {
+                *references -= 1;
+                if *references == 0 {
+                    state.instances.remove(&id);
+                }
+            }
+        }
+
+        state.rebuild();
+    }
+
+    fn load_placements(
+        &self,
+        state: &mut MapTreeState,
+        models: &ModelStore,
+        tile: Option<(usize, usize)>,
+        path: &Path,
+    ) -> Result<()> {
+        let data =
+            std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let set = VmapPlacementSet::read(&data)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        let mut ids = Vec::with_capacity(set.placements.len());
+        for placement in &set.placements {
+            if let Some((references, _)) = state.instances.get_mut(&placement.id) {
+                *references += 1;
+                ids.push(placement.id);
+                continue;
+            }
+
+            match models.get(&placement.model) {
+                Ok(model) => {
+                    let instance = Arc::new(ModelInstance::new(placement, model));
+                    state.instances.insert(placement.id, (1, instance));
+                    ids.push(placement.id);
+                }
+                Err(e) => tracing::warn!("Skipping placement {}: {:#}", placement.id, e),
+            }
+        }
+
+        state.tiles.insert(tile, ids);
+        Ok(())
+    }
+
+    pub fn intersect_ray(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: &mut f32,
+        stop_at_first: bool,
+    ) -> bool {
+        let state = self.state.read().unwrap();
+        state
+            .bvh
+            .intersect_ray(origin, direction, max_distance, stop_at_first, |i, max| {
+                state.tree[i as usize].intersect_ray(origin, direction, max, stop_at_first)
+            })
+    }
+
+    // Finds the WMO group with the nearest floor below the point and hands
+    // it to f along with its instance and the distance down to the floor
+    pub fn locate<R>(
+        &self,
+        point: Vec3,
+        f: impl FnOnce(&ModelInstance, &GroupModel, f32) -> R,
+    ) -> Option<R> {
+        let state = self.state.read().unwrap();
+        let mut best: Option<(&ModelInstance, &GroupModel, f32)> = None;
+
+        state.bvh.query_point(point, |i| {
+            let instance = &state.tree[i as usize];
+            if instance.model.kind != VmapModelKind::Wmo {
+                return;
+            }
+
+            if let Some((group, distance)) = instance.locate(point)
+                && best.is_none_or(|(_, _, d)| distance < d)
+            {
+                best = Some((instance, group, distance));
+            }
+        });
+
+        best.map(|(instance, group, distance)| f(instance, group, distance))
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.state.read().unwrap().bvh.bounds()
+    }
+}
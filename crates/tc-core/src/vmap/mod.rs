@@ -0,0 +1,10 @@
+mod bvh;
+mod instance;
+mod manager;
+mod map;
+mod model;
+pub use bvh::*;
+pub use instance::*;
+pub use manager::*;
+pub use map::*;
+pub use model::*;
@@ -0,0 +1,252 @@
+use crate::{
+    files::vmap::{VMAP_LIQUID_TILE_SIZE, VmapLiquid, VmapModel, VmapModelKind},
+    math::{Aabb, Vec3},
+    vmap::Bvh,
+};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+};
+
+// Two sided Möller-Trumbore test returning the distance along the ray
+pub(crate) fn intersect_triangle(
+    origin: Vec3,
+    direction: Vec3,
+    v0: Vec3,
+    v1: Vec3,
+    v2: Vec3,
+) -> Option<f32> {
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+    let p = direction.cross(edge2);
+    let det = edge1.dot(p);
+    if det.abs() < 1e-8 {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    let s = origin - v0;
+    let u = s.dot(p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = s.cross(edge1);
+    let v = direction.dot(q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = edge2.dot(q) * inv_det;
+    (t > 0.0).then_some(t)
+}
+
+pub struct GroupModel {
+    pub flags: u32,
+    pub group_id: u32,
+    pub bounds: Aabb,
+    vertices: Vec<Vec3>,
+    triangles: Vec<[u32; 3]>,
+    bvh: Bvh,
+    liquid: Option<VmapLiquid>,
+}
+
+impl GroupModel {
+    fn new(group: crate::files::vmap::VmapGroup) -> Self {
+        let triangle_bounds = group
+            .triangles
+            .iter()
+            .map(|t| Aabb::from_points(t.iter().map(|i| group.vertices[*i as usize])))
+            .collect::<Vec<_>>();
+
+        Self {
+            flags: group.flags,
+            group_id: group.group_id,
+            bounds: group.bounds,
+            bvh: Bvh::build(&triangle_bounds),
+            vertices: group.vertices,
+            triangles: group.triangles,
+            liquid: group.liquid,
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    pub fn intersect_ray(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: &mut f32,
+        stop_at_first: bool,
+    ) -> bool {
+        self.bvh
+            .intersect_ray(origin, direction, max_distance, stop_at_first, |i, max| {
+                let [a, b, c] = self.triangles[i as usize];
+                match intersect_triangle(
+                    origin,
+                    direction,
+                    self.vertices[a as usize],
+                    self.vertices[b as usize],
+                    self.vertices[c as usize],
+                ) {
+                    Some(t) if t < *max => {
+                        *max = t;
+                        true
+                    }
+                    _ => false,
+                }
+            })
+    }
+
+    // Distance to the floor below a point inside the group, the ray starts
+    // slightly above so points resting on the floor still find it
+    pub fn floor_distance(&self, point: Vec3, down: Vec3) -> Option<f32> {
+        if self.triangles.is_empty() || !self.bounds.contains(point) {
+            return None;
+        }
+
+        let mut distance = f32::INFINITY;
+        let origin = point - down * 0.1;
+        self.intersect_ray(origin, down, &mut distance, false)
+            .then_some(distance - 0.1)
+    }
+
+    // Liquid type and surface height at a model space position
+    pub fn liquid_level(&self, point: Vec3) -> Option<(u32, f32)> {
+        let liquid = self.liquid.as_ref()?;
+        let tx = (point.x - liquid.corner.x) / VMAP_LIQUID_TILE_SIZE;
+        let ty = (point.y - liquid.corner.y) / VMAP_LIQUID_TILE_SIZE;
+        if tx < 0.0 || ty < 0.0 || tx >= liquid.tiles_x as f32 || ty >= liquid.tiles_y as f32 {
+            return None;
+        }
+
+        let (x, y) = (tx as u32, ty as u32);
+        if !liquid.has_tile(x, y) {
+            return None;
+        }
+
+        let (fx, fy) = (tx.fract(), ty.fract());
+        let top = liquid.height(x, y) * (1.0 - fx) + liquid.height(x + 1, y) * fx;
+        let bottom = liquid.height(x, y + 1) * (1.0 - fx) + liquid.height(x + 1, y + 1) * fx;
+
+        Some((liquid.liquid_type, top * (1.0 - fy) + bottom * fy))
+    }
+}
+
+// Collision model in model space, shared between every instance placing it
+pub struct WorldModel {
+    pub kind: VmapModelKind,
+    pub flags: u32,
+    pub root_id: u32,
+    pub bounds: Aabb,
+    pub groups: Vec<GroupModel>,
+    bvh: Bvh,
+}
+
+impl WorldModel {
+    pub fn new(model: VmapModel) -> Self {
+        let groups = model
+            .groups
+            .into_iter()
+            .map(GroupModel::new)
+            .collect::<Vec<_>>();
+
+        let group_bounds = groups.iter().map(|g| g.bounds).collect::<Vec<_>>();
+        Self {
+            kind: model.kind,
+            flags: model.flags,
+            root_id: model.root_id,
+            bounds: model.bounds,
+            bvh: Bvh::build(&group_bounds),
+            groups,
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.groups.iter().map(|g| g.triangle_count()).sum()
+    }
+
+    pub fn intersect_ray(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: &mut f32,
+        stop_at_first: bool,
+    ) -> bool {
+        self.bvh
+            .intersect_ray(origin, direction, max_distance, stop_at_first, |i, max| {
+                self.groups[i as usize].intersect_ray(origin, direction, max, stop_at_first)
+            })
+    }
+
+    // Group with the nearest floor below the point along with the distance
+    pub fn locate(&self, point: Vec3, down: Vec3) -> Option<(&GroupModel, f32)> {
+        let mut best: Option<(&GroupModel, f32)> = None;
+        self.bvh.query_point(point, |i| {
+            let group = &self.groups[i as usize];
+            if let Some(distance) = group.floor_distance(point, down)
+                && best.is_none_or(|(_, d)| distance < d)
+            {
+                best = Some((group, distance));
+            }
+        });
+
+        best
+    }
+}
+
+// Loaded models keyed by file name. Only weak references are kept so a
+// model is freed once the last tile placing it unloads
+pub struct ModelStore {
+    directory: PathBuf,
+    models: Mutex<HashMap<String, Weak<WorldModel>>>,
+}
+
+impl ModelStore {
+    pub fn new(directory: PathBuf) -> Self {
+        Self {
+            directory,
+            models: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<WorldModel>> {
+        if let Some(model) = self
+            .models
+            .lock()
+            .unwrap()
+            .get(name)
+            .and_then(Weak::upgrade)
+        {
+            return Ok(model);
+        }
+
+        // Parsing happens outside of the lock, two threads racing on the
+        // same model both load it and the last one is kept
+        let path = self.directory.join(name);
+        let data =
+            std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let model = Arc::new(WorldModel::new(
+            VmapModel::read(&data).with_context(|| format!("Failed to parse {}", name))?,
+        ));
+
+        let mut models = self.models.lock().unwrap();
+        models.retain(|_, m| m.strong_count() > 0);
+        models.insert(name.to_string(), Arc::downgrade(&model));
+
+        Ok(model)
+    }
+
+    pub fn loaded(&self) -> usize {
+        self.models
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|m| m.strong_count() > 0)
+            .count()
+    }
+}
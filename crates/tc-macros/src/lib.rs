@@ -0,0 +1,134 @@
+mod migrations;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{Data, DeriveInput, Field, Fields, LitStr, parse_macro_input, spanned::Spanned};
+
+// Field options given through #[row(...)]
+#[derive(Default)]
+struct RowAttributes {
+    rename: Option<String>,
+    optional: bool,
+    flatten: bool,
+}
+
+impl RowAttributes {
+    fn parse(field: &Field) -> syn::Result<Self> {
+        let mut attributes = Self::default();
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("row")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    attributes.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("optional") {
+                    attributes.optional = true;
+                } else if meta.path.is_ident("flatten") {
+                    attributes.flatten = true;
+                } else {
+                    return Err(meta.error("expected `rename`, `optional` or `flatten`"));
+                }
+
+                Ok(())
+            })?;
+        }
+
+        if attributes.flatten && (attributes.rename.is_some() || attributes.optional) {
+            return Err(syn::Error::new(
+                field.span(),
+                "`flatten` cannot be combined with `rename` or `optional`",
+            ));
+        }
+
+        Ok(attributes)
+    }
+}
+
+// Maps the columns of a row onto the fields of a struct. Named fields read
+// the column of the same name and tuple fields the column at their position
+#[proc_macro_derive(FromRow, attributes(row))]
+pub fn derive_from_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_from_row(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+// Embeds the *.sql files of a directory, relative to the crate root, into
+// the binary. Evaluates to Result<MigrationRegistry>
+#[proc_macro]
+pub fn embed_migrations(input: TokenStream) -> TokenStream {
+    let dir = parse_macro_input!(input as LitStr);
+    migrations::expand_embed_migrations(dir)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_from_row(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new(
+            input.span(),
+            "FromRow can only be derived for structs",
+        ));
+    };
+
+    let body = match &data.fields {
+        Fields::Named(fields) => {
+            let values = fields
+                .named
+                .iter()
+                .map(|field| {
+                    let ident = field.ident.as_ref().unwrap();
+                    let name = ident.to_string();
+                    let column = quote!(#name);
+                    let value = field_value(field, column)?;
+                    Ok(quote!(#ident: #value))
+                })
+                .collect::<syn::Result<Vec<_>>>()?;
+
+            quote!(Self { #(#values),* })
+        }
+        Fields::Unnamed(fields) => {
+            let values = fields
+                .unnamed
+                .iter()
+                .enumerate()
+                .map(|(index, field)| field_value(field, quote!(#index)))
+                .collect::<syn::Result<Vec<_>>>()?;
+
+            quote!(Self(#(#values),*))
+        }
+        Fields::Unit => quote!(Self),
+    };
+
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::tc_core::database::FromRow for #name #type_generics #where_clause {
+            fn from_row(
+                row: &::tc_core::database::Row,
+            ) -> ::tc_core::database::Result<Self> {
+                Ok(#body)
+            }
+        }
+    })
+}
+
+fn field_value(field: &Field, column: TokenStream2) -> syn::Result<TokenStream2> {
+    let attributes = RowAttributes::parse(field)?;
+    let ty = &field.ty;
+
+    if attributes.flatten {
+        return Ok(quote!(<#ty as ::tc_core::database::FromRow>::from_row(row)?));
+    }
+
+    let column = match attributes.rename {
+        Some(rename) => quote!(#rename),
+        None => column,
+    };
+
+    Ok(if attributes.optional {
+        quote!(::tc_core::database::row_column_optional::<#ty, _>(row, #column)?)
+    } else {
+        quote!(::tc_core::database::row_column::<#ty, _>(row, #column)?)
+    })
+}
@@ -0,0 +1,43 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use std::path::PathBuf;
+use syn::LitStr;
+
+pub(crate) fn expand_embed_migrations(dir: LitStr) -> syn::Result<TokenStream2> {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR")
+        .map_err(|_| syn::Error::new(dir.span(), "CARGO_MANIFEST_DIR is not set"))?;
+    let path = PathBuf::from(manifest_dir).join(dir.value());
+
+    let entries = std::fs::read_dir(&path).map_err(|e| {
+        syn::Error::new(
+            dir.span(),
+            format!("Failed to read migrations dir {}: {}", path.display(), e),
+        )
+    })?;
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let file = entry
+            .map_err(|e| syn::Error::new(dir.span(), format!("Failed to read entry: {}", e)))?
+            .path();
+
+        if file.extension().is_some_and(|ext| ext == "sql") {
+            files.push(file);
+        }
+    }
+    files.sort();
+
+    let sources = files.iter().map(|file| {
+        let filename = file.file_name().unwrap().to_string_lossy();
+        let file = file.to_string_lossy();
+        quote!((#filename, include_str!(#file)))
+    });
+    let count = files.len();
+
+    // include_str! rebuilds on edits to a file, added files only show up
+    // once the crate is rebuilt
+    Ok(quote! {{
+        let sources: [(&str, &str); #count] = [#(#sources),*];
+        ::tc_core::database::MigrationRegistry::from_sources(sources)
+    }})
+}
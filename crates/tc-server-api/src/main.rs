@@ -4,7 +4,7 @@ mod global_handlers;
 mod routes;
 mod sql;
 
-use crate::{cli::CliArgs, global_handlers::handle_404};
+use crate::{cli::CliArgs, global_handlers::handle_404, sql::LoginStatements};
 use axum::{
     Router,
     routing::{get, post},
@@ -26,7 +26,8 @@ async fn main() -> anyhow::Result<()> {
     let db_config = PoolConfig {
         connection_string: args.db_connection_str.clone(),
         ..Default::default()
-    };
+    }
+    .with_statements::<LoginStatements>();
 
     tracing::info!("Connecting to database...");
     let db = Arc::new(DatabaseHandle::connect(db_config).await?);
@@ -1,4 +1,4 @@
-use crate::{error::ApiError, sql::accounts};
+use crate::{error::ApiError, sql::LoginStatements};
 use axum::{Json, extract::State, http::StatusCode};
 use serde::Deserialize;
 use std::sync::Arc;
@@ -44,8 +44,8 @@ pub async fn create_account(
         &srp6::LargeSafePrime::default(),
     );
 
-    db.execute(
-        accounts::ACCOUNT_CREATE,
+    db.execute_statement(
+        LoginStatements::InsAccount,
         &[
             &input.username,
             &salt.as_bytes_le().to_vec(),
@@ -57,7 +57,7 @@ pub async fn create_account(
     .await
     .map_err(|e| ApiError::Database(e))?;
 
-    db.execute(accounts::ACCOUNT_INIT_REALM_CHARACTERS, &[])
+    db.execute_statement(LoginStatements::InsRealmCharacters, &[])
         .await
         .map_err(|e| ApiError::Database(e))?;
 
@@ -69,7 +69,7 @@ async fn account_exists_by_username(
     db: &Arc<DatabaseHandle>,
 ) -> SqlResult<bool> {
     let exists: bool = db
-        .query_scalar(accounts::ACCOUNT_EXISTS_BY_USERNAME, &[username])
+        .query_statement_scalar(LoginStatements::SelAccountExistsByUsername, &[username])
         .await?;
 
     Ok(exists)
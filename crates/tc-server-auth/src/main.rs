@@ -4,17 +4,31 @@ mod opcode;
 mod packets;
 
 use crate::handler::{AuthServer, ServerState};
-use anyhow::Result;
-use tc_core::{platform::SignalWaiter, server::Server};
+use anyhow::{Context, Result};
+use tc_core::{
+    database::{DatabaseHandle, MigrationMigrator, PoolConfig, embed_migrations},
+    platform::SignalWaiter,
+    server::Server,
+};
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<()> {
+    // Failures before the server starts end the process with an error, so
+    // an orchestrator does not take them for a clean stop
+    let mut startup = Ok(());
     let waiter = SignalWaiter::new();
     waiter
         .wait(async {
             tracing_subscriber::fmt::init();
             tracing::info!("TitanCore v{}", env!("CARGO_PKG_VERSION"));
 
+            if auto_migrate_enabled()
+                && let Err(e) = auto_migrate().await
+            {
+                startup = Err(e.context("Failed to apply migrations"));
+                return;
+            }
+
             let server = Server::new(AuthServer, ServerState::new());
             if let Err(e) = server.run("127.0.0.1:3724".parse().unwrap()).await {
                 tracing::error!("Error while running server: {e}");
@@ -23,5 +37,40 @@ async fn main() -> Result<()> {
         .await;
 
     tracing::info!("Cleaning up");
+    startup
+}
+
+// Opt in through TC_AUTO_MIGRATE=1, otherwise migrations are left to the
+// db-migrator tool
+fn auto_migrate_enabled() -> bool {
+    std::env::var("TC_AUTO_MIGRATE")
+        .is_ok_and(|value| matches!(value.to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+}
+
+// Applies the migrations compiled into the binary before the server starts
+// accepting connections
+async fn auto_migrate() -> Result<()> {
+    let connection_string = std::env::var("TC_DATABASE_CONNECTION")
+        .context("TC_AUTO_MIGRATE needs TC_DATABASE_CONNECTION to be set")?;
+
+    let registry = embed_migrations!("migrations")?;
+    let db = DatabaseHandle::connect(PoolConfig {
+        connection_string,
+        ..Default::default()
+    })
+    .await?;
+
+    let result = MigrationMigrator::new(&db, &registry)
+        .migrate_on_startup()
+        .await;
+    db.shutdown().await;
+
+    let report = result?;
+    tracing::info!(
+        "Database at version {}, applied {} migration(s)",
+        report.final_version,
+        report.applied.len()
+    );
+
     Ok(())
 }
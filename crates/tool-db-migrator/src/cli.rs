@@ -1,7 +1,8 @@
-use crate::db;
+use crate::{db, output::Output, schema, seed};
 use clap::{Parser, Subcommand};
+use std::{path::Path, time::Duration};
 use tc_core::database::{
-    DatabaseHandle, MigrationMigrator, MigrationRegistry, MigrationReport, PoolConfig,
+    DatabaseHandle, MigrationMigrator, MigrationRegistry, MigrationStep, PoolConfig,
 };
 use tokio::fs::DirBuilder;
 
@@ -28,6 +29,64 @@ pub struct CliArgs {
     #[arg(long("create"), help("Create the database if it does not exist"))]
     pub create_db: bool,
 
+    #[arg(
+        long("owner"),
+        requires("create_db"),
+        help("Role owning the database created by --create")
+    )]
+    pub owner: Option<String>,
+
+    #[arg(
+        long("encoding"),
+        requires("create_db"),
+        help("Encoding of the database created by --create, e.g. UTF8")
+    )]
+    pub encoding: Option<String>,
+
+    #[arg(
+        long("template"),
+        requires("create_db"),
+        help("Template the database created by --create is copied from, e.g. template0")
+    )]
+    pub template: Option<String>,
+
+    #[arg(
+        short('m'),
+        long("manifest"),
+        env("TC_MIGRATION_MANIFEST"),
+        help("JSON manifest of databases to migrate in order, replaces --conn and --dir")
+    )]
+    pub manifest: Option<String>,
+
+    #[arg(
+        long("seeds"),
+        env("TC_SEED_DIR"),
+        help("Directory of fixture sets, seeds next to the migration directory by default")
+    )]
+    pub seeds: Option<String>,
+
+    #[arg(
+        long("lock-timeout"),
+        env("TC_MIGRATION_LOCK_TIMEOUT"),
+        help("Seconds to wait for another migrator to finish"),
+        default_value_t = 60
+    )]
+    pub lock_timeout: u64,
+
+    #[arg(
+        long("dry-run"),
+        global(true),
+        help("Print the sql that would run instead of running it")
+    )]
+    pub dry_run: bool,
+
+    #[arg(
+        long("json"),
+        global(true),
+        help("Print results as JSON on stdout, one line per target, logs go to stderr")
+    )]
+    pub json: bool,
+
     #[command(subcommand)]
     pub cmd: CliSubCommand,
 }
@@ -37,26 +96,120 @@ pub enum CliSubCommand {
     #[command(about("Check the status of migrations against the database"))]
     Status,
 
+    #[command(about("List the applied migrations and when they were applied"))]
+    History,
+
     #[command(about("Migrate all pending migrations that have not been applied"))]
     Up,
 
     #[command(about("Migrate the database to a speicif version (up or down)"))]
     To { version: i64 },
 
+    #[command(about("Revert the latest applied migrations"))]
+    Down {
+        #[arg(default_value_t = 1, help("Number of migrations to revert"))]
+        steps: usize,
+    },
+
+    #[command(about("Revert the latest migration and apply it again"))]
+    Redo,
+
+    #[command(about("Record migrations up to a version as applied without running them"))]
+    Baseline { version: i64 },
+
+    #[command(about("Check applied migrations against the migration files"))]
+    Validate,
+
+    #[command(about("Write the current schema to a snapshot file"))]
+    Snapshot {
+        #[arg(
+            long("file"),
+            help("Snapshot file, schema.snapshot in the migration directory by default")
+        )]
+        file: Option<String>,
+    },
+
+    #[command(about("Compare the current schema with a snapshot file"))]
+    Diff {
+        #[arg(
+            long("file"),
+            help("Snapshot file, schema.snapshot in the migration directory by default")
+        )]
+        file: Option<String>,
+    },
+
+    #[command(about(
+        "Apply, revert and reapply every migration on a scratch database created and dropped for the run, checking that reverting restores the schema"
+    ))]
+    VerifyDown,
+
+    #[command(about("Load a fixture set, e.g. dev, into a migrated database"))]
+    Seed { env: String },
+
     #[command(about("Create new migration file with the specified name"))]
-    New { name: String },
+    New {
+        name: String,
+
+        #[arg(
+            long("repeatable"),
+            help("Create a repeatable migration, applied again whenever it changes")
+        )]
+        repeatable: bool,
+
+        #[arg(
+            long("no-transaction"),
+            help("Run the migration outside of a transaction")
+        )]
+        no_transaction: bool,
+    },
+}
+
+impl CliSubCommand {
+    fn mutates(&self) -> bool {
+        matches!(
+            self,
+            Self::Up | Self::To { .. } | Self::Down { .. } | Self::Redo | Self::Baseline { .. }
+        )
+    }
+}
+
+// A database and the migrations it gets
+#[derive(Debug, Clone)]
+pub struct MigrationTarget {
+    pub name: Option<String>,
+    pub conn: String,
+    pub dir: String,
+    pub table: Option<String>,
+    pub seed_dir: Option<String>,
+    pub create_db: bool,
+    pub create_options: db::CreateOptions,
 }
 
+// Returns false when the command ran but found a problem, like drift found
+// by validate
 pub async fn run_migration_cmd(
-    cmd: CliSubCommand,
-    conn: String,
-    dir: String,
-    create_db: bool,
-) -> anyhow::Result<()> {
-    if create_db {
-        let db_name = db::database_from_connection_string(&conn)?;
-        if !db::database_exists(&conn, &db_name).await? {
-            db::create_database(&conn, &db_name).await?;
+    cmd: &CliSubCommand,
+    target: &MigrationTarget,
+    lock_timeout: Duration,
+    dry_run: bool,
+    output: &Output,
+) -> anyhow::Result<bool> {
+    // Runs on a database of its own, the target is never touched
+    if let CliSubCommand::VerifyDown = cmd {
+        return run_verify_down(target, lock_timeout, dry_run, output).await;
+    }
+
+    let conn = &target.conn;
+    if target.create_db {
+        let db_name = db::database_from_connection_string(conn)?;
+        if !db::database_exists(conn, &db_name).await? {
+            // The rest of the command needs the database to exist
+            if dry_run {
+                output.create_database(&db_name);
+                return Ok(true);
+            }
+
+            db::create_database(conn, &db_name, &target.create_options).await?;
         }
     }
 
@@ -66,10 +219,39 @@ pub async fn run_migration_cmd(
     };
 
     let db = DatabaseHandle::connect(config).await?;
-    let registry = MigrationRegistry::from_dir(dir.clone()).await?;
-    let migrator = MigrationMigrator::new(&db, &registry);
+    let registry = MigrationRegistry::from_dir(&target.dir).await?;
+    let migrator = target_migrator(&db, &registry, target, lock_timeout);
 
-    if !migrator.initialized().await? {
+    let result = run_cmd(cmd, target, &db, &migrator, &registry, dry_run, output).await;
+    db.shutdown().await;
+    result
+}
+
+fn target_migrator<'a>(
+    db: &'a DatabaseHandle,
+    registry: &'a MigrationRegistry,
+    target: &MigrationTarget,
+    lock_timeout: Duration,
+) -> MigrationMigrator<'a> {
+    let migrator = MigrationMigrator::new(db, registry).with_lock_timeout(lock_timeout);
+    match &target.table {
+        Some(table) => migrator.with_table_name(table),
+        None => migrator,
+    }
+}
+
+async fn run_cmd(
+    cmd: &CliSubCommand,
+    target: &MigrationTarget,
+    db: &DatabaseHandle,
+    migrator: &MigrationMigrator<'_>,
+    registry: &MigrationRegistry,
+    dry_run: bool,
+    output: &Output,
+) -> anyhow::Result<bool> {
+    // Only commands that change the database create the bookkeeping
+    // tables, the others read a missing table as nothing applied
+    if cmd.mutates() && !dry_run && !migrator.initialized().await? {
         migrator.init().await?;
     }
 
@@ -77,54 +259,290 @@ pub async fn run_migration_cmd(
         CliSubCommand::Status => {
             let current = migrator.current_version().await?.unwrap_or(0);
             let pending = migrator.pending().await?;
-
-            tracing::info!("Current version: {}", current);
-            tracing::info!("Pending migrations: {}", pending.len());
-            for m in &pending {
-                tracing::info!("  - {}: {}", m.version, m.name);
-            }
+            let repeatable = migrator.pending_repeatable().await?;
+            output.status(current, &pending, &repeatable);
+        }
+        CliSubCommand::History => {
+            output.history(&migrator.records().await?);
         }
         CliSubCommand::Up => {
-            let pending = migrator.pending().await?;
-            tracing::info!("Pending migrations: {}", pending.len());
-            for m in &pending {
-                tracing::info!("  - {}: {}", m.version, m.name);
+            if dry_run {
+                output.plan(&migrator.plan_to(i64::MAX).await?);
+                return Ok(true);
             }
 
+            let pending = migrator.pending().await?;
+            let repeatable = migrator.pending_repeatable().await?;
+            output.pending(&pending, &repeatable);
+
             let report = migrator.migrate_pending().await?;
-            print_migration_report(report);
+            output.report(&report);
         }
         CliSubCommand::To { version } => {
-            tracing::info!("Migrating database to version: {}", version);
-            let report = migrator.migrate_to(version).await?;
-            print_migration_report(report);
+            migrate_to(migrator, *version, dry_run, output).await?;
+        }
+        CliSubCommand::Down { steps } => {
+            let version = migrator.down_target(*steps).await?;
+            migrate_to(migrator, version, dry_run, output).await?;
+        }
+        CliSubCommand::Redo => {
+            if dry_run {
+                let current = migrator.current_version().await?.unwrap_or(0);
+                let previous = migrator.down_target(1).await?;
+                let mut steps = migrator.plan(current, previous).await?;
+                steps.extend(migrator.plan(previous, current).await?);
+                output.plan(&steps);
+                return Ok(true);
+            }
+
+            let report = migrator.redo().await?;
+            output.report(&report);
         }
-        _ => {}
+        CliSubCommand::Baseline { version } => {
+            if dry_run {
+                let versions = registry
+                    .up_to(*version)
+                    .map(|m| m.version)
+                    .collect::<Vec<_>>();
+                output.baseline(&versions, true);
+                return Ok(true);
+            }
+
+            let versions = migrator.baseline(*version).await?;
+            output.baseline(&versions, false);
+        }
+        CliSubCommand::Validate => {
+            let issues = migrator.validate().await?;
+            output.validation(&issues);
+            return Ok(issues.is_empty());
+        }
+        CliSubCommand::Snapshot { file } => {
+            let file = snapshot_file(target, file);
+            let snapshot = dump_schema(db, migrator).await?;
+            if dry_run {
+                output.schema(&snapshot);
+                return Ok(true);
+            }
+
+            tokio::fs::write(&file, snapshot).await?;
+            output.snapshot(&file);
+        }
+        CliSubCommand::Diff { file } => {
+            let file = snapshot_file(target, file);
+            let expected = tokio::fs::read_to_string(&file).await?;
+            let actual = dump_schema(db, migrator).await?;
+
+            let diff = schema::diff_schema(&expected, &actual, &file);
+            output.schema_diff(&file, diff.as_deref());
+            return Ok(diff.is_none());
+        }
+        CliSubCommand::Seed { env } => {
+            let pending = migrator.pending().await?;
+            if !pending.is_empty() {
+                anyhow::bail!(
+                    "{} migration(s) are pending, run up before seeding",
+                    pending.len()
+                );
+            }
+
+            let dir = match &target.seed_dir {
+                Some(dir) => Path::new(dir).join(env),
+                None => Path::new(&target.dir)
+                    .parent()
+                    .unwrap_or(Path::new(""))
+                    .join("seeds")
+                    .join(env),
+            };
+
+            let files = seed::seed_files(&dir).await?;
+            if !dry_run {
+                for file in &files {
+                    tracing::info!("Loading seed {}", file.display());
+                    seed::load_seed_file(db, file).await?;
+                }
+            }
+
+            let files = files
+                .iter()
+                .map(|f| f.to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            output.seeded(env, &files, dry_run);
+        }
+        CliSubCommand::VerifyDown | CliSubCommand::New { .. } => {}
     }
 
+    Ok(true)
+}
+
+async fn migrate_to(
+    migrator: &MigrationMigrator<'_>,
+    version: i64,
+    dry_run: bool,
+    output: &Output,
+) -> anyhow::Result<()> {
+    if dry_run {
+        output.plan(&migrator.plan_to(version).await?);
+        return Ok(());
+    }
+
+    tracing::info!("Migrating database to version: {}", version);
+    let report = migrator.migrate_to(version).await?;
+    output.report(&report);
     Ok(())
 }
 
-pub async fn run_new_cmd(name: String, dir: String) -> anyhow::Result<()> {
+fn snapshot_file(target: &MigrationTarget, file: &Option<String>) -> String {
+    file.clone().unwrap_or_else(|| {
+        Path::new(&target.dir)
+            .join("schema.snapshot")
+            .to_string_lossy()
+            .into_owned()
+    })
+}
+
+async fn dump_schema(
+    db: &DatabaseHandle,
+    migrator: &MigrationMigrator<'_>,
+) -> anyhow::Result<String> {
+    // The dump holds the public schema, where bookkeeping tables given as
+    // public.<table> show up unqualified
+    let repeatable_table = migrator.repeatable_table();
+    let exclude = [migrator.table_name(), repeatable_table.as_str()]
+        .map(|name| name.strip_prefix("public.").unwrap_or(name));
+    Ok(schema::dump_schema(db, &exclude).await?)
+}
+
+// Creates an empty database next to the target with the target's create
+// options and drops it again however the run ends
+async fn run_verify_down(
+    target: &MigrationTarget,
+    lock_timeout: Duration,
+    dry_run: bool,
+    output: &Output,
+) -> anyhow::Result<bool> {
+    let registry = MigrationRegistry::from_dir(&target.dir).await?;
+    if dry_run {
+        let steps = registry
+            .all()
+            .map(MigrationStep::Apply)
+            .chain(registry.repeatables().map(MigrationStep::Repeat))
+            .collect::<Vec<_>>();
+        output.plan(&steps);
+        return Ok(true);
+    }
+
+    let db_name = db::database_from_connection_string(&target.conn)?;
+    let scratch = format!(
+        "{}_verify_{}",
+        db_name.chars().take(40).collect::<String>(),
+        std::process::id()
+    );
+
+    db::create_database(&target.conn, &scratch, &target.create_options).await?;
+    let result = verify_down_on(target, &scratch, &registry, lock_timeout, output).await;
+    if let Err(e) = db::drop_database(&target.conn, &scratch).await {
+        tracing::error!("Failed to drop scratch database {}: {}", scratch, e);
+    }
+
+    result
+}
+
+async fn verify_down_on(
+    target: &MigrationTarget,
+    scratch: &str,
+    registry: &MigrationRegistry,
+    lock_timeout: Duration,
+    output: &Output,
+) -> anyhow::Result<bool> {
+    let config = PoolConfig {
+        connection_string: db::with_database(&target.conn, scratch)?,
+        ..Default::default()
+    };
+
+    let db = DatabaseHandle::connect(config).await?;
+    let migrator = target_migrator(&db, registry, target, lock_timeout);
+    let result = async {
+        migrator.init().await?;
+        let steps = migrator.plan_to(i64::MAX).await?;
+        verify_down(&db, &migrator, steps, output).await
+    }
+    .await;
+
+    db.shutdown().await;
+    result
+}
+
+// Stops at the first migration whose down section leaves the schema
+// different from before it was applied
+async fn verify_down(
+    db: &DatabaseHandle,
+    migrator: &MigrationMigrator<'_>,
+    steps: Vec<MigrationStep<'_>>,
+    output: &Output,
+) -> anyhow::Result<bool> {
+    let mut verified = Vec::new();
+    let mut skipped = Vec::new();
+
+    for step in steps {
+        let migration = match step {
+            MigrationStep::Apply(migration) if migration.down.is_some() => migration,
+            MigrationStep::Apply(migration) => {
+                skipped.push(migration.version);
+                migrator.run_step(step).await?;
+                continue;
+            }
+            _ => {
+                migrator.run_step(step).await?;
+                continue;
+            }
+        };
+
+        let before = dump_schema(db, migrator).await?;
+        migrator.run_step(step).await?;
+        migrator.run_step(MigrationStep::Revert(migration)).await?;
+        let after = dump_schema(db, migrator).await?;
+
+        let name = format!("before {}", migration.version);
+        if let Some(diff) = schema::diff_schema(&before, &after, &name) {
+            output.verify_down(&verified, &skipped, Some((migration.version, &diff)));
+            return Ok(false);
+        }
+
+        migrator.run_step(step).await?;
+        verified.push(migration.version);
+    }
+
+    output.verify_down(&verified, &skipped, None);
+    Ok(true)
+}
+
+pub async fn run_new_cmd(
+    name: String,
+    dir: String,
+    repeatable: bool,
+    no_transaction: bool,
+    output: &Output,
+) -> anyhow::Result<()> {
     let mut builder = DirBuilder::new();
     builder.recursive(true);
     builder.create(dir.clone()).await?;
 
-    let timestamp = chrono::Utc::now().format("%Y%m%d%H%M%S");
-    let filename = format!("{}/{}_{}.sql", dir, timestamp, name);
-    tokio::fs::write(
-        &filename,
-        "--#: migration.up\n--#: end\n\n--#: migration.down\n--#: end\n",
-    )
-    .await?;
+    let mut content = String::new();
+    if no_transaction {
+        content.push_str("--#: no-transaction\n\n");
+    }
 
-    tracing::info!("Created new migration: {}", filename);
-    Ok(())
-}
+    let filename = if repeatable {
+        content.push_str("--#: migration.up\n--#: end\n");
+        format!("{}/R_{}.sql", dir, name)
+    } else {
+        content.push_str("--#: migration.up\n--#: end\n\n--#: migration.down\n--#: end\n");
+        let timestamp = chrono::Utc::now().format("%Y%m%d%H%M%S");
+        format!("{}/{}_{}.sql", dir, timestamp, name)
+    };
+
+    tokio::fs::write(&filename, content).await?;
 
-fn print_migration_report(report: MigrationReport) {
-    tracing::info!("Initial Version: {}", report.initial_version);
-    tracing::info!("Final Version: {}", report.final_version);
-    tracing::info!("Applied: {}", report.applied.len());
-    tracing::info!("Reverted: {}", report.reverted.len());
+    output.created(&filename);
+    Ok(())
 }
@@ -1,29 +1,46 @@
-use tc_core::database::{DatabaseHandle, PoolConfig, Result, SqlErrorKind, SqlResultExt};
+use percent_encoding::percent_decode_str;
+use tc_core::database::{
+    DatabaseHandle, PoolConfig, Result, SqlErrorKind, SqlResultExt, quote_identifier, quote_literal,
+};
 use url::Url;
 
+// Options of CREATE DATABASE, the server defaults when unset
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    pub owner: Option<String>,
+    pub encoding: Option<String>,
+    pub template: Option<String>,
+}
+
 pub fn database_from_connection_string(conn: &String) -> Result<String> {
     let url = Url::parse(&conn)
         .sql_err(SqlErrorKind::Connection)
         .map_err(|e| e.context("Failed to parse connection string"))?;
 
+    // The path is percent encoded, the server gets the decoded name
     let path = url.path();
-    let extracted = if path.len() > 1 {
-        &path.to_string()[1..]
-    } else {
-        ""
-    };
+    let extracted = if path.len() > 1 { &path[1..] } else { "" };
+    let decoded = percent_decode_str(extracted)
+        .decode_utf8()
+        .sql_err(SqlErrorKind::Connection)
+        .map_err(|e| e.context("Database name in connection string is not UTF-8"))?;
 
-    Ok(extracted.to_string())
+    Ok(decoded.into_owned())
 }
 
-pub async fn database_exists(conn: &String, db_name: &String) -> Result<bool> {
-    let mut url = Url::parse(&conn)
+// The same connection string pointed at another database on the server
+pub fn with_database(conn: &str, db_name: &str) -> Result<String> {
+    let mut url = Url::parse(conn)
         .sql_err(SqlErrorKind::Connection)
         .map_err(|e| e.context("Failed to parse connection string"))?;
 
-    url.set_path("postgres");
+    url.set_path(db_name);
+    Ok(url.to_string())
+}
+
+pub async fn database_exists(conn: &String, db_name: &String) -> Result<bool> {
     let config = PoolConfig {
-        connection_string: url.to_string(),
+        connection_string: with_database(conn, "postgres")?,
         ..Default::default()
     };
 
@@ -38,20 +55,50 @@ pub async fn database_exists(conn: &String, db_name: &String) -> Result<bool> {
     Ok(exists)
 }
 
-pub async fn create_database(conn: &String, db_name: &String) -> Result<()> {
-    let mut url = Url::parse(&conn)
-        .sql_err(SqlErrorKind::Connection)
-        .map_err(|e| e.context("Failed to parse connection string"))?;
-
-    url.set_path("postgres");
+pub async fn create_database(
+    conn: &String,
+    db_name: &String,
+    options: &CreateOptions,
+) -> Result<()> {
     let config = PoolConfig {
-        connection_string: url.to_string(),
+        connection_string: with_database(conn, "postgres")?,
         ..Default::default()
     };
 
     let db = DatabaseHandle::connect(config).await?;
-    let sql = format!(r#"CREATE DATABASE {};"#, db_name);
+    let mut sql = format!("CREATE DATABASE {}", quote_identifier(db_name)?);
+    if let Some(owner) = &options.owner {
+        sql.push_str(&format!(" OWNER {}", quote_identifier(owner)?));
+    }
+    if let Some(encoding) = &options.encoding {
+        sql.push_str(&format!(" ENCODING {}", quote_literal(encoding)?));
+    }
+    if let Some(template) = &options.template {
+        sql.push_str(&format!(" TEMPLATE {}", quote_identifier(template)?));
+    }
+    sql.push(';');
+
+    tracing::info!("Creating database {}", db_name);
     db.execute(&sql, &[]).await?;
 
     Ok(())
 }
+
+// Force closes what is still connected, for scratch databases
+pub async fn drop_database(conn: &str, db_name: &str) -> Result<()> {
+    let config = PoolConfig {
+        connection_string: with_database(conn, "postgres")?,
+        ..Default::default()
+    };
+
+    let db = DatabaseHandle::connect(config).await?;
+    let sql = format!(
+        "DROP DATABASE IF EXISTS {} WITH (FORCE);",
+        quote_identifier(db_name)?
+    );
+
+    tracing::info!("Dropping database {}", db_name);
+    let result = db.execute(&sql, &[]).await;
+    db.shutdown().await;
+    result.map(|_| ())
+}
@@ -1,44 +1,128 @@
 mod cli;
 mod db;
+mod manifest;
+mod output;
+mod schema;
+mod seed;
 
-use crate::cli::{CliArgs, CliSubCommand};
+use crate::{
+    cli::{CliArgs, CliSubCommand, MigrationTarget},
+    manifest::Manifest,
+    output::Output,
+};
 use clap::Parser;
+use std::path::Path;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::fmt()
+    let args = CliArgs::parse();
+    let output = Output {
+        json: args.json,
+        target: None,
+    };
+
+    let builder = tracing_subscriber::fmt::fmt()
         .without_time()
         .with_level(false)
         .with_target(false)
-        .with_file(false)
-        .init();
+        .with_file(false);
 
-    let args = CliArgs::parse();
+    if args.json {
+        builder.with_writer(std::io::stderr).init();
+    } else {
+        builder.init();
+    }
+
+    if let Err(e) = run(args, &output).await {
+        output.error(&e);
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+async fn run(args: CliArgs, output: &Output) -> anyhow::Result<()> {
     match args.cmd {
-        CliSubCommand::Status | CliSubCommand::Up | CliSubCommand::To { .. } => {
-            let (conn, dir) = match (args.conn, args.dir) {
-                (Some(conn), Some(dir)) => (conn, dir),
-                _ => {
-                    tracing::error!("Error: connection or migration directory not provided");
-                    tracing::error!("Usage: --conn <CONN> --dir <DIR> [status | up | to]");
+        CliSubCommand::New {
+            name,
+            repeatable,
+            no_transaction,
+        } => {
+            let dir = match args.dir {
+                Some(dir) => dir,
+                None => {
+                    tracing::error!("Error: migration directory not provided");
+                    tracing::error!(
+                        "Usage: --dir <DIR> new [--repeatable] [--no-transaction] <NAME>"
+                    );
                     std::process::exit(1);
                 }
             };
 
-            cli::run_migration_cmd(args.cmd, conn, dir, args.create_db).await?;
+            cli::run_new_cmd(name, dir, repeatable, no_transaction, output).await?;
             Ok(())
         }
-        CliSubCommand::New { name } => {
-            let dir = match args.dir {
-                Some(dir) => dir,
-                None => {
-                    tracing::error!("Error: migration directory not provided");
-                    tracing::error!("Usage: --dir <DIR> new <NAME>");
+        cmd => {
+            let targets = match (args.manifest, args.conn, args.dir) {
+                (Some(manifest), _, _) => Manifest::load(Path::new(&manifest)).await?,
+                (None, Some(conn), Some(dir)) => vec![MigrationTarget {
+                    name: None,
+                    conn,
+                    dir,
+                    table: None,
+                    seed_dir: args.seeds.clone(),
+                    create_db: false,
+                    create_options: Default::default(),
+                }],
+                _ => {
+                    tracing::error!("Error: connection or migration directory not provided");
+                    tracing::error!(
+                        "Usage: (--manifest <FILE> | --conn <CONN> --dir <DIR>) [status | history | up | to | down | redo | baseline | validate | snapshot | diff | verify-down | seed]"
+                    );
                     std::process::exit(1);
                 }
             };
 
-            cli::run_new_cmd(name, dir).await?;
+            // Targets run in order and the run stops at the first error,
+            // later databases may depend on earlier ones
+            let lock_timeout = Duration::from_secs(args.lock_timeout);
+            let mut success = true;
+            for mut target in targets {
+                target.create_db |= args.create_db;
+
+                // Options given on the command line apply to every target
+                let options = &mut target.create_options;
+                options.owner = args.owner.clone().or(options.owner.take());
+                options.encoding = args.encoding.clone().or(options.encoding.take());
+                options.template = args.template.clone().or(options.template.take());
+
+                let output = Output {
+                    target: target.name.clone(),
+                    ..output.clone()
+                };
+
+                if let Some(name) = &target.name {
+                    tracing::info!("== {} ==", name);
+                }
+
+                let result =
+                    cli::run_migration_cmd(&cmd, &target, lock_timeout, args.dry_run, &output)
+                        .await;
+
+                match result {
+                    Ok(ok) => success &= ok,
+                    Err(e) => {
+                        output.error(&e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            if !success {
+                std::process::exit(1);
+            }
+
             Ok(())
         }
     }
@@ -0,0 +1,81 @@
+use crate::{cli::MigrationTarget, db::CreateOptions};
+use anyhow::{Context, bail};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+// Several databases migrated in one run, in the order they are listed.
+// Directories are relative to the manifest file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub targets: Vec<TargetConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetConfig {
+    pub name: String,
+    // Connection string, or the environment variable holding it
+    pub conn: Option<String>,
+    pub conn_env: Option<String>,
+    pub dir: PathBuf,
+    // Bookkeeping table, _migrations when unset
+    pub table: Option<String>,
+    // Fixture sets for seed, seeds next to dir when unset
+    pub seeds: Option<PathBuf>,
+    #[serde(default)]
+    pub create: bool,
+    // Options of the database created when create is set
+    pub owner: Option<String>,
+    pub encoding: Option<String>,
+    pub template: Option<String>,
+}
+
+impl Manifest {
+    pub async fn load(path: &Path) -> anyhow::Result<Vec<MigrationTarget>> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+
+        let manifest: Manifest = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid manifest {}", path.display()))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        manifest
+            .targets
+            .into_iter()
+            .map(|target| target.resolve(base))
+            .collect()
+    }
+}
+
+impl TargetConfig {
+    fn resolve(self, base: &Path) -> anyhow::Result<MigrationTarget> {
+        let conn = match (self.conn, &self.conn_env) {
+            (Some(conn), None) => conn,
+            (None, Some(var)) => std::env::var(var).with_context(|| {
+                format!("Target {} reads its connection from {}", self.name, var)
+            })?,
+            _ => bail!(
+                "Target {} needs exactly one of conn and conn_env",
+                self.name
+            ),
+        };
+
+        Ok(MigrationTarget {
+            name: Some(self.name),
+            conn,
+            dir: base.join(self.dir).to_string_lossy().into_owned(),
+            table: self.table,
+            seed_dir: self
+                .seeds
+                .map(|seeds| base.join(seeds).to_string_lossy().into_owned()),
+            create_db: self.create,
+            create_options: CreateOptions {
+                owner: self.owner,
+                encoding: self.encoding,
+                template: self.template,
+            },
+        })
+    }
+}
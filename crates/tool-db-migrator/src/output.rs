@@ -0,0 +1,285 @@
+use serde_json::{Value, json};
+use tc_core::database::{
+    Migration, MigrationRecord, MigrationReport, MigrationStep, RepeatableMigration,
+    ValidationIssue,
+};
+
+// Results go to the log as text, or to stdout as a single JSON document
+// with logging moved to stderr. With a manifest every target prints its own
+// document, tagged with the target name
+#[derive(Debug, Clone, Default)]
+pub struct Output {
+    pub json: bool,
+    pub target: Option<String>,
+}
+
+impl Output {
+    pub fn status(
+        &self,
+        current: i64,
+        pending: &[&Migration],
+        repeatable: &[&RepeatableMigration],
+    ) {
+        if self.json {
+            return self.print_json(json!({
+                "current_version": current,
+                "pending": pending.iter().map(|m| migration_json(m)).collect::<Vec<_>>(),
+                "pending_repeatable": repeatable.iter().map(|r| &r.name).collect::<Vec<_>>(),
+            }));
+        }
+
+        tracing::info!("Current version: {}", current);
+        self.pending(pending, repeatable);
+    }
+
+    pub fn pending(&self, pending: &[&Migration], repeatable: &[&RepeatableMigration]) {
+        if self.json {
+            return;
+        }
+
+        tracing::info!("Pending migrations: {}", pending.len());
+        for m in pending {
+            tracing::info!("  - {}: {}", m.version, m.name);
+        }
+
+        tracing::info!("Pending repeatable migrations: {}", repeatable.len());
+        for r in repeatable {
+            tracing::info!("  - {}", r.name);
+        }
+    }
+
+    pub fn report(&self, report: &MigrationReport) {
+        if self.json {
+            return self.print_json(json!({
+                "initial_version": report.initial_version,
+                "target_version": report.target_version,
+                "final_version": report.final_version,
+                "applied": report.applied,
+                "reverted": report.reverted,
+                "repeated": report.repeated,
+            }));
+        }
+
+        tracing::info!("Initial Version: {}", report.initial_version);
+        tracing::info!("Final Version: {}", report.final_version);
+        tracing::info!("Applied: {}", report.applied.len());
+        tracing::info!("Reverted: {}", report.reverted.len());
+        tracing::info!("Repeated: {}", report.repeated.len());
+    }
+
+    // The sql a run would execute, for --dry-run
+    pub fn plan(&self, steps: &[MigrationStep<'_>]) {
+        if self.json {
+            let steps = steps
+                .iter()
+                .map(|step| {
+                    let (action, version, name) = step_parts(step);
+                    json!({
+                        "action": action,
+                        "version": version,
+                        "name": name,
+                        "transactional": step.transactional(),
+                        "sql": step.sql(),
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            return self.print_json(json!({ "dry_run": true, "steps": steps }));
+        }
+
+        if steps.is_empty() {
+            tracing::info!("Nothing to run");
+            return;
+        }
+
+        for step in steps {
+            let (action, version, name) = step_parts(step);
+            let transaction = if step.transactional() {
+                ""
+            } else {
+                ", no transaction"
+            };
+
+            match version {
+                Some(version) => {
+                    tracing::info!("-- {} {}: {}{}", action, version, name, transaction)
+                }
+                None => tracing::info!("-- {} {}{}", action, name, transaction),
+            }
+
+            tracing::info!("{}\n", step.sql().unwrap_or("-- no 'down' migration"));
+        }
+    }
+
+    pub fn history(&self, records: &[MigrationRecord]) {
+        if self.json {
+            let applied = records
+                .iter()
+                .map(|r| {
+                    json!({
+                        "version": r.version,
+                        "name": r.name,
+                        "applied_at": r.applied_at.to_rfc3339(),
+                        "checksum": r.checksum,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            return self.print_json(json!({ "applied": applied }));
+        }
+
+        if records.is_empty() {
+            tracing::info!("No migrations applied");
+        }
+
+        for r in records {
+            tracing::info!(
+                "{}  {}: {}",
+                r.applied_at.format("%Y-%m-%d %H:%M:%S UTC"),
+                r.version,
+                r.name
+            );
+        }
+    }
+
+    pub fn validation(&self, issues: &[ValidationIssue]) {
+        if self.json {
+            return self.print_json(json!({
+                "valid": issues.is_empty(),
+                "issues": issues.iter().map(|i| i.to_string()).collect::<Vec<_>>(),
+            }));
+        }
+
+        if issues.is_empty() {
+            tracing::info!("Applied migrations match the migration files");
+            return;
+        }
+
+        tracing::error!("Migration drift detected:");
+        for issue in issues {
+            tracing::error!("  - {}", issue);
+        }
+    }
+
+    pub fn baseline(&self, versions: &[i64], dry_run: bool) {
+        if self.json {
+            return self.print_json(json!({ "dry_run": dry_run, "baselined": versions }));
+        }
+
+        let verb = if dry_run { "Would record" } else { "Recorded" };
+        tracing::info!("{} {} migration(s) as applied", verb, versions.len());
+        for version in versions {
+            tracing::info!("  - {}", version);
+        }
+    }
+
+    pub fn schema(&self, schema: &str) {
+        if self.json {
+            return self.print_json(json!({ "dry_run": true, "schema": schema }));
+        }
+
+        tracing::info!("{}", schema.trim_end());
+    }
+
+    pub fn snapshot(&self, file: &str) {
+        if self.json {
+            return self.print_json(json!({ "snapshot": file }));
+        }
+
+        tracing::info!("Wrote schema snapshot {}", file);
+    }
+
+    pub fn schema_diff(&self, file: &str, diff: Option<&str>) {
+        if self.json {
+            return self.print_json(json!({
+                "snapshot": file,
+                "matches": diff.is_none(),
+                "diff": diff,
+            }));
+        }
+
+        match diff {
+            None => tracing::info!("Schema matches {}", file),
+            Some(diff) => {
+                tracing::error!("Schema differs from {}:", file);
+                tracing::error!("{}", diff.trim_end());
+            }
+        }
+    }
+
+    pub fn verify_down(&self, verified: &[i64], skipped: &[i64], failed: Option<(i64, &str)>) {
+        if self.json {
+            return self.print_json(json!({
+                "verified": verified,
+                "skipped": skipped,
+                "failed": failed.map(|(version, diff)| json!({ "version": version, "diff": diff })),
+            }));
+        }
+
+        tracing::info!("Verified: {}", verified.len());
+        if !skipped.is_empty() {
+            tracing::warn!("Skipped, no 'down' migration: {:?}", skipped);
+        }
+
+        if let Some((version, diff)) = failed {
+            tracing::error!("Reverting {} does not restore the schema:", version);
+            tracing::error!("{}", diff.trim_end());
+        }
+    }
+
+    pub fn seeded(&self, env: &str, files: &[String], dry_run: bool) {
+        if self.json {
+            return self.print_json(json!({ "dry_run": dry_run, "seed": env, "files": files }));
+        }
+
+        let verb = if dry_run { "Would load" } else { "Loaded" };
+        tracing::info!("{} {} file(s) of seed {}", verb, files.len(), env);
+        for file in files {
+            tracing::info!("  - {}", file);
+        }
+    }
+
+    pub fn create_database(&self, name: &str) {
+        if self.json {
+            return self.print_json(json!({ "dry_run": true, "create_database": name }));
+        }
+
+        tracing::info!("Would create database {}, the rest needs it to exist", name);
+    }
+
+    pub fn created(&self, filename: &str) {
+        if self.json {
+            return self.print_json(json!({ "created": filename }));
+        }
+
+        tracing::info!("Created new migration: {}", filename);
+    }
+
+    pub fn error(&self, error: &anyhow::Error) {
+        if self.json {
+            return self.print_json(json!({ "error": format!("{:#}", error) }));
+        }
+
+        tracing::error!("Error: {:#}", error);
+    }
+
+    fn print_json(&self, mut value: Value) {
+        if let (Some(target), Value::Object(object)) = (&self.target, &mut value) {
+            object.insert("target".to_string(), json!(target));
+        }
+
+        println!("{}", value);
+    }
+}
+
+fn migration_json(migration: &Migration) -> Value {
+    json!({ "version": migration.version, "name": migration.name })
+}
+
+fn step_parts<'s>(step: &MigrationStep<'s>) -> (&'static str, Option<i64>, &'s str) {
+    match *step {
+        MigrationStep::Apply(m) => ("apply", Some(m.version), &m.name),
+        MigrationStep::Revert(m) => ("revert", Some(m.version), &m.name),
+        MigrationStep::Repeat(r) => ("repeat", None, &r.name),
+    }
+}
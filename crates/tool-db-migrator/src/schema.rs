@@ -0,0 +1,252 @@
+use similar::TextDiff;
+use std::{collections::BTreeMap, fmt::Write};
+use tc_core::database::{DatabaseHandle, Result};
+
+const TABLES_SQL: &str = r#"
+    SELECT c.relname FROM pg_class c
+    JOIN pg_namespace n ON n.oid = c.relnamespace
+    WHERE n.nspname = 'public' AND c.relkind IN ('r', 'p')
+"#;
+
+const COLUMNS_SQL: &str = r#"
+    SELECT c.relname, a.attname, format_type(a.atttypid, a.atttypmod), a.attnotnull,
+        pg_get_expr(d.adbin, d.adrelid)
+    FROM pg_attribute a
+    JOIN pg_class c ON c.oid = a.attrelid
+    JOIN pg_namespace n ON n.oid = c.relnamespace
+    LEFT JOIN pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum
+    WHERE n.nspname = 'public' AND c.relkind IN ('r', 'p')
+        AND a.attnum > 0 AND NOT a.attisdropped
+    ORDER BY c.relname, a.attnum
+"#;
+
+const CONSTRAINTS_SQL: &str = r#"
+    SELECT c.relname, con.conname, pg_get_constraintdef(con.oid)
+    FROM pg_constraint con
+    JOIN pg_class c ON c.oid = con.conrelid
+    JOIN pg_namespace n ON n.oid = c.relnamespace
+    WHERE n.nspname = 'public' AND con.contype IN ('p', 'u', 'f', 'c', 'x')
+"#;
+
+const INDEXES_SQL: &str = r#"
+    SELECT tablename, indexname, indexdef FROM pg_indexes WHERE schemaname = 'public'
+"#;
+
+const VIEWS_SQL: &str = r#"
+    SELECT viewname, definition FROM pg_views WHERE schemaname = 'public'
+"#;
+
+const ENUMS_SQL: &str = r#"
+    SELECT t.typname, string_agg(quote_literal(e.enumlabel), ', ' ORDER BY e.enumsortorder)
+    FROM pg_type t
+    JOIN pg_enum e ON e.enumtypid = t.oid
+    JOIN pg_namespace n ON n.oid = t.typnamespace
+    WHERE n.nspname = 'public'
+    GROUP BY t.typname
+"#;
+
+#[derive(Default)]
+struct Table {
+    columns: Vec<String>,
+    constraints: Vec<String>,
+    indexes: Vec<String>,
+}
+
+#[derive(Default)]
+struct Schema {
+    enums: Vec<(String, String)>,
+    tables: BTreeMap<String, Table>,
+    views: Vec<(String, String)>,
+}
+
+// The public schema as sorted text, so two dumps of the same schema are
+// byte for byte equal. Columns keep their table order. The excluded tables
+// are the migrator's own bookkeeping
+pub async fn dump_schema(db: &DatabaseHandle, exclude: &[&str]) -> Result<String> {
+    let mut schema = Schema::default();
+    for row in db.query(TABLES_SQL, &[]).await? {
+        schema.tables.insert(row.get(0), Table::default());
+    }
+
+    for row in db.query(COLUMNS_SQL, &[]).await? {
+        let not_null: bool = row.get(3);
+        let default: Option<String> = row.get(4);
+
+        let mut column = format!("{} {}", row.get::<_, String>(1), row.get::<_, String>(2));
+        if not_null {
+            column.push_str(" NOT NULL");
+        }
+        if let Some(default) = default {
+            let _ = write!(column, " DEFAULT {}", default);
+        }
+
+        if let Some(table) = schema.tables.get_mut(row.get::<_, &str>(0)) {
+            table.columns.push(column);
+        }
+    }
+
+    for row in db.query(CONSTRAINTS_SQL, &[]).await? {
+        if let Some(table) = schema.tables.get_mut(row.get::<_, &str>(0)) {
+            let constraint = format!("{} {}", row.get::<_, String>(1), row.get::<_, String>(2));
+            table.constraints.push(constraint);
+        }
+    }
+
+    for row in db.query(INDEXES_SQL, &[]).await? {
+        if let Some(table) = schema.tables.get_mut(row.get::<_, &str>(0)) {
+            let index = format!("{} {}", row.get::<_, String>(1), row.get::<_, String>(2));
+            table.indexes.push(index);
+        }
+    }
+
+    for row in db.query(ENUMS_SQL, &[]).await? {
+        schema.enums.push((row.get(0), row.get(1)));
+    }
+
+    for row in db.query(VIEWS_SQL, &[]).await? {
+        schema.views.push((row.get(0), row.get(1)));
+    }
+
+    Ok(render_schema(schema, exclude))
+}
+
+// The catalog queries return rows in no particular order, everything but
+// the columns is sorted here
+fn render_schema(mut schema: Schema, exclude: &[&str]) -> String {
+    let mut out = String::new();
+    schema.enums.sort();
+    for (name, labels) in schema.enums {
+        let _ = writeln!(out, "enum {} ({})", name, labels);
+    }
+
+    for (name, mut table) in schema.tables {
+        if exclude.contains(&name.as_str()) {
+            continue;
+        }
+
+        table.constraints.sort();
+        table.indexes.sort();
+
+        let _ = writeln!(out, "table {}", name);
+        for column in &table.columns {
+            let _ = writeln!(out, "  column {}", column);
+        }
+        for constraint in &table.constraints {
+            let _ = writeln!(out, "  constraint {}", constraint);
+        }
+        for index in &table.indexes {
+            let _ = writeln!(out, "  index {}", index);
+        }
+    }
+
+    schema.views.sort();
+    for (name, definition) in schema.views {
+        let definition = definition.split_whitespace().collect::<Vec<_>>().join(" ");
+        let _ = writeln!(out, "view {} {}", name, definition);
+    }
+
+    out
+}
+
+// Unified diff from the expected schema to the actual one, None when equal
+pub fn diff_schema(expected: &str, actual: &str, expected_name: &str) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+
+    let diff = TextDiff::from_lines(expected, actual)
+        .unified_diff()
+        .context_radius(2)
+        .header(expected_name, "database")
+        .to_string();
+
+    Some(diff)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::schema::{Schema, Table, diff_schema, render_schema};
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    fn schema(reversed: bool) -> Schema {
+        let mut schema = Schema::default();
+        let mut account = Table {
+            columns: strings(&["id integer NOT NULL", "name text"]),
+            constraints: strings(&[
+                "account_pkey PRIMARY KEY (id)",
+                "account_name_key UNIQUE (name)",
+            ]),
+            indexes: strings(&["b_idx CREATE INDEX b_idx", "a_idx CREATE INDEX a_idx"]),
+        };
+        let mut enums = vec![
+            ("realm_type".to_string(), "'pvp', 'pve'".to_string()),
+            ("locale".to_string(), "'enUS'".to_string()),
+        ];
+        let mut views = vec![
+            (
+                "online".to_string(),
+                "SELECT id\n   FROM account;".to_string(),
+            ),
+            (
+                "banned".to_string(),
+                " SELECT  name FROM account;".to_string(),
+            ),
+        ];
+
+        if reversed {
+            account.constraints.reverse();
+            account.indexes.reverse();
+            enums.reverse();
+            views.reverse();
+        }
+
+        schema.enums = enums;
+        schema.views = views;
+        schema.tables.insert("account".to_string(), account);
+        schema
+            .tables
+            .insert("_migrations".to_string(), Table::default());
+        schema
+    }
+
+    #[test]
+    fn test_render_schema() {
+        let rendered = render_schema(schema(false), &["_migrations"]);
+        assert_eq!(rendered, render_schema(schema(true), &["_migrations"]));
+        assert_eq!(
+            rendered,
+            "enum locale ('enUS')\n\
+             enum realm_type ('pvp', 'pve')\n\
+             table account\n\
+             \x20 column id integer NOT NULL\n\
+             \x20 column name text\n\
+             \x20 constraint account_name_key UNIQUE (name)\n\
+             \x20 constraint account_pkey PRIMARY KEY (id)\n\
+             \x20 index a_idx CREATE INDEX a_idx\n\
+             \x20 index b_idx CREATE INDEX b_idx\n\
+             view banned SELECT name FROM account;\n\
+             view online SELECT id FROM account;\n"
+        );
+
+        // Column order is part of the schema and is kept as given
+        let mut swapped = schema(false);
+        swapped.tables.get_mut("account").unwrap().columns.reverse();
+        assert_ne!(render_schema(swapped, &["_migrations"]), rendered);
+    }
+
+    #[test]
+    fn test_diff_schema() {
+        let expected = "table account\n  column id integer\n  column name text\n";
+        assert_eq!(diff_schema(expected, expected, "before"), None);
+
+        let actual = "table account\n  column id integer\n  column name varchar\n";
+        let diff = diff_schema(expected, actual, "before").unwrap();
+        assert!(diff.starts_with("--- before\n+++ database\n"));
+        assert!(diff.contains("\n-  column name text\n"));
+        assert!(diff.contains("\n+  column name varchar\n"));
+        assert!(diff.contains("\n   column id integer\n"));
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use tc_core::{
+    crypto::{defines::Salt, srp6},
+    database::{DatabaseHandle, TransactionContext, split_statements},
+};
+
+const UPSERT_ACCOUNT_SQL: &str = r#"
+    INSERT INTO account (username, salt, verifier, email, reg_mail)
+    VALUES ($1, $2, $3, $4, $4)
+    ON CONFLICT (username) DO UPDATE
+    SET salt = EXCLUDED.salt, verifier = EXCLUDED.verifier,
+        email = EXCLUDED.email, reg_mail = EXCLUDED.reg_mail
+"#;
+
+const GRANT_PERMISSION_SQL: &str = r#"
+    INSERT INTO rbac_account_permissions (account_id, permission_id, granted, realm_id)
+    SELECT id, $2, 1, $3 FROM account WHERE username = $1
+    ON CONFLICT (account_id, permission_id, realm_id) DO UPDATE SET granted = 1
+"#;
+
+const UPSERT_REALM_SQL: &str = r#"
+    INSERT INTO realmlist (name, address, local_address, port, icon, flag, timezone,
+        allowed_security_level, gmaebuild)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+    ON CONFLICT (name) DO UPDATE
+    SET address = EXCLUDED.address, local_address = EXCLUDED.local_address,
+        port = EXCLUDED.port, icon = EXCLUDED.icon, flag = EXCLUDED.flag,
+        timezone = EXCLUDED.timezone, allowed_security_level = EXCLUDED.allowed_security_level,
+        gmaebuild = EXCLUDED.gmaebuild
+"#;
+
+// Like accounts created through the api, every account gets a row per
+// realm, including realms seeded after the account
+const INSERT_REALM_CHARACTERS_SQL: &str = r#"
+    INSERT INTO realmcharacters (realm_id, acct_id, num_chars)
+    SELECT realmlist.id, account.id, 0
+    FROM realmlist CROSS JOIN account
+    ON CONFLICT (realm_id, acct_id) DO NOTHING
+"#;
+
+// Fixtures of one yaml file. Accounts and realms are upserted by name, so
+// loading a file again updates the rows instead of adding new ones
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SeedFile {
+    #[serde(default)]
+    pub accounts: Vec<SeedAccount>,
+    #[serde(default)]
+    pub realms: Vec<SeedRealm>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SeedAccount {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub email: String,
+    // rbac_permissions ids, e.g. 192 for the administrator role
+    #[serde(default)]
+    pub permissions: Vec<i32>,
+    // Realm the permissions apply to, -1 for all of them
+    #[serde(default = "all_realms")]
+    pub realm_id: i32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SeedRealm {
+    pub name: String,
+    #[serde(default = "localhost")]
+    pub address: String,
+    #[serde(default = "localhost")]
+    pub local_address: String,
+    #[serde(default = "default_port")]
+    pub port: i32,
+    #[serde(default)]
+    pub icon: i16,
+    #[serde(default = "default_flag")]
+    pub flag: i16,
+    #[serde(default)]
+    pub timezone: i16,
+    #[serde(default)]
+    pub allowed_security_level: i16,
+    #[serde(default = "default_build")]
+    pub build: i32,
+}
+
+fn all_realms() -> i32 {
+    -1
+}
+
+fn localhost() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_port() -> i32 {
+    8085
+}
+
+fn default_flag() -> i16 {
+    2
+}
+
+fn default_build() -> i32 {
+    12340
+}
+
+// The .sql, .yaml and .yml files of a fixture set in name order
+pub async fn seed_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Failed to read seed dir {}", dir.display()))?;
+
+    let mut files = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default();
+        if matches!(extension, "sql" | "yaml" | "yml") {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+// Loads one fixture file in a transaction of its own. Sql fixtures are
+// expected to be idempotent themselves, e.g. with ON CONFLICT DO NOTHING
+pub async fn load_seed_file(db: &DatabaseHandle, path: &Path) -> anyhow::Result<()> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read seed {}", path.display()))?;
+
+    if path.extension().is_some_and(|e| e == "sql") {
+        db.transaction(async |tx| {
+            for stmt in split_statements(&content) {
+                tx.execute(stmt, &[]).await?;
+            }
+
+            Ok(())
+        })
+        .await
+        .with_context(|| format!("Seed {} failed", path.display()))?;
+
+        return Ok(());
+    }
+
+    let seed: SeedFile = serde_yaml::from_str(&content)
+        .with_context(|| format!("Invalid seed {}", path.display()))?;
+
+    // Verifiers are computed up front, a retried transaction reuses them
+    let accounts = seed
+        .accounts
+        .iter()
+        .map(|account| {
+            let salt = Salt::randomized();
+            let verifier = srp6::calculate_password_verifier(
+                &account.username,
+                &account.password,
+                &salt,
+                &srp6::Generator::default(),
+                &srp6::LargeSafePrime::default(),
+            );
+
+            (
+                account,
+                salt.as_bytes_le().to_vec(),
+                verifier.as_bytes_le().to_vec(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    db.transaction(async |tx| {
+        for realm in &seed.realms {
+            upsert_realm(&tx, realm).await?;
+        }
+
+        for (account, salt, verifier) in &accounts {
+            tx.execute(
+                UPSERT_ACCOUNT_SQL,
+                &[&account.username, salt, verifier, &account.email],
+            )
+            .await?;
+
+            for permission in &account.permissions {
+                tx.execute(
+                    GRANT_PERMISSION_SQL,
+                    &[&account.username, permission, &account.realm_id],
+                )
+                .await?;
+            }
+        }
+
+        tx.execute(INSERT_REALM_CHARACTERS_SQL, &[]).await?;
+        Ok(())
+    })
+    .await
+    .with_context(|| format!("Seed {} failed", path.display()))?;
+
+    Ok(())
+}
+
+async fn upsert_realm(
+    tx: &TransactionContext<'_>,
+    realm: &SeedRealm,
+) -> tc_core::database::Result<u64> {
+    tx.execute(
+        UPSERT_REALM_SQL,
+        &[
+            &realm.name,
+            &realm.address,
+            &realm.local_address,
+            &realm.port,
+            &realm.icon,
+            &realm.flag,
+            &realm.timezone,
+            &realm.allowed_security_level,
+            &realm.build,
+        ],
+    )
+    .await
+}
@@ -0,0 +1,73 @@
+use anyhow::Result;
+use std::{collections::HashMap, path::Path};
+use tc_core::files::{
+    dbc::DbcFile,
+    map::{MAP_LIQUID_MAGMA, MAP_LIQUID_OCEAN, MAP_LIQUID_SLIME, MAP_LIQUID_WATER},
+    mpq::MpqArchiveSet,
+};
+
+const DBC_DIRECTORY: &str = "DBFilesClient\\";
+
+const MAP_DBC_ID: usize = 0;
+const MAP_DBC_DIRECTORY: usize = 1;
+
+const LIQUID_TYPE_DBC_ID: usize = 0;
+const LIQUID_TYPE_DBC_TYPE: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct MapEntry {
+    pub id: u32,
+    pub directory: String,
+}
+
+pub fn extract_dbc_files(archives: &MpqArchiveSet, output: &Path) -> Result<usize> {
+    std::fs::create_dir_all(output)?;
+
+    let mut count = 0;
+    for name in archives.list_files()? {
+        let is_dbc = name.len() > DBC_DIRECTORY.len()
+            && name[..DBC_DIRECTORY.len()].eq_ignore_ascii_case(DBC_DIRECTORY)
+            && (name.to_ascii_lowercase().ends_with(".dbc")
+                || name.to_ascii_lowercase().ends_with(".db2"));
+
+        if !is_dbc {
+            continue;
+        }
+
+        if let Some(data) = archives.read_file(&name)? {
+            std::fs::write(output.join(&name[DBC_DIRECTORY.len()..]), data)?;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+pub fn read_maps(archives: &MpqArchiveSet) -> Result<Vec<MapEntry>> {
+    let dbc = DbcFile::parse(&archives.read_required("DBFilesClient\\Map.dbc")?)?;
+    Ok(dbc
+        .records()
+        .map(|r| MapEntry {
+            id: r.get_u32(MAP_DBC_ID),
+            directory: r.get_string(MAP_DBC_DIRECTORY),
+        })
+        .collect())
+}
+
+// Maps LiquidType.dbc ids to the liquid flags stored in map files
+pub fn read_liquid_flags(archives: &MpqArchiveSet) -> Result<HashMap<u16, u8>> {
+    let dbc = DbcFile::parse(&archives.read_required("DBFilesClient\\LiquidType.dbc")?)?;
+    Ok(dbc
+        .records()
+        .map(|r| {
+            let flags = match r.get_u32(LIQUID_TYPE_DBC_TYPE) {
+                1 => MAP_LIQUID_OCEAN,
+                2 => MAP_LIQUID_MAGMA,
+                3 => MAP_LIQUID_SLIME,
+                _ => MAP_LIQUID_WATER,
+            };
+
+            (r.get_u32(LIQUID_TYPE_DBC_ID) as u16, flags)
+        })
+        .collect())
+}
@@ -1,3 +1,164 @@
-fn main() {
-    println!("Hello, world!");
+mod cli;
+mod dbc;
+mod tile;
+
+use crate::cli::CliArgs;
+use anyhow::{Context, Result, anyhow};
+use clap::Parser;
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use tc_core::files::{
+    adt::AdtFile,
+    map::{MapHeightEncoding, map_file_name},
+    mpq::MpqArchiveSet,
+    wdt::{WdtFile, adt_path, wdt_path},
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+struct TileJob {
+    map_id: u32,
+    directory: String,
+    x: usize,
+    y: usize,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::fmt()
+        .without_time()
+        .with_level(false)
+        .with_target(false)
+        .with_file(false)
+        .init();
+
+    let args = CliArgs::parse();
+    let input = PathBuf::from(&args.input).join("Data");
+    let output = PathBuf::from(&args.output);
+
+    let archives = Arc::new(MpqArchiveSet::open_client_data(
+        &input,
+        args.locale.as_deref(),
+    )?);
+
+    if !args.skip_dbc {
+        tracing::info!("Extracting DBC files...");
+        let count = dbc::extract_dbc_files(&archives, &output.join("dbc"))?;
+        tracing::info!("Extracted {} DBC files", count);
+    }
+
+    if !args.skip_maps {
+        let encoding = MapHeightEncoding {
+            allow_int: !args.float_heights,
+            ..Default::default()
+        };
+
+        let threads = args
+            .threads
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+
+        extract_maps(archives, &args.maps, output.join("maps"), encoding, threads).await?;
+    }
+
+    Ok(())
+}
+
+async fn extract_maps(
+    archives: Arc<MpqArchiveSet>,
+    filter: &[u32],
+    output: PathBuf,
+    encoding: MapHeightEncoding,
+    threads: usize,
+) -> Result<()> {
+    tokio::fs::create_dir_all(&output).await?;
+
+    let liquid_flags = Arc::new(dbc::read_liquid_flags(&archives)?);
+    let maps = dbc::read_maps(&archives)?
+        .into_iter()
+        .filter(|m| filter.is_empty() || filter.contains(&m.id))
+        .collect::<Vec<_>>();
+
+    let mut jobs = Vec::new();
+    for map in &maps {
+        let wdt = match archives.read_file(&wdt_path(&map.directory))? {
+            Some(data) => WdtFile::parse(&data)
+                .with_context(|| format!("Failed to parse WDT for map {}", map.id))?,
+            None => continue,
+        };
+
+        jobs.extend(wdt.tiles().map(|(x, y)| TileJob {
+            map_id: map.id,
+            directory: map.directory.clone(),
+            x,
+            y,
+        }));
+    }
+
+    let total = jobs.len();
+    tracing::info!(
+        "Converting {} tiles across {} maps using {} threads",
+        total,
+        maps.len(),
+        threads
+    );
+
+    let semaphore = Arc::new(Semaphore::new(threads.max(1)));
+    let mut tasks = JoinSet::new();
+    for job in jobs {
+        let semaphore = Arc::clone(&semaphore);
+        let archives = Arc::clone(&archives);
+        let liquid_flags = Arc::clone(&liquid_flags);
+        let output = output.clone();
+
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            let name = format!("{}_{}_{}", job.directory, job.x, job.y);
+            tokio::task::spawn_blocking(move || {
+                extract_tile(&archives, &liquid_flags, &job, &output, &encoding)
+            })
+            .await?
+            .with_context(|| format!("Failed to convert {}", name))
+        });
+    }
+
+    let mut done = 0;
+    let mut failed = 0;
+    let mut last_percent = 0;
+    while let Some(result) = tasks.join_next().await {
+        done += 1;
+        if let Err(e) = result? {
+            failed += 1;
+            tracing::error!("{:#}", e);
+        }
+
+        let percent = done * 100 / total.max(1);
+        if percent != last_percent || done == total {
+            last_percent = percent;
+            tracing::info!("[{:>3}%] {}/{} tiles", percent, done, total);
+        }
+    }
+
+    tracing::info!("Converted {} tiles, {} failed", done - failed, failed);
+    if failed > 0 {
+        return Err(anyhow!("{} of {} tiles failed to convert", failed, total));
+    }
+
+    Ok(())
+}
+
+fn extract_tile(
+    archives: &MpqArchiveSet,
+    liquid_flags: &HashMap<u16, u8>,
+    job: &TileJob,
+    output: &std::path::Path,
+    encoding: &MapHeightEncoding,
+) -> Result<()> {
+    let data = archives.read_required(&adt_path(&job.directory, job.x, job.y))?;
+    let adt = AdtFile::parse(&data)?;
+    let tile = tile::convert_adt(&adt, liquid_flags);
+
+    std::fs::write(
+        output.join(map_file_name(job.map_id, job.x, job.y)),
+        tile.write(encoding),
+    )?;
+
+    Ok(())
 }
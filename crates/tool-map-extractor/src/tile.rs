@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use tc_core::files::{
+    adt::{
+        ADT_CELLS_PER_CHUNK, AdtFile, MCNK_FLAG_LIQUID_MAGMA, MCNK_FLAG_LIQUID_OCEAN,
+        MCNK_FLAG_LIQUID_RIVER, MCNK_FLAG_LIQUID_SLIME,
+    },
+    map::{
+        MAP_CELLS_PER_TILE, MAP_CHUNK_COUNT, MAP_CHUNKS_PER_TILE, MAP_LIQUID_DARK_WATER,
+        MAP_LIQUID_MAGMA, MAP_LIQUID_OCEAN, MAP_LIQUID_SLIME, MAP_LIQUID_WATER, MAP_V8_SIZE,
+        MAP_V9_SIZE, MapAreaData, MapHeightData, MapLiquidData, MapTile,
+    },
+};
+
+pub const CLIENT_BUILD: u32 = 12340;
+
+// Liquid collected across all chunks of a tile before it is cropped to the
+// rectangle that actually contains liquid cells
+struct LiquidGrid {
+    types: Box<[u16; MAP_CHUNK_COUNT]>,
+    flags: Box<[u8; MAP_CHUNK_COUNT]>,
+    cells: Vec<bool>,
+    levels: Vec<f32>,
+}
+
+impl LiquidGrid {
+    fn new() -> Self {
+        Self {
+            types: Box::new([0; MAP_CHUNK_COUNT]),
+            flags: Box::new([0; MAP_CHUNK_COUNT]),
+            cells: vec![false; MAP_CELLS_PER_TILE * MAP_CELLS_PER_TILE],
+            levels: vec![0.0; MAP_V9_SIZE * MAP_V9_SIZE],
+        }
+    }
+
+    fn into_liquid(self) -> Option<MapLiquidData> {
+        let mut bounds: Option<(usize, usize, usize, usize)> = None;
+        for (i, _) in self.cells.iter().enumerate().filter(|(_, c)| **c) {
+            let (x, y) = (i % MAP_CELLS_PER_TILE, i / MAP_CELLS_PER_TILE);
+            bounds = Some(match bounds {
+                Some((min_x, min_y, max_x, max_y)) => {
+                    (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y))
+                }
+                None => (x, y, x, y),
+            });
+        }
+
+        let (min_x, min_y, max_x, max_y) = bounds?;
+        let width = max_x - min_x + 2;
+        let height = max_y - min_y + 2;
+
+        let mut levels = Vec::with_capacity(width * height);
+        for y in min_y..min_y + height {
+            for x in min_x..min_x + width {
+                levels.push(self.levels[y * MAP_V9_SIZE + x]);
+            }
+        }
+
+        Some(MapLiquidData {
+            types: self.types,
+            flags: self.flags,
+            offset_x: min_x as u8,
+            offset_y: min_y as u8,
+            width: width as u8,
+            height: height as u8,
+            levels,
+        })
+    }
+}
+
+pub fn convert_adt(adt: &AdtFile, liquid_flags: &HashMap<u16, u8>) -> MapTile {
+    let mut areas = Box::new([0u16; MAP_CHUNK_COUNT]);
+    let mut holes = Box::new([0u16; MAP_CHUNK_COUNT]);
+    let mut height = MapHeightData {
+        v9: vec![0.0; MAP_V9_SIZE * MAP_V9_SIZE],
+        v8: vec![0.0; MAP_V8_SIZE * MAP_V8_SIZE],
+    };
+    let mut liquid = LiquidGrid::new();
+
+    for chunk_y in 0..MAP_CHUNKS_PER_TILE {
+        for chunk_x in 0..MAP_CHUNKS_PER_TILE {
+            let index = chunk_y * MAP_CHUNKS_PER_TILE + chunk_x;
+            let chunk = adt.chunk(chunk_x, chunk_y);
+            let (base_x, base_y) = (chunk_x * ADT_CELLS_PER_CHUNK, chunk_y * ADT_CELLS_PER_CHUNK);
+
+            areas[index] = chunk.area_id as u16;
+            holes[index] = chunk.holes;
+
+            for y in 0..=ADT_CELLS_PER_CHUNK {
+                for x in 0..=ADT_CELLS_PER_CHUNK {
+                    height.v9[(base_y + y) * MAP_V9_SIZE + base_x + x] = chunk.outer_height(x, y);
+                }
+            }
+
+            for y in 0..ADT_CELLS_PER_CHUNK {
+                for x in 0..ADT_CELLS_PER_CHUNK {
+                    height.v8[(base_y + y) * MAP_V8_SIZE + base_x + x] = chunk.inner_height(x, y);
+                }
+            }
+
+            // Only the first MH2O layer is kept, the server does not model
+            // stacked liquids within a single chunk
+            if let Some(instance) = adt.liquid(chunk_x, chunk_y).first() {
+                liquid.types[index] = instance.liquid_type;
+                liquid.flags[index] = liquid_flags
+                    .get(&instance.liquid_type)
+                    .copied()
+                    .unwrap_or(MAP_LIQUID_WATER);
+
+                // Any deep cell makes the whole chunk dark water, which is
+                // what the server checks to apply fatigue
+                if adt.liquid_attributes(chunk_x, chunk_y).deep != 0 {
+                    liquid.flags[index] |= MAP_LIQUID_DARK_WATER;
+                }
+
+                let (ox, oy) = (
+                    base_x + instance.x_offset as usize,
+                    base_y + instance.y_offset as usize,
+                );
+
+                for y in 0..instance.height as usize {
+                    for x in 0..instance.width as usize {
+                        if instance.has_cell(x, y) {
+                            liquid.cells[(oy + y) * MAP_CELLS_PER_TILE + ox + x] = true;
+                        }
+                    }
+                }
+
+                for y in 0..=instance.height as usize {
+                    for x in 0..=instance.width as usize {
+                        liquid.levels[(oy + y) * MAP_V9_SIZE + ox + x] =
+                            instance.vertex_height(x, y);
+                    }
+                }
+            } else if let Some(legacy) = &chunk.legacy_liquid {
+                let (liquid_type, flags) = if chunk.flags & MCNK_FLAG_LIQUID_RIVER != 0 {
+                    (1, MAP_LIQUID_WATER)
+                } else if chunk.flags & MCNK_FLAG_LIQUID_OCEAN != 0 {
+                    (2, MAP_LIQUID_OCEAN)
+                } else if chunk.flags & MCNK_FLAG_LIQUID_MAGMA != 0 {
+                    (3, MAP_LIQUID_MAGMA)
+                } else if chunk.flags & MCNK_FLAG_LIQUID_SLIME != 0 {
+                    (4, MAP_LIQUID_SLIME)
+                } else {
+                    continue;
+                };
+
+                liquid.types[index] = liquid_type;
+                liquid.flags[index] = flags;
+
+                for y in 0..ADT_CELLS_PER_CHUNK {
+                    for x in 0..ADT_CELLS_PER_CHUNK {
+                        if legacy.has_cell(x, y) {
+                            liquid.cells[(base_y + y) * MAP_CELLS_PER_TILE + base_x + x] = true;
+                            if legacy.is_fatigue(x, y) {
+                                liquid.flags[index] |= MAP_LIQUID_DARK_WATER;
+                            }
+                        }
+                    }
+                }
+
+                for y in 0..=ADT_CELLS_PER_CHUNK {
+                    for x in 0..=ADT_CELLS_PER_CHUNK {
+                        liquid.levels[(base_y + y) * MAP_V9_SIZE + base_x + x] =
+                            legacy.heights[y * 9 + x];
+                    }
+                }
+            }
+        }
+    }
+
+    MapTile {
+        build: CLIENT_BUILD,
+        area: MapAreaData::Grid(areas),
+        height,
+        liquid: liquid.into_liquid(),
+        holes: if holes.iter().any(|h| *h != 0) {
+            Some(holes)
+        } else {
+            None
+        },
+    }
+}
@@ -0,0 +1,47 @@
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct CliArgs {
+    #[arg(
+        short('i'),
+        long("input"),
+        env("TC_DATA_DIR"),
+        help("Directory containing the extracted maps and vmaps folders"),
+        default_value = "."
+    )]
+    pub input: String,
+
+    #[arg(
+        short('o'),
+        long("output"),
+        env("TC_DATA_DIR"),
+        help("Directory the mmaps folder is written to"),
+        default_value = "."
+    )]
+    pub output: String,
+
+    #[arg(
+        short('c'),
+        long("config"),
+        help(
+            "JSON file with agent settings and off-mesh connections, defaults are used when omitted"
+        )
+    )]
+    pub config: Option<String>,
+
+    #[arg(
+        short('m'),
+        long("maps"),
+        value_delimiter(','),
+        help("Comma separated list of map ids to generate, all maps when omitted")
+    )]
+    pub maps: Vec<u32>,
+
+    #[arg(
+        short('t'),
+        long("threads"),
+        help("Number of tiles processed in parallel, defaults to the number of cores")
+    )]
+    pub threads: Option<usize>,
+}
@@ -0,0 +1,257 @@
+use crate::heightfield::{DIRECTIONS, Heightfield};
+use tc_core::math::Vec3;
+
+pub const NOT_CONNECTED: u32 = u32::MAX;
+
+// Open space above a walkable span. Connections point at the span an agent
+// reaches when stepping into each neighboring column
+#[derive(Debug, Clone, Copy)]
+pub struct CompactSpan {
+    pub floor: u16,
+    pub clearance: u16,
+    pub connections: [u32; 4],
+}
+
+// Walkable spans of a heightfield packed per column, the structure every
+// later step works on
+pub struct CompactHeightfield {
+    pub width: usize,
+    pub height: usize,
+    pub origin: Vec3,
+    pub cell_size: f32,
+    pub cell_height: f32,
+    // First span and span count of each column
+    pub cells: Vec<(u32, u32)>,
+    pub spans: Vec<CompactSpan>,
+    pub areas: Vec<u8>,
+}
+
+impl CompactHeightfield {
+    pub fn build(heightfield: &Heightfield, walkable_height: i32, climb: i32) -> Self {
+        let mut compact = Self {
+            width: heightfield.width,
+            height: heightfield.height,
+            origin: heightfield.origin,
+            cell_size: heightfield.cell_size,
+            cell_height: heightfield.cell_height,
+            cells: Vec::with_capacity(heightfield.columns.len()),
+            spans: Vec::new(),
+            areas: Vec::new(),
+        };
+
+        for column in &heightfield.columns {
+            let first = compact.spans.len() as u32;
+            for (i, span) in column.iter().enumerate() {
+                if span.area == 0 {
+                    continue;
+                }
+
+                let top = column.get(i + 1).map_or(u16::MAX, |s| s.min);
+                compact.spans.push(CompactSpan {
+                    floor: span.max,
+                    clearance: top - span.max,
+                    connections: [NOT_CONNECTED; 4],
+                });
+                compact.areas.push(span.area);
+            }
+
+            compact
+                .cells
+                .push((first, compact.spans.len() as u32 - first));
+        }
+
+        for y in 0..compact.height {
+            for x in 0..compact.width {
+                for i in compact.span_range(x, y) {
+                    for direction in 0..4 {
+                        let Some((nx, ny)) = compact.neighbor(x, y, direction) else {
+                            continue;
+                        };
+
+                        let span = compact.spans[i];
+                        let connection = compact.span_range(nx, ny).find(|n| {
+                            let other = &compact.spans[*n];
+                            let bottom = span.floor.max(other.floor) as i32;
+                            let top = (span.floor as i32 + span.clearance as i32)
+                                .min(other.floor as i32 + other.clearance as i32);
+
+                            top - bottom >= walkable_height
+                                && (other.floor as i32 - span.floor as i32).abs() <= climb
+                        });
+
+                        if let Some(n) = connection {
+                            compact.spans[i].connections[direction] = n as u32;
+                        }
+                    }
+                }
+            }
+        }
+
+        compact
+    }
+
+    pub fn span_range(&self, x: usize, y: usize) -> std::ops::Range<usize> {
+        let (first, count) = self.cells[y * self.width + x];
+        first as usize..(first + count) as usize
+    }
+
+    pub fn neighbor(&self, x: usize, y: usize, direction: usize) -> Option<(usize, usize)> {
+        let (dx, dy) = DIRECTIONS[direction];
+        let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+        (nx >= 0 && ny >= 0 && (nx as usize) < self.width && (ny as usize) < self.height)
+            .then_some((nx as usize, ny as usize))
+    }
+
+    pub fn connection(&self, span: usize, direction: usize) -> Option<usize> {
+        let connection = self.spans[span].connections[direction];
+        (connection != NOT_CONNECTED).then_some(connection as usize)
+    }
+
+    pub fn floor_z(&self, span: usize) -> f32 {
+        self.origin.z + self.spans[span].floor as f32 * self.cell_height
+    }
+
+    // Removes every span closer than radius cells to an edge of the walkable
+    // area so agents keep their distance from walls. The tile border is not
+    // an edge, the neighboring tile continues the surface
+    pub fn erode(&mut self, radius: u32) {
+        if radius == 0 {
+            return;
+        }
+
+        // Chamfer distance with 2 for straight and 3 for diagonal steps
+        let mut distance = vec![u8::MAX; self.spans.len()];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                for i in self.span_range(x, y) {
+                    let open = (0..4).all(|d| {
+                        self.neighbor(x, y, d).is_none()
+                            || self.connection(i, d).is_some_and(|n| self.areas[n] != 0)
+                    });
+
+                    if !open || self.areas[i] == 0 {
+                        distance[i] = 0;
+                    }
+                }
+            }
+        }
+
+        let relax = |i: usize, first: usize, second: usize, distance: &mut [u8]| {
+            if let Some(a) = self.connection(i, first) {
+                distance[i] = distance[i].min(distance[a].saturating_add(2));
+                if let Some(b) = self.connection(a, second) {
+                    distance[i] = distance[i].min(distance[b].saturating_add(3));
+                }
+            }
+        };
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                for i in self.span_range(x, y) {
+                    relax(i, 2, 3, &mut distance);
+                    relax(i, 3, 0, &mut distance);
+                }
+            }
+        }
+
+        for y in (0..self.height).rev() {
+            for x in (0..self.width).rev() {
+                for i in self.span_range(x, y) {
+                    relax(i, 0, 1, &mut distance);
+                    relax(i, 1, 2, &mut distance);
+                }
+            }
+        }
+
+        let threshold = (radius * 2).min(u8::MAX as u32) as u8;
+        for (area, distance) in self.areas.iter_mut().zip(distance) {
+            if distance < threshold {
+                *area = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compact::CompactHeightfield,
+        heightfield::{Heightfield, Span},
+    };
+    use tc_core::{files::navmesh::NAV_AREA_GROUND, math::Vec3};
+
+    const WALKABLE_HEIGHT: i32 = 4;
+    const CLIMB: i32 = 2;
+
+    // Single walkable span per column with its top at the given height,
+    // columns where it returns None are a wall
+    fn heightfield(
+        width: usize,
+        height: usize,
+        floor: impl Fn(usize, usize) -> Option<u16>,
+    ) -> Heightfield {
+        let mut heightfield = Heightfield::new(width, height, Vec3::new(0.0, 0.0, 0.0), 1.0, 0.5);
+        for y in 0..height {
+            for x in 0..width {
+                let span = match floor(x, y) {
+                    Some(floor) => Span {
+                        min: 0,
+                        max: floor,
+                        area: NAV_AREA_GROUND,
+                    },
+                    None => Span {
+                        min: 0,
+                        max: 100,
+                        area: 0,
+                    },
+                };
+                heightfield.columns[y * width + x].push(span);
+            }
+        }
+
+        heightfield
+    }
+
+    #[test]
+    fn test_build_connections() {
+        let flat = heightfield(4, 4, |_, _| Some(10));
+        let compact = CompactHeightfield::build(&flat, WALKABLE_HEIGHT, CLIMB);
+        assert_eq!(compact.spans.len(), 16);
+
+        let corner = compact.span_range(0, 0).start;
+        assert_eq!(compact.connection(corner, 0), Some(1));
+        assert_eq!(compact.connection(corner, 1), Some(4));
+        assert_eq!(compact.connection(corner, 2), None);
+        assert_eq!(compact.connection(corner, 3), None);
+
+        // The right half is a step of 6 cells, more than climb
+        let step = heightfield(4, 4, |x, _| Some(if x < 2 { 10 } else { 16 }));
+        let compact = CompactHeightfield::build(&step, WALKABLE_HEIGHT, CLIMB);
+        let low = compact.span_range(1, 1).start;
+        let high = compact.span_range(2, 1).start;
+        assert_eq!(compact.connection(low, 0), None);
+        assert_eq!(compact.connection(high, 2), None);
+        assert_eq!(compact.connection(low, 2), Some(low - 1));
+        assert_eq!(compact.connection(high, 0), Some(high + 1));
+    }
+
+    #[test]
+    fn test_erode_wall() {
+        let walled = heightfield(11, 3, |x, _| (x != 5).then_some(10));
+        let mut compact = CompactHeightfield::build(&walled, WALKABLE_HEIGHT, CLIMB);
+        compact.erode(2);
+
+        // Two cells of radius clear the wall and the cell next to it on
+        // both sides, the tile border is not eroded
+        for y in 0..3 {
+            let walkable = [0, 1, 2, 3, 4, 6, 7, 8, 9, 10]
+                .map(|x| compact.areas[compact.span_range(x, y).start] != 0);
+            assert_eq!(
+                walkable,
+                [
+                    true, true, true, false, false, false, false, true, true, true
+                ]
+            );
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use std::{collections::HashMap, path::Path};
+use tc_core::{
+    files::{
+        map::{MAP_TILE_SIZE, world_to_tile},
+        navmesh::{NAV_AREA_GROUND, NAV_POLY_FLAG_WALK, NavMeshParams, NavOffMeshConnection},
+    },
+    math::Vec3,
+};
+
+// Polygons keep at most four vertices per cell along their outline, which
+// has to fit the tile format's vertex count
+const MAX_POLY_CELLS: usize = 63;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AgentConfig {
+    pub radius: f32,
+    pub height: f32,
+    // Highest step the agent walks up without jumping
+    pub climb: f32,
+    // Steepest walkable slope in degrees
+    pub max_slope: f32,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            radius: 0.6,
+            height: 2.0,
+            climb: 1.0,
+            max_slope: 50.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OffMeshConfig {
+    pub start: [f32; 3],
+    pub end: [f32; 3],
+    #[serde(default = "default_off_mesh_radius")]
+    pub radius: f32,
+    #[serde(default)]
+    pub bidirectional: bool,
+}
+
+fn default_off_mesh_radius() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MapConfig {
+    pub off_mesh_connections: Vec<OffMeshConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MmapConfig {
+    pub agent: AgentConfig,
+    // Horizontal size of a heightfield cell, rounded so a whole number of
+    // cells covers a tile
+    pub cell_size: f32,
+    pub cell_height: f32,
+    // Largest polygon side in cells
+    pub max_poly_cells: usize,
+    // Height difference within a polygon before its detail mesh follows
+    // the surface instead of using the polygon's corners
+    pub detail_max_error: f32,
+    // Water shallower than this is walked through like ground
+    pub min_water_depth: f32,
+    pub maps: HashMap<u32, MapConfig>,
+}
+
+impl Default for MmapConfig {
+    fn default() -> Self {
+        Self {
+            agent: AgentConfig::default(),
+            cell_size: MAP_TILE_SIZE / 1000.0,
+            cell_height: 0.25,
+            max_poly_cells: 32,
+            detail_max_error: 0.5,
+            min_water_depth: 1.5,
+            maps: HashMap::new(),
+        }
+    }
+}
+
+impl MmapConfig {
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let config = match path {
+            Some(path) => {
+                let data = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                serde_json::from_str::<Self>(&data)
+                    .with_context(|| format!("Failed to parse {}", path.display()))?
+            }
+            None => Self::default(),
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.cell_size <= 0.0 || self.cell_height <= 0.0 {
+            return Err(anyhow!("Cell size and height must be positive"));
+        }
+
+        if self.agent.height <= 0.0 || self.agent.radius < 0.0 || self.agent.climb < 0.0 {
+            return Err(anyhow!(
+                "Agent height must be positive, radius and climb not negative"
+            ));
+        }
+
+        if !(0.0..90.0).contains(&self.agent.max_slope) {
+            return Err(anyhow!("Max slope must be between 0 and 90 degrees"));
+        }
+
+        if !(1..=MAX_POLY_CELLS).contains(&self.max_poly_cells) {
+            return Err(anyhow!(
+                "Max poly cells must be between 1 and {}",
+                MAX_POLY_CELLS
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn cells_per_tile(&self) -> usize {
+        (MAP_TILE_SIZE / self.cell_size).round().max(1.0) as usize
+    }
+
+    pub fn params(&self) -> NavMeshParams {
+        NavMeshParams {
+            walkable_height: self.agent.height,
+            walkable_radius: self.agent.radius,
+            walkable_climb: self.agent.climb,
+            cell_size: MAP_TILE_SIZE / self.cells_per_tile() as f32,
+            cell_height: self.cell_height,
+        }
+    }
+
+    // Connections are stored in the tile holding their start point
+    pub fn off_mesh_connections(
+        &self,
+        map_id: u32,
+        tile_x: usize,
+        tile_y: usize,
+    ) -> Vec<NavOffMeshConnection> {
+        let Some(map) = self.maps.get(&map_id) else {
+            return Vec::new();
+        };
+
+        map.off_mesh_connections
+            .iter()
+            .filter(|c| world_to_tile(c.start[0], c.start[1]) == (tile_x, tile_y))
+            .map(|c| NavOffMeshConnection {
+                start: Vec3::from_array(c.start),
+                end: Vec3::from_array(c.end),
+                radius: c.radius,
+                bidirectional: c.bidirectional,
+                area: NAV_AREA_GROUND,
+                flags: NAV_POLY_FLAG_WALK,
+            })
+            .collect()
+    }
+}
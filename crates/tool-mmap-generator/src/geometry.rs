@@ -0,0 +1,247 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tc_core::{
+    files::{
+        map::{
+            MAP_CELLS_PER_TILE, MAP_CHUNKS_PER_TILE, MAP_LIQUID_MAGMA, MAP_LIQUID_SLIME,
+            MAP_TILE_SIZE, MapTile, map_file_name, tile_world_origin,
+        },
+        navmesh::{NAV_AREA_GROUND, NAV_AREA_MAGMA, NAV_AREA_SLIME, NAV_AREA_WATER},
+        vmap::{
+            VmapModel, VmapPlacement, VmapPlacementSet, vmap_map_file_name, vmap_tile_file_name,
+        },
+    },
+    math::{Aabb, Vec3},
+};
+
+// Triangle soup of everything an agent can collide with on one tile, in
+// world space. Each triangle carries the area it marks when walkable
+#[derive(Default)]
+pub struct TileGeometry {
+    pub vertices: Vec<Vec3>,
+    pub triangles: Vec<[u32; 3]>,
+    pub areas: Vec<u8>,
+}
+
+impl TileGeometry {
+    pub fn is_empty(&self) -> bool {
+        self.triangles.is_empty()
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        Aabb::from_points(self.vertices.iter().copied())
+    }
+
+    fn push(&mut self, triangle: [Vec3; 3], area: u8) {
+        let base = self.vertices.len() as u32;
+        self.vertices.extend(triangle);
+        self.triangles.push([base, base + 1, base + 2]);
+        self.areas.push(area);
+    }
+}
+
+// World space bounds of a tile with unlimited height
+pub fn tile_bounds(tile_x: usize, tile_y: usize) -> Aabb {
+    let (x, y) = tile_world_origin(tile_x, tile_y);
+    Aabb::new(
+        Vec3::new(x - MAP_TILE_SIZE, y - MAP_TILE_SIZE, f32::MIN),
+        Vec3::new(x, y, f32::MAX),
+    )
+}
+
+// Terrain of a map tile, four triangles per cell fanning out from its
+// center height. Cells under liquid deeper than min_water_depth take the
+// liquid's area, magma and slime mark any depth
+fn add_terrain(
+    geometry: &mut TileGeometry,
+    tile: &MapTile,
+    tile_x: usize,
+    tile_y: usize,
+    min_water_depth: f32,
+) {
+    let (ox, oy) = tile_world_origin(tile_x, tile_y);
+    let cell = MAP_TILE_SIZE / MAP_CELLS_PER_TILE as f32;
+    let point = |x: f32, y: f32, z: f32| Vec3::new(ox - y * cell, oy - x * cell, z);
+
+    for cy in 0..MAP_CELLS_PER_TILE {
+        for cx in 0..MAP_CELLS_PER_TILE {
+            if tile.is_hole(cx, cy) {
+                continue;
+            }
+
+            let (x, y) = (cx as f32, cy as f32);
+            let corners = [
+                point(x, y, tile.height.v9(cx, cy)),
+                point(x + 1.0, y, tile.height.v9(cx + 1, cy)),
+                point(x + 1.0, y + 1.0, tile.height.v9(cx + 1, cy + 1)),
+                point(x, y + 1.0, tile.height.v9(cx, cy + 1)),
+            ];
+            let center = point(x + 0.5, y + 0.5, tile.height.v8(cx, cy));
+
+            let area = cell_area(tile, cx, cy, &corners, min_water_depth);
+            for i in 0..4 {
+                geometry.push([corners[i], corners[(i + 1) % 4], center], area);
+            }
+        }
+    }
+}
+
+fn cell_area(
+    tile: &MapTile,
+    cx: usize,
+    cy: usize,
+    corners: &[Vec3; 4],
+    min_water_depth: f32,
+) -> u8 {
+    let Some(liquid) = &tile.liquid else {
+        return NAV_AREA_GROUND;
+    };
+
+    let chunk = (cy / 8) * MAP_CHUNKS_PER_TILE + cx / 8;
+    let flags = liquid.flags[chunk];
+    let Some(level) = liquid.level_at(cx, cy).filter(|_| flags != 0) else {
+        return NAV_AREA_GROUND;
+    };
+
+    let floor = corners.iter().map(|c| c.z).fold(f32::MAX, f32::min);
+    if level <= floor {
+        return NAV_AREA_GROUND;
+    }
+
+    if flags & MAP_LIQUID_MAGMA != 0 {
+        NAV_AREA_MAGMA
+    } else if flags & MAP_LIQUID_SLIME != 0 {
+        NAV_AREA_SLIME
+    } else if level - floor >= min_water_depth {
+        NAV_AREA_WATER
+    } else {
+        NAV_AREA_GROUND
+    }
+}
+
+// Converted models read from the vmaps folder, kept for the whole run since
+// neighboring tiles place the same models over and over
+pub struct ModelCache {
+    directory: PathBuf,
+    models: Mutex<HashMap<String, Arc<VmapModel>>>,
+}
+
+impl ModelCache {
+    pub fn new(directory: PathBuf) -> Self {
+        Self {
+            directory,
+            models: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, name: &str) -> Result<Arc<VmapModel>> {
+        if let Some(model) = self.models.lock().unwrap().get(name) {
+            return Ok(Arc::clone(model));
+        }
+
+        let path = self.directory.join(name);
+        let data =
+            std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let model =
+            Arc::new(VmapModel::read(&data).with_context(|| format!("Failed to parse {}", name))?);
+
+        self.models
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), Arc::clone(&model));
+        Ok(model)
+    }
+
+    // Placements of a tile together with the map's global placements.
+    // Missing files mean the tile or map has no models
+    fn placements(&self, map_id: u32, tile_x: usize, tile_y: usize) -> Result<Vec<VmapPlacement>> {
+        let mut placements = Vec::new();
+        for name in [
+            vmap_map_file_name(map_id),
+            vmap_tile_file_name(map_id, tile_x, tile_y),
+        ] {
+            let path = self.directory.join(name);
+            if !path.exists() {
+                continue;
+            }
+
+            let data = std::fs::read(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let set = VmapPlacementSet::read(&data)
+                .with_context(|| format!("Failed to parse {}", path.display()))?;
+            placements.extend(set.placements);
+        }
+
+        Ok(placements)
+    }
+}
+
+fn overlaps_xy(a: &Aabb, b: &Aabb) -> bool {
+    a.min.x <= b.max.x && a.max.x >= b.min.x && a.min.y <= b.max.y && a.max.y >= b.min.y
+}
+
+// Model triangles reaching into the tile, taken to world space
+fn add_models(
+    geometry: &mut TileGeometry,
+    models: &ModelCache,
+    map_id: u32,
+    tile_x: usize,
+    tile_y: usize,
+) -> Result<()> {
+    let bounds = tile_bounds(tile_x, tile_y);
+    for placement in models.placements(map_id, tile_x, tile_y)? {
+        if !overlaps_xy(&placement.bounds, &bounds) {
+            continue;
+        }
+
+        let model = match models.get(&placement.model) {
+            Ok(model) => model,
+            Err(e) => {
+                tracing::warn!("Skipping placement {}: {:#}", placement.id, e);
+                continue;
+            }
+        };
+
+        for group in &model.groups {
+            let vertices = group
+                .vertices
+                .iter()
+                .map(|v| placement.rotation * (*v * placement.scale) + placement.position)
+                .collect::<Vec<_>>();
+
+            for triangle in &group.triangles {
+                let triangle = triangle.map(|i| vertices[i as usize]);
+                if overlaps_xy(&Aabb::from_points(triangle), &bounds) {
+                    geometry.push(triangle, NAV_AREA_GROUND);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn load_tile_geometry(
+    maps: &Path,
+    models: &ModelCache,
+    map_id: u32,
+    tile_x: usize,
+    tile_y: usize,
+    min_water_depth: f32,
+) -> Result<TileGeometry> {
+    let path = maps.join(map_file_name(map_id, tile_x, tile_y));
+    let data =
+        std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let tile =
+        MapTile::read(&data).with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    let mut geometry = TileGeometry::default();
+    add_terrain(&mut geometry, &tile, tile_x, tile_y, min_water_depth);
+    add_models(&mut geometry, models, map_id, tile_x, tile_y)?;
+
+    Ok(geometry)
+}
@@ -0,0 +1,403 @@
+use crate::geometry::TileGeometry;
+use tc_core::math::Vec3;
+
+const MAX_HEIGHT: i32 = u16::MAX as i32;
+
+// Neighbor offsets in the order of the navmesh tile sides: +x, +y, -x, -y
+pub const DIRECTIONS: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+
+// Solid range of a column in cell heights, the top is the surface an agent
+// stands on. Area zero marks spans that are not walkable
+#[derive(Debug, Clone, Copy)]
+pub struct Span {
+    pub min: u16,
+    pub max: u16,
+    pub area: u8,
+}
+
+// Voxelized tile geometry, columns of solid spans sorted bottom up. Column
+// x runs along world x and column y along world y from the origin
+pub struct Heightfield {
+    pub width: usize,
+    pub height: usize,
+    pub origin: Vec3,
+    pub cell_size: f32,
+    pub cell_height: f32,
+    pub columns: Vec<Vec<Span>>,
+}
+
+impl Heightfield {
+    pub fn new(
+        width: usize,
+        height: usize,
+        origin: Vec3,
+        cell_size: f32,
+        cell_height: f32,
+    ) -> Self {
+        Self {
+            width,
+            height,
+            origin,
+            cell_size,
+            cell_height,
+            columns: vec![Vec::new(); width * height],
+        }
+    }
+
+    pub fn column(&self, x: usize, y: usize) -> &[Span] {
+        &self.columns[y * self.width + x]
+    }
+
+    pub fn neighbor(&self, x: usize, y: usize, direction: usize) -> Option<(usize, usize)> {
+        let (dx, dy) = DIRECTIONS[direction];
+        let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+        (nx >= 0 && ny >= 0 && (nx as usize) < self.width && (ny as usize) < self.height)
+            .then_some((nx as usize, ny as usize))
+    }
+
+    // Inserts a span merging it with every span it touches. The merged span
+    // keeps the walkable area when both tops are within merge_threshold,
+    // otherwise the area of the higher top wins
+    fn add_span(&mut self, x: usize, y: usize, mut span: Span, merge_threshold: i32) {
+        let column = &mut self.columns[y * self.width + x];
+        let mut i = 0;
+        while i < column.len() {
+            let existing = column[i];
+            if existing.min > span.max {
+                break;
+            }
+
+            if existing.max < span.min {
+                i += 1;
+                continue;
+            }
+
+            if (existing.max as i32 - span.max as i32).abs() <= merge_threshold {
+                span.area = span.area.max(existing.area);
+            } else if existing.max > span.max {
+                span.area = existing.area;
+            }
+
+            span.min = span.min.min(existing.min);
+            span.max = span.max.max(existing.max);
+            column.remove(i);
+        }
+
+        column.insert(i, span);
+    }
+
+    // Triangles steeper than the walkable slope are rasterized as solid but
+    // unwalkable spans
+    pub fn rasterize(
+        &mut self,
+        geometry: &TileGeometry,
+        walkable_slope: f32,
+        merge_threshold: i32,
+    ) {
+        let min_normal_z = walkable_slope.to_radians().cos();
+        for (triangle, area) in geometry.triangles.iter().zip(&geometry.areas) {
+            let [a, b, c] = triangle.map(|i| geometry.vertices[i as usize]);
+            let normal = (b - a).cross(c - a).normalize();
+            let area = if normal.z.abs() >= min_normal_z {
+                *area
+            } else {
+                0
+            };
+
+            self.rasterize_triangle([a, b, c], area, merge_threshold);
+        }
+    }
+
+    fn rasterize_triangle(&mut self, triangle: [Vec3; 3], area: u8, merge_threshold: i32) {
+        let inv_cs = 1.0 / self.cell_size;
+        let inv_ch = 1.0 / self.cell_height;
+        let (w, h) = (self.width as i32, self.height as i32);
+
+        let min_y = triangle.iter().map(|v| v.y).fold(f32::MAX, f32::min);
+        let max_y = triangle.iter().map(|v| v.y).fold(f32::MIN, f32::max);
+        let y0 = (((min_y - self.origin.y) * inv_cs).floor() as i32).clamp(-1, h - 1);
+        let y1 = (((max_y - self.origin.y) * inv_cs).floor() as i32).clamp(-1, h - 1);
+        if y1 < 0 || ((min_y - self.origin.y) * inv_cs) >= h as f32 {
+            return;
+        }
+
+        // Cut the triangle into rows, then every row into cells
+        let mut rest = triangle.to_vec();
+        for y in y0..=y1 {
+            let edge = self.origin.y + (y + 1) as f32 * self.cell_size;
+            let (row, above) = divide_poly(&rest, 1, edge);
+            rest = above;
+            if row.len() < 3 || y < 0 {
+                continue;
+            }
+
+            let min_x = row.iter().map(|v| v.x).fold(f32::MAX, f32::min);
+            let max_x = row.iter().map(|v| v.x).fold(f32::MIN, f32::max);
+            let x0 = (((min_x - self.origin.x) * inv_cs).floor() as i32).clamp(-1, w - 1);
+            let x1 = (((max_x - self.origin.x) * inv_cs).floor() as i32).clamp(-1, w - 1);
+            if x1 < 0 || ((min_x - self.origin.x) * inv_cs) >= w as f32 {
+                continue;
+            }
+
+            let mut row_rest = row;
+            for x in x0..=x1 {
+                let edge = self.origin.x + (x + 1) as f32 * self.cell_size;
+                let (cell, right) = divide_poly(&row_rest, 0, edge);
+                row_rest = right;
+                if cell.len() < 3 || x < 0 {
+                    continue;
+                }
+
+                let min_z = cell.iter().map(|v| v.z).fold(f32::MAX, f32::min) - self.origin.z;
+                let max_z = cell.iter().map(|v| v.z).fold(f32::MIN, f32::max) - self.origin.z;
+                if max_z < 0.0 {
+                    continue;
+                }
+
+                let min = ((min_z * inv_ch).floor() as i32).clamp(0, MAX_HEIGHT);
+                let max = ((max_z * inv_ch).ceil() as i32).clamp(min + 1, MAX_HEIGHT);
+                if min >= MAX_HEIGHT {
+                    continue;
+                }
+
+                let span = Span {
+                    min: min as u16,
+                    max: max as u16,
+                    area,
+                };
+                self.add_span(x as usize, y as usize, span, merge_threshold);
+            }
+        }
+    }
+
+    // Lets agents step onto low obstacles like curbs or stairs lying on a
+    // walkable surface
+    pub fn filter_low_hanging_obstacles(&mut self, climb: i32) {
+        for column in &mut self.columns {
+            let mut previous: Option<Span> = None;
+            for span in column.iter_mut() {
+                let walkable = span.area != 0;
+                if let Some(previous) = previous
+                    && !walkable
+                    && previous.area != 0
+                    && (span.max as i32 - previous.max as i32).abs() <= climb
+                {
+                    span.area = previous.area;
+                }
+
+                // Keep the original flag so it does not climb up a stack
+                // of obstacles
+                previous = Some(Span {
+                    area: if walkable { span.area } else { 0 },
+                    ..*span
+                });
+            }
+        }
+    }
+
+    // Marks spans next to drops deeper than climb, or on a slope that is too
+    // steep across neighbors, as unwalkable. Columns past the tile border
+    // are left out since the neighboring tile continues the surface
+    pub fn filter_ledges(&mut self, walkable_height: i32, climb: i32) {
+        let mut unwalkable = Vec::new();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let column = self.column(x, y);
+                for (i, span) in column.iter().enumerate() {
+                    if span.area == 0 {
+                        continue;
+                    }
+
+                    let bottom = span.max as i32;
+                    let top = column.get(i + 1).map_or(MAX_HEIGHT, |s| s.min as i32);
+                    if self.is_ledge(x, y, bottom, top, walkable_height, climb) {
+                        unwalkable.push((y * self.width + x, i));
+                    }
+                }
+            }
+        }
+
+        for (column, i) in unwalkable {
+            self.columns[column][i].area = 0;
+        }
+    }
+
+    fn is_ledge(
+        &self,
+        x: usize,
+        y: usize,
+        bottom: i32,
+        top: i32,
+        walkable_height: i32,
+        climb: i32,
+    ) -> bool {
+        let mut lowest = MAX_HEIGHT;
+        let (mut accessible_min, mut accessible_max) = (bottom, bottom);
+
+        for direction in 0..4 {
+            let Some((nx, ny)) = self.neighbor(x, y, direction) else {
+                continue;
+            };
+
+            // The space below the first span counts as a floor far down
+            let column = self.column(nx, ny);
+            let neighbor_top = column.first().map_or(MAX_HEIGHT, |s| s.min as i32);
+            if top.min(neighbor_top) - bottom.max(-climb) > walkable_height {
+                lowest = lowest.min(-climb - bottom);
+            }
+
+            for (i, span) in column.iter().enumerate() {
+                let neighbor_bottom = span.max as i32;
+                let neighbor_top = column.get(i + 1).map_or(MAX_HEIGHT, |s| s.min as i32);
+                if top.min(neighbor_top) - bottom.max(neighbor_bottom) > walkable_height {
+                    lowest = lowest.min(neighbor_bottom - bottom);
+                    if (neighbor_bottom - bottom).abs() <= climb {
+                        accessible_min = accessible_min.min(neighbor_bottom);
+                        accessible_max = accessible_max.max(neighbor_bottom);
+                    }
+                }
+            }
+        }
+
+        lowest < -climb || accessible_max - accessible_min > climb
+    }
+
+    pub fn filter_low_height(&mut self, walkable_height: i32) {
+        for column in &mut self.columns {
+            for i in 0..column.len() {
+                let top = column.get(i + 1).map_or(MAX_HEIGHT, |s| s.min as i32);
+                if top - (column[i].max as i32) < walkable_height {
+                    column[i].area = 0;
+                }
+            }
+        }
+    }
+}
+
+// Splits a convex polygon at a plane along an axis, returning the parts
+// below and above it
+fn divide_poly(poly: &[Vec3], axis: usize, offset: f32) -> (Vec<Vec3>, Vec<Vec3>) {
+    let mut below = Vec::with_capacity(poly.len() + 2);
+    let mut above = Vec::with_capacity(poly.len() + 2);
+    let distance = poly.iter().map(|v| offset - v[axis]).collect::<Vec<_>>();
+
+    for i in 0..poly.len() {
+        let j = (i + poly.len() - 1) % poly.len();
+        let (dj, di) = (distance[j], distance[i]);
+        if (dj >= 0.0) != (di >= 0.0) {
+            let s = dj / (dj - di);
+            let point = poly[j] + (poly[i] - poly[j]) * s;
+            below.push(point);
+            above.push(point);
+
+            if di > 0.0 {
+                below.push(poly[i]);
+            } else if di < 0.0 {
+                above.push(poly[i]);
+            }
+        } else {
+            if di >= 0.0 {
+                below.push(poly[i]);
+                if di != 0.0 {
+                    continue;
+                }
+            }
+
+            above.push(poly[i]);
+        }
+    }
+
+    (below, above)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{geometry::TileGeometry, heightfield::Heightfield};
+    use tc_core::{files::navmesh::NAV_AREA_GROUND, math::Vec3};
+
+    // Cells are 1 wide and 0.5 high, so 2 cells of climb is 1 unit
+    const WALKABLE_HEIGHT: i32 = 4;
+    const CLIMB: i32 = 2;
+
+    // Axis aligned quad at height z as two triangles
+    fn quad(geometry: &mut TileGeometry, min: (f32, f32), max: (f32, f32), z: f32) {
+        let base = geometry.vertices.len() as u32;
+        geometry.vertices.extend([
+            Vec3::new(min.0, min.1, z),
+            Vec3::new(max.0, min.1, z),
+            Vec3::new(max.0, max.1, z),
+            Vec3::new(min.0, max.1, z),
+        ]);
+        geometry.triangles.push([base, base + 1, base + 2]);
+        geometry.triangles.push([base, base + 2, base + 3]);
+        geometry.areas.extend([NAV_AREA_GROUND; 2]);
+    }
+
+    fn heightfield(width: usize, geometry: &TileGeometry) -> Heightfield {
+        let mut heightfield = Heightfield::new(width, 4, Vec3::new(0.0, 0.0, 0.0), 1.0, 0.5);
+        heightfield.rasterize(geometry, 45.0, 1);
+        heightfield.filter_low_hanging_obstacles(CLIMB);
+        heightfield.filter_ledges(WALKABLE_HEIGHT, CLIMB);
+        heightfield.filter_low_height(WALKABLE_HEIGHT);
+        heightfield
+    }
+
+    // An 8 by 4 floor at z 10 with a raised second half on the right
+    fn step(height: f32) -> Heightfield {
+        let mut geometry = TileGeometry::default();
+        quad(&mut geometry, (0.0, 0.0), (8.0, 4.0), 10.0);
+        quad(&mut geometry, (4.0, 0.0), (8.0, 4.0), 10.0 + height);
+        heightfield(8, &geometry)
+    }
+
+    #[test]
+    fn test_rasterize_flat_quad() {
+        let mut geometry = TileGeometry::default();
+        quad(&mut geometry, (0.0, 0.0), (4.0, 4.0), 2.0);
+        let heightfield = heightfield(4, &geometry);
+
+        for column in &heightfield.columns {
+            assert_eq!(column.len(), 1);
+            assert_eq!((column[0].min, column[0].max), (4, 5));
+            assert_eq!(column[0].area, NAV_AREA_GROUND);
+        }
+    }
+
+    #[test]
+    fn test_rasterize_steep_triangle() {
+        let mut geometry = TileGeometry::default();
+        geometry.vertices.extend([
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(4.0, 0.0, 0.0),
+            Vec3::new(4.0, 4.0, 8.0),
+        ]);
+        geometry.triangles.push([0, 1, 2]);
+        geometry.areas.push(NAV_AREA_GROUND);
+
+        let mut heightfield = Heightfield::new(4, 4, Vec3::new(0.0, 0.0, 0.0), 1.0, 0.5);
+        heightfield.rasterize(&geometry, 45.0, 1);
+
+        let spans = heightfield.columns.iter().flatten().collect::<Vec<_>>();
+        assert!(!spans.is_empty());
+        assert!(spans.iter().all(|s| s.area == 0));
+    }
+
+    #[test]
+    fn test_step_taller_than_climb() {
+        // 3 units are 6 cells, the edge of the upper floor is a ledge while
+        // the lower floor stays walkable up to the step
+        let heightfield = step(3.0);
+        for y in 0..4 {
+            assert_ne!(heightfield.column(3, y)[0].area, 0);
+            assert_eq!(heightfield.column(4, y)[1].area, 0);
+            assert_ne!(heightfield.column(5, y)[1].area, 0);
+        }
+
+        // Half a unit is within climb and the edge can be stepped onto
+        let heightfield = step(0.5);
+        for y in 0..4 {
+            let column = heightfield.column(4, y);
+            assert_ne!(column.last().unwrap().area, 0);
+        }
+    }
+}
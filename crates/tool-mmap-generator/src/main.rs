@@ -1,3 +1,219 @@
-fn main() {
-    println!("Hello, world!");
+mod cli;
+mod compact;
+mod config;
+mod geometry;
+mod heightfield;
+mod polymesh;
+
+use crate::{
+    cli::CliArgs,
+    compact::CompactHeightfield,
+    config::MmapConfig,
+    geometry::{ModelCache, load_tile_geometry, tile_bounds},
+    heightfield::Heightfield,
+};
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tc_core::{
+    files::navmesh::{NavMeshTile, navmesh_tile_file_name},
+    math::Vec3,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+struct TileJob {
+    map_id: u32,
+    x: usize,
+    y: usize,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::fmt()
+        .without_time()
+        .with_level(false)
+        .with_target(false)
+        .with_file(false)
+        .init();
+
+    let args = CliArgs::parse();
+    let config = Arc::new(MmapConfig::load(args.config.as_deref().map(Path::new))?);
+    let input = PathBuf::from(&args.input);
+    let maps = input.join("maps");
+    let output = PathBuf::from(&args.output).join("mmaps");
+    tokio::fs::create_dir_all(&output).await?;
+
+    let jobs = find_tiles(&maps, &args.maps)?;
+    let threads = args
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+
+    let total = jobs.len();
+    tracing::info!(
+        "Generating navmesh for {} tiles using {} threads",
+        total,
+        threads
+    );
+
+    let models = Arc::new(ModelCache::new(input.join("vmaps")));
+    let semaphore = Arc::new(Semaphore::new(threads.max(1)));
+    let mut tasks = JoinSet::new();
+    for job in jobs {
+        let semaphore = Arc::clone(&semaphore);
+        let models = Arc::clone(&models);
+        let config = Arc::clone(&config);
+        let maps = maps.clone();
+        let output = output.clone();
+
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            let name = format!("{:04}_{:02}_{:02}", job.map_id, job.x, job.y);
+            tokio::task::spawn_blocking(move || {
+                generate_tile(&maps, &models, &config, &job, &output)
+            })
+            .await?
+            .with_context(|| format!("Failed to generate {}", name))
+        });
+    }
+
+    let mut done = 0;
+    let mut failed = 0;
+    let mut polys = 0;
+    let mut last_percent = 0;
+    while let Some(result) = tasks.join_next().await {
+        done += 1;
+        match result? {
+            Ok(count) => polys += count,
+            Err(e) => {
+                failed += 1;
+                tracing::error!("{:#}", e);
+            }
+        }
+
+        let percent = done * 100 / total.max(1);
+        if percent != last_percent || done == total {
+            last_percent = percent;
+            tracing::info!("[{:>3}%] {}/{} tiles", percent, done, total);
+        }
+    }
+
+    tracing::info!(
+        "Generated {} tiles with {} polygons, {} failed",
+        done - failed,
+        polys,
+        failed
+    );
+    Ok(())
+}
+
+// Tiles with an extracted map file, parsed from the map_file_name format
+fn find_tiles(maps: &Path, filter: &[u32]) -> Result<Vec<TileJob>> {
+    let mut jobs = Vec::new();
+    let entries =
+        std::fs::read_dir(maps).with_context(|| format!("Failed to read {}", maps.display()))?;
+
+    for entry in entries {
+        let name = entry?.file_name();
+        let Some(stem) = name.to_str().and_then(|n| n.strip_suffix(".map")) else {
+            continue;
+        };
+
+        let parts = stem
+            .split('_')
+            .map(|p| p.parse::<usize>().ok())
+            .collect::<Option<Vec<_>>>();
+        let Some(&[map_id, x, y]) = parts.as_deref() else {
+            continue;
+        };
+
+        if filter.is_empty() || filter.contains(&(map_id as u32)) {
+            jobs.push(TileJob {
+                map_id: map_id as u32,
+                x,
+                y,
+            });
+        }
+    }
+
+    jobs.sort_by_key(|j| (j.map_id, j.x, j.y));
+    Ok(jobs)
+}
+
+// Builds and writes the navmesh of one tile, returning its polygon count.
+// Tiles without anything walkable are not written
+fn generate_tile(
+    maps: &Path,
+    models: &ModelCache,
+    config: &MmapConfig,
+    job: &TileJob,
+    output: &Path,
+) -> Result<usize> {
+    let geometry = load_tile_geometry(
+        maps,
+        models,
+        job.map_id,
+        job.x,
+        job.y,
+        config.min_water_depth,
+    )?;
+
+    let params = config.params();
+    let walkable_height = (params.walkable_height / params.cell_height).ceil() as i32;
+    let climb = (params.walkable_climb / params.cell_height).floor() as i32;
+    let radius = (params.walkable_radius / params.cell_size).ceil() as u32;
+
+    let off_mesh_connections = config.off_mesh_connections(job.map_id, job.x, job.y);
+    let mesh = if geometry.is_empty() {
+        None
+    } else {
+        let tile = tile_bounds(job.x, job.y);
+        let bounds = geometry.bounds();
+        let cells = config.cells_per_tile();
+        let origin = Vec3::new(tile.min.x, tile.min.y, bounds.min.z);
+
+        let mut heightfield =
+            Heightfield::new(cells, cells, origin, params.cell_size, params.cell_height);
+        heightfield.rasterize(&geometry, config.agent.max_slope, climb);
+        heightfield.filter_low_hanging_obstacles(climb);
+        heightfield.filter_ledges(walkable_height, climb);
+        heightfield.filter_low_height(walkable_height);
+
+        let mut compact = CompactHeightfield::build(&heightfield, walkable_height, climb);
+        drop(heightfield);
+        compact.erode(radius);
+
+        Some(polymesh::build(
+            &compact,
+            config.max_poly_cells,
+            params.walkable_climb,
+            config.detail_max_error,
+        ))
+    };
+
+    let Some(mesh) = mesh.filter(|m| !m.polys.is_empty() || !off_mesh_connections.is_empty())
+    else {
+        return Ok(0);
+    };
+
+    let count = mesh.polys.len();
+    let tile = NavMeshTile {
+        map_id: job.map_id,
+        tile_x: job.x as u32,
+        tile_y: job.y as u32,
+        params,
+        vertices: mesh.vertices,
+        polys: mesh.polys,
+        details: mesh.details,
+        off_mesh_connections,
+    };
+
+    std::fs::write(
+        output.join(navmesh_tile_file_name(job.map_id, job.x, job.y)),
+        tile.write(),
+    )?;
+
+    Ok(count)
 }
@@ -0,0 +1,374 @@
+use crate::compact::CompactHeightfield;
+use std::collections::HashMap;
+use tc_core::{
+    files::navmesh::{
+        NAV_AREA_WATER, NAV_NEIGHBOR_BORDER, NAV_NEIGHBOR_NONE, NAV_POLY_FLAG_SWIM,
+        NAV_POLY_FLAG_WALK, NavPoly, NavPolyDetail,
+    },
+    math::Vec3,
+};
+
+const UNASSIGNED: u32 = u32::MAX;
+
+// Detail meshes sample at most this many points along each polygon side
+const MAX_DETAIL_SAMPLES: usize = 9;
+
+// Rectangle of cells whose spans are all connected to each other and share
+// an area, spans are stored row by row
+struct Rect {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    area: u8,
+    spans: Vec<usize>,
+}
+
+impl Rect {
+    fn span(&self, x: usize, y: usize) -> usize {
+        self.spans[y * self.width + x]
+    }
+}
+
+pub struct PolyMesh {
+    pub vertices: Vec<Vec3>,
+    pub polys: Vec<NavPoly>,
+    pub details: Vec<NavPolyDetail>,
+}
+
+// Covers the walkable spans with convex polygons by greedily growing
+// rectangles, then splits the rectangle outlines wherever the neighbor
+// across them changes so every edge maps to a single neighbor
+pub fn build(
+    compact: &CompactHeightfield,
+    max_cells: usize,
+    climb: f32,
+    detail_max_error: f32,
+) -> PolyMesh {
+    let mut owner = vec![UNASSIGNED; compact.spans.len()];
+    let mut rects = Vec::new();
+
+    for y in 0..compact.height {
+        for x in 0..compact.width {
+            for span in compact.span_range(x, y) {
+                if compact.areas[span] == 0 || owner[span] != UNASSIGNED {
+                    continue;
+                }
+
+                let rect = grow_rect(compact, &owner, x, y, span, max_cells);
+                for span in &rect.spans {
+                    owner[*span] = rects.len() as u32;
+                }
+
+                rects.push(rect);
+            }
+        }
+    }
+
+    let mut mesh = PolyMesh {
+        vertices: Vec::new(),
+        polys: Vec::with_capacity(rects.len()),
+        details: Vec::with_capacity(rects.len()),
+    };
+    let mut lookup: HashMap<(usize, usize), Vec<u32>> = HashMap::new();
+
+    for rect in &rects {
+        let outline = outline(compact, &owner, rect);
+        let mut poly = NavPoly {
+            vertices: Vec::with_capacity(outline.len()),
+            neighbors: Vec::with_capacity(outline.len()),
+            area: rect.area,
+            flags: if rect.area == NAV_AREA_WATER {
+                NAV_POLY_FLAG_SWIM
+            } else {
+                NAV_POLY_FLAG_WALK
+            },
+        };
+
+        // Corners shared between polygons on the same floor share a vertex
+        for (corner, z, neighbor) in outline {
+            let point = Vec3::new(
+                compact.origin.x + corner.0 as f32 * compact.cell_size,
+                compact.origin.y + corner.1 as f32 * compact.cell_size,
+                z,
+            );
+
+            let candidates = lookup.entry(corner).or_default();
+            let vertex = match candidates
+                .iter()
+                .find(|v| (mesh.vertices[**v as usize].z - z).abs() <= climb)
+            {
+                Some(vertex) => *vertex,
+                None => {
+                    mesh.vertices.push(point);
+                    candidates.push(mesh.vertices.len() as u32 - 1);
+                    mesh.vertices.len() as u32 - 1
+                }
+            };
+
+            poly.vertices.push(vertex);
+            poly.neighbors.push(neighbor);
+        }
+
+        mesh.details.push(detail(compact, rect, detail_max_error));
+        mesh.polys.push(poly);
+    }
+
+    mesh
+}
+
+fn grow_rect(
+    compact: &CompactHeightfield,
+    owner: &[u32],
+    x: usize,
+    y: usize,
+    span: usize,
+    max_cells: usize,
+) -> Rect {
+    let area = compact.areas[span];
+    let free = |span: usize| owner[span] == UNASSIGNED && compact.areas[span] == area;
+
+    let mut row = vec![span];
+    while row.len() < max_cells {
+        match compact.connection(*row.last().unwrap(), 0) {
+            Some(next) if free(next) => row.push(next),
+            _ => break,
+        }
+    }
+
+    let width = row.len();
+    let mut spans = row.clone();
+    let mut height = 1;
+    'grow: while height < max_cells {
+        let mut next_row = Vec::with_capacity(width);
+        for (i, span) in row.iter().enumerate() {
+            let Some(next) = compact.connection(*span, 1).filter(|n| free(*n)) else {
+                break 'grow;
+            };
+
+            // The new row has to be connected along itself as well
+            if i > 0 && compact.connection(next_row[i - 1], 0) != Some(next) {
+                break 'grow;
+            }
+
+            next_row.push(next);
+        }
+
+        spans.extend_from_slice(&next_row);
+        row = next_row;
+        height += 1;
+    }
+
+    Rect {
+        x,
+        y,
+        width,
+        height,
+        area,
+        spans,
+    }
+}
+
+// Neighbor value across the side of a rectangle cell
+fn neighbor(
+    compact: &CompactHeightfield,
+    owner: &[u32],
+    span: usize,
+    x: usize,
+    y: usize,
+    direction: usize,
+) -> u32 {
+    match compact.connection(span, direction) {
+        Some(other) if owner[other] != UNASSIGNED => owner[other] + 1,
+        Some(_) => NAV_NEIGHBOR_NONE,
+        None if compact.neighbor(x, y, direction).is_none() => {
+            NAV_NEIGHBOR_BORDER | direction as u32
+        }
+        None => NAV_NEIGHBOR_NONE,
+    }
+}
+
+// Outline of a rectangle counter clockwise from its lowest corner as grid
+// corners with a height and the neighbor across the edge starting there
+fn outline(
+    compact: &CompactHeightfield,
+    owner: &[u32],
+    rect: &Rect,
+) -> Vec<((usize, usize), f32, u32)> {
+    let (w, h) = (rect.width, rect.height);
+
+    // Each unit step of the outline as start corner, cell inside the
+    // rectangle and the direction facing out of it
+    let steps = (0..w)
+        .map(|i| ((rect.x + i, rect.y), (i, 0), 3))
+        .chain((0..h).map(|j| ((rect.x + w, rect.y + j), (w - 1, j), 0)))
+        .chain(
+            (0..w)
+                .rev()
+                .map(|i| ((rect.x + i + 1, rect.y + h), (i, h - 1), 1)),
+        )
+        .chain((0..h).rev().map(|j| ((rect.x, rect.y + j + 1), (0, j), 2)));
+
+    let mut outline: Vec<((usize, usize), f32, u32)> = Vec::new();
+    let mut previous = None;
+    for (corner, (cx, cy), direction) in steps {
+        let span = rect.span(cx, cy);
+        let value = neighbor(compact, owner, span, rect.x + cx, rect.y + cy, direction);
+
+        // New vertices at the rectangle corners and wherever the neighbor
+        // changes along a side
+        if previous != Some((direction, value)) {
+            outline.push((corner, compact.floor_z(span), value));
+        }
+
+        previous = Some((direction, value));
+    }
+
+    outline
+}
+
+// Surface samples over the rectangle, reduced to its four corners when the
+// surface stays within max_error of a flat floor
+fn detail(compact: &CompactHeightfield, rect: &Rect, max_error: f32) -> NavPolyDetail {
+    let heights = rect
+        .spans
+        .iter()
+        .map(|s| compact.floor_z(*s))
+        .collect::<Vec<_>>();
+    let min = heights.iter().copied().fold(f32::MAX, f32::min);
+    let max = heights.iter().copied().fold(f32::MIN, f32::max);
+
+    let samples = |size: usize| -> Vec<usize> {
+        if max - min <= max_error {
+            return vec![0, size];
+        }
+
+        let step = size.div_ceil(MAX_DETAIL_SAMPLES - 1).max(1);
+        let mut samples = (0..size).step_by(step).collect::<Vec<_>>();
+        samples.push(size);
+        samples
+    };
+
+    let (xs, ys) = (samples(rect.width), samples(rect.height));
+    let mut detail = NavPolyDetail::default();
+    for gy in &ys {
+        for gx in &xs {
+            let (cx, cy) = ((*gx).min(rect.width - 1), (*gy).min(rect.height - 1));
+            detail.vertices.push(Vec3::new(
+                compact.origin.x + (rect.x + gx) as f32 * compact.cell_size,
+                compact.origin.y + (rect.y + gy) as f32 * compact.cell_size,
+                heights[cy * rect.width + cx],
+            ));
+        }
+    }
+
+    let row = xs.len();
+    for j in 0..ys.len() - 1 {
+        for i in 0..row - 1 {
+            let a = (j * row + i) as u8;
+            let b = a + 1;
+            let c = ((j + 1) * row + i + 1) as u8;
+            let d = c - 1;
+            detail.triangles.push([a, b, c]);
+            detail.triangles.push([a, c, d]);
+        }
+    }
+
+    detail
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compact::CompactHeightfield,
+        heightfield::{Heightfield, Span},
+        polymesh::build,
+    };
+    use tc_core::{
+        files::navmesh::{
+            NAV_AREA_GROUND, NAV_NEIGHBOR_BORDER, NAV_NEIGHBOR_NONE, NAV_POLY_FLAG_WALK,
+        },
+        math::Vec3,
+    };
+
+    // A 4 by 4 field of single spans with their tops at floor(x, y) cells
+    fn compact(floor: impl Fn(usize, usize) -> u16) -> CompactHeightfield {
+        let mut heightfield = Heightfield::new(4, 4, Vec3::new(0.0, 0.0, 0.0), 1.0, 0.5);
+        for y in 0..4 {
+            for x in 0..4 {
+                heightfield.columns[y * 4 + x].push(Span {
+                    min: 0,
+                    max: floor(x, y),
+                    area: NAV_AREA_GROUND,
+                });
+            }
+        }
+
+        CompactHeightfield::build(&heightfield, 4, 2)
+    }
+
+    #[test]
+    fn test_flat_quad() {
+        let mesh = build(&compact(|_, _| 10), 16, 1.0, 0.5);
+        assert_eq!(mesh.polys.len(), 1);
+
+        let poly = &mesh.polys[0];
+        assert_eq!(poly.area, NAV_AREA_GROUND);
+        assert_eq!(poly.flags, NAV_POLY_FLAG_WALK);
+        assert_eq!(
+            poly.neighbors,
+            [3, 0, 1, 2].map(|side| NAV_NEIGHBOR_BORDER | side)
+        );
+
+        let corners = poly
+            .vertices
+            .iter()
+            .map(|v| mesh.vertices[*v as usize])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            corners,
+            [(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (0.0, 4.0)].map(|(x, y)| Vec3::new(x, y, 5.0))
+        );
+
+        // A flat floor needs no detail beyond its corners
+        assert_eq!(mesh.details[0].vertices.len(), 4);
+        assert_eq!(mesh.details[0].triangles.len(), 2);
+    }
+
+    #[test]
+    fn test_split_by_max_cells() {
+        let mesh = build(&compact(|_, _| 10), 2, 1.0, 0.5);
+        assert_eq!(mesh.polys.len(), 4);
+
+        // Neighboring rectangles share their corners, a 3 by 3 grid
+        assert_eq!(mesh.vertices.len(), 9);
+        for poly in &mesh.polys {
+            let inner = poly
+                .neighbors
+                .iter()
+                .filter(|n| **n != NAV_NEIGHBOR_NONE && *n & NAV_NEIGHBOR_BORDER == 0)
+                .count();
+            assert_eq!(inner, 2);
+        }
+    }
+
+    #[test]
+    fn test_step_taller_than_climb() {
+        // 6 cells are 3 units, not connected and no vertices shared
+        let mesh = build(&compact(|x, _| if x < 2 { 10 } else { 16 }), 16, 1.0, 0.5);
+        assert_eq!(mesh.polys.len(), 2);
+        assert_eq!(mesh.vertices.len(), 8);
+        assert!(mesh.polys.iter().all(|p| {
+            p.neighbors
+                .iter()
+                .all(|n| *n & NAV_NEIGHBOR_BORDER != 0 || *n == NAV_NEIGHBOR_NONE)
+        }));
+
+        let heights = mesh
+            .polys
+            .iter()
+            .map(|p| mesh.vertices[p.vertices[0] as usize].z)
+            .collect::<Vec<_>>();
+        assert_eq!(heights, [5.0, 8.0]);
+    }
+}
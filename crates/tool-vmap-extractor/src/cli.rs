@@ -0,0 +1,45 @@
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct CliArgs {
+    #[arg(
+        short('i'),
+        long("input"),
+        env("TC_CLIENT_DIR"),
+        help("Client directory containing the Data folder"),
+        default_value = "."
+    )]
+    pub input: String,
+
+    #[arg(
+        short('o'),
+        long("output"),
+        env("TC_DATA_DIR"),
+        help("Directory the vmaps folder is written to"),
+        default_value = "."
+    )]
+    pub output: String,
+
+    #[arg(
+        short('l'),
+        long("locale"),
+        help("Client locale to read DBCs from, detected from the Data folder when omitted")
+    )]
+    pub locale: Option<String>,
+
+    #[arg(
+        short('m'),
+        long("maps"),
+        value_delimiter(','),
+        help("Comma separated list of map ids to extract, all maps when omitted")
+    )]
+    pub maps: Vec<u32>,
+
+    #[arg(
+        short('t'),
+        long("threads"),
+        help("Number of tiles processed in parallel, defaults to the number of cores")
+    )]
+    pub threads: Option<usize>,
+}
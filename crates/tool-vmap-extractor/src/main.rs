@@ -1,3 +1,183 @@
-fn main() {
-    println!("Hello, world!");
+mod cli;
+mod model;
+mod placement;
+
+use crate::{cli::CliArgs, model::ModelCache};
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::{path::PathBuf, sync::Arc};
+use tc_core::files::{
+    adt::AdtFile,
+    dbc::DbcFile,
+    mpq::MpqArchiveSet,
+    vmap::{VmapPlacementSet, vmap_map_file_name, vmap_tile_file_name},
+    wdt::{WdtFile, adt_path, wdt_path},
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+const MAP_DBC_ID: usize = 0;
+const MAP_DBC_DIRECTORY: usize = 1;
+
+struct TileJob {
+    map_id: u32,
+    directory: String,
+    x: usize,
+    y: usize,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::fmt()
+        .without_time()
+        .with_level(false)
+        .with_target(false)
+        .with_file(false)
+        .init();
+
+    let args = CliArgs::parse();
+    let input = PathBuf::from(&args.input).join("Data");
+    let output = PathBuf::from(&args.output).join("vmaps");
+    tokio::fs::create_dir_all(&output).await?;
+
+    let archives = Arc::new(MpqArchiveSet::open_client_data(
+        &input,
+        args.locale.as_deref(),
+    )?);
+
+    let threads = args
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+
+    let models = Arc::new(ModelCache::new(Arc::clone(&archives), output.clone()));
+    extract_placements(archives, &models, &args.maps, output, threads).await?;
+
+    tracing::info!("Extracted {} models", models.len());
+    Ok(())
+}
+
+async fn extract_placements(
+    archives: Arc<MpqArchiveSet>,
+    models: &Arc<ModelCache>,
+    filter: &[u32],
+    output: PathBuf,
+    threads: usize,
+) -> Result<()> {
+    let dbc = DbcFile::parse(&archives.read_required("DBFilesClient\\Map.dbc")?)?;
+    let maps = dbc
+        .records()
+        .map(|r| (r.get_u32(MAP_DBC_ID), r.get_string(MAP_DBC_DIRECTORY)))
+        .filter(|(id, _)| filter.is_empty() || filter.contains(id))
+        .collect::<Vec<_>>();
+
+    let mut jobs = Vec::new();
+    for (map_id, directory) in &maps {
+        let wdt = match archives.read_file(&wdt_path(directory))? {
+            Some(data) => WdtFile::parse(&data)
+                .with_context(|| format!("Failed to parse WDT for map {}", map_id))?,
+            None => continue,
+        };
+
+        // Every map gets a map file so the runtime can tell maps without
+        // any models apart from maps that were never extracted
+        let global = match (&wdt.wmo_name, &wdt.wmo) {
+            (Some(name), Some(wmo)) => {
+                let models = Arc::clone(models);
+                let names = vec![name.clone()];
+                let wmos = vec![wmo.clone()];
+                tokio::task::spawn_blocking(move || {
+                    placement::wmo_placements(&models, &names, &wmos)
+                })
+                .await?
+            }
+            _ => Vec::new(),
+        };
+
+        tokio::fs::write(
+            output.join(vmap_map_file_name(*map_id)),
+            VmapPlacementSet { placements: global }.write(),
+        )
+        .await?;
+
+        jobs.extend(wdt.tiles().map(|(x, y)| TileJob {
+            map_id: *map_id,
+            directory: directory.clone(),
+            x,
+            y,
+        }));
+    }
+
+    let total = jobs.len();
+    tracing::info!(
+        "Extracting placements from {} tiles across {} maps using {} threads",
+        total,
+        maps.len(),
+        threads
+    );
+
+    let semaphore = Arc::new(Semaphore::new(threads.max(1)));
+    let mut tasks = JoinSet::new();
+    for job in jobs {
+        let semaphore = Arc::clone(&semaphore);
+        let archives = Arc::clone(&archives);
+        let models = Arc::clone(models);
+        let output = output.clone();
+
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            let name = format!("{}_{}_{}", job.directory, job.x, job.y);
+            tokio::task::spawn_blocking(move || extract_tile(&archives, &models, &job, &output))
+                .await?
+                .with_context(|| format!("Failed to extract {}", name))
+        });
+    }
+
+    let mut done = 0;
+    let mut failed = 0;
+    let mut last_percent = 0;
+    while let Some(result) = tasks.join_next().await {
+        done += 1;
+        if let Err(e) = result? {
+            failed += 1;
+            tracing::error!("{:#}", e);
+        }
+
+        let percent = done * 100 / total.max(1);
+        if percent != last_percent || done == total {
+            last_percent = percent;
+            tracing::info!("[{:>3}%] {}/{} tiles", percent, done, total);
+        }
+    }
+
+    tracing::info!("Extracted {} tiles, {} failed", done - failed, failed);
+    Ok(())
+}
+
+fn extract_tile(
+    archives: &MpqArchiveSet,
+    models: &ModelCache,
+    job: &TileJob,
+    output: &std::path::Path,
+) -> Result<()> {
+    let data = archives.read_required(&adt_path(&job.directory, job.x, job.y))?;
+    let adt = AdtFile::parse(&data)?;
+
+    let mut placements = placement::wmo_placements(models, &adt.wmo_names, &adt.wmos);
+    placements.extend(placement::doodad_placements(
+        models,
+        &adt.doodad_names,
+        &adt.doodads,
+    ));
+
+    // Tiles without models are not written, a missing tile file reads as
+    // an empty tile at runtime
+    if placements.is_empty() {
+        return Ok(());
+    }
+
+    std::fs::write(
+        output.join(vmap_tile_file_name(job.map_id, job.x, job.y)),
+        VmapPlacementSet { placements }.write(),
+    )?;
+
+    Ok(())
 }
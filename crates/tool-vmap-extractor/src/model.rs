@@ -0,0 +1,400 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+};
+use tc_core::{
+    files::{
+        m2::M2Collision,
+        mpq::MpqArchiveSet,
+        vmap::{
+            VMAP_LIQUID_TILE_SIZE, VmapGroup, VmapLiquid, VmapModel, VmapModelKind,
+            vmap_model_file_name,
+        },
+        wmo::{WmoGroup, WmoRoot, wmo_group_path},
+    },
+    math::{Aabb, Vec3},
+};
+
+// What placements need to know about an extracted model
+pub struct ModelInfo {
+    pub kind: VmapModelKind,
+    // None for WMOs without any collision of their own
+    pub file_name: Option<String>,
+    pub bounds: Aabb,
+    // Kept for WMOs so placements can expand their doodad sets
+    pub root: Option<WmoRoot>,
+}
+
+type ModelSlot = Arc<OnceLock<Option<Arc<ModelInfo>>>>;
+
+// Extracts every model the first time a placement references it. Tiles are
+// processed in parallel so concurrent requests for the same model wait on
+// the first extraction instead of repeating it
+pub struct ModelCache {
+    archives: Arc<MpqArchiveSet>,
+    output: PathBuf,
+    models: Mutex<HashMap<String, ModelSlot>>,
+}
+
+impl ModelCache {
+    pub fn new(archives: Arc<MpqArchiveSet>, output: PathBuf) -> Self {
+        Self {
+            archives,
+            output,
+            models: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.models
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|s| matches!(s.get(), Some(Some(info)) if info.file_name.is_some()))
+            .count()
+    }
+
+    // Returns None for models that are missing or have no collision
+    pub fn get(&self, path: &str, kind: VmapModelKind) -> Option<Arc<ModelInfo>> {
+        let slot = Arc::clone(
+            self.models
+                .lock()
+                .unwrap()
+                .entry(path.to_ascii_lowercase())
+                .or_default(),
+        );
+
+        slot.get_or_init(|| match self.extract(path, kind) {
+            Ok(info) => info.map(Arc::new),
+            Err(e) => {
+                tracing::warn!("Skipping model {}: {:#}", path, e);
+                None
+            }
+        })
+        .clone()
+    }
+
+    fn extract(&self, path: &str, kind: VmapModelKind) -> Result<Option<ModelInfo>> {
+        let (model, root) = match kind {
+            VmapModelKind::M2 => (convert_m2(&self.archives, path)?, None),
+            VmapModelKind::Wmo => {
+                let (model, root) = convert_wmo(&self.archives, path)?;
+                (model, Some(root))
+            }
+        };
+
+        // WMOs without collision are still needed for their doodads
+        let bounds = match &model {
+            Some(model) => model.bounds,
+            None if root.is_some() => Aabb::EMPTY,
+            None => return Ok(None),
+        };
+
+        let file_name = match &model {
+            Some(model) => {
+                let file_name = vmap_model_file_name(path);
+                std::fs::write(self.output.join(&file_name), model.write())
+                    .with_context(|| format!("Failed to write {}", file_name))?;
+
+                Some(file_name)
+            }
+            None => None,
+        };
+
+        Ok(Some(ModelInfo {
+            kind,
+            file_name,
+            bounds,
+            root,
+        }))
+    }
+}
+
+pub fn convert_m2(archives: &MpqArchiveSet, path: &str) -> Result<Option<VmapModel>> {
+    let collision = M2Collision::parse(&archives.read_required(path)?)?;
+    Ok(m2_model(&collision))
+}
+
+fn m2_model(collision: &M2Collision) -> Option<VmapModel> {
+    if collision.is_empty() {
+        return None;
+    }
+
+    let triangles = collision
+        .indices
+        .chunks_exact(3)
+        .map(|t| [t[0] as u32, t[1] as u32, t[2] as u32])
+        .filter(|t| t.iter().all(|i| (*i as usize) < collision.vertices.len()));
+
+    let (vertices, triangles) = compact(&collision.vertices, triangles);
+    if triangles.is_empty() {
+        return None;
+    }
+
+    let bounds = Aabb::from_points(vertices.iter().copied());
+    Some(VmapModel {
+        kind: VmapModelKind::M2,
+        flags: 0,
+        root_id: 0,
+        bounds,
+        groups: vec![VmapGroup {
+            flags: 0,
+            group_id: 0,
+            bounds,
+            vertices,
+            triangles,
+            liquid: None,
+        }],
+    })
+}
+
+pub fn convert_wmo(archives: &MpqArchiveSet, path: &str) -> Result<(Option<VmapModel>, WmoRoot)> {
+    let root = WmoRoot::parse(&archives.read_required(path)?)?;
+
+    let mut groups = Vec::with_capacity(root.group_count as usize);
+    for index in 0..root.group_count {
+        let group_path = wmo_group_path(path, index);
+        groups.push(
+            WmoGroup::parse(&archives.read_required(&group_path)?)
+                .with_context(|| format!("Failed to parse {}", group_path))?,
+        );
+    }
+
+    Ok((wmo_model(&root, &groups), root))
+}
+
+fn wmo_model(root: &WmoRoot, wmo_groups: &[WmoGroup]) -> Option<VmapModel> {
+    let mut groups = Vec::with_capacity(wmo_groups.len());
+    for group in wmo_groups {
+        let triangles = group
+            .collision_triangles()
+            .map(|t| [t[0] as u32, t[1] as u32, t[2] as u32])
+            .filter(|t| t.iter().all(|i| (*i as usize) < group.vertices.len()));
+
+        let (vertices, triangles) = compact(&group.vertices, triangles);
+        let liquid = group.liquid.as_ref().and_then(|liquid| {
+            let liquid_type = group.liquid_type(root.flags);
+            let covered =
+                (0..liquid.tiles_y).any(|y| (0..liquid.tiles_x).any(|x| liquid.has_tile(x, y)));
+
+            (liquid_type != 0 && covered).then(|| VmapLiquid {
+                liquid_type,
+                tiles_x: liquid.tiles_x,
+                tiles_y: liquid.tiles_y,
+                corner: Vec3::from_array(liquid.corner),
+                heights: liquid.heights.clone(),
+                flags: liquid.flags.clone(),
+            })
+        });
+
+        if triangles.is_empty() && liquid.is_none() {
+            continue;
+        }
+
+        let mut bounds = Aabb::from_points(vertices.iter().copied());
+        if let Some(liquid) = &liquid {
+            bounds = bounds.merge(&liquid_bounds(liquid));
+        }
+
+        groups.push(VmapGroup {
+            flags: group.flags,
+            group_id: group.group_id,
+            bounds,
+            vertices,
+            triangles,
+            liquid,
+        });
+    }
+
+    if groups.is_empty() {
+        return None;
+    }
+
+    let bounds = groups
+        .iter()
+        .fold(Aabb::EMPTY, |bounds, g| bounds.merge(&g.bounds));
+
+    Some(VmapModel {
+        kind: VmapModelKind::Wmo,
+        flags: root.flags as u32,
+        root_id: root.wmo_id,
+        bounds,
+        groups,
+    })
+}
+
+fn liquid_bounds(liquid: &VmapLiquid) -> Aabb {
+    let (min, max) = liquid
+        .heights
+        .iter()
+        .fold((f32::MAX, f32::MIN), |(min, max), h| {
+            (min.min(*h), max.max(*h))
+        });
+
+    let size = Vec3::new(
+        liquid.tiles_x as f32 * VMAP_LIQUID_TILE_SIZE,
+        liquid.tiles_y as f32 * VMAP_LIQUID_TILE_SIZE,
+        0.0,
+    );
+
+    Aabb::new(
+        Vec3::new(liquid.corner.x, liquid.corner.y, min),
+        Vec3::new(liquid.corner.x + size.x, liquid.corner.y + size.y, max),
+    )
+}
+
+// Drops degenerate triangles and vertices no triangle references
+fn compact(
+    vertices: &[[f32; 3]],
+    triangles: impl Iterator<Item = [u32; 3]>,
+) -> (Vec<Vec3>, Vec<[u32; 3]>) {
+    let mut remap = vec![u32::MAX; vertices.len()];
+    let mut used = Vec::new();
+    let mut out = Vec::new();
+
+    for triangle in triangles {
+        if triangle[0] == triangle[1] || triangle[1] == triangle[2] || triangle[0] == triangle[2] {
+            continue;
+        }
+
+        out.push(triangle.map(|i| {
+            let slot = &mut remap[i as usize];
+            if *slot == u32::MAX {
+                *slot = used.len() as u32;
+                used.push(Vec3::from_array(vertices[i as usize]));
+            }
+
+            *slot
+        }));
+    }
+
+    (used, out)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::model::{m2_model, wmo_model};
+    use tc_core::{
+        files::{
+            m2::M2Collision,
+            vmap::{VmapModel, VmapModelKind},
+            wmo::{WMO_ROOT_FLAG_LIQUID_TYPE_ID, WmoGroup, WmoLiquid, WmoRoot},
+        },
+        math::Vec3,
+    };
+
+    fn root() -> WmoRoot {
+        WmoRoot {
+            group_count: 2,
+            wmo_id: 77,
+            bounds_min: [0.0; 3],
+            bounds_max: [0.0; 3],
+            flags: WMO_ROOT_FLAG_LIQUID_TYPE_ID,
+            doodad_sets: Vec::new(),
+            doodads: Vec::new(),
+        }
+    }
+
+    fn group(group_id: u32, materials: Vec<(u8, u8)>, liquid: Option<WmoLiquid>) -> WmoGroup {
+        WmoGroup {
+            flags: 0x2000,
+            bounds_min: [0.0; 3],
+            bounds_max: [0.0; 3],
+            group_liquid: 13,
+            group_id,
+            vertices: vec![
+                [0.0, 0.0, 0.0],
+                [1.0, 0.0, 0.0],
+                [0.0, 1.0, 1.0],
+                [1.0, 1.0, 0.0],
+            ],
+            indices: vec![0, 1, 2, 1, 3, 2],
+            materials,
+            liquid,
+        }
+    }
+
+    #[test]
+    fn test_m2_model() {
+        // A degenerate triangle, one with an index out of range and an
+        // unreferenced vertex are all dropped
+        let collision = M2Collision {
+            vertices: vec![
+                [5.0, 5.0, 5.0],
+                [0.0, 0.0, 0.0],
+                [2.0, 0.0, 0.0],
+                [0.0, 3.0, 1.0],
+            ],
+            indices: vec![1, 1, 2, 1, 2, 9, 3, 2, 1],
+        };
+
+        let model = m2_model(&collision).unwrap();
+        assert_eq!(model.kind, VmapModelKind::M2);
+        assert_eq!(model.groups.len(), 1);
+        assert_eq!(model.groups[0].triangles, [[0, 1, 2]]);
+        assert_eq!(
+            model.groups[0].vertices,
+            [
+                Vec3::new(0.0, 3.0, 1.0),
+                Vec3::new(2.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 0.0)
+            ]
+        );
+        assert_eq!(model.bounds.min, Vec3::ZERO);
+        assert_eq!(model.bounds.max, Vec3::new(2.0, 3.0, 1.0));
+        assert_eq!(VmapModel::read(&model.write()).unwrap(), model);
+
+        let degenerate = M2Collision {
+            vertices: collision.vertices.clone(),
+            indices: vec![1, 1, 2],
+        };
+        assert!(m2_model(&degenerate).is_none());
+        assert!(m2_model(&M2Collision::default()).is_none());
+    }
+
+    #[test]
+    fn test_wmo_model() {
+        let liquid = WmoLiquid {
+            tiles_x: 1,
+            tiles_y: 2,
+            corner: [-4.0, 0.0, 0.0],
+            material: 0,
+            heights: vec![0.5, 0.5, 0.5, 0.5, 2.0, 2.0],
+            flags: vec![0, 0x0F],
+        };
+
+        // Render only detail geometry does not collide, the second group
+        // has nothing but liquid and the third has nothing at all
+        let groups = [
+            group(1, vec![(0x08, 0), (0x24, 0)], None),
+            group(2, vec![(0x24, 0), (0x24, 0)], Some(liquid)),
+            group(3, vec![(0x24, 0), (0x24, 0)], None),
+        ];
+
+        let model = wmo_model(&root(), &groups).unwrap();
+        assert_eq!(model.kind, VmapModelKind::Wmo);
+        assert_eq!(model.root_id, 77);
+        assert_eq!(model.flags, WMO_ROOT_FLAG_LIQUID_TYPE_ID as u32);
+        assert_eq!(
+            model.groups.iter().map(|g| g.group_id).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert_eq!(model.groups[0].triangles.len(), 1);
+        assert!(model.groups[0].liquid.is_none());
+
+        // Liquid tiles are 4.1666 units wide, the liquid bounds take part
+        // in the group and model bounds
+        let water = &model.groups[1];
+        assert!(water.triangles.is_empty());
+        assert_eq!(water.liquid.as_ref().unwrap().liquid_type, 13);
+        assert_eq!(water.bounds.min, Vec3::new(-4.0, 0.0, 0.5));
+        assert!((water.bounds.max.y - 2.0 * 533.333_3 / 128.0).abs() < 1e-3);
+        assert_eq!(model.bounds.min, Vec3::new(-4.0, 0.0, 0.0));
+        assert_eq!(model.bounds.max.z, 2.0);
+        assert_eq!(VmapModel::read(&model.write()).unwrap(), model);
+
+        assert!(wmo_model(&root(), &groups[2..]).is_none());
+    }
+}
@@ -0,0 +1,211 @@
+use crate::model::{ModelCache, ModelInfo};
+use tc_core::{
+    files::{
+        adt::{AdtDoodadPlacement, AdtWmoPlacement},
+        m2::m2_path,
+        vmap::{VmapModelKind, VmapPlacement, placement_position, placement_rotation},
+        wmo::WmoDoodad,
+    },
+    math::{Mat3, Vec3},
+};
+
+// Top bits of a placement id tell apart the id spaces of ADT doodads, ADT
+// WMOs and doodads spawned from a WMO's doodad set
+const ID_KIND_DOODAD: u64 = 0;
+const ID_KIND_WMO: u64 = 1;
+const ID_KIND_WMO_DOODAD: u64 = 2;
+
+fn placement_id(kind: u64, unique_id: u32, doodad_index: usize) -> u64 {
+    kind << 62 | (doodad_index as u64 & 0x3FFF_FFFF) << 32 | unique_id as u64
+}
+
+fn placement(
+    id: u64,
+    info: &ModelInfo,
+    flags: u16,
+    name_set: u16,
+    position: Vec3,
+    rotation: Mat3,
+    scale: f32,
+) -> Option<VmapPlacement> {
+    Some(VmapPlacement {
+        id,
+        kind: info.kind,
+        flags,
+        name_set,
+        model: info.file_name.clone()?,
+        position,
+        rotation,
+        scale,
+        bounds: info.bounds.transform(&rotation, scale, position),
+    })
+}
+
+pub fn doodad_placements(
+    models: &ModelCache,
+    names: &[String],
+    doodads: &[AdtDoodadPlacement],
+) -> Vec<VmapPlacement> {
+    doodads
+        .iter()
+        .filter_map(|doodad| {
+            let name = names.get(doodad.name_id as usize)?;
+            let info = models.get(&m2_path(name), VmapModelKind::M2)?;
+
+            placement(
+                placement_id(ID_KIND_DOODAD, doodad.unique_id, 0),
+                &info,
+                doodad.flags,
+                0,
+                placement_position(doodad.position),
+                placement_rotation(doodad.rotation),
+                doodad.scale(),
+            )
+        })
+        .collect()
+}
+
+// Doodad transforms are relative to the WMO's model space
+fn wmo_doodad_transform(doodad: &WmoDoodad, position: Vec3, rotation: Mat3) -> (Vec3, Mat3) {
+    let [x, y, z, w] = doodad.rotation;
+    let local = Mat3::from_quaternion(x, y, z, w);
+    (
+        rotation * Vec3::from_array(doodad.position) + position,
+        rotation * local,
+    )
+}
+
+// WMO placements along with the doodads of their active doodad sets
+pub fn wmo_placements(
+    models: &ModelCache,
+    names: &[String],
+    wmos: &[AdtWmoPlacement],
+) -> Vec<VmapPlacement> {
+    let mut placements = Vec::new();
+    for wmo in wmos {
+        let Some(info) = names
+            .get(wmo.name_id as usize)
+            .and_then(|name| models.get(name, VmapModelKind::Wmo))
+        else {
+            continue;
+        };
+
+        let position = placement_position(wmo.position);
+        let rotation = placement_rotation(wmo.rotation);
+        placements.extend(placement(
+            placement_id(ID_KIND_WMO, wmo.unique_id, 0),
+            &info,
+            wmo.flags,
+            wmo.name_set,
+            position,
+            rotation,
+            1.0,
+        ));
+
+        let Some(root) = &info.root else {
+            continue;
+        };
+
+        for (index, doodad) in root.doodads_in_set(wmo.doodad_set) {
+            let Some(doodad_info) = models.get(&m2_path(&doodad.name), VmapModelKind::M2) else {
+                continue;
+            };
+
+            let (doodad_position, doodad_rotation) =
+                wmo_doodad_transform(doodad, position, rotation);
+            placements.extend(placement(
+                placement_id(ID_KIND_WMO_DOODAD, wmo.unique_id, index),
+                &doodad_info,
+                0,
+                wmo.name_set,
+                doodad_position,
+                doodad_rotation,
+                doodad.scale,
+            ));
+        }
+    }
+
+    placements
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        model::ModelInfo,
+        placement::{ID_KIND_WMO_DOODAD, placement, placement_id, wmo_doodad_transform},
+    };
+    use std::f32::consts::FRAC_PI_2;
+    use tc_core::{
+        files::{
+            vmap::{VmapModelKind, VmapPlacementSet, placement_rotation},
+            wmo::WmoDoodad,
+        },
+        math::{Aabb, Mat3, Vec3},
+    };
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1e-4
+    }
+
+    fn info(file_name: Option<&str>) -> ModelInfo {
+        ModelInfo {
+            kind: VmapModelKind::M2,
+            file_name: file_name.map(str::to_string),
+            bounds: Aabb::new(Vec3::ZERO, Vec3::new(2.0, 1.0, 3.0)),
+            root: None,
+        }
+    }
+
+    #[test]
+    fn test_placement_id() {
+        let id = placement_id(ID_KIND_WMO_DOODAD, 0xDEAD_BEEF, 5);
+        assert_eq!(id >> 62, ID_KIND_WMO_DOODAD);
+        assert_eq!((id >> 32) & 0x3FFF_FFFF, 5);
+        assert_eq!(id as u32, 0xDEAD_BEEF);
+    }
+
+    #[test]
+    fn test_placement_bounds() {
+        let rotation = placement_rotation([0.0, 90.0, 0.0]);
+        let position = Vec3::new(100.0, 200.0, 10.0);
+        let placed =
+            placement(1, &info(Some("tree.m2.vmo")), 4, 2, position, rotation, 2.0).unwrap();
+
+        // The 2x1x3 box doubled and turned so model x runs along world -y
+        // and model y along world x
+        assert!(close(placed.bounds.min, Vec3::new(100.0, 196.0, 10.0)));
+        assert!(close(placed.bounds.max, Vec3::new(102.0, 200.0, 16.0)));
+        assert_eq!((placed.flags, placed.name_set, placed.scale), (4, 2, 2.0));
+
+        let set = VmapPlacementSet {
+            placements: vec![placed],
+        };
+        assert_eq!(VmapPlacementSet::read(&set.write()).unwrap(), set);
+
+        // Models without collision are not placed
+        assert!(placement(1, &info(None), 0, 0, position, rotation, 1.0).is_none());
+    }
+
+    #[test]
+    fn test_wmo_doodad_transform() {
+        let (s, c) = (FRAC_PI_2 / 2.0).sin_cos();
+        let doodad = WmoDoodad {
+            name: "chair.m2".to_string(),
+            position: [1.0, 0.0, 2.0],
+            rotation: [0.0, 0.0, s, c],
+            scale: 1.0,
+        };
+
+        // The WMO is turned a quarter around z, the doodad another quarter
+        let (position, rotation) = wmo_doodad_transform(
+            &doodad,
+            Vec3::new(10.0, 20.0, 30.0),
+            Mat3::rotation_z(FRAC_PI_2),
+        );
+        assert!(close(position, Vec3::new(10.0, 21.0, 32.0)));
+        assert!(close(
+            rotation * Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(-1.0, 0.0, 0.0)
+        ));
+    }
+}